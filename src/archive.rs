@@ -0,0 +1,366 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use tempfile::NamedTempFile;
+
+use crate::digest::{check_digest, sha256_digest, HashingReader};
+use crate::image::ImageConfig;
+use crate::manifest::{
+    Descriptor, ImageManifest, DOCKER_CONFIG_V1, DOCKER_LAYER_GZIP, DOCKER_LAYER_TAR,
+    DOCKER_MANIFEST_V2,
+};
+use crate::platform::Platform;
+use crate::reference::{familiar_name, ImageReference};
+use crate::store::{Store, StoredImage};
+
+/// One image in a docker-archive's `manifest.json`
+///
+/// See: https://github.com/moby/moby/blob/master/image/spec/v1.2.md#combined-image-json--filesystem-changeset-format
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ArchiveImage {
+    /// Path of the config within the archive
+    pub config: String,
+    /// Docker writes `null` for images that are only known by digest
+    #[serde(default)]
+    pub repo_tags: Option<Vec<String>>,
+    /// Paths of each layer's uncompressed tar within the archive, in manifest order
+    pub layers: Vec<String>,
+}
+
+/// Writes stored images to `output` as a tar in the format `docker save` writes and
+/// `docker load` reads
+///
+/// Layers are decompressed on the way out, since that's what the format calls for and what the
+/// config's diff IDs are computed over. Layers shared between the images are only written once.
+pub fn save_docker_archive(
+    store: &Store,
+    images: &[ImageReference],
+    output: impl Write,
+) -> Result<()> {
+    let _blobs = store.share_blobs()?;
+    // Nothing's written unless every image is there, rather than leaving half an archive
+    let mut stored_images = Vec::new();
+    for image in images {
+        let stored = store
+            .lookup(image)?
+            .with_context(|| format!("No such image: {}, pull it first", image))?;
+        stored_images.push((image, stored));
+    }
+
+    let mut archive = tar::Builder::new(output);
+    let mut entries: Vec<ArchiveImage> = Vec::new();
+    let mut written = HashSet::new();
+    for (image, stored) in stored_images {
+        let manifest = store.image_manifest(&stored)?;
+        let raw_config = store.read_blob(&manifest.config.digest)?;
+        let config: ImageConfig = serde_json::from_slice(&raw_config)
+            .with_context(|| format!("Tried to parse the config of {}", image))?;
+
+        let config_path = format!("{}.json", hex(&manifest.config.digest));
+        let repo_tags = match &image.digest {
+            Some(_) => Vec::new(),
+            None => vec![format!(
+                "{}:{}",
+                familiar_name(&image.registry, &image.repository),
+                image.tag()
+            )],
+        };
+        // Several tags of one image share its entry
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.config == config_path) {
+            let tags = entry.repo_tags.get_or_insert_with(Vec::new);
+            for tag in repo_tags {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            continue;
+        }
+
+        if written.insert(config_path.clone()) {
+            append_file(
+                &mut archive,
+                &config_path,
+                &mut io::Cursor::new(&raw_config),
+            )?;
+        }
+        let mut layers = Vec::new();
+        for (layer, diff_id) in manifest.layers.iter().zip(&config.rootfs.diff_ids) {
+            let layer_path = format!("{}/layer.tar", hex(diff_id));
+            if written.insert(layer_path.clone()) {
+                append_layer(&mut archive, store, layer, diff_id, &layer_path).with_context(
+                    || format!("Tried to save layer {} of {}", layer.digest, image),
+                )?;
+            }
+            layers.push(layer_path);
+        }
+        entries.push(ArchiveImage {
+            config: config_path,
+            repo_tags: (!repo_tags.is_empty()).then_some(repo_tags),
+            layers,
+        });
+    }
+
+    let raw_manifest = serde_json::to_vec(&entries)?;
+    append_file(
+        &mut archive,
+        "manifest.json",
+        &mut io::Cursor::new(&raw_manifest),
+    )?;
+    archive
+        .into_inner()
+        .context("Tried to finish writing the archive")?
+        .flush()
+        .context("Tried to finish writing the archive")
+}
+
+/// An image `load` put in the store
+#[derive(Debug)]
+pub struct LoadedImage {
+    /// The config's digest
+    pub id: String,
+    pub tags: Vec<ImageReference>,
+}
+
+/// A file read out of an archive into the store's `downloads/`, not yet a blob
+struct ArchiveFile {
+    digest: String,
+    size: u64,
+    file: NamedTempFile,
+}
+
+/// Imports every image in a docker-archive tar, like `docker save` writes, into the store and
+/// tags them with the archive's `RepoTags`
+///
+/// Each file is hashed as it's read, since the archive can't be seeked and `manifest.json` can
+/// be anywhere in it, and only the ones an image refers to end up as blobs. Layers are checked
+/// against their config's diff IDs before anything is tagged. Each image gets a docker schema 2
+/// manifest written for it, since the format has none.
+pub fn load_docker_archive(store: &Store, input: impl Read) -> Result<Vec<LoadedImage>> {
+    let _blobs = store.share_blobs()?;
+    let downloads = store.root().join("downloads");
+    let mut files: HashMap<PathBuf, ArchiveFile> = HashMap::new();
+    let mut links: HashMap<PathBuf, PathBuf> = HashMap::new();
+
+    let mut archive = tar::Archive::new(input);
+    for entry in archive.entries().context("Tried to read the archive")? {
+        let mut entry = entry.context("Tried to read the archive")?;
+        let path = normalize(&entry.path()?);
+        match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let mut file = NamedTempFile::new_in(&downloads).with_context(|| {
+                    format!("Tried to create a file in {}", downloads.display())
+                })?;
+                let mut reader = HashingReader::new(&mut entry);
+                let size = io::copy(&mut reader, &mut file).with_context(|| {
+                    format!("Tried to read {} from the archive", path.display())
+                })?;
+                let digest = reader.finalize();
+                files.insert(path, ArchiveFile { digest, size, file });
+            }
+            // Older versions of docker link layers that are shared between images
+            tar::EntryType::Symlink => {
+                if let Some(target) = entry.link_name()? {
+                    let parent = path.parent().unwrap_or(Path::new(""));
+                    links.insert(path.clone(), normalize(&parent.join(target)));
+                }
+            }
+            tar::EntryType::Link => {
+                if let Some(target) = entry.link_name()? {
+                    links.insert(path, normalize(&target));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let lookup = |path: &str| {
+        let mut path = normalize(Path::new(path));
+        // Links can point at links, but not forever
+        for _ in 0..8 {
+            match links.get(&path) {
+                Some(target) => path = target.clone(),
+                None => break,
+            }
+        }
+        files
+            .get(&path)
+            .with_context(|| format!("The archive doesn't have {}", path.display()))
+    };
+    let manifest_file = lookup("manifest.json")
+        .context("Only docker-archive tars (as written by docker save) can be loaded")?;
+    let raw_manifest = std::fs::read(manifest_file.file.path())?;
+    let entries: Vec<ArchiveImage> = serde_json::from_slice(&raw_manifest)
+        .context("Tried to parse the archive's manifest.json")?;
+
+    let mut loaded = Vec::new();
+    for entry in entries {
+        let config_file = lookup(&entry.config)?;
+        let raw_config = std::fs::read(config_file.file.path())?;
+        let config: ImageConfig = serde_json::from_slice(&raw_config)
+            .with_context(|| format!("Tried to parse {} from the archive", entry.config))?;
+        if config.rootfs.diff_ids.len() != entry.layers.len() {
+            bail!(
+                "{} lists {} diff IDs but the archive has {} layers for it",
+                entry.config,
+                config.rootfs.diff_ids.len(),
+                entry.layers.len()
+            );
+        }
+
+        let mut layers = Vec::new();
+        for (path, diff_id) in entry.layers.iter().zip(&config.rootfs.diff_ids) {
+            let layer_file = lookup(path)?;
+            let mut magic = [0; 2];
+            let compressed = std::fs::File::open(layer_file.file.path())?
+                .read_exact(&mut magic)
+                .is_ok()
+                && magic == [0x1f, 0x8b];
+            let layer = Descriptor {
+                media_type: match compressed {
+                    true => DOCKER_LAYER_GZIP,
+                    false => DOCKER_LAYER_TAR,
+                }
+                .to_string(),
+                digest: layer_file.digest.clone(),
+                size: layer_file.size,
+                urls: Vec::new(),
+                platform: None,
+                annotations: BTreeMap::new(),
+            };
+            insert_blob(store, layer_file)?;
+            let actual_diff_id = match compressed {
+                true => store.diff_id(&layer)?,
+                false => layer.digest.clone(),
+            };
+            check_digest(&actual_diff_id, diff_id)
+                .with_context(|| format!("{} doesn't match its diff ID", path))?;
+            layers.push(layer);
+        }
+        insert_blob(store, config_file)?;
+
+        let config_descriptor = Descriptor {
+            media_type: DOCKER_CONFIG_V1.to_string(),
+            digest: config_file.digest.clone(),
+            size: config_file.size,
+            urls: Vec::new(),
+            platform: None,
+            annotations: BTreeMap::new(),
+        };
+        let stored = write_manifest(store, config_descriptor, layers, &config)?;
+        let mut tags = Vec::new();
+        for tag in entry.repo_tags.unwrap_or_default() {
+            let image = ImageReference::parse(&tag)
+                .with_context(|| format!("Tried to parse RepoTag {} from the archive", tag))?;
+            store.tag(&image, &stored)?;
+            tags.push(image);
+        }
+        loaded.push(LoadedImage {
+            id: config_file.digest.clone(),
+            tags,
+        });
+    }
+    Ok(loaded)
+}
+
+/// Writes a docker schema 2 manifest for a config and layers that are already in the store, for
+/// images that didn't come with one
+pub fn write_manifest(
+    store: &Store,
+    config_descriptor: Descriptor,
+    layers: Vec<Descriptor>,
+    config: &ImageConfig,
+) -> Result<StoredImage> {
+    let manifest = ImageManifest {
+        schema_version: 2,
+        media_type: Some(DOCKER_MANIFEST_V2.to_string()),
+        config: config_descriptor,
+        layers,
+    };
+    let raw_manifest = serde_json::to_vec(&manifest)?;
+    let manifest_digest = sha256_digest(&raw_manifest);
+    store.write_blob(&manifest_digest, &raw_manifest)?;
+
+    Ok(StoredImage {
+        digest: manifest_digest.clone(),
+        manifest: manifest_digest,
+        platform: Platform {
+            os: config.os.clone(),
+            architecture: config.architecture.clone(),
+            variant: config.variant.clone(),
+        }
+        .to_string(),
+    })
+}
+
+/// Moves a file read out of an archive into place as a blob, unless the store already has it
+fn insert_blob(store: &Store, file: &ArchiveFile) -> Result<()> {
+    if store.has_blob(&file.digest)? {
+        return Ok(());
+    }
+    store.link_blob(&file.digest, file.file.path())
+}
+
+/// Resolves `.` and `..` in a path within the archive, without looking at the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+/// Decompresses a layer into the archive, checking it against its diff ID on the way
+fn append_layer(
+    archive: &mut tar::Builder<impl Write>,
+    store: &Store,
+    layer: &Descriptor,
+    diff_id: &str,
+    path: &str,
+) -> Result<()> {
+    let decoder = store
+        .open_layer(layer)
+        .context("Foreign layers can only be saved once they've been downloaded")?;
+    let mut decoder = HashingReader::new(decoder);
+
+    // Tar headers come before the data, so the size has to be known before it's written. Layers
+    // can be big, so it's kept on the store's disk rather than in /tmp.
+    let downloads = store.root().join("downloads");
+    let mut decompressed = tempfile::tempfile_in(&downloads)
+        .with_context(|| format!("Tried to create a file in {}", downloads.display()))?;
+    io::copy(&mut decoder, &mut decompressed).context("Tried to decompress")?;
+    let (decoder, actual_diff_id) = decoder.into_inner();
+    decoder.finish()?;
+    check_digest(&actual_diff_id, diff_id).context("Layer doesn't match its diff ID")?;
+    append_file(archive, path, &mut decompressed)
+}
+
+/// Adds a file to the archive, after finding out how big it is
+fn append_file(
+    archive: &mut tar::Builder<impl Write>,
+    path: &str,
+    data: &mut (impl io::Read + Seek),
+) -> Result<()> {
+    let size = data.seek(SeekFrom::End(0))?;
+    data.seek(SeekFrom::Start(0))?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::Regular);
+    archive
+        .append_data(&mut header, path, data)
+        .with_context(|| format!("Tried to write {} into the archive", path))
+}
+
+/// The hex part of a digest, which is what archives name their files after
+fn hex(digest: &str) -> &str {
+    digest.trim_start_matches("sha256:")
+}
@@ -0,0 +1,492 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{CONTENT_TYPE, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::Read;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::credentials::Credentials;
+use crate::endpoint::Endpoint;
+use crate::reference::ImageReference;
+use crate::retry::{send_with_retries, RetryPolicy, RetryableStatus};
+
+/// Header registries use to say which version of the API they speak
+static DOCKER_DISTRIBUTION_API_VERSION: &str = "Docker-Distribution-API-Version";
+
+/// Token lifetime to assume when the token server doesn't say, per the spec
+const DEFAULT_TOKEN_LIFETIME: u64 = 60;
+
+/// Tokens this close to expiring are refreshed before use rather than risking a 401 mid-request
+const EXPIRY_MARGIN: u64 = 10;
+
+/// How a registry asked us to authenticate, from a 401's `WWW-Authenticate` header
+///
+/// See: https://distribution.github.io/distribution/spec/auth/token/#how-to-authenticate
+#[derive(Debug)]
+enum AuthChallenge {
+    /// Get a token from `realm` and send that instead of credentials
+    Bearer {
+        realm: String,
+        service: Option<String>,
+    },
+    /// Send the username and password with every request, which some private registries use
+    Basic,
+}
+
+impl AuthChallenge {
+    /// Parses a `WWW-Authenticate` header value like
+    /// `Bearer realm="https://auth.docker.io/token",service="registry.docker.io"`
+    fn parse(header: &str) -> Result<AuthChallenge> {
+        let (scheme, params) = header.trim().split_once(' ').unwrap_or((header, ""));
+        if scheme.eq_ignore_ascii_case("basic") {
+            return Ok(AuthChallenge::Basic);
+        }
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            bail!("Unsupported authentication scheme '{}'", scheme);
+        }
+
+        let param_re = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+        let mut realm = None;
+        let mut service = None;
+        for captures in param_re.captures_iter(params) {
+            match &captures[1] {
+                "realm" => realm = Some(captures[2].to_string()),
+                "service" => service = Some(captures[2].to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(AuthChallenge::Bearer {
+            realm: realm.context("No realm found in registry's auth challenge")?,
+            service,
+        })
+    }
+}
+
+/// Probes the registry's `/v2/` endpoint to find out how to authenticate against it
+///
+/// This is the first request we send any registry, so it's also where hosts that can't be reached
+/// or that don't speak the v2 API are caught. Returns `None` if the registry allows anonymous
+/// access.
+///
+/// See: https://distribution.github.io/distribution/spec/api/#api-version-check
+fn probe_registry(endpoint: &Endpoint, retry: &RetryPolicy) -> Result<Option<AuthChallenge>> {
+    let client = endpoint.client()?;
+    let response = send_with_retries(retry, "Probing registry", || {
+        Ok(client.get(endpoint.url("/v2/")))
+    })
+    .map_err(|e| endpoint.explain_unreachable(e))
+    .with_context(|| format!("Tried to reach registry {}", endpoint.host))?;
+
+    match response.status() {
+        StatusCode::UNAUTHORIZED => {}
+        StatusCode::NOT_FOUND => bail!(
+            "{} doesn't serve the registry API (GET /v2/ responded with 404), is it a v2 registry?",
+            endpoint.host
+        ),
+        status if status.is_success() => {
+            check_api_version(endpoint, &response)?;
+            return Ok(None);
+        }
+        // Not what the spec says to send, but some registries do and work fine anyway
+        _ => return Ok(None),
+    }
+
+    let header = response
+        .headers()
+        .get(WWW_AUTHENTICATE)
+        .context("Registry responded with 401 but did not send a WWW-Authenticate header")?
+        .to_str()
+        .context("Tried to read the registry's WWW-Authenticate header")?;
+    AuthChallenge::parse(header).map(Some)
+}
+
+/// Refuses hosts that answered `/v2/` with something other than the v2 API, like a web page
+///
+/// Registries should say which API they speak, though not all of them do.
+fn check_api_version(endpoint: &Endpoint, response: &Response) -> Result<()> {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    match (
+        header(DOCKER_DISTRIBUTION_API_VERSION),
+        header(CONTENT_TYPE.as_str()),
+    ) {
+        (Some(version), _) if !version.split_whitespace().any(|v| v == "registry/2.0") => bail!(
+            "{} speaks registry API {}, only registry/2.0 is supported",
+            endpoint.host,
+            version
+        ),
+        (None, Some(content_type)) if content_type.starts_with("text/html") => bail!(
+            "{} served a web page instead of the registry API, is it a v2 registry?",
+            endpoint.host
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that a registry accepts the given credentials, which is all `login` needs to do
+///
+/// A token is requested without any scope, matching what the docker CLI does when logging in.
+pub fn validate_credentials(
+    endpoint: &Endpoint,
+    credentials: &Credentials,
+    retry: &RetryPolicy,
+) -> Result<()> {
+    match probe_registry(endpoint, retry)? {
+        Some(AuthChallenge::Basic) => {
+            let client = endpoint.client()?;
+            let response = send_with_retries(retry, "Checking credentials", || {
+                Ok(client
+                    .get(endpoint.url("/v2/"))
+                    .basic_auth(&credentials.username, Some(&credentials.password)))
+            })?;
+            if response.status() == StatusCode::UNAUTHORIZED {
+                bail!(
+                    "Registry {} rejected the credentials for {}",
+                    endpoint.host,
+                    credentials.username
+                );
+            }
+            Ok(())
+        }
+        Some(challenge) => {
+            request_token(endpoint, &challenge, &[], Some(credentials), retry).map(|_| ())
+        }
+        None => {
+            eprintln!(
+                "Warning: {} does not require authentication, credentials were not checked",
+                endpoint.host
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Requests a token from the realm advertised in a registry's auth challenge
+///
+/// See: https://distribution.github.io/distribution/spec/auth/scope/
+fn request_token(
+    endpoint: &Endpoint,
+    challenge: &AuthChallenge,
+    scopes: &[String],
+    credentials: Option<&Credentials>,
+    retry: &RetryPolicy,
+) -> Result<CachedToken> {
+    let (realm, service) = match challenge {
+        AuthChallenge::Bearer { realm, service } => (realm, service),
+        AuthChallenge::Basic => bail!("Registry {} doesn't hand out tokens", endpoint.host),
+    };
+    let mut query = Vec::new();
+    for scope in scopes {
+        query.push(("scope", scope));
+    }
+    if let Some(service) = service {
+        query.push(("service", service));
+    }
+
+    // Private registries tend to serve their own token endpoint, so it gets the same TLS settings
+    let client = endpoint.client()?;
+    let auth_response = send_with_retries(retry, "Requesting auth token", || {
+        let request = client.get(realm).query(&query);
+        Ok(match credentials {
+            Some(c) => request.basic_auth(&c.username, Some(&c.password)),
+            None => request,
+        })
+    })
+    .map_err(|e| endpoint.explain_unreachable(e))
+    .context("Tried to request an auth token")?;
+    if auth_response.status() == StatusCode::UNAUTHORIZED {
+        bail!(
+            "Registry {} rejected the credentials for {}",
+            endpoint.host,
+            credentials
+                .map(|c| c.username.as_str())
+                .unwrap_or("anonymous")
+        );
+    }
+    let raw_data = auth_response
+        .bytes()
+        .context("Tried to read docker registry's auth response")?;
+    let parsed_response: TokenResponse = serde_json::from_slice(&raw_data)
+        .context("Tried to parse docker registry's auth response")?;
+
+    let token = parsed_response
+        .token
+        .or(parsed_response.access_token)
+        .context("No token found in registry's auth response (expected token or access_token)")?;
+    Ok(CachedToken {
+        token,
+        expires_at: now() + parsed_response.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME),
+    })
+}
+
+/// What a token server hands back, registries may use either field name for the token
+///
+/// See: https://distribution.github.io/distribution/spec/auth/token/#token-response-fields
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A bearer token along with when it stops being valid (in seconds since the epoch)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedToken {
+    token: String,
+    expires_at: u64,
+}
+
+impl CachedToken {
+    fn is_fresh(&self) -> bool {
+        self.expires_at > now() + EXPIRY_MARGIN
+    }
+}
+
+/// Tokens persisted across invocations, keyed by registry, scope, and who asked for them
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenCache {
+    tokens: HashMap<String, CachedToken>,
+}
+
+impl TokenCache {
+    fn path() -> Option<PathBuf> {
+        let cache_dir = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(cache_dir.join("minidocker").join("tokens.json"))
+    }
+
+    /// Loads the cache, a missing or unreadable cache is just an empty one
+    fn load() -> TokenCache {
+        let mut raw_data = String::new();
+        let loaded = TokenCache::path()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|mut file| file.read_to_string(&mut raw_data).ok())
+            .and_then(|_| serde_json::from_str(&raw_data).ok());
+        loaded.unwrap_or_default()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = TokenCache::path().context("Neither XDG_CACHE_HOME nor HOME are set")?;
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Tried to create {}", parent.display()))?;
+
+        // Write to a temporary file first so concurrent runs never see a half-written cache
+        let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .with_context(|| format!("Tried to open {}", temp_path.display()))?;
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        serde_json::to_writer(&mut file, self)
+            .with_context(|| format!("Tried to write {}", temp_path.display()))?;
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Tried to move token cache into {}", path.display()))
+    }
+
+    /// Remembers a token, dropping any that have expired while we're at it
+    fn store(key: &str, token: &CachedToken) {
+        let mut cache = TokenCache::load();
+        cache.tokens.retain(|_, t| t.is_fresh());
+        cache.tokens.insert(key.to_string(), token.clone());
+        if let Err(e) = cache.save() {
+            eprintln!("Warning: failed to cache registry token: {:#}", e);
+        }
+    }
+}
+
+/// Authentication state for pulling from (or pushing to) a single repository
+///
+/// Tokens are cached per (registry, repository, scope, user) both in memory and on disk, are
+/// refreshed shortly before they expire, and can be refreshed on demand when the registry rejects
+/// one with a 401. Registries that allow anonymous access never get a token, and ones that want
+/// basic auth get our credentials with every request instead.
+pub struct RepositoryAuth {
+    endpoint: Endpoint,
+    scopes: Vec<String>,
+    credentials: Option<Credentials>,
+    retry: RetryPolicy,
+    token: Mutex<Option<CachedToken>>,
+    basic: AtomicBool,
+}
+
+impl RepositoryAuth {
+    pub fn new(
+        image: &ImageReference,
+        endpoint: Endpoint,
+        credentials: Option<Credentials>,
+        retry: &RetryPolicy,
+    ) -> Result<RepositoryAuth> {
+        let scopes = vec![format!("repository:{}:pull", image.repository)];
+        RepositoryAuth::with_scopes(endpoint, scopes, credentials, retry)
+    }
+
+    /// Authentication for pushing, which needs to pull too in order to check for existing blobs
+    ///
+    /// Blobs can only be mounted from repositories we're allowed to pull from, so those are
+    /// included in the token's scope as well.
+    pub fn for_push(
+        image: &ImageReference,
+        mount_from: &[String],
+        endpoint: Endpoint,
+        credentials: Option<Credentials>,
+        retry: &RetryPolicy,
+    ) -> Result<RepositoryAuth> {
+        let mut scopes = vec![format!("repository:{}:pull,push", image.repository)];
+        scopes.extend(
+            mount_from
+                .iter()
+                .map(|repository| format!("repository:{}:pull", repository)),
+        );
+        RepositoryAuth::with_scopes(endpoint, scopes, credentials, retry)
+    }
+
+    fn with_scopes(
+        endpoint: Endpoint,
+        scopes: Vec<String>,
+        credentials: Option<Credentials>,
+        retry: &RetryPolicy,
+    ) -> Result<RepositoryAuth> {
+        let auth = RepositoryAuth {
+            endpoint,
+            scopes,
+            credentials,
+            retry: retry.clone(),
+            token: Mutex::new(None),
+            basic: AtomicBool::new(false),
+        };
+
+        match TokenCache::load().tokens.remove(&auth.cache_key()) {
+            Some(token) if token.is_fresh() => *auth.token.lock().unwrap() = Some(token),
+            _ => auth.refresh()?,
+        }
+        Ok(auth)
+    }
+
+    /// The registry this repository lives on
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    fn cache_key(&self) -> String {
+        let user = self
+            .credentials
+            .as_ref()
+            .map(|c| c.username.as_str())
+            .unwrap_or("anonymous");
+        format!("{}|{}|{}", self.endpoint.host, self.scopes.join(" "), user)
+    }
+
+    /// Discards the current token and asks the registry for a new one
+    ///
+    /// See: https://distribution.github.io/distribution/spec/auth/token/
+    pub fn refresh(&self) -> Result<()> {
+        let challenge = probe_registry(&self.endpoint, &self.retry)?;
+        self.basic.store(
+            matches!(challenge, Some(AuthChallenge::Basic)),
+            Ordering::SeqCst,
+        );
+        let token = match challenge {
+            Some(AuthChallenge::Basic) if self.credentials.is_none() => bail!(
+                "Registry {} requires a username and password, run login first",
+                self.endpoint.host
+            ),
+            Some(AuthChallenge::Basic) | None => None,
+            Some(challenge) => {
+                let token = request_token(
+                    &self.endpoint,
+                    &challenge,
+                    &self.scopes,
+                    self.credentials.as_ref(),
+                    &self.retry,
+                )?;
+                TokenCache::store(&self.cache_key(), &token);
+                Some(token)
+            }
+        };
+        *self.token.lock().unwrap() = token;
+        Ok(())
+    }
+
+    /// Adds the bearer token to a request (if the registry needs one), refreshing it first if
+    /// it's about to expire
+    pub fn authorize(&self, request: RequestBuilder) -> Result<RequestBuilder> {
+        if let (true, Some(credentials)) = (self.basic.load(Ordering::SeqCst), &self.credentials) {
+            return Ok(request.basic_auth(&credentials.username, Some(&credentials.password)));
+        }
+        let expired = matches!(&*self.token.lock().unwrap(), Some(token) if !token.is_fresh());
+        if expired {
+            self.refresh()?;
+        }
+        Ok(match &*self.token.lock().unwrap() {
+            Some(token) => request.bearer_auth(&token.token),
+            None => request,
+        })
+    }
+
+    /// Turns a 401 into a retryable error after refreshing the token, for requests that handle
+    /// their own retries
+    pub fn check_unauthorized(&self, response: &Response) -> Result<()> {
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.refresh()?;
+            return Err(RetryableStatus::from_response(response).into());
+        }
+        Ok(())
+    }
+
+    /// Sends an authorized request, retrying transient failures and refreshing the token once if
+    /// the registry rejects it
+    pub fn send(
+        &self,
+        description: &str,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response> {
+        self.try_send(description, || Ok(request()))
+    }
+
+    /// Like `send`, for requests that can fail to be built (e.g. because their body needs to be
+    /// opened first)
+    pub fn try_send(
+        &self,
+        description: &str,
+        request: impl Fn() -> Result<RequestBuilder>,
+    ) -> Result<Response> {
+        let response = send_with_retries(&self.retry, description, || self.authorize(request()?))
+            .map_err(|e| self.endpoint.explain_unreachable(e))?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        // The token may have been revoked or expired early, get a new one and try once more
+        self.refresh()?;
+        send_with_retries(&self.retry, description, || self.authorize(request()?))
+    }
+}
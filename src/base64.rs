@@ -0,0 +1,48 @@
+use anyhow::{bail, Result};
+
+static ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes bytes with the standard, padded base64 alphabet
+///
+/// See: https://datatracker.ietf.org/doc/html/rfc4648#section-4
+pub fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let group = u32::from_be_bytes([
+            0,
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard base64 (padding is optional)
+pub fn decode(encoded: &str) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut group = 0u32;
+    let mut bits = 0;
+    for c in encoded.trim().trim_end_matches('=').bytes() {
+        let value = match ALPHABET.iter().position(|&a| a == c) {
+            Some(value) => value as u32,
+            None => bail!("Invalid base64 character '{}'", c as char),
+        };
+        group = (group << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((group >> bits) as u8);
+            group &= (1 << bits) - 1;
+        }
+    }
+    Ok(decoded)
+}
@@ -0,0 +1,642 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::driver::is_filesystem;
+
+/// Where `run` records the container's cgroup, so `rm` can remove it if `run` didn't get to
+pub static CGROUP_RECORD: &str = "cgroup";
+
+/// From `linux/magic.h`, which the libc crate doesn't have
+const CGROUP2_SUPER_MAGIC: libc::c_long = 0x6367_7270;
+
+/// From `linux/bpf.h`, for the eBPF program cgroup2 limits devices with, which the libc crate
+/// doesn't have
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_ATTACH: libc::c_long = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+const BPF_F_ALLOW_MULTI: u32 = 0x2;
+const BPF_DEVCG_DEV_BLOCK: i32 = 1;
+const BPF_DEVCG_DEV_CHAR: i32 = 2;
+const BPF_DEVCG_ACC_MKNOD: i32 = 1;
+const BPF_DEVCG_ACC_READ: i32 = 2;
+const BPF_DEVCG_ACC_WRITE: i32 = 4;
+
+/// eBPF opcodes, the few the device program needs
+const LDX_MEM_W: u8 = 0x61;
+const ALU64_AND_K: u8 = 0x57;
+const ALU64_RSH_K: u8 = 0x77;
+const ALU64_MOV_K: u8 = 0xb7;
+const ALU64_MOV_X: u8 = 0xbf;
+const JMP_JNE_K: u8 = 0x55;
+const JMP_JNE_X: u8 = 0x5d;
+const JMP_EXIT: u8 = 0x95;
+
+/// `struct bpf_insn`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Instruction {
+    code: u8,
+    /// The destination register in the low four bits, the source in the high four
+    registers: u8,
+    offset: i16,
+    immediate: i32,
+}
+
+/// The `BPF_PROG_LOAD` part of `union bpf_attr`
+#[repr(C)]
+struct ProgramLoad {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+/// The `BPF_PROG_ATTACH` part of `union bpf_attr`
+#[repr(C)]
+struct ProgramAttach {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Char,
+    Block,
+}
+
+/// A device (or every device of a major number, or of a type) a container may use, and how
+///
+/// See: https://docs.kernel.org/admin-guide/cgroup-v1/devices.html
+#[derive(Debug, Clone)]
+pub struct DeviceRule {
+    /// `None` for both
+    pub kind: Option<DeviceType>,
+    /// `None` for all of them
+    pub major: Option<u32>,
+    pub minor: Option<u32>,
+    /// Some of `r`, `w`, and `m`
+    pub access: String,
+}
+
+impl DeviceRule {
+    fn new(
+        kind: Option<DeviceType>,
+        major: Option<u32>,
+        minor: Option<u32>,
+        access: &str,
+    ) -> DeviceRule {
+        DeviceRule {
+            kind,
+            major,
+            minor,
+            access: access.to_string(),
+        }
+    }
+
+    /// Like `c 1:3 rwm`, what `devices.allow` takes, one for each type a rule without one is for
+    ///
+    /// That's rather than `a`, which is every device whatever numbers it's given.
+    fn to_v1(&self) -> Vec<String> {
+        let number = |number: Option<u32>| match number {
+            Some(number) => number.to_string(),
+            None => String::from("*"),
+        };
+        let kinds = match self.kind {
+            Some(kind) => vec![kind],
+            None => vec![DeviceType::Char, DeviceType::Block],
+        };
+        kinds
+            .into_iter()
+            .map(|kind| {
+                let kind = match kind {
+                    DeviceType::Char => 'c',
+                    DeviceType::Block => 'b',
+                };
+                format!(
+                    "{} {}:{} {}",
+                    kind,
+                    number(self.major),
+                    number(self.minor),
+                    self.access
+                )
+            })
+            .collect()
+    }
+}
+
+/// What every container may use: the devices in its `/dev` and its terminals, and making (but
+/// not opening) device nodes of any kind, which are runc's defaults
+///
+/// See: https://github.com/opencontainers/runc/blob/main/libcontainer/specconv/spec_linux.go
+pub fn default_device_rules() -> Vec<DeviceRule> {
+    let char = |major, minor| DeviceRule::new(Some(DeviceType::Char), Some(major), minor, "rwm");
+    vec![
+        DeviceRule::new(None, None, None, "m"),
+        // null, zero, full, random, and urandom
+        char(1, Some(3)),
+        char(1, Some(5)),
+        char(1, Some(7)),
+        char(1, Some(8)),
+        char(1, Some(9)),
+        // tty, console, and ptmx
+        char(5, Some(0)),
+        char(5, Some(1)),
+        char(5, Some(2)),
+        // Its devpts terminals
+        char(136, None),
+    ]
+}
+
+/// Which cgroup hierarchy limits devices, with where it's mounted
+enum Hierarchy {
+    /// cgroup2, with an eBPF program attached to the cgroup
+    Unified(PathBuf),
+    /// cgroup v1's `devices` controller, with `devices.allow` and `devices.deny`
+    Devices(PathBuf),
+}
+
+impl Hierarchy {
+    /// The one the host uses, which for hybrid hosts (with both) is v1's, that's the one their
+    /// devices are limited by
+    fn find() -> Result<Hierarchy> {
+        let root = Path::new("/sys/fs/cgroup");
+        if is_filesystem(root, CGROUP2_SUPER_MAGIC)? {
+            return Ok(Hierarchy::Unified(root.to_path_buf()));
+        }
+        let devices = root.join("devices");
+        if devices.is_dir() {
+            return Ok(Hierarchy::Devices(devices));
+        }
+        let unified = root.join("unified");
+        if unified.is_dir() && is_filesystem(&unified, CGROUP2_SUPER_MAGIC)? {
+            return Ok(Hierarchy::Unified(unified));
+        }
+        bail!("Neither cgroup2 nor the devices controller are mounted on /sys/fs/cgroup")
+    }
+
+    /// Where the cgroup we're in is, going by `/proc/self/cgroup`
+    fn current(&self) -> Result<PathBuf> {
+        let cgroups =
+            fs::read_to_string("/proc/self/cgroup").context("Tried to read /proc/self/cgroup")?;
+        for line in cgroups.lines() {
+            let mut fields = line.splitn(3, ':');
+            let (id, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(id), Some(controllers), Some(path)) => (id, controllers, path),
+                _ => continue,
+            };
+            let (root, matches) = match self {
+                Hierarchy::Unified(root) => (root, id == "0" && controllers.is_empty()),
+                Hierarchy::Devices(root) => (
+                    root,
+                    controllers
+                        .split(',')
+                        .any(|controller| controller == "devices"),
+                ),
+            };
+            if matches {
+                return Ok(root.join(path.trim_start_matches('/')));
+            }
+        }
+        bail!("/proc/self/cgroup doesn't say which cgroup we're in")
+    }
+}
+
+/// A cgroup of the container's own, under the one we're in, which limits the devices it can use
+///
+/// Only the container's command (and what it runs) is in it, not us. Created before
+/// `pivot_root`, the host's `/sys/fs/cgroup` is out of reach after it, so what's needed to join
+/// it and to remove it is opened beforehand.
+pub struct Cgroup {
+    path: PathBuf,
+    /// The cgroup we're in
+    parent: File,
+    name: CString,
+    procs: File,
+}
+
+impl Cgroup {
+    /// Creates the cgroup, recording where it is in the container's directory
+    pub fn create(container: &Path, rules: &[DeviceRule]) -> Result<Cgroup> {
+        let hierarchy = Hierarchy::find()?;
+        let parent_path = hierarchy.current()?;
+        let id = container.file_name().unwrap().to_string_lossy();
+        let name = format!("minidocker-{}", id);
+        let path = parent_path.join(&name);
+        fs::create_dir(&path)
+            .with_context(|| format!("Tried to create cgroup {}", path.display()))?;
+        let cgroup = || -> Result<Cgroup> {
+            fs::write(container.join(CGROUP_RECORD), path.as_os_str().as_bytes())
+                .context("Tried to record the container's cgroup")?;
+            match &hierarchy {
+                Hierarchy::Unified(_) => attach_device_program(&path, rules)?,
+                Hierarchy::Devices(_) => limit_devices_v1(&path, rules)?,
+            }
+            let procs_path = path.join("cgroup.procs");
+            let procs = File::options()
+                .write(true)
+                .open(&procs_path)
+                .with_context(|| format!("Tried to open {}", procs_path.display()))?;
+            Ok(Cgroup {
+                parent: File::open(&parent_path)
+                    .with_context(|| format!("Tried to open {}", parent_path.display()))?,
+                name: CString::new(name.as_bytes())?,
+                procs,
+                path: path.clone(),
+            })
+        }();
+        if cgroup.is_err() {
+            let _ = fs::remove_dir(&path);
+        }
+        cgroup
+    }
+
+    /// What the container's command joins the cgroup with, from `pre_exec`
+    pub fn procs(&self) -> Result<CgroupProcs> {
+        let procs = self
+            .procs
+            .try_clone()
+            .with_context(|| format!("Tried to open {}", self.path.display()))?;
+        Ok(CgroupProcs(procs))
+    }
+
+    /// Best effort, once the container has exited. What's left of it (its command's children,
+    /// killed along with it) might still take a moment to leave.
+    pub fn remove(self) {
+        for _ in 0..50 {
+            let removed = unsafe {
+                libc::unlinkat(
+                    self.parent.as_raw_fd(),
+                    self.name.as_ptr(),
+                    libc::AT_REMOVEDIR,
+                )
+            } == 0;
+            if removed || io::Error::last_os_error().raw_os_error() != Some(libc::EBUSY) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// A cgroup's `cgroup.procs`
+pub struct CgroupProcs(File);
+
+impl CgroupProcs {
+    /// Moves the calling process into the cgroup
+    pub fn join(&self) -> io::Result<()> {
+        (&self.0).write_all(b"0")
+    }
+}
+
+/// Denies the cgroup every device but what the rules allow
+///
+/// See: https://docs.kernel.org/admin-guide/cgroup-v1/devices.html
+fn limit_devices_v1(cgroup: &Path, rules: &[DeviceRule]) -> Result<()> {
+    let deny = cgroup.join("devices.deny");
+    fs::write(&deny, "a").with_context(|| format!("Tried to write to {}", deny.display()))?;
+    let allow = cgroup.join("devices.allow");
+    for line in rules.iter().flat_map(DeviceRule::to_v1) {
+        fs::write(&allow, &line)
+            .with_context(|| format!("Tried to allow {} in {}", line, allow.display()))?;
+    }
+    Ok(())
+}
+
+/// Loads an eBPF program allowing the devices the rules do (and nothing else) and attaches it
+/// to the cgroup, which is how cgroup2 limits devices
+///
+/// The program gets the device's type and what's being done with it in one `u32`, then its major
+/// and minor numbers, and returns 1 to allow it. Other programs attached to the cgroups above
+/// this one still get their say.
+///
+/// See: https://docs.kernel.org/admin-guide/cgroup-v2.html#device-controller
+fn attach_device_program(cgroup: &Path, rules: &[DeviceRule]) -> Result<()> {
+    let instructions = device_program(rules);
+    // Only matters to the helpers only GPL programs may call, and this one calls none
+    let license = c"GPL";
+    let mut name = [0; 16];
+    name[..b"minidocker".len()].copy_from_slice(b"minidocker");
+    let load = ProgramLoad {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: instructions.len() as u32,
+        insns: instructions.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+        kern_version: 0,
+        prog_flags: 0,
+        prog_name: name,
+        prog_ifindex: 0,
+        expected_attach_type: BPF_CGROUP_DEVICE,
+    };
+    let program = bpf(BPF_PROG_LOAD, &load).context("Tried to load the device cgroup program")?;
+    let directory =
+        File::open(cgroup).with_context(|| format!("Tried to open {}", cgroup.display()))?;
+    let attach = ProgramAttach {
+        target_fd: directory.as_raw_fd() as u32,
+        attach_bpf_fd: program as u32,
+        attach_type: BPF_CGROUP_DEVICE,
+        attach_flags: BPF_F_ALLOW_MULTI,
+    };
+    let attached = bpf(BPF_PROG_ATTACH, &attach);
+    // The cgroup holds on to the program once it's attached
+    unsafe {
+        libc::close(program);
+    }
+    attached
+        .with_context(|| format!("Tried to attach the device program to {}", cgroup.display()))?;
+    Ok(())
+}
+
+fn bpf<T>(command: libc::c_long, attr: &T) -> io::Result<libc::c_int> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            command,
+            attr as *const T,
+            std::mem::size_of::<T>(),
+        )
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(fd as libc::c_int),
+    }
+}
+
+/// The device program, which checks each rule in turn, skipping past the rest of one as soon as
+/// something doesn't match
+///
+/// Registers: r2 is the device's type, r3 the access, r4 the major number, and r5 the minor.
+fn device_program(rules: &[DeviceRule]) -> Vec<Instruction> {
+    let instruction = |code, destination: u8, source: u8, offset, immediate| Instruction {
+        code,
+        registers: destination | (source << 4),
+        offset,
+        immediate,
+    };
+    let mut program = vec![
+        // struct bpf_cgroup_dev_ctx { u32 access_type; u32 major; u32 minor; }, in r1
+        instruction(LDX_MEM_W, 2, 1, 0, 0),
+        instruction(ALU64_AND_K, 2, 0, 0, 0xffff),
+        instruction(LDX_MEM_W, 3, 1, 0, 0),
+        instruction(ALU64_RSH_K, 3, 0, 0, 16),
+        instruction(LDX_MEM_W, 4, 1, 4, 0),
+        instruction(LDX_MEM_W, 5, 1, 8, 0),
+    ];
+    for rule in rules {
+        let access = rule.access.chars().fold(0, |access, c| {
+            access
+                | match c {
+                    'r' => BPF_DEVCG_ACC_READ,
+                    'w' => BPF_DEVCG_ACC_WRITE,
+                    _ => BPF_DEVCG_ACC_MKNOD,
+                }
+        });
+        // Each check jumps past the end of the rule when it doesn't match, the offsets are
+        // filled in once it's known where that is
+        let mut checks = Vec::new();
+        if let Some(kind) = rule.kind {
+            let kind = match kind {
+                DeviceType::Char => BPF_DEVCG_DEV_CHAR,
+                DeviceType::Block => BPF_DEVCG_DEV_BLOCK,
+            };
+            checks.push(instruction(JMP_JNE_K, 2, 0, 0, kind));
+        }
+        if let Some(major) = rule.major {
+            checks.push(instruction(JMP_JNE_K, 4, 0, 0, major as i32));
+        }
+        if let Some(minor) = rule.minor {
+            checks.push(instruction(JMP_JNE_K, 5, 0, 0, minor as i32));
+        }
+        // Everything asked for has to be allowed
+        checks.push(instruction(ALU64_MOV_X, 6, 3, 0, 0));
+        checks.push(instruction(ALU64_AND_K, 6, 0, 0, access));
+        checks.push(instruction(JMP_JNE_X, 6, 3, 0, 0));
+        checks.push(instruction(ALU64_MOV_K, 0, 0, 0, 1));
+        checks.push(instruction(JMP_EXIT, 0, 0, 0, 0));
+        let length = checks.len();
+        for (i, check) in checks.iter_mut().enumerate() {
+            if check.code == JMP_JNE_K || check.code == JMP_JNE_X {
+                check.offset = (length - i - 1) as i16;
+            }
+        }
+        program.extend(checks);
+    }
+    program.push(instruction(ALU64_MOV_K, 0, 0, 0, 0));
+    program.push(instruction(JMP_EXIT, 0, 0, 0, 0));
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where each rule's instructions start in a program made from them, then where the deny
+    /// exit does
+    fn starts(rules: &[DeviceRule]) -> Vec<usize> {
+        let mut starts = vec![6];
+        for rule in rules {
+            let compares = [
+                rule.kind.is_some(),
+                rule.major.is_some(),
+                rule.minor.is_some(),
+            ];
+            let length = compares.iter().filter(|compare| **compare).count() + 5;
+            starts.push(starts.last().unwrap() + length);
+        }
+        starts
+    }
+
+    /// Runs the program the way the kernel would for a device, returning whether it's allowed
+    fn run(program: &[Instruction], kind: i32, access: i32, major: u32, minor: u32) -> bool {
+        let context = [(access << 16 | kind) as u32, major, minor];
+        let mut registers = [0u64; 11];
+        let mut pc = 0;
+        loop {
+            let Instruction {
+                code,
+                registers: operands,
+                offset,
+                immediate,
+            } = program[pc];
+            let (destination, source) = ((operands & 0xf) as usize, (operands >> 4) as usize);
+            pc += 1;
+            match code {
+                LDX_MEM_W => {
+                    assert_eq!(source, 1);
+                    registers[destination] = context[offset as usize / 4] as u64;
+                }
+                ALU64_AND_K => registers[destination] &= immediate as u64,
+                ALU64_RSH_K => registers[destination] >>= immediate,
+                ALU64_MOV_K => registers[destination] = immediate as u64,
+                ALU64_MOV_X => registers[destination] = registers[source],
+                JMP_JNE_K if registers[destination] != immediate as u64 => {
+                    pc = (pc as i64 + offset as i64) as usize
+                }
+                JMP_JNE_X if registers[destination] != registers[source] => {
+                    pc = (pc as i64 + offset as i64) as usize
+                }
+                JMP_JNE_K | JMP_JNE_X => {}
+                JMP_EXIT => return registers[0] == 1,
+                code => panic!("Unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    const R: i32 = BPF_DEVCG_ACC_READ;
+    const W: i32 = BPF_DEVCG_ACC_WRITE;
+    const M: i32 = BPF_DEVCG_ACC_MKNOD;
+    const CHAR: i32 = BPF_DEVCG_DEV_CHAR;
+    const BLOCK: i32 = BPF_DEVCG_DEV_BLOCK;
+
+    #[test]
+    fn jumps_land_on_the_next_rule_or_the_deny_exit() {
+        let rule_sets = [
+            vec![],
+            vec![DeviceRule::new(
+                Some(DeviceType::Char),
+                Some(1),
+                Some(3),
+                "rwm",
+            )],
+            vec![
+                DeviceRule::new(None, None, None, "m"),
+                DeviceRule::new(Some(DeviceType::Char), Some(1), Some(3), "rw"),
+                DeviceRule::new(Some(DeviceType::Block), Some(8), None, "r"),
+                DeviceRule::new(Some(DeviceType::Char), None, None, "w"),
+            ],
+        ];
+        for rules in rule_sets {
+            let program = device_program(&rules);
+            let starts = starts(&rules);
+            let deny = *starts.last().unwrap();
+            assert_eq!(program.len(), deny + 2);
+            assert_eq!(
+                (program[deny].code, program[deny].immediate),
+                (ALU64_MOV_K, 0)
+            );
+            assert_eq!(program[deny + 1].code, JMP_EXIT);
+            for (rule, window) in starts.windows(2).enumerate() {
+                let (start, next) = (window[0], window[1]);
+                for (i, instruction) in program.iter().enumerate().take(next).skip(start) {
+                    if matches!(instruction.code, JMP_JNE_K | JMP_JNE_X) {
+                        let target = i as i64 + 1 + instruction.offset as i64;
+                        assert_eq!(target, next as i64, "rule {} jumps to {}", rule, target);
+                    }
+                }
+                // Allows only once everything before it matched
+                assert_eq!(
+                    (program[next - 2].code, program[next - 2].immediate),
+                    (ALU64_MOV_K, 1)
+                );
+                assert_eq!(program[next - 1].code, JMP_EXIT);
+            }
+        }
+    }
+
+    #[test]
+    fn wildcards_skip_their_compares() {
+        let compares = |rule: DeviceRule| {
+            device_program(&[rule])
+                .iter()
+                .filter(|instruction| instruction.code == JMP_JNE_K)
+                .map(|instruction| instruction.registers & 0xf)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            compares(DeviceRule::new(
+                Some(DeviceType::Char),
+                Some(1),
+                Some(3),
+                "r"
+            )),
+            [2, 4, 5]
+        );
+        assert_eq!(
+            compares(DeviceRule::new(Some(DeviceType::Block), Some(8), None, "r")),
+            [2, 4]
+        );
+        assert_eq!(
+            compares(DeviceRule::new(Some(DeviceType::Char), None, Some(3), "r")),
+            [2, 5]
+        );
+        assert_eq!(compares(DeviceRule::new(None, Some(1), None, "r")), [4]);
+        assert!(compares(DeviceRule::new(None, None, None, "r")).is_empty());
+    }
+
+    #[test]
+    fn no_rules_deny_everything() {
+        let program = device_program(&[]);
+        for kind in [CHAR, BLOCK] {
+            assert!(!run(&program, kind, R, 1, 3));
+            assert!(!run(&program, kind, M, 0, 0));
+        }
+    }
+
+    #[test]
+    fn one_rule_allows_only_its_device_and_access() {
+        let program = device_program(&[DeviceRule::new(
+            Some(DeviceType::Char),
+            Some(1),
+            Some(3),
+            "rw",
+        )]);
+        assert!(run(&program, CHAR, R, 1, 3));
+        assert!(run(&program, CHAR, R | W, 1, 3));
+        assert!(!run(&program, CHAR, M, 1, 3));
+        assert!(!run(&program, CHAR, R | M, 1, 3));
+        assert!(!run(&program, BLOCK, R, 1, 3));
+        assert!(!run(&program, CHAR, R, 1, 5));
+        assert!(!run(&program, CHAR, R, 2, 3));
+    }
+
+    #[test]
+    fn several_rules_are_each_tried() {
+        let program = device_program(&[
+            DeviceRule::new(None, None, None, "m"),
+            DeviceRule::new(Some(DeviceType::Char), Some(1), Some(3), "rw"),
+            DeviceRule::new(Some(DeviceType::Block), Some(8), None, "r"),
+            DeviceRule::new(Some(DeviceType::Char), Some(136), None, "rw"),
+        ]);
+        assert!(run(&program, CHAR, M, 4, 1));
+        assert!(run(&program, BLOCK, M, 8, 0));
+        assert!(run(&program, CHAR, W, 1, 3));
+        assert!(run(&program, BLOCK, R, 8, 16));
+        assert!(!run(&program, BLOCK, W, 8, 16));
+        assert!(!run(&program, CHAR, R, 8, 0));
+        assert!(run(&program, CHAR, R | W, 136, 4));
+        assert!(!run(&program, BLOCK, R, 136, 4));
+        assert!(!run(&program, CHAR, R, 1, 5));
+    }
+
+    #[test]
+    fn typeless_rules_are_both_in_v1() {
+        assert_eq!(
+            DeviceRule::new(None, None, None, "m").to_v1(),
+            ["c *:* m", "b *:* m"]
+        );
+        assert_eq!(
+            DeviceRule::new(Some(DeviceType::Block), Some(8), None, "r").to_v1(),
+            ["b 8:* r"]
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::rootfs::is_opaque;
+
+/// How a path in a container differs from its image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Deleted,
+}
+
+impl ChangeKind {
+    /// The letter `docker diff` marks it with
+    pub fn letter(&self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Changed => 'C',
+            ChangeKind::Deleted => 'D',
+        }
+    }
+}
+
+/// A path a container added, changed, or deleted, like `docker diff` lists them
+///
+/// Directories something changed in count as changed themselves, and what's in an added
+/// directory is added too, but a deleted directory is just the one path.
+///
+/// See: https://docs.docker.com/reference/cli/docker/container/diff/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Absolute, as the container sees it
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+/// What a container changed of its image, sorted by path, and where to find what it changed
+#[derive(Debug)]
+pub struct Changes {
+    pub changes: Vec<Change>,
+    /// What's at the added and changed paths now is at the same paths under here, which is the
+    /// container's rootfs or an overlay's upper directory
+    pub root: PathBuf,
+    /// Directories the image has that were replaced rather than changed, so none of what the
+    /// image has in them is in the container, whatever `changes` says about it
+    pub opaque: Vec<PathBuf>,
+}
+
+/// Compares a container's whole rootfs with a snapshot of its image (`None` for images without
+/// layers), for drivers that copy the image
+///
+/// Files count as changed when their type, permissions, ownership, size, modification time, or
+/// (for symlinks) target aren't the same, their contents aren't compared. Copies keep the
+/// modification times of what they're copied from, so those only differ if something wrote
+/// to them.
+pub fn compare_trees(image: Option<&Path>, rootfs: &Path) -> Result<Changes> {
+    let mut changes = Vec::new();
+    compare_directories(image, rootfs, Path::new("/"), &mut changes)
+        .with_context(|| format!("Tried to compare {} with its image", rootfs.display()))?;
+    Ok(Changes {
+        changes,
+        root: rootfs.to_path_buf(),
+        opaque: Vec::new(),
+    })
+}
+
+fn compare_directories(
+    image: Option<&Path>,
+    rootfs: &Path,
+    relative: &Path,
+    changes: &mut Vec<Change>,
+) -> io::Result<()> {
+    let strip = |path: &Path| path.strip_prefix("/").unwrap().to_path_buf();
+    let image_dir = image.map(|image| image.join(strip(relative)));
+    let rootfs_dir = rootfs.join(strip(relative));
+    let mut names = entry_names(&rootfs_dir)?;
+    if let Some(image_dir) = &image_dir {
+        names.extend(entry_names(image_dir)?);
+    }
+    for name in names {
+        let path = relative.join(&name);
+        let (old_path, new_path) = (
+            image_dir.as_ref().map(|image_dir| image_dir.join(&name)),
+            rootfs_dir.join(&name),
+        );
+        let old = match &old_path {
+            Some(old_path) => metadata_if_exists(old_path)?,
+            None => None,
+        };
+        let new = match (&old, metadata_if_exists(&new_path)?) {
+            (None, None) => continue,
+            (Some(_), None) => {
+                changes.push(deleted(path));
+                continue;
+            }
+            (_, Some(new)) => new,
+        };
+        let kind = match (&old, &old_path) {
+            (Some(old), Some(old_path)) => match differ(old, &new, old_path, &new_path)? {
+                true => Some(ChangeKind::Changed),
+                false => None,
+            },
+            _ => Some(ChangeKind::Added),
+        };
+        let start = changes.len();
+        if let Some(kind) = kind {
+            changes.push(Change {
+                path: path.clone(),
+                kind,
+            });
+        }
+        if new.is_dir() {
+            // What's in a directory that used to be something else is all new
+            let image = match &old {
+                Some(old) if old.is_dir() => image,
+                _ => None,
+            };
+            compare_directories(image, rootfs, &path, changes)?;
+            if kind.is_none() && changes.len() > start {
+                changes.insert(start, changed(path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What an overlay's upper directory says the container changed, topmost of the image's lower
+/// directories first
+///
+/// Everything in the upper directory was either made by the container or copied up when it
+/// changed something, whiteouts (`0:0` character devices) mark what it deleted, and opaque
+/// directories replaced what the image had there.
+pub fn overlay_changes(upper: &Path, lower_dirs: &[PathBuf]) -> Result<Changes> {
+    let mut changes = Changes {
+        changes: Vec::new(),
+        root: upper.to_path_buf(),
+        opaque: Vec::new(),
+    };
+    upper_changes(upper, lower_dirs, Path::new("/"), false, &mut changes)
+        .with_context(|| format!("Tried to list the changes in {}", upper.display()))?;
+    Ok(changes)
+}
+
+fn upper_changes(
+    upper: &Path,
+    lower_dirs: &[PathBuf],
+    relative: &Path,
+    replaced: bool,
+    changes: &mut Changes,
+) -> io::Result<()> {
+    let directory = upper.join(relative.strip_prefix("/").unwrap());
+    for name in entry_names(&directory)? {
+        let path = relative.join(&name);
+        let metadata = fs::symlink_metadata(directory.join(&name))?;
+        if is_whiteout(&metadata) {
+            changes.changes.push(deleted(path));
+            continue;
+        }
+        let kind = match !replaced && in_lower_dirs(lower_dirs, &path)? {
+            true => ChangeKind::Changed,
+            false => ChangeKind::Added,
+        };
+        changes.changes.push(Change {
+            path: path.clone(),
+            kind,
+        });
+        if metadata.is_dir() {
+            let opaque = kind == ChangeKind::Changed && is_opaque(&directory.join(&name))?;
+            if opaque {
+                changes.opaque.push(path.clone());
+            }
+            let replaced = replaced || kind == ChangeKind::Added || opaque;
+            upper_changes(upper, lower_dirs, &path, replaced, changes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the image has `path`, which is whatever the topmost lower directory that has
+/// anything there says, a whiteout there means a layer deleted it
+fn in_lower_dirs(lower_dirs: &[PathBuf], path: &Path) -> io::Result<bool> {
+    let relative = path.strip_prefix("/").unwrap();
+    for lower_dir in lower_dirs {
+        if let Some(metadata) = metadata_if_exists(&lower_dir.join(relative))? {
+            return Ok(!is_whiteout(&metadata));
+        }
+    }
+    Ok(false)
+}
+
+/// How overlayfs marks a deleted path
+fn is_whiteout(metadata: &fs::Metadata) -> bool {
+    metadata.mode() & libc::S_IFMT == libc::S_IFCHR && metadata.rdev() == 0
+}
+
+/// Whether two versions of a path aren't the same, going by their metadata
+fn differ(
+    old: &fs::Metadata,
+    new: &fs::Metadata,
+    old_path: &Path,
+    new_path: &Path,
+) -> io::Result<bool> {
+    if old.mode() != new.mode()
+        || old.uid() != new.uid()
+        || old.gid() != new.gid()
+        || old.rdev() != new.rdev()
+        || (old.mtime(), old.mtime_nsec()) != (new.mtime(), new.mtime_nsec())
+    {
+        return Ok(true);
+    }
+    // A directory's size is just how much space its entries took at some point
+    if !new.is_dir() && old.size() != new.size() {
+        return Ok(true);
+    }
+    match new.file_type().is_symlink() {
+        true => Ok(fs::read_link(old_path)? != fs::read_link(new_path)?),
+        false => Ok(false),
+    }
+}
+
+/// The names in a directory, sorted, none if it doesn't exist
+fn entry_names(directory: &Path) -> io::Result<BTreeSet<OsString>> {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries.map(|entry| Ok(entry?.file_name())).collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn metadata_if_exists(path: &Path) -> io::Result<Option<fs::Metadata>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn changed(path: PathBuf) -> Change {
+    Change {
+        path,
+        kind: ChangeKind::Changed,
+    }
+}
+
+fn deleted(path: PathBuf) -> Change {
+    Change {
+        path,
+        kind: ChangeKind::Deleted,
+    }
+}
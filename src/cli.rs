@@ -0,0 +1,2057 @@
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::credentials::Credentials;
+use crate::idmap::{IdMap, IdMappings, IdRange};
+use crate::lockfile::DEFAULT_LOCKFILE;
+use crate::platform::Platform;
+use crate::progress::{Progress, ProgressMode};
+use crate::recompress::DEFAULT_ZSTD_LEVEL;
+use crate::reference::{normalize_registry, DOCKER_HUB};
+use crate::retry::RetryPolicy;
+use crate::table::parse_size;
+use crate::throttle::Throttle;
+
+/// Matches the docker daemon's default
+const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 3;
+
+/// How many ranges a large blob is split into and downloaded concurrently
+const DEFAULT_DOWNLOAD_RANGES: usize = 4;
+
+/// How many images `prefetch` pulls at once, each with its own concurrent downloads
+const DEFAULT_PREFETCH_JOBS: usize = 2;
+
+/// Blobs bigger than this are pushed in chunks of this size
+const DEFAULT_UPLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Matches docker's default, which is plenty for most but not for browsers or databases
+const DEFAULT_SHM_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Options for the `pull` subcommand, which `run` also needs to pull missing images
+#[derive(Debug, Clone)]
+pub struct PullOptions {
+    pub platform: Platform,
+    pub max_concurrent_downloads: usize,
+    /// How many ranges each large blob is downloaded in at once, on top of the concurrent blobs
+    pub download_ranges: usize,
+    /// Caps the combined speed of every blob download, shared with clones of these options
+    pub limit_rate: Option<Arc<Throttle>>,
+    /// Where each layer's progress is shown, shared with clones of these options
+    pub progress: Arc<Progress>,
+    /// Public key the image's cosign signature has to verify against, if signatures are checked
+    pub verify_key: Option<PathBuf>,
+    pub retry: RetryPolicy,
+    pub credentials: Option<Credentials>,
+    pub config: Config,
+    pub image: String,
+}
+
+/// When `run` should pull its image
+///
+/// See: https://docs.docker.com/reference/cli/docker/container/run/#pull
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullPolicy {
+    /// Re-resolve the reference every time, picking up tags that moved
+    Always,
+    /// Only pull images that aren't in the store
+    Missing,
+    /// Never touch the registry, failing if the image isn't in the store
+    Never,
+}
+
+impl PullPolicy {
+    fn parse(policy: &str) -> Result<PullPolicy> {
+        match policy {
+            "always" => Ok(PullPolicy::Always),
+            "missing" => Ok(PullPolicy::Missing),
+            "never" => Ok(PullPolicy::Never),
+            other => bail!(
+                "Unknown pull policy '{}', expected always, missing, or never",
+                other
+            ),
+        }
+    }
+}
+
+/// A host path or named volume mounted into the container, from
+/// `-v source:destination[:options]`
+///
+/// See: https://docs.docker.com/engine/storage/bind-mounts/
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub source: VolumeSource,
+    /// Where it goes in the container, always absolute
+    pub destination: String,
+    pub read_only: bool,
+    pub propagation: Propagation,
+}
+
+/// Whether mounts made under a bind mount after the container starts show up on the other side,
+/// `rprivate` unless `-v` says otherwise, like docker
+///
+/// `shared` ones go both ways, `slave` ones only from the host into the container, and
+/// `private` ones not at all. Plain ones are about the bind mount, `r` ones about what's
+/// mounted under it too.
+///
+/// See: https://docs.kernel.org/filesystems/sharedsubtree.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    Shared,
+    Slave,
+    Private,
+    RShared,
+    RSlave,
+    RPrivate,
+}
+
+impl Propagation {
+    fn parse(option: &str) -> Option<Propagation> {
+        match option {
+            "shared" => Some(Propagation::Shared),
+            "slave" => Some(Propagation::Slave),
+            "private" => Some(Propagation::Private),
+            "rshared" => Some(Propagation::RShared),
+            "rslave" => Some(Propagation::RSlave),
+            "rprivate" => Some(Propagation::RPrivate),
+            _ => None,
+        }
+    }
+}
+
+/// What a `-v` mounts, told apart the way docker does, by whether it's an absolute path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VolumeSource {
+    /// A host path, which is created as a directory if there's nothing there yet
+    Host(PathBuf),
+    /// A volume in the store, which is created if there's none by that name yet
+    Named(String),
+}
+
+impl Volume {
+    fn parse(spec: &str) -> Result<Volume> {
+        let mut parts = spec.split(':');
+        let (source, destination) = match (parts.next(), parts.next()) {
+            (Some(source), Some(destination)) if !source.is_empty() => (source, destination),
+            _ => bail!("Invalid volume '{}', expected source:destination", spec),
+        };
+        let source = match source.starts_with('/') {
+            true => VolumeSource::Host(PathBuf::from(source)),
+            false if source.starts_with('.') || source.contains('/') => bail!(
+                "Invalid volume '{}', the host path {} has to be absolute",
+                spec,
+                source
+            ),
+            false => VolumeSource::Named(source.to_string()),
+        };
+        if !destination.starts_with('/') || destination.trim_end_matches('/').is_empty() {
+            bail!(
+                "Invalid volume '{}', the container path {} has to be absolute and not /",
+                spec,
+                destination
+            );
+        }
+        let mut read_only = false;
+        let mut propagation = Propagation::RPrivate;
+        for option in parts
+            .next()
+            .into_iter()
+            .flat_map(|options| options.split(','))
+        {
+            match option {
+                "ro" => read_only = true,
+                "rw" => read_only = false,
+                other => match Propagation::parse(other) {
+                    Some(_) if matches!(source, VolumeSource::Named(_)) => bail!(
+                        "Invalid volume '{}', only host paths can have a propagation",
+                        spec
+                    ),
+                    Some(parsed) => propagation = parsed,
+                    None => bail!(
+                        "Unknown volume option '{}' in '{}', expected ro, rw, or a propagation \
+                         ([r]shared, [r]slave, or [r]private)",
+                        other,
+                        spec
+                    ),
+                },
+            }
+        }
+        if parts.next().is_some() {
+            bail!(
+                "Invalid volume '{}', expected source:destination[:options]",
+                spec
+            );
+        }
+        Ok(Volume {
+            source,
+            destination: destination.to_string(),
+            read_only,
+            propagation,
+        })
+    }
+}
+
+/// A tmpfs mounted in the container, from `--tmpfs destination[:options]`
+///
+/// Like docker's, they're `noexec`, `nosuid`, and `nodev` unless the options say otherwise.
+///
+/// See: https://docs.docker.com/engine/storage/tmpfs/
+#[derive(Debug, Clone)]
+pub struct Tmpfs {
+    /// Where it goes in the container, always absolute
+    pub destination: String,
+    pub read_only: bool,
+    pub exec: bool,
+    pub suid: bool,
+    pub dev: bool,
+    /// `size`, `mode`, `uid`, `gid`, and `nr_inodes`, passed on to the kernel as they are
+    pub data: Vec<String>,
+}
+
+impl Tmpfs {
+    fn parse(spec: &str) -> Result<Tmpfs> {
+        let (destination, options) = spec.split_once(':').unwrap_or((spec, ""));
+        if !destination.starts_with('/') || destination.trim_end_matches('/').is_empty() {
+            bail!(
+                "Invalid tmpfs '{}', the container path {} has to be absolute and not /",
+                spec,
+                destination
+            );
+        }
+        let mut tmpfs = Tmpfs {
+            destination: destination.to_string(),
+            read_only: false,
+            exec: false,
+            suid: false,
+            dev: false,
+            data: Vec::new(),
+        };
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                None => match option {
+                    "ro" => tmpfs.read_only = true,
+                    "rw" => tmpfs.read_only = false,
+                    "exec" => tmpfs.exec = true,
+                    "noexec" => tmpfs.exec = false,
+                    "suid" => tmpfs.suid = true,
+                    "nosuid" => tmpfs.suid = false,
+                    "dev" => tmpfs.dev = true,
+                    "nodev" => tmpfs.dev = false,
+                    other => bail!("Unknown tmpfs option '{}' in '{}'", other, spec),
+                },
+                // Sizes are the kernel's, 64m is 64 MiB and 50% is half of memory
+                Some(("size", size))
+                    if size
+                        .trim_end_matches(['k', 'm', 'g', 'K', 'M', 'G', '%'])
+                        .parse::<u64>()
+                        .is_ok() =>
+                {
+                    tmpfs.data.push(option.to_string())
+                }
+                Some(("mode", mode)) if u32::from_str_radix(mode, 8).is_ok() => {
+                    tmpfs.data.push(option.to_string())
+                }
+                Some(("uid" | "gid" | "nr_inodes", number)) if number.parse::<u64>().is_ok() => {
+                    tmpfs.data.push(option.to_string())
+                }
+                Some(_) => bail!(
+                    "Invalid tmpfs option '{}' in '{}', expected size, mode (octal), uid, gid, or nr_inodes",
+                    option,
+                    spec
+                ),
+            }
+        }
+        Ok(tmpfs)
+    }
+}
+
+/// A host device the container gets to use, from `--device host-path[:container-path][:permissions]`
+///
+/// A directory (like `/dev/snd`) brings every device in it along. The permissions are some of
+/// `r` (read), `w` (write), and `m` (mknod), all three unless given.
+///
+/// See: https://docs.docker.com/reference/cli/docker/container/run/#device
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub host: PathBuf,
+    /// Where it goes in the container, the same as on the host unless given
+    pub container: PathBuf,
+    pub permissions: String,
+}
+
+impl Device {
+    fn parse(spec: &str) -> Result<Device> {
+        let permissions = |permissions: &str| {
+            !permissions.is_empty() && permissions.chars().all(|c| "rwm".contains(c))
+        };
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (host, container, permissions) = match parts[..] {
+            [host] => (host, host, "rwm"),
+            [host, given] if permissions(given) => (host, host, given),
+            [host, container] => (host, container, "rwm"),
+            [host, container, given] if permissions(given) => (host, container, given),
+            [_, _, given] => bail!(
+                "Invalid device '{}', the permissions {} have to be some of r, w, and m",
+                spec,
+                given
+            ),
+            _ => bail!(
+                "Invalid device '{}', expected host-path[:container-path][:permissions]",
+                spec
+            ),
+        };
+        for path in [host, container] {
+            if !path.starts_with('/') {
+                bail!("Invalid device '{}', {} has to be absolute", spec, path);
+            }
+        }
+        Ok(Device {
+            host: PathBuf::from(host),
+            container: PathBuf::from(container),
+            permissions: permissions.to_string(),
+        })
+    }
+}
+
+/// What `save` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveFormat {
+    /// A tar like `docker save` writes
+    DockerArchive,
+    /// An OCI image layout directory, like skopeo, podman, and umoci read
+    Oci,
+}
+
+impl SaveFormat {
+    fn parse(format: &str) -> Result<SaveFormat> {
+        match format {
+            "docker-archive" | "docker" => Ok(SaveFormat::DockerArchive),
+            "oci" | "oci-dir" => Ok(SaveFormat::Oci),
+            other => bail!(
+                "Unknown save format '{}', expected docker-archive or oci",
+                other
+            ),
+        }
+    }
+}
+
+/// Options for the `run` subcommand
+#[derive(Debug)]
+pub struct RunOptions {
+    pub pull: PullOptions,
+    pub pull_policy: PullPolicy,
+    /// Never touch the network, only run what's already in the store
+    pub offline: bool,
+    /// Replaces the image's `Entrypoint`, an empty string removes it
+    pub entrypoint: Option<String>,
+    /// `KEY=value` (or bare `KEY` to pass ours through) on top of the image's `Env`
+    pub env: Vec<String>,
+    pub working_dir: Option<String>,
+    /// Host paths and named volumes mounted into the container, in the order they're mounted
+    pub volumes: Vec<Volume>,
+    /// Scratch space kept in memory rather than in the rootfs, mounted after the volumes
+    pub tmpfs: Vec<Tmpfs>,
+    /// Host devices created in the container's `/dev` (or wherever they're to go), which the
+    /// device cgroup lets it use on top of the defaults every container gets
+    pub devices: Vec<Device>,
+    /// How big the container's `/dev/shm` can get, in bytes
+    pub shm_size: u64,
+    /// How much the container may write to its rootfs, in bytes, from `--storage-opt size=`
+    pub storage_size: Option<u64>,
+    /// Mount the rootfs read-only, with a tmpfs on `/tmp` and `/run` (unless something else is
+    /// mounted there) for what does need writing
+    pub read_only: bool,
+    /// `user[:group]` to run as instead of the image's `User`
+    pub user: Option<String>,
+    /// Run in a user namespace with these maps, from `--uidmap` and `--gidmap` (which default to
+    /// each other if only one is given)
+    pub id_mappings: Option<IdMappings>,
+    /// Lockfile the image's digest has to match, set by `--locked`
+    pub locked: Option<PathBuf>,
+    /// Hardlink layer files into the rootfs instead of copying them with the vfs driver, for
+    /// containers that won't write to them
+    pub link_layers: bool,
+    /// Leave out `/sys` and the cgroup filesystem in it, for containers that don't need to know
+    /// about the system they're on
+    pub no_sysfs: bool,
+    /// Run the command on a terminal of its own, which our stdin goes to and stdout comes from
+    pub tty: bool,
+    /// Remove the container once it exits, along with the anonymous volumes made for the
+    /// image's `Volumes`, rather than keeping it until `rm`
+    pub remove: bool,
+    /// Replaces the image's `Cmd` if given
+    pub command: Option<String>,
+    pub args: Vec<String>,
+}
+
+/// Options for the `lock` subcommand
+#[derive(Debug)]
+pub struct LockOptions {
+    /// How each image is resolved, its `image` goes unused in favor of `images`
+    pub pull: PullOptions,
+    /// References to lock, if empty everything already in the lockfile is locked again
+    pub images: Vec<String>,
+    pub lockfile: PathBuf,
+}
+
+/// Options for the `prefetch` subcommand
+#[derive(Debug)]
+pub struct PrefetchOptions {
+    /// How each image is pulled, its `image` goes unused in favor of `images`
+    pub pull: PullOptions,
+    pub images: Vec<String>,
+    /// Files listing more images to pull, one per line
+    pub files: Vec<PathBuf>,
+    pub jobs: usize,
+}
+
+/// Options for the `login` subcommand
+#[derive(Debug)]
+pub struct LoginOptions {
+    pub registry: String,
+    pub credentials: Credentials,
+    pub credential_helper: Option<String>,
+    pub retry: RetryPolicy,
+    pub config: Config,
+}
+
+/// Options for the `push` subcommand
+#[derive(Debug)]
+pub struct PushOptions {
+    pub retry: RetryPolicy,
+    pub credentials: Option<Credentials>,
+    pub config: Config,
+    /// Blobs bigger than this are uploaded in chunks of this size, which can be resumed
+    pub chunk_size: u64,
+    pub image: String,
+}
+
+/// Options for subcommands that work with a repository rather than pulling (`tags`)
+#[derive(Debug)]
+pub struct RepositoryOptions {
+    pub retry: RetryPolicy,
+    pub credentials: Option<Credentials>,
+    pub config: Config,
+    pub image: String,
+}
+
+/// Options for the `search` subcommand
+#[derive(Debug)]
+pub struct SearchOptions {
+    pub retry: RetryPolicy,
+    pub config: Config,
+    pub term: String,
+    pub limit: usize,
+    /// Show descriptions in full instead of cutting them off to keep the table narrow
+    pub no_trunc: bool,
+    pub filter: SearchFilter,
+}
+
+/// Conditions from `--filter` that search results must meet
+///
+/// See: https://docs.docker.com/reference/cli/docker/search/#filter
+#[derive(Debug, Default)]
+pub struct SearchFilter {
+    pub stars: Option<u64>,
+    pub is_official: Option<bool>,
+    pub is_automated: Option<bool>,
+}
+
+/// Matches the docker CLI's default
+const DEFAULT_SEARCH_LIMIT: usize = 25;
+
+/// Docker Hub refuses to return more results than this
+const MAX_SEARCH_LIMIT: usize = 100;
+
+/// Options for the `images` subcommand
+#[derive(Debug)]
+pub struct ImagesOptions {
+    /// Only list images of this `repository[:tag]`
+    pub repository: Option<String>,
+    /// `json` or a template like `{{.Repository}}:{{.Tag}}` to print instead of a table
+    pub format: Option<String>,
+    /// Add a column with the digest each reference resolved to
+    pub digests: bool,
+    /// Show image IDs in full
+    pub no_trunc: bool,
+}
+
+/// Options for the `history` subcommand
+#[derive(Debug)]
+pub struct HistoryOptions {
+    /// A reference or image ID
+    pub image: String,
+    /// `json` or a template like `{{.CreatedBy}}` to print instead of a table
+    pub format: Option<String>,
+    /// Show image IDs and commands in full
+    pub no_trunc: bool,
+    /// Only print image IDs
+    pub quiet: bool,
+}
+
+/// Options for the `image inspect` subcommand
+#[derive(Debug)]
+pub struct InspectOptions {
+    /// References or image IDs
+    pub images: Vec<String>,
+    /// A template like `{{.Config.Cmd}}` or `{{json .Config}}` to print instead of JSON
+    pub format: Option<String>,
+}
+
+/// Which kind of document `sbom` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    /// CycloneDX 1.5 JSON
+    CycloneDx,
+    /// SPDX 2.3 JSON
+    Spdx,
+}
+
+/// Options for the `sbom` subcommand
+#[derive(Debug)]
+pub struct SbomOptions {
+    /// A reference or image ID
+    pub image: String,
+    pub format: SbomFormat,
+    /// Where to write the document instead of stdout
+    pub output: Option<PathBuf>,
+}
+
+/// Options for the `tag` subcommand
+#[derive(Debug)]
+pub struct TagOptions {
+    /// A reference or image ID that's in the store
+    pub source: String,
+    /// The `[registry/]repository[:tag]` to point at it
+    pub target: String,
+}
+
+/// Options for the `store verify` subcommand
+#[derive(Debug)]
+pub struct VerifyOptions {
+    /// Remove whatever is corrupted, so it's fetched again by the next pull
+    pub repair: bool,
+}
+
+/// Options for the `store recompress` subcommand
+#[derive(Debug)]
+pub struct RecompressOptions {
+    /// References or image IDs, every stored image if there are none
+    pub images: Vec<String>,
+    /// The zstd level, 1 to 19
+    pub level: usize,
+}
+
+/// Options for the `rmi` subcommand
+#[derive(Debug)]
+pub struct RmiOptions {
+    /// References or image IDs (their config's digest, possibly shortened) to remove
+    pub images: Vec<String>,
+    /// Remove an image ID even if several references point to it, untagging all of them, and
+    /// images containers (running or kept until `rm`) were made from
+    pub force: bool,
+}
+
+/// Options for the `system prune` and `image prune` subcommands
+#[derive(Debug)]
+pub struct PruneOptions {
+    /// Don't ask before removing anything
+    pub force: bool,
+}
+
+/// Options for the `system df` subcommand
+#[derive(Debug)]
+pub struct DfOptions {
+    /// Break usage down by image and by extracted layer too
+    pub verbose: bool,
+}
+
+/// Options for the `save` subcommand
+#[derive(Debug)]
+pub struct SaveOptions {
+    pub images: Vec<String>,
+    /// Where to write the archive, stdout if not given. The directory to write to for
+    /// `SaveFormat::Oci`, which adds to a layout that's already there.
+    pub output: Option<PathBuf>,
+    pub format: SaveFormat,
+}
+
+/// Options for the `load` subcommand
+#[derive(Debug)]
+pub struct LoadOptions {
+    /// The archive to read, stdin if not given, or an OCI image layout directory
+    pub input: Option<PathBuf>,
+    /// Only print errors
+    pub quiet: bool,
+}
+
+/// Options for the `import` subcommand
+#[derive(Debug)]
+pub struct ImportOptions {
+    /// The tarball to import, `-` for stdin
+    pub source: String,
+    /// What to tag the image as, if anything
+    pub reference: Option<String>,
+    /// Dockerfile instructions (`CMD`, `ENTRYPOINT`, `ENV`, `USER`, or `WORKDIR`) applied to the
+    /// image's config
+    pub changes: Vec<String>,
+    /// Recorded in the image's history
+    pub message: Option<String>,
+    pub platform: Platform,
+}
+
+/// Options for the `volume create` subcommand
+#[derive(Debug)]
+pub struct VolumeCreateOptions {
+    /// A random one is made up if not given
+    pub name: Option<String>,
+}
+
+/// Options for the `volume ls` subcommand
+#[derive(Debug)]
+pub struct VolumeLsOptions {
+    /// Only print names
+    pub quiet: bool,
+}
+
+/// Options for the `volume inspect` subcommand
+#[derive(Debug)]
+pub struct VolumeInspectOptions {
+    pub names: Vec<String>,
+    /// A template like `{{.Mountpoint}}` to print instead of JSON
+    pub format: Option<String>,
+}
+
+/// Options for the `volume rm` subcommand
+#[derive(Debug)]
+pub struct VolumeRmOptions {
+    pub names: Vec<String>,
+    /// Don't fail on volumes that don't exist
+    pub force: bool,
+}
+
+/// Options for the `ps` subcommand
+#[derive(Debug)]
+pub struct PsOptions {
+    /// Containers that have exited too, not just running ones
+    pub all: bool,
+    /// Only print IDs
+    pub quiet: bool,
+    /// Print whole IDs and commands
+    pub no_trunc: bool,
+}
+
+/// Options for the `rm` subcommand
+#[derive(Debug)]
+pub struct RmOptions {
+    /// IDs or the start of them
+    pub containers: Vec<String>,
+}
+
+/// Options for the `diff` subcommand
+#[derive(Debug)]
+pub struct DiffOptions {
+    pub container: String,
+}
+
+/// Options for the `commit` subcommand
+#[derive(Debug)]
+pub struct CommitOptions {
+    pub container: String,
+    /// What to tag the image as, if anything
+    pub reference: Option<String>,
+    /// Dockerfile instructions (`CMD`, `ENTRYPOINT`, `ENV`, `USER`, or `WORKDIR`) applied to the
+    /// image's config
+    pub changes: Vec<String>,
+    /// Recorded in the new layer's history
+    pub message: Option<String>,
+    /// Recorded in the new layer's history
+    pub author: Option<String>,
+}
+
+/// Which way `cp` copies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyDirection {
+    FromContainer,
+    ToContainer,
+}
+
+/// Options for the `cp` subcommand
+#[derive(Debug)]
+pub struct CpOptions {
+    pub container: String,
+    pub direction: CopyDirection,
+    /// As given, a trailing `/` or `/.` means something
+    pub container_path: String,
+    pub host_path: String,
+    /// Keep the ownership of what's copied (and its file capabilities), instead of giving it to
+    /// whoever it's copied for
+    pub archive: bool,
+    /// Copy what the source path is a symlink to, rather than the symlink
+    pub follow_link: bool,
+}
+
+/// Options for the `export` subcommand
+#[derive(Debug)]
+pub struct ExportOptions {
+    pub container: String,
+    /// Where to write the tar, stdout if not given
+    pub output: Option<PathBuf>,
+}
+
+/// Options for the `logout` subcommand
+#[derive(Debug)]
+pub struct LogoutOptions {
+    pub registry: String,
+}
+
+/// A parsed command line invocation
+#[derive(Debug)]
+pub enum Command {
+    Pull(PullOptions),
+    // Boxed, it has far more options than any other command
+    Run(Box<RunOptions>),
+    Login(LoginOptions),
+    Logout(LogoutOptions),
+    Tags(RepositoryOptions),
+    Push(PushOptions),
+    ManifestInspect(PullOptions),
+    Search(SearchOptions),
+    Lock(LockOptions),
+    Prefetch(PrefetchOptions),
+    Images(ImagesOptions),
+    Rmi(RmiOptions),
+    Prune(PruneOptions),
+    SystemDf(DfOptions),
+    Save(SaveOptions),
+    Load(LoadOptions),
+    Import(ImportOptions),
+    History(HistoryOptions),
+    ImageInspect(InspectOptions),
+    Sbom(SbomOptions),
+    Tag(TagOptions),
+    StoreVerify(VerifyOptions),
+    StoreRecompress(RecompressOptions),
+    VolumeCreate(VolumeCreateOptions),
+    VolumeLs(VolumeLsOptions),
+    VolumeInspect(VolumeInspectOptions),
+    VolumeRm(VolumeRmOptions),
+    Ps(PsOptions),
+    Rm(RmOptions),
+    Diff(DiffOptions),
+    Commit(CommitOptions),
+    Cp(CpOptions),
+    Export(ExportOptions),
+}
+
+/// Flags that go before the subcommand and apply to all of them
+#[derive(Debug, Default)]
+pub struct GlobalOptions {
+    /// Where the store is kept, overriding `MINIDOCKER_ROOT`
+    pub data_root: Option<PathBuf>,
+    /// How big the store may get, overriding `MINIDOCKER_MAX_CACHE_SIZE`
+    pub max_cache_size: Option<u64>,
+    /// How containers' root filesystems are assembled, overriding `MINIDOCKER_STORAGE_DRIVER`
+    pub storage_driver: Option<String>,
+    /// What's done with layers' extended attributes, overriding `MINIDOCKER_XATTRS`
+    pub xattrs: Option<String>,
+}
+
+/// Parses the flags that come before the subcommand, returning them along with the arguments
+/// to parse the subcommand from (with the program name still in `args[0]`)
+///
+/// Usage: your_docker.sh [--data-root dir] [--max-cache-size size]
+///        [--storage-driver vfs|overlay|btrfs] [--xattrs auto|preserve|user|skip]
+///        <subcommand> ...
+pub fn parse_global(args: &[String]) -> Result<(GlobalOptions, Vec<String>)> {
+    let mut options = GlobalOptions::default();
+    let (program, rest) = args.split_first().context("No program name given")?;
+
+    let mut flags = Flags::new(rest);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--data-root" => options.data_root = Some(PathBuf::from(flags.value(&flag)?)),
+            "--max-cache-size" => {
+                options.max_cache_size = Some(parse_size(&flags.value(&flag)?)?);
+            }
+            "--storage-driver" => options.storage_driver = Some(flags.value(&flag)?),
+            "--xattrs" => options.xattrs = Some(flags.value(&flag)?),
+            _ => bail!(
+                "Unknown flag '{}', subcommand flags go after the subcommand",
+                flag
+            ),
+        }
+    }
+
+    let mut args = vec![program.clone()];
+    args.extend_from_slice(flags.remaining());
+    Ok((options, args))
+}
+
+/// Parses the process arguments (including the program name in `args[0]`)
+///
+/// Usage: your_docker.sh pull [pull flags] <image>
+/// Usage: your_docker.sh run [--pull always|missing|never] [--offline] [--entrypoint cmd]
+///        [-e KEY[=value]]... [-w dir] [-v host-path|volume:path[:ro|rw|propagation[,...]]]...
+///        [--tmpfs path[:size=64m,mode=1777,...]]... [--device host-path[:path][:rwm]]...
+///        [--shm-size size] [--storage-opt size=size] [--read-only] [--user user[:group]]
+///        [--uidmap container:host:count]... [--gidmap container:host:count]...
+///        [--locked [--lockfile path]]
+///        [--link-layers] [--no-sysfs] [-t|--tty] [--rm] [pull flags] <image>
+///        [<command> <arg1> <arg2> ...]
+/// Usage: your_docker.sh ps [-a|--all] [-q|--quiet] [--no-trunc]
+/// Usage: your_docker.sh rm <container>...
+/// Usage: your_docker.sh diff <container>
+/// Usage: your_docker.sh cp [-a|--archive] [-L|--follow-link] <container:path> <host path>
+/// Usage: your_docker.sh cp [-a|--archive] [-L|--follow-link] <host path> <container:path>
+/// Usage: your_docker.sh export [-o|--output rootfs.tar] <container>
+/// Usage: your_docker.sh commit [-c|--change instruction]... [-m|--message msg]
+///        [-a|--author author] <container> [repository[:tag]]
+/// Usage: your_docker.sh images [--digests] [--no-trunc] [--format json|template] [repository[:tag]]
+/// Usage: your_docker.sh history [--no-trunc] [-q|--quiet] [--format json|template] <image>
+/// Usage: your_docker.sh image inspect [-f|--format template] <image>...
+/// Usage: your_docker.sh sbom [--format cyclonedx|spdx] [-o|--output file] <image>
+/// Usage: your_docker.sh tag <source image or ID> <target[:tag]>
+/// Usage: your_docker.sh rmi [-f|--force] <image or ID>...
+/// Usage: your_docker.sh system prune|image prune [-f|--force]
+/// Usage: your_docker.sh system df [-v|--verbose]
+/// Usage: your_docker.sh store verify [--repair]
+/// Usage: your_docker.sh store recompress [--level n] [<image>...]
+/// Usage: your_docker.sh volume create [name]
+/// Usage: your_docker.sh volume ls [-q|--quiet]
+/// Usage: your_docker.sh volume inspect [-f|--format template] <volume>...
+/// Usage: your_docker.sh volume rm [-f|--force] <volume>...
+/// Usage: your_docker.sh save [-o|--output image.tar|dir] [--format docker-archive|oci] <image>...
+/// Usage: your_docker.sh load [-i|--input image.tar|dir] [-q|--quiet]
+/// Usage: your_docker.sh import [-c|--change instruction]... [-m|--message msg]
+///        [--platform os/arch[/variant]] <rootfs.tar|-> [repository[:tag]]
+/// Usage: your_docker.sh lock [--lockfile path] [pull flags] [<image>...]
+/// Usage: your_docker.sh prefetch [-f images.txt]... [--jobs n] [pull flags] [<image>...]
+/// Usage: your_docker.sh login [--username user --password-stdin] [--credential-helper name]
+///        [--insecure-registry host]... [--proxy url] [registry]
+/// Usage: your_docker.sh logout [registry]
+/// Usage: your_docker.sh tags [registry flags] <image>
+/// Usage: your_docker.sh push [--chunk-size bytes] [registry flags] <image>
+/// Usage: your_docker.sh manifest inspect [pull flags] <image>
+/// Usage: your_docker.sh search [--limit n] [--no-trunc] [-f key=value]... [registry flags] <term>
+///
+/// Pull flags: [--platform os/arch[/variant]] [--max-concurrent-downloads n]
+///             [--download-ranges n] [--limit-rate bytes[k|m|g]]
+///             [-q|--quiet] [--progress auto|tty|plain|json|quiet]
+///             [--verify --verify-key cosign.pub] [registry flags]
+/// Registry flags: [--retry-attempts n] [--retry-backoff-ms ms] [--no-retry-jitter]
+///        [--username user --password-stdin] [--insecure-registry host]... [--proxy url]
+pub fn parse(args: &[String]) -> Result<Command> {
+    let subcommand = args.get(1).context("No subcommand given")?;
+    match subcommand.as_str() {
+        "pull" => parse_pull(&args[2..]).map(Command::Pull),
+        "run" => parse_run(&args[2..]).map(|options| Command::Run(Box::new(options))),
+        "login" => parse_login(&args[2..]).map(Command::Login),
+        "logout" => parse_logout(&args[2..]).map(Command::Logout),
+        "tags" => parse_repository("tags", &args[2..]).map(Command::Tags),
+        "push" => parse_push(&args[2..]).map(Command::Push),
+        "manifest" => match args.get(2).map(String::as_str) {
+            Some("inspect") => parse_pull(&args[3..]).map(Command::ManifestInspect),
+            Some(other) => bail!("Unknown manifest subcommand '{}'", other),
+            None => bail!("No manifest subcommand given"),
+        },
+        "search" => parse_search(&args[2..]).map(Command::Search),
+        "lock" => parse_lock(&args[2..]).map(Command::Lock),
+        "prefetch" => parse_prefetch(&args[2..]).map(Command::Prefetch),
+        "images" => parse_images(&args[2..]).map(Command::Images),
+        "history" => parse_history(&args[2..]).map(Command::History),
+        "sbom" => parse_sbom(&args[2..]).map(Command::Sbom),
+        "tag" => parse_tag(&args[2..]).map(Command::Tag),
+        "rmi" => parse_rmi(&args[2..]).map(Command::Rmi),
+        "save" => parse_save(&args[2..]).map(Command::Save),
+        "load" => parse_load(&args[2..]).map(Command::Load),
+        "import" => parse_import(&args[2..]).map(Command::Import),
+        "system" | "image" => match args.get(2).map(String::as_str) {
+            Some("prune") => parse_prune(&args[3..]).map(Command::Prune),
+            Some("df") if subcommand == "system" => parse_df(&args[3..]).map(Command::SystemDf),
+            Some("inspect") if subcommand == "image" => {
+                parse_inspect(&args[3..]).map(Command::ImageInspect)
+            }
+            Some(other) => bail!("Unknown {} subcommand '{}'", subcommand, other),
+            None => bail!("No {} subcommand given", subcommand),
+        },
+        "store" => match args.get(2).map(String::as_str) {
+            Some("verify") => parse_verify(&args[3..]).map(Command::StoreVerify),
+            Some("recompress") => parse_recompress(&args[3..]).map(Command::StoreRecompress),
+            Some(other) => bail!("Unknown store subcommand '{}'", other),
+            None => bail!("No store subcommand given"),
+        },
+        "ps" => parse_ps(&args[2..]).map(Command::Ps),
+        "rm" => parse_rm(&args[2..]).map(Command::Rm),
+        "diff" => parse_diff(&args[2..]).map(Command::Diff),
+        "commit" => parse_commit(&args[2..]).map(Command::Commit),
+        "cp" => parse_cp(&args[2..]).map(Command::Cp),
+        "export" => parse_export(&args[2..]).map(Command::Export),
+        "volume" => match args.get(2).map(String::as_str) {
+            Some("create") => parse_volume_create(&args[3..]).map(Command::VolumeCreate),
+            Some("ls") | Some("list") => parse_volume_ls(&args[3..]).map(Command::VolumeLs),
+            Some("inspect") => parse_volume_inspect(&args[3..]).map(Command::VolumeInspect),
+            Some("rm") | Some("remove") => parse_volume_rm(&args[3..]).map(Command::VolumeRm),
+            Some(other) => bail!("Unknown volume subcommand '{}'", other),
+            None => bail!("No volume subcommand given"),
+        },
+        other => bail!("Unknown subcommand '{}'", other),
+    }
+}
+
+fn parse_pull(args: &[String]) -> Result<PullOptions> {
+    let mut pull_flags = PullFlags::new();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        if !pull_flags.parse(&flag, &mut flags)? {
+            bail!("Unknown flag '{}' for pull", flag);
+        }
+    }
+
+    match flags.remaining() {
+        [image] => pull_flags.finish(image.clone()),
+        [] => bail!("No image given to pull"),
+        _ => bail!(
+            "Expected a single image to pull, got {:?}",
+            flags.remaining()
+        ),
+    }
+}
+
+fn parse_run(args: &[String]) -> Result<RunOptions> {
+    let mut pull_flags = PullFlags::new();
+    let mut pull_policy = None;
+    let mut offline = false;
+    let mut entrypoint = None;
+    let mut env = Vec::new();
+    let mut working_dir = None;
+    let mut volumes = Vec::new();
+    let mut tmpfs = Vec::new();
+    let mut devices = Vec::new();
+    let mut shm_size = DEFAULT_SHM_SIZE;
+    let mut storage_size = None;
+    let mut read_only = false;
+    let mut user = None;
+    let mut uidmap = Vec::new();
+    let mut gidmap = Vec::new();
+    let mut locked = false;
+    let mut lockfile = None;
+    let mut link_layers = false;
+    let mut no_sysfs = false;
+    let mut tty = false;
+    let mut remove = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        if pull_flags.parse(&flag, &mut flags)? {
+            continue;
+        }
+        match flag.as_str() {
+            "--pull" => pull_policy = Some(PullPolicy::parse(&flags.value(&flag)?)?),
+            "--offline" => offline = true,
+            "--entrypoint" => entrypoint = Some(flags.value(&flag)?),
+            "--env" | "-e" => env.push(flags.value(&flag)?),
+            "--workdir" | "-w" => working_dir = Some(flags.value(&flag)?),
+            "--volume" | "-v" => volumes.push(Volume::parse(&flags.value(&flag)?)?),
+            "--tmpfs" => tmpfs.push(Tmpfs::parse(&flags.value(&flag)?)?),
+            "--device" => devices.push(Device::parse(&flags.value(&flag)?)?),
+            "--shm-size" => {
+                shm_size = parse_size(&flags.value(&flag)?)?;
+                // A tmpfs of size 0 has no limit at all
+                if shm_size == 0 {
+                    bail!("--shm-size has to be more than 0");
+                }
+            }
+            "--storage-opt" => {
+                let option = flags.value(&flag)?;
+                match option.split_once('=') {
+                    Some(("size", size)) => match parse_size(size)? {
+                        0 => bail!("--storage-opt size has to be more than 0"),
+                        size => storage_size = Some(size),
+                    },
+                    _ => bail!("Unknown storage option '{}', expected size=<size>", option),
+                }
+            }
+            "--read-only" => read_only = true,
+            // -u is already --username, unlike with docker
+            "--user" => user = Some(flags.value(&flag)?),
+            "--uidmap" => uidmap.push(IdRange::parse(&flags.value(&flag)?)?),
+            "--gidmap" => gidmap.push(IdRange::parse(&flags.value(&flag)?)?),
+            "--locked" => locked = true,
+            "--lockfile" => lockfile = Some(PathBuf::from(flags.value(&flag)?)),
+            "--link-layers" => link_layers = true,
+            "--no-sysfs" => no_sysfs = true,
+            "--tty" | "-t" => tty = true,
+            "--rm" => remove = true,
+            _ => bail!("Unknown flag '{}' for run", flag),
+        }
+    }
+
+    let locked = match (locked, lockfile) {
+        (true, lockfile) => Some(lockfile.unwrap_or_else(|| PathBuf::from(DEFAULT_LOCKFILE))),
+        (false, Some(_)) => bail!("--lockfile only makes sense along with --locked"),
+        (false, None) => None,
+    };
+
+    if read_only {
+        for path in ["/tmp", "/run"] {
+            let taken = volumes
+                .iter()
+                .map(|volume: &Volume| &volume.destination)
+                .chain(tmpfs.iter().map(|tmpfs: &Tmpfs| &tmpfs.destination))
+                .any(|destination| destination.trim_end_matches('/') == path);
+            if !taken {
+                tmpfs.push(Tmpfs {
+                    destination: path.to_string(),
+                    read_only: false,
+                    // Programs do run scripts they've written to /tmp
+                    exec: true,
+                    suid: false,
+                    dev: false,
+                    data: vec![match path {
+                        "/tmp" => String::from("mode=1777"),
+                        _ => String::from("mode=755"),
+                    }],
+                });
+            }
+        }
+    }
+
+    let id_mappings = match (uidmap.is_empty(), gidmap.is_empty()) {
+        (true, true) => None,
+        (false, true) => Some((uidmap.clone(), uidmap)),
+        (true, false) => Some((gidmap.clone(), gidmap)),
+        (false, false) => Some((uidmap, gidmap)),
+    }
+    .map(|(uids, gids)| IdMappings {
+        uids: IdMap::new(uids),
+        gids: IdMap::new(gids),
+    });
+
+    let pull_policy = match (pull_policy, offline) {
+        (None, false) => PullPolicy::Missing,
+        (None, true) | (Some(PullPolicy::Never), true) => PullPolicy::Never,
+        (Some(_), true) => bail!("--offline can only be combined with --pull=never"),
+        (Some(policy), false) => policy,
+    };
+
+    let mut positional = flags.remaining().iter();
+    let image = positional.next().context("No image given to run")?.clone();
+    let command = positional.next().cloned();
+
+    Ok(RunOptions {
+        pull: pull_flags.finish(image)?,
+        pull_policy,
+        offline,
+        entrypoint,
+        env,
+        working_dir,
+        volumes,
+        tmpfs,
+        devices,
+        shm_size,
+        storage_size,
+        read_only,
+        user,
+        id_mappings,
+        locked,
+        link_layers,
+        no_sysfs,
+        tty,
+        remove,
+        command,
+        args: positional.cloned().collect(),
+    })
+}
+
+fn parse_lock(args: &[String]) -> Result<LockOptions> {
+    let mut pull_flags = PullFlags::new();
+    let mut lockfile = PathBuf::from(DEFAULT_LOCKFILE);
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        if pull_flags.parse(&flag, &mut flags)? {
+            continue;
+        }
+        match flag.as_str() {
+            "--lockfile" => lockfile = PathBuf::from(flags.value(&flag)?),
+            _ => bail!("Unknown flag '{}' for lock", flag),
+        }
+    }
+
+    Ok(LockOptions {
+        pull: pull_flags.finish(String::new())?,
+        images: flags.remaining().to_vec(),
+        lockfile,
+    })
+}
+
+fn parse_prefetch(args: &[String]) -> Result<PrefetchOptions> {
+    let mut pull_flags = PullFlags::new();
+    let mut files = Vec::new();
+    let mut jobs = DEFAULT_PREFETCH_JOBS;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        if pull_flags.parse(&flag, &mut flags)? {
+            continue;
+        }
+        match flag.as_str() {
+            "--file" | "-f" => files.push(PathBuf::from(flags.value(&flag)?)),
+            "--jobs" | "-j" => {
+                jobs = parse_number(&flag, &flags.value(&flag)?)?;
+                if jobs == 0 {
+                    bail!("--jobs must be at least 1");
+                }
+            }
+            _ => bail!("Unknown flag '{}' for prefetch", flag),
+        }
+    }
+
+    let images = flags.remaining().to_vec();
+    if images.is_empty() && files.is_empty() {
+        bail!("No images given to prefetch, list them or pass -f images.txt");
+    }
+    // Bars for several images at once would be unreadable, prefetch reports its own progress
+    pull_flags.progress = Some(ProgressMode::Quiet);
+    Ok(PrefetchOptions {
+        pull: pull_flags.finish(String::new())?,
+        images,
+        files,
+        jobs,
+    })
+}
+
+fn parse_login(args: &[String]) -> Result<LoginOptions> {
+    let mut registry_flags = RegistryFlags::default();
+    let mut credential_helper = None;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        if registry_flags.parse(&flag, &mut flags)? {
+            continue;
+        }
+        match flag.as_str() {
+            "--credential-helper" => credential_helper = Some(flags.value(&flag)?),
+            _ => bail!("Unknown flag '{}' for login", flag),
+        }
+    }
+
+    let (retry, credentials, config) = registry_flags.finish()?;
+    Ok(LoginOptions {
+        registry: parse_registry(flags.remaining())?,
+        credentials: credentials
+            .context("login requires --username and --password-stdin (or MINIDOCKER_AUTH)")?,
+        credential_helper,
+        retry,
+        config,
+    })
+}
+
+fn parse_logout(args: &[String]) -> Result<LogoutOptions> {
+    let mut flags = Flags::new(args);
+    if let Some(flag) = flags.next_flag() {
+        bail!("Unknown flag '{}' for logout", flag);
+    }
+    Ok(LogoutOptions {
+        registry: parse_registry(flags.remaining())?,
+    })
+}
+
+fn parse_push(args: &[String]) -> Result<PushOptions> {
+    let mut registry_flags = RegistryFlags::default();
+    let mut chunk_size = DEFAULT_UPLOAD_CHUNK_SIZE;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        if registry_flags.parse(&flag, &mut flags)? {
+            continue;
+        }
+        match flag.as_str() {
+            "--chunk-size" => {
+                chunk_size = parse_number(&flag, &flags.value(&flag)?)?;
+                if chunk_size == 0 {
+                    bail!("--chunk-size must be at least 1");
+                }
+            }
+            _ => bail!("Unknown flag '{}' for push", flag),
+        }
+    }
+
+    let image = match flags.remaining() {
+        [image] => image.clone(),
+        [] => bail!("No image given to push"),
+        _ => bail!("Expected a single image, got {:?}", flags.remaining()),
+    };
+    let (retry, credentials, config) = registry_flags.finish()?;
+    Ok(PushOptions {
+        retry,
+        credentials,
+        config,
+        chunk_size: chunk_size as u64,
+        image,
+    })
+}
+
+fn parse_search(args: &[String]) -> Result<SearchOptions> {
+    let mut registry_flags = RegistryFlags::default();
+    let mut limit = DEFAULT_SEARCH_LIMIT;
+    let mut no_trunc = false;
+    let mut filter = SearchFilter::default();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        if registry_flags.parse(&flag, &mut flags)? {
+            continue;
+        }
+        match flag.as_str() {
+            "--limit" => {
+                limit = parse_number(&flag, &flags.value(&flag)?)?;
+                if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+                    bail!("--limit must be between 1 and {}", MAX_SEARCH_LIMIT);
+                }
+            }
+            "--no-trunc" => no_trunc = true,
+            "--filter" | "-f" => {
+                let value = flags.value(&flag)?;
+                let (key, condition) = value
+                    .split_once('=')
+                    .with_context(|| format!("Expected key=value for {}, got '{}'", flag, value))?;
+                let parse_bool = |condition: &str| match condition {
+                    "true" => Ok(true),
+                    "false" => Ok(false),
+                    _ => Err(anyhow::anyhow!(
+                        "Expected true or false for {}, got '{}'",
+                        key,
+                        condition
+                    )),
+                };
+                match key {
+                    "stars" => filter.stars = Some(parse_number(key, condition)? as u64),
+                    "is-official" => filter.is_official = Some(parse_bool(condition)?),
+                    "is-automated" => filter.is_automated = Some(parse_bool(condition)?),
+                    _ => bail!(
+                        "Unknown search filter '{}', expected stars, is-official, or is-automated",
+                        key
+                    ),
+                }
+            }
+            _ => bail!("Unknown flag '{}' for search", flag),
+        }
+    }
+
+    let term = match flags.remaining() {
+        [term] => term.clone(),
+        [] => bail!("No search term given"),
+        _ => bail!("Expected a single search term, got {:?}", flags.remaining()),
+    };
+    let (retry, _, config) = registry_flags.finish()?;
+    Ok(SearchOptions {
+        retry,
+        config,
+        term,
+        limit,
+        no_trunc,
+        filter,
+    })
+}
+
+fn parse_history(args: &[String]) -> Result<HistoryOptions> {
+    let mut format = None;
+    let mut no_trunc = false;
+    let mut quiet = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--format" => format = Some(flags.value(&flag)?),
+            "--no-trunc" => no_trunc = true,
+            "--quiet" | "-q" => quiet = true,
+            _ => bail!("Unknown flag '{}' for history", flag),
+        }
+    }
+
+    let image = match flags.remaining() {
+        [image] => image.clone(),
+        [] => bail!("No image given to history"),
+        _ => bail!("Expected a single image, got {:?}", flags.remaining()),
+    };
+    Ok(HistoryOptions {
+        image,
+        format,
+        no_trunc,
+        quiet,
+    })
+}
+
+fn parse_inspect(args: &[String]) -> Result<InspectOptions> {
+    let mut format = None;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--format" | "-f" => format = Some(flags.value(&flag)?),
+            _ => bail!("Unknown flag '{}' for image inspect", flag),
+        }
+    }
+
+    if flags.remaining().is_empty() {
+        bail!("No images given to inspect");
+    }
+    Ok(InspectOptions {
+        images: flags.remaining().to_vec(),
+        format,
+    })
+}
+
+fn parse_sbom(args: &[String]) -> Result<SbomOptions> {
+    let mut format = SbomFormat::CycloneDx;
+    let mut output = None;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--format" => {
+                format = match flags.value(&flag)?.as_str() {
+                    "cyclonedx" | "cyclonedx-json" => SbomFormat::CycloneDx,
+                    "spdx" | "spdx-json" => SbomFormat::Spdx,
+                    other => bail!(
+                        "Unknown sbom format '{}', expected cyclonedx or spdx",
+                        other
+                    ),
+                }
+            }
+            "--output" | "-o" => output = Some(PathBuf::from(flags.value(&flag)?)),
+            _ => bail!("Unknown flag '{}' for sbom", flag),
+        }
+    }
+
+    let image = match flags.remaining() {
+        [image] => image.clone(),
+        [] => bail!("No image given to sbom"),
+        _ => bail!("Expected a single image, got {:?}", flags.remaining()),
+    };
+    Ok(SbomOptions {
+        image,
+        format,
+        output,
+    })
+}
+
+fn parse_tag(args: &[String]) -> Result<TagOptions> {
+    let mut flags = Flags::new(args);
+    if let Some(flag) = flags.next_flag() {
+        bail!("Unknown flag '{}' for tag", flag);
+    }
+    match flags.remaining() {
+        [source, target] => Ok(TagOptions {
+            source: source.clone(),
+            target: target.clone(),
+        }),
+        _ => bail!(
+            "Expected a source image and a target reference, got {:?}",
+            flags.remaining()
+        ),
+    }
+}
+
+fn parse_recompress(args: &[String]) -> Result<RecompressOptions> {
+    let mut level = DEFAULT_ZSTD_LEVEL;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--level" => {
+                level = parse_number(&flag, &flags.value(&flag)?)?;
+                if !(1..=19).contains(&level) {
+                    bail!("--level must be between 1 and 19");
+                }
+            }
+            _ => bail!("Unknown flag '{}' for store recompress", flag),
+        }
+    }
+    Ok(RecompressOptions {
+        images: flags.remaining().to_vec(),
+        level,
+    })
+}
+
+fn parse_verify(args: &[String]) -> Result<VerifyOptions> {
+    let mut repair = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--repair" => repair = true,
+            _ => bail!("Unknown flag '{}' for store verify", flag),
+        }
+    }
+    if !flags.remaining().is_empty() {
+        bail!("Unexpected arguments {:?}", flags.remaining());
+    }
+    Ok(VerifyOptions { repair })
+}
+
+fn parse_volume_create(args: &[String]) -> Result<VolumeCreateOptions> {
+    let mut flags = Flags::new(args);
+    if let Some(flag) = flags.next_flag() {
+        bail!("Unknown flag '{}' for volume create", flag);
+    }
+    match flags.remaining() {
+        [name] => Ok(VolumeCreateOptions {
+            name: Some(name.clone()),
+        }),
+        [] => Ok(VolumeCreateOptions { name: None }),
+        _ => bail!(
+            "Expected at most one volume name, got {:?}",
+            flags.remaining()
+        ),
+    }
+}
+
+fn parse_volume_ls(args: &[String]) -> Result<VolumeLsOptions> {
+    let mut quiet = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--quiet" | "-q" => quiet = true,
+            _ => bail!("Unknown flag '{}' for volume ls", flag),
+        }
+    }
+    if !flags.remaining().is_empty() {
+        bail!("Unexpected arguments {:?}", flags.remaining());
+    }
+    Ok(VolumeLsOptions { quiet })
+}
+
+fn parse_volume_inspect(args: &[String]) -> Result<VolumeInspectOptions> {
+    let mut format = None;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--format" | "-f" => format = Some(flags.value(&flag)?),
+            _ => bail!("Unknown flag '{}' for volume inspect", flag),
+        }
+    }
+    if flags.remaining().is_empty() {
+        bail!("No volumes given to inspect");
+    }
+    Ok(VolumeInspectOptions {
+        names: flags.remaining().to_vec(),
+        format,
+    })
+}
+
+fn parse_volume_rm(args: &[String]) -> Result<VolumeRmOptions> {
+    let mut force = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--force" | "-f" => force = true,
+            _ => bail!("Unknown flag '{}' for volume rm", flag),
+        }
+    }
+    if flags.remaining().is_empty() {
+        bail!("No volumes given to remove");
+    }
+    Ok(VolumeRmOptions {
+        names: flags.remaining().to_vec(),
+        force,
+    })
+}
+
+fn parse_ps(args: &[String]) -> Result<PsOptions> {
+    let mut all = false;
+    let mut quiet = false;
+    let mut no_trunc = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--all" | "-a" => all = true,
+            "--quiet" | "-q" => quiet = true,
+            "--no-trunc" => no_trunc = true,
+            _ => bail!("Unknown flag '{}' for ps", flag),
+        }
+    }
+    if !flags.remaining().is_empty() {
+        bail!("Unexpected arguments {:?}", flags.remaining());
+    }
+    Ok(PsOptions {
+        all,
+        quiet,
+        no_trunc,
+    })
+}
+
+fn parse_rm(args: &[String]) -> Result<RmOptions> {
+    let mut flags = Flags::new(args);
+    if let Some(flag) = flags.next_flag() {
+        bail!("Unknown flag '{}' for rm", flag);
+    }
+    if flags.remaining().is_empty() {
+        bail!("No containers given to remove");
+    }
+    Ok(RmOptions {
+        containers: flags.remaining().to_vec(),
+    })
+}
+
+fn parse_diff(args: &[String]) -> Result<DiffOptions> {
+    let mut flags = Flags::new(args);
+    if let Some(flag) = flags.next_flag() {
+        bail!("Unknown flag '{}' for diff", flag);
+    }
+    match flags.remaining() {
+        [container] => Ok(DiffOptions {
+            container: container.clone(),
+        }),
+        [] => bail!("No container given to diff"),
+        _ => bail!(
+            "Expected a single container to diff, got {:?}",
+            flags.remaining()
+        ),
+    }
+}
+
+fn parse_commit(args: &[String]) -> Result<CommitOptions> {
+    let mut changes = Vec::new();
+    let mut message = None;
+    let mut author = None;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--change" | "-c" => changes.push(flags.value(&flag)?),
+            "--message" | "-m" => message = Some(flags.value(&flag)?),
+            "--author" | "-a" => author = Some(flags.value(&flag)?),
+            _ => bail!("Unknown flag '{}' for commit", flag),
+        }
+    }
+
+    let (container, reference) = match flags.remaining() {
+        [container] => (container.clone(), None),
+        [container, reference] => (container.clone(), Some(reference.clone())),
+        [] => bail!("No container given to commit"),
+        _ => bail!(
+            "Expected a container and an optional repository[:tag], got {:?}",
+            flags.remaining()
+        ),
+    };
+    Ok(CommitOptions {
+        container,
+        reference,
+        changes,
+        message,
+        author,
+    })
+}
+
+fn parse_cp(args: &[String]) -> Result<CpOptions> {
+    let mut archive = false;
+    let mut follow_link = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--archive" | "-a" => archive = true,
+            "--follow-link" | "-L" => follow_link = true,
+            _ => bail!("Unknown flag '{}' for cp", flag),
+        }
+    }
+
+    let (source, destination) = match flags.remaining() {
+        [source, destination] => (source, destination),
+        _ => bail!(
+            "Expected a source and a destination to copy, got {:?}",
+            flags.remaining()
+        ),
+    };
+    let (container, direction, container_path, host_path) =
+        match (container_path(source), container_path(destination)) {
+            (Some((container, path)), None) => {
+                (container, CopyDirection::FromContainer, path, destination)
+            }
+            (None, Some((container, path))) => {
+                (container, CopyDirection::ToContainer, path, source)
+            }
+            (Some(_), Some(_)) => bail!("Copying between containers isn't supported"),
+            (None, None) => bail!("Either the source or the destination has to be container:path"),
+        };
+    Ok(CpOptions {
+        container: container.to_string(),
+        direction,
+        container_path: container_path.to_string(),
+        host_path: host_path.clone(),
+        archive,
+        follow_link,
+    })
+}
+
+/// Splits `container:path`, like docker a host path with a `:` in it needs a `/` before it
+/// (like `./a:b`) not to be taken for one
+fn container_path(argument: &str) -> Option<(&str, &str)> {
+    match argument.split_once(':') {
+        Some((container, path)) if !container.is_empty() && !container.contains('/') => {
+            Some((container, path))
+        }
+        _ => None,
+    }
+}
+
+fn parse_export(args: &[String]) -> Result<ExportOptions> {
+    let mut output = None;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--output" | "-o" => output = Some(PathBuf::from(flags.value(&flag)?)),
+            _ => bail!("Unknown flag '{}' for export", flag),
+        }
+    }
+
+    match flags.remaining() {
+        [container] => Ok(ExportOptions {
+            container: container.clone(),
+            output,
+        }),
+        [] => bail!("No container given to export"),
+        _ => bail!(
+            "Expected a single container to export, got {:?}",
+            flags.remaining()
+        ),
+    }
+}
+
+fn parse_images(args: &[String]) -> Result<ImagesOptions> {
+    let mut format = None;
+    let mut digests = false;
+    let mut no_trunc = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--format" => format = Some(flags.value(&flag)?),
+            "--digests" => digests = true,
+            "--no-trunc" => no_trunc = true,
+            _ => bail!("Unknown flag '{}' for images", flag),
+        }
+    }
+
+    let repository = match flags.remaining() {
+        [repository] => Some(repository.clone()),
+        [] => None,
+        _ => bail!(
+            "Expected at most one repository, got {:?}",
+            flags.remaining()
+        ),
+    };
+    Ok(ImagesOptions {
+        repository,
+        format,
+        digests,
+        no_trunc,
+    })
+}
+
+fn parse_rmi(args: &[String]) -> Result<RmiOptions> {
+    let mut force = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--force" | "-f" => force = true,
+            _ => bail!("Unknown flag '{}' for rmi", flag),
+        }
+    }
+
+    if flags.remaining().is_empty() {
+        bail!("No images given to remove");
+    }
+    Ok(RmiOptions {
+        images: flags.remaining().to_vec(),
+        force,
+    })
+}
+
+fn parse_prune(args: &[String]) -> Result<PruneOptions> {
+    let mut force = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--force" | "-f" => force = true,
+            _ => bail!("Unknown flag '{}' for prune", flag),
+        }
+    }
+
+    if !flags.remaining().is_empty() {
+        bail!("prune doesn't take arguments, got {:?}", flags.remaining());
+    }
+    Ok(PruneOptions { force })
+}
+
+fn parse_df(args: &[String]) -> Result<DfOptions> {
+    let mut verbose = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--verbose" | "-v" => verbose = true,
+            _ => bail!("Unknown flag '{}' for system df", flag),
+        }
+    }
+
+    if !flags.remaining().is_empty() {
+        bail!(
+            "system df doesn't take arguments, got {:?}",
+            flags.remaining()
+        );
+    }
+    Ok(DfOptions { verbose })
+}
+
+fn parse_save(args: &[String]) -> Result<SaveOptions> {
+    let mut output = None;
+    let mut format = SaveFormat::DockerArchive;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--output" | "-o" => output = Some(PathBuf::from(flags.value(&flag)?)),
+            "--format" => format = SaveFormat::parse(&flags.value(&flag)?)?,
+            _ => bail!("Unknown flag '{}' for save", flag),
+        }
+    }
+
+    if flags.remaining().is_empty() {
+        bail!("No images given to save");
+    }
+    if format == SaveFormat::Oci && output.is_none() {
+        bail!("--format oci writes a directory, which needs -o");
+    }
+    Ok(SaveOptions {
+        images: flags.remaining().to_vec(),
+        output,
+        format,
+    })
+}
+
+fn parse_load(args: &[String]) -> Result<LoadOptions> {
+    let mut input = None;
+    let mut quiet = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--input" | "-i" => input = Some(PathBuf::from(flags.value(&flag)?)),
+            "--quiet" | "-q" => quiet = true,
+            _ => bail!("Unknown flag '{}' for load", flag),
+        }
+    }
+
+    if !flags.remaining().is_empty() {
+        bail!("load doesn't take arguments, got {:?}", flags.remaining());
+    }
+    Ok(LoadOptions { input, quiet })
+}
+
+fn parse_import(args: &[String]) -> Result<ImportOptions> {
+    let mut changes = Vec::new();
+    let mut message = None;
+    let mut platform = Platform::host();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.as_str() {
+            "--change" | "-c" => changes.push(flags.value(&flag)?),
+            "--message" | "-m" => message = Some(flags.value(&flag)?),
+            "--platform" => platform = Platform::parse(&flags.value(&flag)?)?,
+            _ => bail!("Unknown flag '{}' for import", flag),
+        }
+    }
+
+    let (source, reference) = match flags.remaining() {
+        [source] => (source.clone(), None),
+        [source, reference] => (source.clone(), Some(reference.clone())),
+        [] => bail!("No tarball given to import"),
+        _ => bail!(
+            "Expected a tarball and an optional repository[:tag], got {:?}",
+            flags.remaining()
+        ),
+    };
+    Ok(ImportOptions {
+        source,
+        reference,
+        changes,
+        message,
+        platform,
+    })
+}
+
+fn parse_repository(subcommand: &str, args: &[String]) -> Result<RepositoryOptions> {
+    let mut registry_flags = RegistryFlags::default();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        if !registry_flags.parse(&flag, &mut flags)? {
+            bail!("Unknown flag '{}' for {}", flag, subcommand);
+        }
+    }
+
+    let image = match flags.remaining() {
+        [image] => image.clone(),
+        [] => bail!("No image given to {}", subcommand),
+        _ => bail!("Expected a single image, got {:?}", flags.remaining()),
+    };
+    let (retry, credentials, config) = registry_flags.finish()?;
+    Ok(RepositoryOptions {
+        retry,
+        credentials,
+        config,
+        image,
+    })
+}
+
+/// The optional registry argument of `login`/`logout`, defaulting to Docker Hub
+fn parse_registry(positional: &[String]) -> Result<String> {
+    match positional {
+        [] => Ok(String::from(DOCKER_HUB)),
+        [registry] => Ok(registry_host(registry)),
+        _ => bail!("Expected at most one registry, got {:?}", positional),
+    }
+}
+
+/// Strips any scheme or trailing slash people tend to include when naming a registry
+fn registry_host(registry: &str) -> String {
+    let host = registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/');
+    normalize_registry(host)
+}
+
+/// Flags shared by every subcommand that pulls images
+struct PullFlags {
+    platform: Platform,
+    max_concurrent_downloads: usize,
+    download_ranges: usize,
+    limit_rate: Option<u64>,
+    progress: Option<ProgressMode>,
+    verify: bool,
+    verify_key: Option<PathBuf>,
+    registry_flags: RegistryFlags,
+}
+
+impl PullFlags {
+    fn new() -> PullFlags {
+        PullFlags {
+            platform: Platform::host(),
+            max_concurrent_downloads: DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+            download_ranges: DEFAULT_DOWNLOAD_RANGES,
+            limit_rate: None,
+            progress: None,
+            verify: false,
+            verify_key: None,
+            registry_flags: RegistryFlags::default(),
+        }
+    }
+
+    /// Handles `flag` if it's one of ours, returning whether it was
+    fn parse(&mut self, flag: &str, flags: &mut Flags) -> Result<bool> {
+        if self.registry_flags.parse(flag, flags)? {
+            return Ok(true);
+        }
+        match flag {
+            "--platform" => self.platform = Platform::parse(&flags.value(flag)?)?,
+            "--max-concurrent-downloads" => {
+                self.max_concurrent_downloads = parse_number(flag, &flags.value(flag)?)?;
+                if self.max_concurrent_downloads == 0 {
+                    bail!("--max-concurrent-downloads must be at least 1");
+                }
+            }
+            "--download-ranges" => {
+                self.download_ranges = parse_number(flag, &flags.value(flag)?)?;
+                if self.download_ranges == 0 {
+                    bail!("--download-ranges must be at least 1");
+                }
+            }
+            "--limit-rate" => {
+                let limit_rate = parse_rate(flag, &flags.value(flag)?)?;
+                if limit_rate == 0 {
+                    bail!("--limit-rate must be at least 1 byte per second");
+                }
+                self.limit_rate = Some(limit_rate);
+            }
+            "--quiet" | "-q" => self.progress = Some(ProgressMode::Quiet),
+            "--progress" => self.progress = Some(ProgressMode::parse(&flags.value(flag)?)?),
+            "--verify" => self.verify = true,
+            "--verify-key" => self.verify_key = Some(PathBuf::from(flags.value(flag)?)),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn finish(self, image: String) -> Result<PullOptions> {
+        let verify_key = match (self.verify, self.verify_key) {
+            (true, Some(key)) => Some(key),
+            (true, None) => bail!(
+                "--verify needs --verify-key, keyless verification through Fulcio and Rekor isn't supported"
+            ),
+            (false, Some(_)) => bail!("--verify-key only makes sense along with --verify"),
+            (false, None) => None,
+        };
+        let (retry, credentials, config) = self.registry_flags.finish()?;
+        Ok(PullOptions {
+            platform: self.platform,
+            max_concurrent_downloads: self.max_concurrent_downloads,
+            download_ranges: self.download_ranges,
+            limit_rate: self.limit_rate.map(|rate| Arc::new(Throttle::new(rate))),
+            progress: Arc::new(Progress::new(
+                self.progress.unwrap_or_else(ProgressMode::detect),
+            )),
+            verify_key,
+            retry,
+            credentials,
+            config,
+            image,
+        })
+    }
+}
+
+/// Flags shared by every subcommand that talks to a registry
+#[derive(Default)]
+struct RegistryFlags {
+    retry: RetryPolicy,
+    username: Option<String>,
+    password_stdin: bool,
+    insecure_registries: Vec<String>,
+    proxy: Option<String>,
+}
+
+impl RegistryFlags {
+    /// Handles `flag` if it's one of ours, returning whether it was
+    fn parse(&mut self, flag: &str, flags: &mut Flags) -> Result<bool> {
+        match flag {
+            "--retry-attempts" => {
+                self.retry.attempts = parse_number(flag, &flags.value(flag)?)?.max(1) as u32;
+            }
+            "--retry-backoff-ms" => {
+                self.retry.initial_backoff =
+                    Duration::from_millis(parse_number(flag, &flags.value(flag)?)? as u64);
+            }
+            "--no-retry-jitter" => self.retry.jitter = false,
+            "--username" | "-u" => self.username = Some(flags.value(flag)?),
+            "--password-stdin" => self.password_stdin = true,
+            "--insecure-registry" => self
+                .insecure_registries
+                .push(registry_host(&flags.value(flag)?)),
+            "--proxy" => self.proxy = Some(flags.value(flag)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Resolves credentials and merges our flags into the settings from `daemon.json`
+    fn finish(self) -> Result<(RetryPolicy, Option<Credentials>, Config)> {
+        let credentials = match (self.username, self.password_stdin) {
+            (Some(username), true) => Some(Credentials::with_password_from_stdin(&username)?),
+            (Some(_), false) => bail!("--username requires --password-stdin"),
+            (None, true) => bail!("--password-stdin requires --username"),
+            (None, false) => Credentials::from_env()?,
+        };
+        let mut config = Config::load()?;
+        config.insecure_registries.extend(self.insecure_registries);
+        if let Some(proxy) = self.proxy {
+            config.proxies.http_proxy = Some(proxy.clone());
+            config.proxies.https_proxy = Some(proxy);
+        }
+        Ok((self.retry, credentials, config))
+    }
+}
+
+fn parse_number(flag: &str, value: &str) -> Result<usize> {
+    value
+        .parse()
+        .with_context(|| format!("Flag '{}' expects a number, got '{}'", flag, value))
+}
+
+/// Parses bytes per second, with an optional `k`, `m`, or `g` suffix (powers of 1024) like curl
+fn parse_rate(flag: &str, value: &str) -> Result<u64> {
+    let (number, multiplier) = match value.to_ascii_lowercase().chars().last() {
+        Some('k') => (&value[..value.len() - 1], 1024),
+        Some('m') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    let rate = parse_number(flag, number)? as u64;
+    rate.checked_mul(multiplier)
+        .with_context(|| format!("Flag '{}' got a rate that's too large, '{}'", flag, value))
+}
+
+/// Walks the leading `--flag [value]` arguments of a subcommand
+///
+/// Flag parsing stops at the first positional argument (or a literal `--`) so that flags meant
+/// for the containerized command are passed through untouched.
+struct Flags<'a> {
+    args: &'a [String],
+    position: usize,
+    inline_value: Option<String>,
+}
+
+impl<'a> Flags<'a> {
+    fn new(args: &'a [String]) -> Flags<'a> {
+        Flags {
+            args,
+            position: 0,
+            inline_value: None,
+        }
+    }
+
+    /// Returns the next flag, splitting `--flag=value` forms apart
+    fn next_flag(&mut self) -> Option<String> {
+        let arg = self.args.get(self.position)?;
+        if arg == "--" {
+            self.position += 1;
+            return None;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            return None;
+        }
+        self.position += 1;
+
+        match arg.split_once('=') {
+            Some((flag, value)) => {
+                self.inline_value = Some(value.to_string());
+                Some(flag.to_string())
+            }
+            None => {
+                self.inline_value = None;
+                Some(arg.clone())
+            }
+        }
+    }
+
+    /// Consumes the value belonging to the flag that was just returned
+    fn value(&mut self, flag: &str) -> Result<String> {
+        if let Some(value) = self.inline_value.take() {
+            return Ok(value);
+        }
+        let value = self
+            .args
+            .get(self.position)
+            .with_context(|| format!("Flag '{}' requires a value", flag))?;
+        self.position += 1;
+        Ok(value.clone())
+    }
+
+    fn remaining(&self) -> &'a [String] {
+        &self.args[self.position..]
+    }
+}
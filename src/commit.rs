@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+
+use crate::archive::{write_manifest, LoadedImage};
+use crate::changes::{ChangeKind, Changes};
+use crate::cli::CommitOptions;
+use crate::containers::Container;
+use crate::image::History;
+use crate::images::format_timestamp;
+use crate::import::{apply_change, store_layer, write_config};
+use crate::reference::ImageReference;
+use crate::rootfs::{OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use crate::store::Store;
+
+/// Makes an image of a container, which is its image with what the container changed as one
+/// more layer, like `docker commit`
+///
+/// The layer has what the container added and changed as they are now, and whiteouts for what
+/// it deleted. The config is its image's, with `--change` instructions applied to it and the
+/// container's command (and `--message` and `--author`) recorded in its history. The image's
+/// layers are reused as they are, so pushing it only uploads the new one.
+///
+/// See: https://docs.docker.com/reference/cli/docker/container/commit/
+/// See: https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+pub fn commit_container(
+    store: &Store,
+    container: &Container,
+    options: &CommitOptions,
+) -> Result<LoadedImage> {
+    let reference = options
+        .reference
+        .as_deref()
+        .map(ImageReference::parse)
+        .transpose()?;
+    let manifest = store.image_manifest(&container.record.stored)?;
+    let mut config = store.image_config(&manifest)?;
+    let mut container_config = config.config.clone();
+    for change in &options.changes {
+        apply_change(&mut container_config, change)?;
+    }
+
+    let _blobs = store.share_blobs()?;
+    let changes = container.changes(store)?;
+    let downloads = store.root().join("downloads");
+    let mut file = NamedTempFile::new_in(&downloads)
+        .with_context(|| format!("Tried to create a file in {}", downloads.display()))?;
+    write_layer(&changes, file.as_file_mut())
+        .context("Tried to archive the container's changes")?;
+    file.seek(SeekFrom::Start(0))?;
+    let (layer, diff_id) =
+        store_layer(store, file.as_file()).context("Tried to store the layer")?;
+
+    let created = format_timestamp(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    );
+    config.created = Some(created.clone());
+    config.config = container_config;
+    config.rootfs.diff_ids.push(diff_id);
+    config.history.push(History {
+        created: Some(created),
+        created_by: Some(container.record.command.join(" ")),
+        author: options.author.clone(),
+        comment: options.message.clone(),
+        empty_layer: false,
+    });
+    let config_descriptor = write_config(store, &config)?;
+
+    let id = config_descriptor.digest.clone();
+    let mut layers = manifest.layers.clone();
+    layers.push(layer);
+    let stored = write_manifest(store, config_descriptor, layers, &config)?;
+    let mut tags = Vec::new();
+    if let Some(image) = reference {
+        store.tag(&image, &stored)?;
+        tags.push(image);
+    }
+    Ok(LoadedImage { id, tags })
+}
+
+/// Writes an uncompressed layer tarball of a container's changes
+fn write_layer(changes: &Changes, file: &mut File) -> Result<()> {
+    let mut builder = tar::Builder::new(file);
+    // Symlinks are archived as symlinks, what they point to is the container's, not ours
+    builder.follow_symlinks(false);
+    for change in &changes.changes {
+        let path = change.path.strip_prefix("/").unwrap();
+        if change.kind == ChangeKind::Deleted {
+            let name = format!(
+                "{}{}",
+                WHITEOUT_PREFIX,
+                path.file_name().unwrap().to_string_lossy()
+            );
+            append_empty(&mut builder, &path.with_file_name(name))?;
+            continue;
+        }
+        let source = changes.root.join(path);
+        let metadata = fs::symlink_metadata(&source)
+            .with_context(|| format!("Tried to read {}", source.display()))?;
+        // Sockets only mean anything to whatever's listening on them, docker leaves them out too
+        if metadata.file_type().is_socket() {
+            continue;
+        }
+        builder
+            .append_path_with_name(&source, path)
+            .with_context(|| format!("Tried to archive {}", change.path.display()))?;
+        if changes.opaque.contains(&change.path) {
+            append_empty(&mut builder, &path.join(OPAQUE_WHITEOUT))?;
+        }
+    }
+    builder.finish()?;
+    Ok(())
+}
+
+/// Appends an empty file, which is what whiteouts are
+fn append_empty(builder: &mut tar::Builder<&mut File>, path: &Path) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(0);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    builder
+        .append_data(&mut header, path, io::empty())
+        .with_context(|| format!("Tried to archive {}", path.display()))
+}
@@ -0,0 +1,96 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::process::{Child, Command, Stdio};
+
+use crate::manifest::{
+    DOCKER_FOREIGN_LAYER_GZIP, DOCKER_LAYER_GZIP, DOCKER_LAYER_TAR, OCI_LAYER_GZIP, OCI_LAYER_TAR,
+    OCI_LAYER_ZSTD, OCI_NONDISTRIBUTABLE_LAYER_GZIP, OCI_NONDISTRIBUTABLE_LAYER_TAR,
+    OCI_NONDISTRIBUTABLE_LAYER_ZSTD,
+};
+
+static UNCOMPRESSED_LAYERS: [&str; 3] = [
+    DOCKER_LAYER_TAR,
+    OCI_LAYER_TAR,
+    OCI_NONDISTRIBUTABLE_LAYER_TAR,
+];
+static GZIP_LAYERS: [&str; 4] = [
+    DOCKER_LAYER_GZIP,
+    OCI_LAYER_GZIP,
+    DOCKER_FOREIGN_LAYER_GZIP,
+    OCI_NONDISTRIBUTABLE_LAYER_GZIP,
+];
+static ZSTD_LAYERS: [&str; 2] = [OCI_LAYER_ZSTD, OCI_NONDISTRIBUTABLE_LAYER_ZSTD];
+
+/// How a layer blob is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Picks the decompressor for a layer based on its media type
+    ///
+    /// See: https://github.com/opencontainers/image-spec/blob/main/layer.md#zstd-media-types
+    pub fn from_media_type(media_type: &str) -> Result<Compression> {
+        if UNCOMPRESSED_LAYERS.contains(&media_type) {
+            Ok(Compression::None)
+        } else if GZIP_LAYERS.contains(&media_type) {
+            Ok(Compression::Gzip)
+        } else if ZSTD_LAYERS.contains(&media_type) {
+            Ok(Compression::Zstd)
+        } else {
+            bail!("Unsupported layer media type '{}'", media_type)
+        }
+    }
+}
+
+/// A decompressing reader over a layer blob
+///
+/// There's no zstd implementation among our dependencies, so zstd layers are piped through the
+/// `zstd` binary which is why `finish` needs to be called once everything has been read.
+pub struct Decoder {
+    reader: Box<dyn Read>,
+    child: Option<Child>,
+}
+
+impl Decoder {
+    pub fn new(compression: Compression, blob: File) -> Result<Decoder> {
+        let (reader, child): (Box<dyn Read>, _) = match compression {
+            Compression::None => (Box::new(BufReader::new(blob)), None),
+            Compression::Gzip => (Box::new(GzDecoder::new(BufReader::new(blob))), None),
+            Compression::Zstd => {
+                let mut child = Command::new("zstd")
+                    .args(["--decompress", "--stdout", "--quiet"])
+                    .stdin(Stdio::from(blob))
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .context("Tried to run zstd to decompress a zstd layer, is it installed?")?;
+                let stdout = child.stdout.take().unwrap();
+                (Box::new(stdout), Some(child))
+            }
+        };
+        Ok(Decoder { reader, child })
+    }
+
+    /// Makes sure the external decompressor (if any) didn't fail
+    pub fn finish(self) -> Result<()> {
+        drop(self.reader);
+        if let Some(mut child) = self.child {
+            let status = child.wait().context("Tried to wait for zstd")?;
+            if !status.success() {
+                bail!("zstd failed to decompress layer ({})", status);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for Decoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+/// Our config directory, `$XDG_CONFIG_HOME/minidocker` (or `~/.config/minidocker`)
+pub fn config_dir() -> Result<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            std::env::var_os("HOME").context("Neither XDG_CONFIG_HOME nor HOME are set")?,
+        )
+        .join(".config"),
+    };
+    Ok(config_home.join("minidocker"))
+}
+
+/// Settings from `daemon.json`, named after (and a subset of) the docker daemon's config file
+///
+/// See: https://docs.docker.com/reference/cli/dockerd/#daemon-configuration-file
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    /// Registries that may be reached over plain HTTP or with unverifiable certificates
+    #[serde(default)]
+    pub insecure_registries: Vec<String>,
+    #[serde(default)]
+    pub proxies: ProxyConfig,
+    /// Pull-through caches to try before Docker Hub, as URLs like `https://mirror.gcr.io`
+    #[serde(default)]
+    pub registry_mirrors: Vec<String>,
+    /// Where the store goes, unless `--data-root` or `MINIDOCKER_ROOT` say otherwise
+    pub data_root: Option<PathBuf>,
+    /// Read-only stores to copy images from before pulling them
+    #[serde(default)]
+    pub additional_stores: Vec<PathBuf>,
+    /// How big the store may get, like `10GB`, before the least recently used images are evicted
+    pub max_cache_size: Option<String>,
+    /// How containers' root filesystems are assembled, unless `--storage-driver` says otherwise
+    pub storage_driver: Option<String>,
+    /// Recompress layers with zstd as they're pulled, like `store recompress` does
+    #[serde(default)]
+    pub recompress_layers: bool,
+    /// Extract device nodes from layers rather than refusing to
+    #[serde(default)]
+    pub allow_device_nodes: bool,
+    /// What's done with layers' extended attributes, `auto`, `preserve`, `user`, or `skip`,
+    /// unless `--xattrs` says otherwise
+    pub xattrs: Option<String>,
+}
+
+/// Proxies for registry traffic, `HTTP_PROXY`/`HTTPS_PROXY`/`NO_PROXY` are used when unset
+///
+/// See: https://docs.docker.com/engine/daemon/proxy/#daemon-configuration
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProxyConfig {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    /// Comma separated hosts (and their subdomains) to reach directly
+    pub no_proxy: Option<String>,
+}
+
+impl ProxyConfig {
+    pub fn is_set(&self) -> bool {
+        self.http_proxy.is_some() || self.https_proxy.is_some()
+    }
+
+    /// Whether `host` should skip the proxy, falling back to `NO_PROXY` if we don't have a list
+    pub fn bypasses(&self, host: &str) -> bool {
+        let no_proxy = match &self.no_proxy {
+            Some(no_proxy) => no_proxy.clone(),
+            None => std::env::var("NO_PROXY")
+                .or_else(|_| std::env::var("no_proxy"))
+                .unwrap_or_default(),
+        };
+        no_proxy
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .any(|entry| {
+                let domain = entry.trim_start_matches('.');
+                entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+            })
+    }
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        Ok(config_dir()?.join("daemon.json"))
+    }
+
+    /// Loads `daemon.json`, a missing file is the same as an empty one
+    pub fn load() -> Result<Config> {
+        let path = Config::path()?;
+        match fs::read_to_string(&path) {
+            Ok(raw_data) => serde_json::from_str(&raw_data)
+                .with_context(|| format!("Tried to parse {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e).with_context(|| format!("Tried to read {}", path.display())),
+        }
+    }
+
+    /// Loopback registries are always insecure, same as with the docker daemon
+    pub fn is_insecure(&self, registry: &str) -> bool {
+        let host = registry.rsplit_once(':').map_or(registry, |(host, _)| host);
+        let loopback = host == "localhost"
+            || host
+                .parse::<Ipv4Addr>()
+                .is_ok_and(|address| address.is_loopback());
+        loopback || self.insecure_registries.iter().any(|r| r == registry)
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+/// A terminal for the container, allocated from its own devpts
+pub struct Console {
+    /// Our end, what the container writes to its terminal comes out of here
+    master: File,
+    /// The container's end, under `/dev/pts`
+    pub slave: PathBuf,
+}
+
+impl Console {
+    /// Allocates a terminal from the container's `/dev/ptmx` and bind mounts it on `/dev/console`,
+    /// where software expecting to have been started on a console looks for it
+    ///
+    /// Call after `pivot_root`, so the terminal comes from the container's devpts rather than the
+    /// host's. The window size is copied from our own terminal, if we're on one.
+    pub fn open() -> Result<Console> {
+        let fd = unsafe {
+            libc::open(
+                c"/dev/ptmx".as_ptr(),
+                libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Tried to open /dev/ptmx");
+        }
+        let master = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::unlockpt(fd) } != 0 {
+            return Err(io::Error::last_os_error()).context("Tried to unlock a terminal");
+        }
+        let mut name = [0 as libc::c_char; 64];
+        if unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) } != 0 {
+            return Err(io::Error::last_os_error()).context("Tried to find a terminal's name");
+        }
+        let slave = PathBuf::from(
+            unsafe { CStr::from_ptr(name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+        );
+
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 {
+            unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &size) };
+        }
+
+        let console = Path::new("/dev/console");
+        File::create(console).with_context(|| format!("Tried to create {}", console.display()))?;
+        crate::mounts::bind(&slave, console)?;
+        Ok(Console { master, slave })
+    }
+
+    /// Opens the container's end, once for each of its standard streams
+    pub fn open_slave(&self) -> Result<File> {
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.slave)
+            .with_context(|| format!("Tried to open {}", self.slave.display()))
+    }
+
+    /// Passes our stdin to the container's terminal and its output to our stdout until every
+    /// process in the container has closed it
+    ///
+    /// Our own terminal is put in raw mode meanwhile, so keys like Ctrl-C reach the container
+    /// rather than us. It's all done from one thread with `poll`, since no more threads can be
+    /// started once we've unshared the PID namespace.
+    pub fn relay(mut self) -> Result<()> {
+        let _raw = RawMode::enable();
+        let mut stdout = io::stdout();
+        let mut fds = [
+            libc::pollfd {
+                fd: self.master.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let mut buffer = [0; 4096];
+        loop {
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                match io::Error::last_os_error() {
+                    e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e).context("Tried to wait for the container's terminal"),
+                }
+            }
+            if fds[0].revents != 0 {
+                let read = match self.master.read(&mut buffer) {
+                    Ok(0) => return Ok(()),
+                    Ok(read) => read,
+                    // That's what reading gets once nobody has the terminal open anymore
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) => return Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e).context("Tried to read from the container's terminal"),
+                };
+                stdout.write_all(&buffer[..read])?;
+                stdout.flush()?;
+            }
+            if fds[1].revents != 0 {
+                // Straight from the fd, what stdin buffers poll wouldn't know about
+                let read = unsafe {
+                    libc::read(
+                        libc::STDIN_FILENO,
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len(),
+                    )
+                };
+                match read {
+                    // A negative fd is left out of the poll from then on
+                    read if read <= 0 => fds[1].fd = -1,
+                    read => self.master.write_all(&buffer[..read as usize])?,
+                }
+            }
+        }
+    }
+}
+
+/// Makes the terminal the container's standard streams are on its controlling terminal, from
+/// the container's first process between fork and exec
+pub fn take_controlling_terminal() -> io::Result<()> {
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Our terminal's settings from before it was put in raw mode, which are restored when dropped
+struct RawMode {
+    original: Option<libc::termios>,
+}
+
+impl RawMode {
+    /// Does nothing if stdin isn't a terminal
+    fn enable() -> RawMode {
+        let stdin = io::stdin().as_raw_fd();
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(stdin, &mut original) } != 0 {
+            return RawMode { original: None };
+        }
+        let mut raw = original;
+        unsafe {
+            libc::cfmakeraw(&mut raw);
+            libc::tcsetattr(stdin, libc::TCSANOW, &raw);
+        }
+        RawMode {
+            original: Some(original),
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            unsafe { libc::tcsetattr(io::stdin().as_raw_fd(), libc::TCSANOW, original) };
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::changes::{Change, ChangeKind, Changes};
+use crate::driver::{storage_driver, ImageLayers};
+use crate::progress::{Progress, ProgressMode};
+use crate::store::{disk_usage, write_atomically, Store, StoredImage};
+
+/// What `run` records about a container, in `containers/<id>/`
+pub static CONTAINER_RECORD: &str = "container.json";
+
+/// What the container's command exited with, once it has
+static EXIT_CODE: &str = "exit-code";
+
+/// What `run` makes to mount on if the image doesn't have them, which aren't the container's
+/// changes (docker keeps them in an init layer of their own)
+static MOUNT_POINTS: &[&str] = &["/dev", "/proc", "/sys"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerRecord {
+    /// The image as `run` was given it
+    pub image: String,
+    pub stored: StoredImage,
+    /// What it runs, arguments included
+    pub command: Vec<String>,
+    pub created: u64,
+}
+
+/// A container, kept from when it's run until `rm` (or until it exits, with `run --rm`)
+#[derive(Debug)]
+pub struct Container {
+    pub id: String,
+    /// Its directory under `containers/`
+    pub path: PathBuf,
+    pub record: ContainerRecord,
+    /// The storage driver its rootfs was made by
+    pub driver: String,
+    pub running: bool,
+    pub exit_code: Option<i32>,
+}
+
+impl Container {
+    /// Roughly what `docker ps` says
+    pub fn status(&self) -> String {
+        match (self.running, self.exit_code) {
+            (true, _) => String::from("Up"),
+            (false, Some(code)) => format!("Exited ({})", code),
+            // Killed before it could say
+            (false, None) => String::from("Exited"),
+        }
+    }
+
+    /// How much space what the container wrote takes up, which is all of its rootfs for drivers
+    /// that copy the image
+    pub fn size(&self) -> Result<u64> {
+        let upper = self.path.join("upper");
+        match upper.is_dir() {
+            true => disk_usage(&upper),
+            false => disk_usage(&self.path.join("rootfs")),
+        }
+    }
+
+    /// Where its rootfs is on the host, which has what the container wrote to it (but not to
+    /// the volumes and tmpfs it mounts, those are its own)
+    pub fn rootfs(&self, store: &Store) -> Result<PathBuf> {
+        let rootfs = self.path.join("rootfs");
+        // Overlays stay mounted until `rm`, unless the host was rebooted since
+        let unmounted = self.path.join("upper").is_dir()
+            && !store.image_manifest(&self.record.stored)?.layers.is_empty()
+            && fs::metadata(&rootfs)?.dev() == fs::metadata(&self.path)?.dev();
+        match unmounted {
+            true => bail!("The overlay of container {} isn't mounted anymore", self.id),
+            false => Ok(rootfs),
+        }
+    }
+
+    /// What the container added, changed, and deleted, relative to its image
+    pub fn changes(&self, store: &Store) -> Result<Changes> {
+        let manifest = store.image_manifest(&self.record.stored)?;
+        let config = store.image_config(&manifest)?;
+        let progress = Progress::new(ProgressMode::Quiet);
+        let layers = ImageLayers {
+            manifest: &manifest,
+            config: &config,
+            progress: &progress,
+        };
+        let mut changes =
+            storage_driver(&self.driver, false)?.changes(store, &layers, &self.path)?;
+        let made_by_run = |change: &Change| {
+            change.kind == ChangeKind::Added
+                && MOUNT_POINTS
+                    .iter()
+                    .any(|mount_point| change.path.starts_with(mount_point))
+        };
+        changes.changes.retain(|change| !made_by_run(change));
+        Ok(changes)
+    }
+}
+
+/// Records what a container runs, which also keeps it around once it has exited
+pub fn record_container(
+    container: &Path,
+    image: &str,
+    stored: &StoredImage,
+    command: &[String],
+) -> Result<()> {
+    let record = ContainerRecord {
+        image: image.to_string(),
+        stored: stored.clone(),
+        command: command.to_vec(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    };
+    write_atomically(
+        &container.join(CONTAINER_RECORD),
+        &serde_json::to_vec_pretty(&record)?,
+    )
+}
+
+/// Where `run` writes what the container's command exited with
+pub struct ExitCodeFile(File);
+
+impl ExitCodeFile {
+    /// Call before `pivot_root` leaves the store behind
+    pub fn create(container: &Path) -> Result<ExitCodeFile> {
+        let path = container.join(EXIT_CODE);
+        let file =
+            File::create(&path).with_context(|| format!("Tried to create {}", path.display()))?;
+        Ok(ExitCodeFile(file))
+    }
+
+    /// Best effort, the container has exited either way
+    pub fn write(mut self, code: i32) {
+        let _ = self.0.write_all(code.to_string().as_bytes());
+    }
+}
+
+/// Every recorded container, newest first
+pub fn list_containers(store: &Store) -> Result<Vec<Container>> {
+    let directory = store.root().join("containers");
+    let entries = fs::read_dir(&directory)
+        .with_context(|| format!("Tried to list {}", directory.display()))?;
+    let mut containers = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        // Still starting, or about to be removed
+        if !path.join(CONTAINER_RECORD).exists() {
+            continue;
+        }
+        // Finer grained than `created`, when several were run in the same second
+        let recorded = fs::metadata(path.join(CONTAINER_RECORD))?.modified()?;
+        containers.push((recorded, load_container(store, &path)?));
+    }
+    containers.sort_by_key(|(recorded, _)| std::cmp::Reverse(*recorded));
+    Ok(containers
+        .into_iter()
+        .map(|(_, container)| container)
+        .collect())
+}
+
+/// Finds a container by its ID, or by as much of the start of it as tells it apart
+pub fn find_container(store: &Store, id: &str) -> Result<Container> {
+    let mut matching: Vec<Container> = list_containers(store)?
+        .into_iter()
+        .filter(|container| container.id.starts_with(id))
+        .collect();
+    match matching.len() {
+        _ if id.is_empty() => bail!("No container given"),
+        0 => bail!("No such container: {}", id),
+        1 => Ok(matching.remove(0)),
+        _ => bail!("Multiple containers match {}, give more of its ID", id),
+    }
+}
+
+fn load_container(store: &Store, path: &Path) -> Result<Container> {
+    let id = path.file_name().unwrap().to_string_lossy().into_owned();
+    let record_path = path.join(CONTAINER_RECORD);
+    let record: ContainerRecord = serde_json::from_slice(
+        &fs::read(&record_path)
+            .with_context(|| format!("Tried to read {}", record_path.display()))?,
+    )
+    .with_context(|| format!("Tried to parse {}", record_path.display()))?;
+    let driver = fs::read_to_string(path.join("driver")).unwrap_or_else(|_| String::from("vfs"));
+    let exit_code = fs::read_to_string(path.join(EXIT_CODE))
+        .ok()
+        .and_then(|code| code.trim().parse().ok());
+    Ok(Container {
+        running: store.container_running(&id),
+        id,
+        path: path.to_path_buf(),
+        record,
+        driver: driver.trim().to_string(),
+        exit_code,
+    })
+}
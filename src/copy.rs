@@ -0,0 +1,120 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{CopyDirection, CpOptions};
+use crate::containers::Container;
+use crate::rootfs::{copy_path, resolve_in_rootfs};
+use crate::store::Store;
+
+/// One end of a copy, whose paths either are the host's or are the container's, in its rootfs
+enum Side<'a> {
+    Host,
+    Container(&'a Path),
+}
+
+impl Side<'_> {
+    /// Where `path` is, following symlinks all the way (where they point to doesn't have to
+    /// exist)
+    fn resolve(&self, path: &Path) -> Result<PathBuf> {
+        match self {
+            Side::Host => Ok(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())),
+            Side::Container(rootfs) => resolve_in_rootfs(rootfs, path),
+        }
+    }
+
+    /// Where `path` is, following symlinks on the way but not the one it might be itself
+    fn locate(&self, path: &Path) -> Result<PathBuf> {
+        match (self, path.parent(), path.file_name()) {
+            (Side::Host, _, _) => Ok(path.to_path_buf()),
+            (Side::Container(rootfs), Some(parent), Some(name)) => {
+                Ok(resolve_in_rootfs(rootfs, parent)?.join(name))
+            }
+            // `/`, or ends with `..`
+            (Side::Container(rootfs), _, _) => resolve_in_rootfs(rootfs, path),
+        }
+    }
+}
+
+/// Copies files out of a container or into it, whether it's running or not, like `docker cp`
+///
+/// Paths in the container are resolved in its rootfs, with its symlinks pointing where they
+/// would in the container, so none of them can get a copy written or read outside of it. A file is
+/// copied into the destination if that's a directory, and to it otherwise. A directory is too,
+/// unless the source ends with `/.`, then what's in it is. Copies into the container are owned by
+/// its root and copies out of it by us, unless `--archive` keeps the ownership they had.
+///
+/// Only the rootfs is copied to and from, the container's own mounts (volumes, tmpfs, `/dev`,
+/// `/proc`, and `/sys`) aren't the host's to see.
+///
+/// See: https://docs.docker.com/reference/cli/docker/container/cp/
+pub fn copy(store: &Store, container: &Container, options: &CpOptions) -> Result<()> {
+    if options.container_path.is_empty() {
+        bail!("No path in the container given");
+    }
+    let rootfs = container.rootfs(store)?;
+    let (container, host) = (Side::Container(&rootfs), Side::Host);
+    let owner = match (options.archive, options.direction) {
+        (true, _) => None,
+        (false, CopyDirection::ToContainer) => Some((0, 0)),
+        (false, CopyDirection::FromContainer) => Some(unsafe { (libc::getuid(), libc::getgid()) }),
+    };
+    let ((source_side, source), (destination_side, destination)) = match options.direction {
+        CopyDirection::FromContainer => (
+            (container, &options.container_path),
+            (host, &options.host_path),
+        ),
+        CopyDirection::ToContainer => (
+            (host, &options.host_path),
+            (container, &options.container_path),
+        ),
+    };
+
+    let source_path = Path::new(source);
+    let from = match options.follow_link {
+        true => source_side.resolve(source_path)?,
+        false => source_side.locate(source_path)?,
+    };
+    let metadata = match fs::symlink_metadata(&from) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => bail!("No such file: {}", source),
+        Err(e) => return Err(e).with_context(|| format!("Tried to read {}", source)),
+    };
+    if source.ends_with('/') && !metadata.is_dir() {
+        bail!("{} isn't a directory", source);
+    }
+    // What's in the directory rather than the directory, like for `cp -R source/. destination`
+    let contents =
+        metadata.is_dir() && (source.ends_with("/.") || source_path.file_name().is_none());
+
+    let destination_path = Path::new(destination);
+    let resolved = destination_side.resolve(destination_path)?;
+    let to = match fs::metadata(&resolved) {
+        Ok(existing) if existing.is_dir() && contents => resolved,
+        Ok(existing) if existing.is_dir() => resolved.join(source_path.file_name().unwrap()),
+        Ok(_) if metadata.is_dir() => {
+            bail!("Can't copy directory {} to file {}", source, destination)
+        }
+        Ok(_) => resolved,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if destination.ends_with('/') && !metadata.is_dir() {
+                bail!("Directory {} doesn't exist", destination);
+            }
+            resolved
+        }
+        Err(e) => return Err(e).with_context(|| format!("Tried to read {}", destination)),
+    };
+    let replacing_directory = match fs::symlink_metadata(&to) {
+        Ok(existing) => existing.is_dir() && !metadata.is_dir(),
+        Err(_) => false,
+    };
+    if replacing_directory {
+        bail!(
+            "Can't replace a directory in {} with file {}",
+            destination,
+            source
+        );
+    }
+    copy_path(&from, &to, owner)
+}
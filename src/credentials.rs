@@ -0,0 +1,387 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use crate::base64;
+use crate::config::config_dir;
+use crate::reference::DOCKER_HUB;
+
+/// Environment variable holding `username:password` credentials for the registry being pulled from
+static AUTH_ENV: &str = "MINIDOCKER_AUTH";
+
+/// A username and password (or access token) for a registry
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Parses credentials in `username:password` form
+    pub fn parse(value: &str) -> Result<Credentials> {
+        match value.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Credentials {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => bail!("Credentials must be in username:password form"),
+        }
+    }
+
+    /// Credentials from the `MINIDOCKER_AUTH` environment variable, if it's set
+    pub fn from_env() -> Result<Option<Credentials>> {
+        match std::env::var(AUTH_ENV) {
+            Ok(value) if !value.is_empty() => Credentials::parse(&value)
+                .with_context(|| format!("Tried to parse {}", AUTH_ENV))
+                .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads a password from the first line of stdin, like `docker login --password-stdin`
+    pub fn with_password_from_stdin(username: &str) -> Result<Credentials> {
+        let mut password = String::new();
+        std::io::stdin()
+            .lock()
+            .read_line(&mut password)
+            .context("Tried to read password from stdin")?;
+        let password = password.trim_end_matches(['\r', '\n']).to_string();
+        if password.is_empty() {
+            bail!("No password was provided on stdin");
+        }
+
+        Ok(Credentials {
+            username: username.to_string(),
+            password,
+        })
+    }
+}
+
+// Keep passwords out of debug output and error messages
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// The parts of the docker CLI's `config.json` that deal with registry credentials
+///
+/// `login` stores credentials in a file of the same format under our own config directory, so
+/// the docker CLI's config is only ever read.
+///
+/// See: https://docs.docker.com/reference/cli/docker/#configuration-files
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    auths: HashMap<String, AuthEntry>,
+    /// Helper used for every registry without an entry in `cred_helpers`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    creds_store: Option<String>,
+    /// Per-registry helpers, keyed by registry host
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AuthEntry {
+    /// base64 encoded `username:password`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+/// Location of the docker CLI's config file, honoring `DOCKER_CONFIG` like the CLI does
+fn docker_config_path() -> Option<PathBuf> {
+    match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => Some(PathBuf::from(dir).join("config.json")),
+        None => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".docker").join("config.json")),
+    }
+}
+
+/// Location of the credentials written by `login`
+fn store_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("config.json"))
+}
+
+fn load_config(path: &Path) -> Result<DockerConfig> {
+    let raw_data = match fs::read_to_string(path) {
+        Ok(raw_data) => raw_data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(DockerConfig::default()),
+        Err(e) => return Err(e).with_context(|| format!("Tried to read {}", path.display())),
+    };
+    serde_json::from_str(&raw_data).with_context(|| format!("Tried to parse {}", path.display()))
+}
+
+fn save_config(path: &Path, config: &DockerConfig) -> Result<()> {
+    let parent = path.parent().unwrap();
+    fs::create_dir_all(parent).with_context(|| format!("Tried to create {}", parent.display()))?;
+
+    // Credentials end up in here, make sure only we can read them
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Tried to open {}", path.display()))?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    serde_json::to_writer_pretty(&mut file, config)
+        .with_context(|| format!("Tried to write {}", path.display()))
+}
+
+/// Reduces a `config.json` auths key (e.g. `https://index.docker.io/v1/`) to the registry host it
+/// refers to, using the same host names as `ImageReference`
+fn normalize_auth_key(key: &str) -> String {
+    let host = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match host {
+        "index.docker.io" | "docker.io" | "registry-1.docker.io" => String::from(DOCKER_HUB),
+        _ => host.to_string(),
+    }
+}
+
+impl AuthEntry {
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        if let Some(auth) = self.auth.as_deref().filter(|a| !a.is_empty()) {
+            let decoded = String::from_utf8(base64::decode(auth)?)
+                .context("Decoded auth entry is not valid UTF-8")?;
+            return Credentials::parse(&decoded).map(Some);
+        }
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Ok(Some(Credentials {
+                username: username.clone(),
+                password: password.clone(),
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl DockerConfig {
+    /// The credential helper responsible for a registry, if any
+    fn helper_for(&self, registry: &str) -> Option<&String> {
+        self.cred_helpers
+            .iter()
+            .find(|(key, _)| normalize_auth_key(key) == registry)
+            .map(|(_, helper)| helper)
+            .or(self.creds_store.as_ref())
+    }
+
+    /// Like the docker CLI, a registry specific credential helper takes precedence over the
+    /// default `credsStore`, which takes precedence over credentials stored in the file itself.
+    fn lookup(&self, registry: &str) -> Result<Option<Credentials>> {
+        if let Some(helper) = self.helper_for(registry) {
+            return from_helper(helper, registry);
+        }
+
+        for (key, entry) in &self.auths {
+            if normalize_auth_key(key) == registry {
+                return entry
+                    .credentials()
+                    .with_context(|| format!("Tried to read docker credentials for {}", key));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// What a credential helper prints in response to `get` (and expects for `store`)
+///
+/// See: https://github.com/docker/docker-credential-helpers#development
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    #[serde(rename = "ServerURL", default)]
+    server_url: String,
+    username: String,
+    secret: String,
+}
+
+/// The server URL credential helpers store Docker Hub credentials under
+static DOCKER_HUB_SERVER_URL: &str = "https://index.docker.io/v1/";
+
+/// The key a registry's credentials are stored under by the docker CLI
+fn server_url(registry: &str) -> String {
+    if registry == DOCKER_HUB {
+        String::from(DOCKER_HUB_SERVER_URL)
+    } else {
+        registry.to_string()
+    }
+}
+
+/// Runs `docker-credential-<helper> <action>`, feeding `input` on stdin
+fn run_helper(helper: &str, action: &str, input: &[u8]) -> Result<Output> {
+    let program = format!("docker-credential-{}", helper);
+    let mut child = Command::new(&program)
+        .arg(action)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Tried to run credential helper {}", program))?;
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input)
+        .with_context(|| format!("Tried to send input to {}", program))?;
+    child
+        .wait_with_output()
+        .with_context(|| format!("Tried to wait for {}", program))
+}
+
+/// Helpers report errors on stdout, falling back to stderr for anything unexpected
+fn helper_error(output: &Output) -> String {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.trim().is_empty() {
+        String::from_utf8_lossy(&output.stderr).trim().to_string()
+    } else {
+        stdout.trim().to_string()
+    }
+}
+
+/// Asks a `docker-credential-<helper>` binary for a registry's credentials
+///
+/// Returns `None` when the helper doesn't know about the registry.
+fn from_helper(helper: &str, registry: &str) -> Result<Option<Credentials>> {
+    let output = run_helper(helper, "get", server_url(registry).as_bytes())?;
+
+    if !output.status.success() {
+        let message = helper_error(&output);
+        // There's no dedicated exit code for this, helpers just print this message
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        bail!(
+            "docker-credential-{} failed for {}: {}",
+            helper,
+            registry,
+            message
+        );
+    }
+
+    let helper_credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Tried to parse the output of docker-credential-{}", helper))?;
+    Ok(Some(Credentials {
+        username: helper_credentials.username,
+        password: helper_credentials.secret,
+    }))
+}
+
+/// Looks up stored credentials for a registry
+///
+/// Credentials saved by `login` win over the docker CLI's login state.
+pub fn for_registry(registry: &str) -> Result<Option<Credentials>> {
+    if let Some(credentials) = load_config(&store_path()?)?.lookup(registry)? {
+        return Ok(Some(credentials));
+    }
+    match docker_config_path() {
+        Some(path) => load_config(&path)?.lookup(registry),
+        None => Ok(None),
+    }
+}
+
+/// Saves credentials for a registry, either in our config file or through a credential helper
+///
+/// Returns a description of where the credentials ended up.
+pub fn store(registry: &str, credentials: &Credentials, helper: Option<&str>) -> Result<String> {
+    let path = store_path()?;
+    let mut config = load_config(&path)?;
+    config
+        .auths
+        .retain(|key, _| normalize_auth_key(key) != registry);
+    config
+        .cred_helpers
+        .retain(|key, _| normalize_auth_key(key) != registry);
+
+    let location = match helper {
+        Some(helper) => {
+            let input = serde_json::to_vec(&HelperCredentials {
+                server_url: server_url(registry),
+                username: credentials.username.clone(),
+                secret: credentials.password.clone(),
+            })?;
+            let output = run_helper(helper, "store", &input)?;
+            if !output.status.success() {
+                bail!(
+                    "docker-credential-{} failed to store credentials: {}",
+                    helper,
+                    helper_error(&output)
+                );
+            }
+            config
+                .cred_helpers
+                .insert(registry.to_string(), helper.to_string());
+            format!("docker-credential-{}", helper)
+        }
+        None => {
+            let auth = format!("{}:{}", credentials.username, credentials.password);
+            config.auths.insert(
+                server_url(registry),
+                AuthEntry {
+                    auth: Some(base64::encode(auth.as_bytes())),
+                    ..AuthEntry::default()
+                },
+            );
+            path.display().to_string()
+        }
+    };
+
+    save_config(&path, &config)?;
+    Ok(location)
+}
+
+/// Removes credentials saved by `login`, returning whether there were any
+pub fn erase(registry: &str) -> Result<bool> {
+    let path = store_path()?;
+    let mut config = load_config(&path)?;
+
+    let helper = config
+        .cred_helpers
+        .iter()
+        .find(|(key, _)| normalize_auth_key(key) == registry)
+        .map(|(_, helper)| helper.clone());
+    if let Some(helper) = &helper {
+        let output = run_helper(helper, "erase", server_url(registry).as_bytes())?;
+        if !output.status.success() && !helper_error(&output).contains("credentials not found") {
+            bail!(
+                "docker-credential-{} failed to erase credentials: {}",
+                helper,
+                helper_error(&output)
+            );
+        }
+    }
+
+    let before = config.auths.len() + config.cred_helpers.len();
+    config
+        .auths
+        .retain(|key, _| normalize_auth_key(key) != registry);
+    config
+        .cred_helpers
+        .retain(|key, _| normalize_auth_key(key) != registry);
+    let removed = before != config.auths.len() + config.cred_helpers.len();
+
+    if removed {
+        save_config(&path, &config)?;
+    }
+    Ok(removed)
+}
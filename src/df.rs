@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use crate::containers::list_containers;
+use crate::images::{list_images, ImageSummary};
+use crate::store::{disk_usage, Store};
+use crate::table::{format_size, print_table, short_digest};
+use crate::volumes::list_volumes;
+
+/// How much of the store one kind of thing takes up
+#[derive(Debug)]
+pub struct Usage {
+    pub kind: &'static str,
+    pub total: usize,
+    /// How many of them a stored image still needs
+    pub active: usize,
+    pub size: u64,
+    /// What `system prune` (or, for containers, `rm`) would free
+    pub reclaimable: u64,
+}
+
+/// One extracted layer, for `system df -v`
+#[derive(Debug)]
+pub struct LayerUsage {
+    pub digest: String,
+    pub size: u64,
+    /// How many stored images are made of it
+    pub images: usize,
+}
+
+/// Disk usage of the store, broken down the way `docker system df` does
+#[derive(Debug)]
+pub struct DiskUsage {
+    pub summary: Vec<Usage>,
+    pub images: Vec<ImageSummary>,
+    pub layers: Vec<LayerUsage>,
+}
+
+/// Walks the store to find out what's taking up space
+///
+/// Containers count as active while they run, what the others take up is reclaimed by `rm`.
+/// Volumes count as active while a running container has them mounted.
+pub fn disk_usage_of(store: &Store) -> Result<DiskUsage> {
+    let in_use = store.repositories()?.in_use();
+    let images = list_images(store, None)?;
+
+    // Tags that share an image ID are one image
+    let mut image_ids: Vec<&str> = images.iter().map(|image| image.id.as_str()).collect();
+    image_ids.sort();
+    image_ids.dedup();
+
+    let mut blob_usage = Usage {
+        kind: "Images",
+        total: image_ids.len(),
+        active: image_ids.len(),
+        size: 0,
+        reclaimable: 0,
+    };
+    for entry in list_store_directory(store, "blobs/sha256")? {
+        let (digest, size) = entry?;
+        blob_usage.size += size;
+        if !in_use.contains(&digest) {
+            blob_usage.reclaimable += size;
+        }
+    }
+
+    let users = image_users(&images);
+    let mut layer_usage = Usage {
+        kind: "Extracted layers",
+        total: 0,
+        active: 0,
+        size: 0,
+        reclaimable: 0,
+    };
+    let mut layers = Vec::new();
+    for entry in list_store_directory(store, "layers/sha256")? {
+        let (digest, size) = entry?;
+        layer_usage.total += 1;
+        layer_usage.size += size;
+        match in_use.contains(&digest) {
+            true => layer_usage.active += 1,
+            false => layer_usage.reclaimable += size,
+        }
+        let images = users.get(digest.as_str()).copied().unwrap_or(0);
+        layers.push(LayerUsage {
+            digest,
+            size,
+            images,
+        });
+    }
+    layers.sort_by_key(|layer| std::cmp::Reverse(layer.size));
+
+    let mut container_usage = Usage {
+        kind: "Containers",
+        total: 0,
+        active: 0,
+        size: 0,
+        reclaimable: 0,
+    };
+    for container in list_containers(store)? {
+        let size = container.size()?;
+        container_usage.total += 1;
+        container_usage.size += size;
+        match container.running {
+            true => container_usage.active += 1,
+            false => container_usage.reclaimable += size,
+        }
+    }
+
+    let mut volume_usage = Usage {
+        kind: "Local Volumes",
+        total: 0,
+        active: 0,
+        size: 0,
+        reclaimable: 0,
+    };
+    for volume in list_volumes(store)? {
+        let size = volume.size()?;
+        volume_usage.total += 1;
+        volume_usage.size += size;
+        match volume.in_use {
+            true => volume_usage.active += 1,
+            false => volume_usage.reclaimable += size,
+        }
+    }
+
+    Ok(DiskUsage {
+        summary: vec![blob_usage, layer_usage, container_usage, volume_usage],
+        images,
+        layers,
+    })
+}
+
+/// How many images need each blob, counting tags that share an image ID once
+fn image_users(images: &[ImageSummary]) -> HashMap<&str, usize> {
+    let mut users: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for image in images {
+        for (digest, _) in &image.blobs {
+            users.entry(digest).or_default().insert(&image.id);
+        }
+    }
+    users
+        .into_iter()
+        .map(|(digest, ids)| (digest, ids.len()))
+        .collect()
+}
+
+/// Every entry of a directory in the store, by digest, along with how much space it takes up
+fn list_store_directory(
+    store: &Store,
+    directory: &str,
+) -> Result<impl Iterator<Item = Result<(String, u64)>>> {
+    let directory = store.root().join(directory);
+    let entries = fs::read_dir(&directory)
+        .with_context(|| format!("Tried to list {}", directory.display()))?;
+    Ok(entries.map(|entry| {
+        let path = entry?.path();
+        let digest = format!("sha256:{}", path.file_name().unwrap().to_string_lossy());
+        Ok((digest, disk_usage(&path)?))
+    }))
+}
+
+/// Prints the summary table, followed by a breakdown of each image and layer if `verbose`
+///
+/// See: https://docs.docker.com/reference/cli/docker/system/df/
+pub fn print_disk_usage(usage: &DiskUsage, verbose: bool) {
+    let rows: Vec<Vec<String>> = usage
+        .summary
+        .iter()
+        .map(|usage| {
+            let percent = match usage.size {
+                0 => 0,
+                size => usage.reclaimable * 100 / size,
+            };
+            vec![
+                usage.kind.to_string(),
+                usage.total.to_string(),
+                usage.active.to_string(),
+                format_size(usage.size),
+                format!("{} ({}%)", format_size(usage.reclaimable), percent),
+            ]
+        })
+        .collect();
+    print_table(&["TYPE", "TOTAL", "ACTIVE", "SIZE", "RECLAIMABLE"], &rows);
+    if !verbose {
+        return;
+    }
+
+    // A blob counts as shared when more than one image needs it
+    let users = image_users(&usage.images);
+    println!("\nImages space usage:\n");
+    let rows: Vec<Vec<String>> = usage
+        .images
+        .iter()
+        .map(|image| {
+            let shared: u64 = image
+                .blobs
+                .iter()
+                .filter(|(digest, _)| users.get(digest.as_str()).is_some_and(|&n| n > 1))
+                .map(|(_, size)| size)
+                .sum();
+            vec![
+                image.repository.clone(),
+                image.tag.clone(),
+                short_digest(&image.id).to_string(),
+                format_size(image.size),
+                format_size(shared),
+                format_size(image.size - shared),
+            ]
+        })
+        .collect();
+    print_table(
+        &[
+            "REPOSITORY",
+            "TAG",
+            "IMAGE ID",
+            "SIZE",
+            "SHARED SIZE",
+            "UNIQUE SIZE",
+        ],
+        &rows,
+    );
+
+    println!("\nExtracted layers space usage:\n");
+    let rows: Vec<Vec<String>> = usage
+        .layers
+        .iter()
+        .map(|layer| {
+            vec![
+                short_digest(&layer.digest).to_string(),
+                format_size(layer.size),
+                layer.images.to_string(),
+            ]
+        })
+        .collect();
+    print_table(&["LAYER", "SIZE", "IMAGES"], &rows);
+}
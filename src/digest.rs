@@ -0,0 +1,346 @@
+use anyhow::{bail, Result};
+use std::fmt::Write;
+use std::io::{self, Read};
+
+/// Round constants, the first 32 bits of the fractional parts of the cube roots of the first 64
+/// primes
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// An incremental SHA-256 hasher, used to compute content digests
+///
+/// See: https://nvlpubs.nist.gov/nistpubs/FIPS/NIST.FIPS.180-4.pdf
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    buffer: [u8; 64],
+    buffered: usize,
+    length: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: INITIAL_STATE,
+            buffer: [0; 64],
+            buffered: 0,
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        if self.buffered > 0 {
+            let take = (64 - self.buffered).min(data.len());
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 64 {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffered = 0;
+        }
+
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block.try_into().unwrap());
+        }
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    /// Returns the digest in the `sha256:<hex>` form used by registries
+    pub fn finalize(mut self) -> String {
+        let bit_length = self.length.wrapping_mul(8);
+
+        let mut padding = vec![0x80u8];
+        let padded_length = (self.buffered + 1) % 64;
+        let zeros = if padded_length <= 56 {
+            56 - padded_length
+        } else {
+            120 - padded_length
+        };
+        padding.resize(1 + zeros, 0);
+        padding.extend_from_slice(&bit_length.to_be_bytes());
+
+        // Don't count the padding towards the message length
+        let length = self.length;
+        self.update(&padding);
+        self.length = length;
+
+        let mut digest = String::from("sha256:");
+        for word in self.state {
+            write!(digest, "{:08x}", word).unwrap();
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+/// Computes the `sha256:<hex>` digest of some content
+pub fn sha256_digest(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Checks that a digest string is well formed
+///
+/// Only sha256 is supported since that's the only algorithm registries use in practice.
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/descriptor.md#digests
+pub fn validate_digest(digest: &str) -> Result<()> {
+    let (algorithm, encoded) = match digest.split_once(':') {
+        Some(parts) => parts,
+        None => bail!("Invalid digest '{}', expected <algorithm>:<hex>", digest),
+    };
+    if algorithm != "sha256" {
+        bail!("Unsupported digest algorithm '{}'", algorithm);
+    }
+    if encoded.len() != 64
+        || !encoded
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    {
+        bail!("Invalid sha256 digest '{}'", digest);
+    }
+    Ok(())
+}
+
+/// Checks that the digest computed over some content matches the one it was requested by
+pub fn check_digest(actual: &str, expected: &str) -> Result<()> {
+    if actual != expected {
+        bail!(
+            "Digest mismatch: expected {} but content hashed to {}",
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
+
+/// Wraps a reader, hashing everything that's read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the digest of everything read so far
+    pub fn finalize(self) -> String {
+        self.hasher.finalize()
+    }
+
+    /// Returns the wrapped reader along with the digest of everything read so far
+    pub fn into_inner(self) -> (R, String) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        sha256_digest(data)
+            .trim_start_matches("sha256:")
+            .to_string()
+    }
+
+    /// From FIPS 180-4's examples
+    ///
+    /// See: https://csrc.nist.gov/projects/cryptographic-standards-and-guidelines/example-values
+    #[test]
+    fn known_answers() {
+        assert_eq!(
+            hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            hex(b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"),
+            "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"
+        );
+        assert_eq!(
+            hex(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    /// Where the length stops fitting in the last block, and blocks end or start right at the
+    /// end of the message
+    #[test]
+    fn padding_boundaries() {
+        let expected = [
+            (
+                55,
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                56,
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                57,
+                "f13b2d724659eb3bf47f2dd6af1accc87b81f09f59f2b75e5c0bed6589dfe8c6",
+            ),
+            (
+                63,
+                "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34",
+            ),
+            (
+                64,
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+            (
+                65,
+                "635361c48bb9eab14198e76ea8ab7f1a41685d6ad62aa9146d301d4f17eb0ae0",
+            ),
+            (
+                119,
+                "31eba51c313a5c08226adf18d4a359cfdfd8d2e816b13f4af952f7ea6584dcfb",
+            ),
+            (
+                120,
+                "2f3d335432c70b580af0e8e1b3674a7c020d683aa5f73aaaedfdc55af904c21c",
+            ),
+            (
+                128,
+                "6836cf13bac400e9105071cd6af47084dfacad4e5e302c94bfed24e013afb73e",
+            ),
+        ];
+        for (length, digest) in expected {
+            assert_eq!(hex(&vec![b'a'; length]), digest, "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn chunked_updates_hash_the_same() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let expected = "sha256:4e4c294b331f7a2099a379bec34b9f9fc03dc46ab465d998f4d683da53487e6d";
+        assert_eq!(sha256_digest(&data), expected);
+        for chunk in [1, 3, 55, 56, 63, 64, 65, 127, 999] {
+            let mut hasher = Sha256::new();
+            for part in data.chunks(chunk) {
+                hasher.update(part);
+            }
+            assert_eq!(hasher.finalize(), expected, "{} byte chunks", chunk);
+        }
+        // Empty updates in between, and a split right after a full buffer
+        let mut hasher = Sha256::new();
+        hasher.update(&data[..64]);
+        hasher.update(&[]);
+        hasher.update(&data[64..200]);
+        hasher.update(&[]);
+        hasher.update(&data[200..]);
+        assert_eq!(hasher.finalize(), expected);
+
+        let mut reader = HashingReader::new(&data[..]);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(reader.finalize(), expected);
+    }
+
+    #[test]
+    fn digests_are_validated() {
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert!(validate_digest(&format!("sha256:{}", hex)).is_ok());
+        for digest in [
+            hex.to_string(),
+            format!("sha512:{}", hex),
+            format!("sha256:{}", &hex[1..]),
+            format!("sha256:{}0", hex),
+            format!("sha256:{}", hex.to_uppercase()),
+            format!("sha256:{}g", &hex[1..]),
+            format!("sha256:../{}", &hex[3..]),
+            String::from("sha256:"),
+        ] {
+            assert!(validate_digest(&digest).is_err(), "{}", digest);
+        }
+    }
+
+    #[test]
+    fn mismatches_are_caught() {
+        let digest = sha256_digest(b"abc");
+        assert!(check_digest(&digest, &digest).is_ok());
+        assert!(check_digest(&sha256_digest(b"abd"), &digest).is_err());
+    }
+}
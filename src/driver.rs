@@ -0,0 +1,494 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::changes::{compare_trees, overlay_changes, Changes};
+use crate::config::Config;
+use crate::image::ImageConfig;
+use crate::manifest::ImageManifest;
+use crate::progress::{Phase, Progress};
+use crate::rootfs::{copy_layer, FileCopy};
+use crate::store::Store;
+
+/// Which storage driver containers get, overriding `daemon.json`'s `storage-driver`, which
+/// `--storage-driver` sets
+pub static STORAGE_DRIVER_ENV: &str = "MINIDOCKER_STORAGE_DRIVER";
+
+/// `BTRFS_SUPER_MAGIC` from `linux/magic.h`
+const BTRFS_SUPER_MAGIC: libc::c_long = 0x9123683e;
+
+/// `FUSE_SUPER_MAGIC` from `linux/magic.h`
+const FUSE_SUPER_MAGIC: libc::c_long = 0x65735546;
+
+/// `OVERLAYFS_SUPER_MAGIC` from `linux/magic.h`
+const OVERLAYFS_SUPER_MAGIC: libc::c_long = 0x794c7630;
+
+/// How long mount options may be, which is a page
+const MAX_MOUNT_OPTIONS: usize = 4096;
+
+/// The image a driver is assembling a rootfs from
+pub struct ImageLayers<'a> {
+    pub manifest: &'a ImageManifest,
+    pub config: &'a ImageConfig,
+    /// Where extracting layers that haven't been yet is reported
+    pub progress: &'a Progress,
+}
+
+/// Assembles containers' root filesystems out of the layers in the store
+///
+/// A driver is handed a container's own directory under `containers/`, puts whatever it needs
+/// in there, and returns where the rootfs is. Whatever it made or mounted is taken apart by
+/// `remove_rootfs` once the container is removed, which may well be in another process, so
+/// everything it needs to know has to be in that directory.
+pub trait StorageDriver {
+    /// What `--storage-driver` calls it
+    fn name(&self) -> &'static str;
+
+    fn create_rootfs(
+        &self,
+        store: &Store,
+        image: &ImageLayers,
+        container: &Path,
+    ) -> Result<PathBuf>;
+
+    /// Undoes `create_rootfs`, after which the container's directory is removed
+    fn remove_rootfs(&self, store: &Store, container: &Path) -> Result<()>;
+
+    /// What the container changed of its image, sorted by path, whether it's still running or
+    /// not
+    fn changes(&self, store: &Store, image: &ImageLayers, container: &Path) -> Result<Changes>;
+}
+
+/// The driver `--storage-driver` (or `MINIDOCKER_STORAGE_DRIVER`, or `daemon.json`'s
+/// `storage-driver`) names, if any do
+fn configured_name() -> Result<Option<String>> {
+    match std::env::var(STORAGE_DRIVER_ENV) {
+        Ok(name) if !name.is_empty() => Ok(Some(name)),
+        _ => Ok(Config::load()?.storage_driver),
+    }
+}
+
+/// The driver containers get, which is `overlay` unless another one is configured
+///
+/// When none is, `overlay` falls back to `vfs` wherever neither overlayfs nor fuse-overlayfs
+/// can be mounted, and `link_layers` asks for `vfs` hardlinking files.
+pub fn configured_driver(link_layers: bool) -> Result<Box<dyn StorageDriver>> {
+    match configured_name()? {
+        Some(name) => storage_driver(&name, link_layers),
+        None if link_layers => storage_driver("vfs", link_layers),
+        None => Ok(Box::new(Overlay { fallback: true })),
+    }
+}
+
+/// Looks a driver up by name, `link_layers` makes `vfs` hardlink files rather than copy them
+pub fn storage_driver(name: &str, link_layers: bool) -> Result<Box<dyn StorageDriver>> {
+    match name {
+        "vfs" => Ok(Box::new(Vfs {
+            files: match link_layers {
+                true => FileCopy::Hardlink,
+                false => FileCopy::Clone,
+            },
+        })),
+        // What Docker calls it, its first overlay driver is long gone
+        "overlay" | "overlay2" => Ok(Box::new(Overlay { fallback: false })),
+        "btrfs" => Ok(Box::new(Btrfs)),
+        other => bail!(
+            "Unknown storage driver '{}', expected vfs, overlay, or btrfs",
+            other
+        ),
+    }
+}
+
+/// Copies the image's snapshot into every container, reflinking files where the filesystem
+/// can, which works anywhere but takes as long (and, without reflinks, as much space) as the
+/// image is big
+///
+/// See: https://docs.docker.com/engine/storage/drivers/vfs-driver/
+struct Vfs {
+    files: FileCopy,
+}
+
+impl StorageDriver for Vfs {
+    fn name(&self) -> &'static str {
+        "vfs"
+    }
+
+    fn create_rootfs(
+        &self,
+        store: &Store,
+        image: &ImageLayers,
+        container: &Path,
+    ) -> Result<PathBuf> {
+        let rootfs = container.join("rootfs");
+        fs::create_dir(&rootfs).with_context(|| format!("Tried to create {}", rootfs.display()))?;
+        let _blobs = store.share_blobs()?;
+        if let Some(snapshot) =
+            store.image_snapshot(image.manifest, image.config, image.progress)?
+        {
+            copy_layer(&snapshot, &rootfs, self.files)?;
+        }
+        Ok(rootfs)
+    }
+
+    fn remove_rootfs(&self, _store: &Store, _container: &Path) -> Result<()> {
+        // It's just files in the container's directory
+        Ok(())
+    }
+
+    fn changes(&self, store: &Store, image: &ImageLayers, container: &Path) -> Result<Changes> {
+        compare_with_image(store, image, &container.join("rootfs"))
+    }
+}
+
+/// Compares a rootfs that started out as a copy of the image's snapshot with that snapshot
+fn compare_with_image(store: &Store, image: &ImageLayers, rootfs: &Path) -> Result<Changes> {
+    let _blobs = store.share_blobs()?;
+    let snapshot = store.image_snapshot(image.manifest, image.config, image.progress)?;
+    compare_trees(snapshot.as_deref(), rootfs)
+}
+
+/// Mounts an overlayfs with the image's layers as lower directories and a directory of the
+/// container's own as the upper one, so nothing is copied when a container starts and files are
+/// only copied up once the container changes them
+///
+/// Layers are extracted once and linked into lower directories (see `Store::overlay_layer`)
+/// that every container of every image with that layer shares. Mounting overlayfs takes
+/// `CAP_SYS_ADMIN`, and the store can't be on overlayfs itself, so where it can't be mounted
+/// fuse-overlayfs serves the same overlay instead, should it be installed.
+///
+/// See: https://docs.docker.com/engine/storage/drivers/overlayfs-driver/
+struct Overlay {
+    /// Whether to use `vfs` instead where overlayfs can't be mounted, for when nobody asked for
+    /// `overlay` in particular
+    fallback: bool,
+}
+
+impl Overlay {
+    /// Returns the lower directories of an image's layers, topmost first, extracting any
+    /// layers that haven't been yet
+    fn lower_dirs(&self, store: &Store, image: &ImageLayers) -> Result<Vec<PathBuf>> {
+        let (manifest, config) = (image.manifest, image.config);
+        if manifest.layers.len() != config.rootfs.diff_ids.len() {
+            bail!(
+                "Image has {} layers but its config lists {} diff IDs",
+                manifest.layers.len(),
+                config.rootfs.diff_ids.len()
+            );
+        }
+        let mut lower_dirs: Vec<PathBuf> = Vec::new();
+        let layers = manifest.layers.iter().zip(&config.rootfs.diff_ids).rev();
+        for (layer, diff_id) in layers {
+            // Foreign layers we can't download leave the layers under them as they were
+            if layer.is_foreign() && layer.urls.is_empty() {
+                continue;
+            }
+            let path = store.overlay_layer_path(&layer.digest)?;
+            // overlayfs won't mount the same directory twice, and a layer further up already
+            // puts everything a repeat of it further down would
+            if lower_dirs.contains(&path) {
+                continue;
+            }
+            if !path.is_dir() {
+                image.progress.set_phase(&layer.digest, Phase::Extracting);
+            }
+            lower_dirs.push(store.overlay_layer(layer, diff_id)?);
+            image.progress.set_phase(&layer.digest, Phase::Done);
+        }
+        Ok(lower_dirs)
+    }
+}
+
+impl StorageDriver for Overlay {
+    fn name(&self) -> &'static str {
+        "overlay"
+    }
+
+    fn create_rootfs(
+        &self,
+        store: &Store,
+        image: &ImageLayers,
+        container: &Path,
+    ) -> Result<PathBuf> {
+        let vfs = Vfs {
+            files: FileCopy::Clone,
+        };
+        let rootfs = container.join("rootfs");
+        let upper = container.join("upper");
+        let work = container.join("work");
+        for directory in [&rootfs, &upper, &work] {
+            fs::create_dir(directory)
+                .with_context(|| format!("Tried to create {}", directory.display()))?;
+        }
+        let _blobs = store.share_blobs()?;
+        let lower_dirs = self.lower_dirs(store, image)?;
+        // Without any layers, there's nothing for the container to write on top of
+        if lower_dirs.is_empty() {
+            return Ok(rootfs);
+        }
+        let options = overlay_options(&lower_dirs, &upper, &work)?;
+        let overlay_layers = store.root().join("overlay/sha256");
+        // Unprivileged users can't mount overlayfs (outside of a user namespace, on kernels from
+        // before 5.11), but FUSE filesystems they can
+        let kernel = match is_filesystem(store.root(), OVERLAYFS_SUPER_MAGIC)? {
+            true => Err(anyhow!(
+                "{} is on overlayfs, which can't hold an overlay's upper directory",
+                store.root().display()
+            )),
+            false => mount_overlay(&overlay_layers, &options, &rootfs),
+        };
+        let mounted = kernel.or_else(|kernel_error| {
+            mount_fuse_overlay(&overlay_layers, &options, &rootfs).map_err(|fuse_error| {
+                anyhow!(
+                    "Couldn't mount an overlay on {}: {:#}, nor with fuse-overlayfs: {:#}",
+                    rootfs.display(),
+                    kernel_error,
+                    fuse_error
+                )
+            })
+        });
+        match mounted {
+            Ok(()) => Ok(rootfs),
+            Err(_) if self.fallback => {
+                for directory in [&rootfs, &upper, &work] {
+                    fs::remove_dir(directory)
+                        .with_context(|| format!("Tried to remove {}", directory.display()))?;
+                }
+                vfs.create_rootfs(store, image, container)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove_rootfs(&self, _store: &Store, container: &Path) -> Result<()> {
+        let rootfs = container.join("rootfs");
+        // Which users without CAP_SYS_ADMIN can only unmount through fusermount
+        if rootfs.is_dir() && is_filesystem(&rootfs, FUSE_SUPER_MAGIC)? {
+            return fusermount(&rootfs);
+        }
+        let path = CString::new(rootfs.as_os_str().as_bytes())?;
+        if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } == 0 {
+            return Ok(());
+        }
+        match io::Error::last_os_error() {
+            // Nothing's mounted there, because the image had no layers, or we fell back to vfs,
+            // or the container never got that far
+            e if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOENT)) => Ok(()),
+            e => Err(e).with_context(|| format!("Tried to unmount {}", rootfs.display())),
+        }
+    }
+
+    fn changes(&self, store: &Store, image: &ImageLayers, container: &Path) -> Result<Changes> {
+        let upper = container.join("upper");
+        // Fell back to vfs
+        if !upper.is_dir() {
+            return compare_with_image(store, image, &container.join("rootfs"));
+        }
+        let _blobs = store.share_blobs()?;
+        let lower_dirs = self.lower_dirs(store, image)?;
+        // Nothing was mounted, the container wrote to its rootfs directory
+        if lower_dirs.is_empty() {
+            return compare_trees(None, &container.join("rootfs"));
+        }
+        overlay_changes(&upper, &lower_dirs)
+    }
+}
+
+/// The mount options for an overlay of `lower_dirs` (topmost first) and `upper`
+///
+/// Lower directories are given relative to the store's `overlay/sha256`, and are mounted from
+/// there, so that images with dozens of layers still fit in the mount options.
+fn overlay_options(lower_dirs: &[PathBuf], upper: &Path, work: &Path) -> Result<String> {
+    let names: Vec<_> = lower_dirs
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy())
+        .collect();
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        names.join(":"),
+        upper.display(),
+        work.display()
+    );
+    if options.len() >= MAX_MOUNT_OPTIONS {
+        bail!(
+            "Image has too many layers ({}) to mount as an overlay",
+            lower_dirs.len()
+        );
+    }
+    Ok(options)
+}
+
+/// Mounts the kernel's overlayfs on `rootfs`, from within `overlay_layers`
+fn mount_overlay(overlay_layers: &Path, options: &str, rootfs: &Path) -> Result<()> {
+    let current_dir = std::env::current_dir().context("Tried to find the current directory")?;
+    std::env::set_current_dir(overlay_layers)
+        .with_context(|| format!("Tried to change directory to {}", overlay_layers.display()))?;
+    let target = CString::new(rootfs.as_os_str().as_bytes())?;
+    let c_options = CString::new(options)?;
+    let mounted = unsafe {
+        libc::mount(
+            c"overlay".as_ptr(),
+            target.as_ptr(),
+            c"overlay".as_ptr(),
+            0,
+            c_options.as_ptr() as *const libc::c_void,
+        )
+    };
+    let error = io::Error::last_os_error();
+    std::env::set_current_dir(&current_dir)
+        .with_context(|| format!("Tried to change directory to {}", current_dir.display()))?;
+    if mounted != 0 {
+        return Err(error)
+            .with_context(|| format!("Tried to mount an overlay on {}", rootfs.display()));
+    }
+    Ok(())
+}
+
+/// Mounts fuse-overlayfs on `rootfs`, from within `overlay_layers`, which goes on serving it in
+/// the background until it's unmounted
+///
+/// See: https://github.com/containers/fuse-overlayfs
+fn mount_fuse_overlay(overlay_layers: &Path, options: &str, rootfs: &Path) -> Result<()> {
+    let output = Command::new("fuse-overlayfs")
+        .arg("-o")
+        .arg(options)
+        .arg(rootfs)
+        .current_dir(overlay_layers)
+        .output()
+        .context("Tried to run fuse-overlayfs, is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "fuse-overlayfs failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Lazily unmounts a FUSE filesystem, with whichever of FUSE 3's and FUSE 2's `fusermount` is
+/// installed
+fn fusermount(path: &Path) -> Result<()> {
+    let mut failure = None;
+    for program in ["fusermount3", "fusermount"] {
+        match Command::new(program).args(["-u", "-z"]).arg(path).output() {
+            Ok(output) if output.status.success() => return Ok(()),
+            Ok(output) => {
+                failure = Some(anyhow!(
+                    "{} failed: {}",
+                    program,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Tried to run {}", program)),
+        }
+    }
+    Err(failure.unwrap_or_else(|| anyhow!("Tried to run fusermount, is FUSE installed?")))
+        .with_context(|| format!("Tried to unmount {}", path.display()))
+}
+
+/// Keeps a subvolume of each image's snapshot and gives every container a snapshot of it, which
+/// takes no time or space however big the image is
+///
+/// The store has to be on btrfs, and the `btrfs` command from btrfs-progs installed.
+///
+/// See: https://docs.docker.com/engine/storage/drivers/btrfs-driver/
+struct Btrfs;
+
+impl Btrfs {
+    /// Returns the subvolume of an image's snapshot, making it if it isn't there yet
+    fn image_subvolume(&self, store: &Store, snapshot: &Path) -> Result<PathBuf> {
+        let chain_id = format!("sha256:{}", snapshot.file_name().unwrap().to_string_lossy());
+        let path = store.subvolume_path(&chain_id)?;
+        let _lock = store.lock_subvolume(&chain_id)?;
+        if path.is_dir() {
+            return Ok(path);
+        }
+        let temp_path = path.with_extension("tmp");
+        if temp_path.exists() {
+            fs::remove_dir_all(&temp_path)
+                .with_context(|| format!("Tried to remove {}", temp_path.display()))?;
+        }
+        btrfs(&["subvolume", "create"], &[&temp_path])?;
+        if let Err(e) = copy_layer(snapshot, &temp_path, FileCopy::Clone) {
+            let _ = fs::remove_dir_all(&temp_path);
+            return Err(e);
+        }
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Tried to move {} into place", path.display()))?;
+        Ok(path)
+    }
+}
+
+impl StorageDriver for Btrfs {
+    fn name(&self) -> &'static str {
+        "btrfs"
+    }
+
+    fn create_rootfs(
+        &self,
+        store: &Store,
+        image: &ImageLayers,
+        container: &Path,
+    ) -> Result<PathBuf> {
+        if !is_filesystem(store.root(), BTRFS_SUPER_MAGIC)? {
+            bail!(
+                "The btrfs storage driver needs the store on btrfs, {} isn't",
+                store.root().display()
+            );
+        }
+        let rootfs = container.join("rootfs");
+        let _blobs = store.share_blobs()?;
+        match store.image_snapshot(image.manifest, image.config, image.progress)? {
+            Some(snapshot) => {
+                let subvolume = self.image_subvolume(store, &snapshot)?;
+                btrfs(&["subvolume", "snapshot", "-q"], &[&subvolume, &rootfs])?;
+            }
+            None => btrfs(&["subvolume", "create"], &[&rootfs])?,
+        }
+        Ok(rootfs)
+    }
+
+    fn remove_rootfs(&self, _store: &Store, container: &Path) -> Result<()> {
+        let rootfs = container.join("rootfs");
+        match rootfs.is_dir() {
+            true => btrfs(&["subvolume", "delete"], &[&rootfs]),
+            false => Ok(()),
+        }
+    }
+
+    fn changes(&self, store: &Store, image: &ImageLayers, container: &Path) -> Result<Changes> {
+        compare_with_image(store, image, &container.join("rootfs"))
+    }
+}
+
+/// Runs `btrfs <args> <paths>`
+fn btrfs(args: &[&str], paths: &[&Path]) -> Result<()> {
+    let output = Command::new("btrfs")
+        .args(args)
+        .args(paths)
+        .output()
+        .context("Tried to run btrfs, is btrfs-progs installed?")?;
+    if !output.status.success() {
+        bail!(
+            "btrfs {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Whether `path` is on a filesystem of the type `magic` identifies
+pub fn is_filesystem(path: &Path, magic: libc::c_long) -> Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Tried to statfs {}", path.display()));
+    }
+    Ok(stat.f_type as libc::c_long == magic)
+}
@@ -0,0 +1,273 @@
+use anyhow::{bail, Context, Result};
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::redirect::Policy;
+use reqwest::{Certificate, Proxy, Url};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::config::{config_dir, Config, ProxyConfig};
+use crate::reference::DOCKER_HUB;
+
+/// How long to wait when checking whether an insecure registry speaks HTTPS at all
+const SCHEME_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to check that idle pooled connections are still alive
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Docker's per-registry certificate directory, checked after our own `certs.d`
+static DOCKER_CERTS_DIR: &str = "/etc/docker/certs.d";
+
+/// Where (and how) to reach a registry
+pub struct Endpoint {
+    pub host: String,
+    /// Allow plain HTTP and certificates that can't be verified
+    pub insecure: bool,
+    /// Extra CAs to trust for this registry
+    root_certificates: Vec<Certificate>,
+    proxies: ProxyConfig,
+    scheme: OnceLock<&'static str>,
+    clients: OnceLock<RegistryClient>,
+}
+
+/// The HTTP clients every request to a registry goes through
+///
+/// They're built once per endpoint so auth, manifest, and blob requests all share a connection
+/// pool. They speak HTTP/1.1 over TLS, negotiating HTTP/2 takes ALPN, which reqwest only does
+/// with its `native-tls-alpn` feature and our fixed dependencies don't enable.
+pub struct RegistryClient {
+    client: Client,
+    /// Leaves redirects to the caller, blob downloads need to decide who gets our token
+    no_redirects: Client,
+}
+
+impl Endpoint {
+    pub fn new(host: &str, config: &Config) -> Result<Endpoint> {
+        let mut endpoint = Endpoint {
+            host: host.to_string(),
+            insecure: config.is_insecure(host),
+            root_certificates: Vec::new(),
+            proxies: config.proxies.clone(),
+            scheme: OnceLock::new(),
+            clients: OnceLock::new(),
+        };
+        for proxy in [&config.proxies.http_proxy, &config.proxies.https_proxy]
+            .into_iter()
+            .flatten()
+        {
+            Url::parse(proxy).with_context(|| format!("Invalid proxy URL '{}'", proxy))?;
+        }
+        for dir in certs_dirs(host)? {
+            endpoint
+                .load_certificates(&dir)
+                .with_context(|| format!("Tried to load certificates from {}", dir.display()))?;
+        }
+        Ok(endpoint)
+    }
+
+    /// Endpoints for the mirrors configured for `registry`, which only Docker Hub can have
+    ///
+    /// See: https://docs.docker.com/docker-hub/image-library/mirror/
+    pub fn mirrors(registry: &str, config: &Config) -> Result<Vec<Endpoint>> {
+        if registry != DOCKER_HUB {
+            return Ok(Vec::new());
+        }
+        config
+            .registry_mirrors
+            .iter()
+            .map(|mirror| {
+                Endpoint::from_url(mirror, config)
+                    .with_context(|| format!("Invalid registry mirror '{}'", mirror))
+            })
+            .collect()
+    }
+
+    /// An endpoint for a registry given as a URL, which pins the scheme to the URL's
+    fn from_url(url: &str, config: &Config) -> Result<Endpoint> {
+        let url = Url::parse(url)?;
+        if !url.path().trim_end_matches('/').is_empty() {
+            bail!("Registry URLs can't have a path");
+        }
+        let scheme = match url.scheme() {
+            "http" => "http",
+            "https" => "https",
+            other => bail!("Unsupported scheme '{}'", other),
+        };
+        let host = url.host_str().context("No host found")?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        let endpoint = Endpoint::new(&host, config)?;
+        endpoint.scheme.set(scheme).unwrap();
+        Ok(endpoint)
+    }
+
+    /// Loads certificates laid out like docker's `certs.d/<host>` directories, where `*.crt` files
+    /// are CAs
+    ///
+    /// `<name>.cert` and `<name>.key` client certificates are refused rather than ignored, a
+    /// registry asking for one would otherwise fail in ways that don't say why.
+    ///
+    /// See: https://docs.docker.com/engine/security/certificates/
+    fn load_certificates(&mut self, dir: &Path) -> Result<()> {
+        let mut paths = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        paths.sort();
+
+        for path in paths {
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("crt") => {
+                    let pem = fs::read(&path)
+                        .with_context(|| format!("Tried to read {}", path.display()))?;
+                    let certificate = Certificate::from_pem(&pem)
+                        .with_context(|| format!("Tried to parse CA {}", path.display()))?;
+                    self.root_certificates.push(certificate);
+                }
+                // reqwest can only present client certificates when built with its
+                // `native-tls` feature, which our fixed dependencies don't enable
+                Some("cert") => bail!(
+                    "{} is a client certificate, but mutual TLS isn't supported. Remove it to \
+                     connect to the registry without one.",
+                    path.display()
+                ),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// A client builder set up with this registry's TLS and proxy settings
+    ///
+    /// Without configured proxies reqwest picks up `HTTP_PROXY`/`HTTPS_PROXY`/`NO_PROXY` itself.
+    fn client_builder(&self) -> ClientBuilder {
+        let mut builder = Client::builder().danger_accept_invalid_certs(self.insecure);
+        if self.proxies.is_set() {
+            let proxies = self.proxies.clone();
+            builder = builder.proxy(Proxy::custom(move |url| {
+                if proxies.bypasses(url.host_str().unwrap_or_default()) {
+                    return None;
+                }
+                let proxy = match url.scheme() {
+                    "http" => proxies.http_proxy.as_deref(),
+                    _ => proxies.https_proxy.as_deref(),
+                };
+                // Already validated in Endpoint::new
+                proxy.and_then(|proxy| Url::parse(proxy).ok())
+            }));
+        }
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        builder
+    }
+
+    /// The shared client for this registry
+    pub fn client(&self) -> Result<&Client> {
+        Ok(&self.clients()?.client)
+    }
+
+    /// The shared client for this registry that doesn't follow redirects
+    pub fn no_redirects_client(&self) -> Result<&Client> {
+        Ok(&self.clients()?.no_redirects)
+    }
+
+    fn clients(&self) -> Result<&RegistryClient> {
+        if let Some(clients) = self.clients.get() {
+            return Ok(clients);
+        }
+        let build = |builder: ClientBuilder| {
+            builder
+                .tcp_keepalive(TCP_KEEPALIVE)
+                .build()
+                .context("Tried to create HTTP client")
+        };
+        let clients = RegistryClient {
+            client: build(self.client_builder())?,
+            no_redirects: build(self.client_builder().redirect(Policy::none()))?,
+        };
+        // Another thread may have beaten us to it, either client is as good as the other
+        Ok(self.clients.get_or_init(|| clients))
+    }
+
+    /// Adds advice to errors from not being able to talk to the registry at all, which are
+    /// passed through as is otherwise
+    pub fn explain_unreachable(&self, error: anyhow::Error) -> anyhow::Error {
+        let request_error = match error
+            .chain()
+            .find_map(|e| e.downcast_ref::<reqwest::Error>())
+        {
+            Some(request_error) => request_error,
+            None => return error,
+        };
+        // TLS failures surface as connect errors, only their message says what went wrong
+        let certificate = error
+            .chain()
+            .any(|e| e.to_string().to_lowercase().contains("certificate"));
+        let advice = if request_error.is_timeout() {
+            format!(
+                "Timed out talking to registry {}, check your network or proxy settings",
+                self.host
+            )
+        } else if certificate && !self.insecure {
+            format!(
+                "Registry {} presented a certificate we can't verify, put its CA in a certs.d/{} \
+                 directory or pass --insecure-registry {}",
+                self.host, self.host, self.host
+            )
+        } else if request_error.is_connect() {
+            format!(
+                "Can't connect to registry {} over {}, check the host name and your network or \
+                 proxy settings",
+                self.host,
+                self.scheme()
+            )
+        } else {
+            return error;
+        };
+        error.context(advice)
+    }
+
+    /// Builds a URL for a registry API path such as `/v2/`
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.scheme(), self.host, path)
+    }
+
+    /// Secure registries use HTTPS unless configured by URL, insecure ones fall back to HTTP like
+    /// the docker daemon does when HTTPS can't be reached at all
+    fn scheme(&self) -> &'static str {
+        if let Some(scheme) = self.scheme.get() {
+            return scheme;
+        }
+        if !self.insecure {
+            return "https";
+        }
+        self.scheme.get_or_init(|| {
+            let reachable = self
+                .client_builder()
+                .timeout(SCHEME_PROBE_TIMEOUT)
+                .build()
+                .and_then(|client| client.get(format!("https://{}/v2/", self.host)).send());
+            match reachable {
+                Err(e) if e.is_connect() || e.is_timeout() => "http",
+                _ => "https",
+            }
+        })
+    }
+}
+
+/// Directories that may hold certificates for `host`, ports included like `localhost:5000`
+fn certs_dirs(host: &str) -> Result<Vec<PathBuf>> {
+    Ok(vec![
+        config_dir()?.join("certs.d").join(host),
+        Path::new(DOCKER_CERTS_DIR).join(host),
+    ])
+}
@@ -0,0 +1,97 @@
+use reqwest::blocking::Response;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::fmt;
+
+use crate::reference::ImageReference;
+
+pub static BLOB_UNKNOWN: &str = "BLOB_UNKNOWN";
+pub static MANIFEST_UNKNOWN: &str = "MANIFEST_UNKNOWN";
+pub static NAME_UNKNOWN: &str = "NAME_UNKNOWN";
+pub static UNAUTHORIZED: &str = "UNAUTHORIZED";
+pub static DENIED: &str = "DENIED";
+pub static TOOMANYREQUESTS: &str = "TOOMANYREQUESTS";
+
+/// The body registries send along with a failed response
+///
+/// See: https://distribution.github.io/distribution/spec/api/#errors
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    errors: Vec<ErrorDetail>,
+}
+
+/// One entry of a registry's `errors` array
+#[derive(Debug, Deserialize)]
+pub struct ErrorDetail {
+    pub code: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+/// A failed registry response, along with whatever the registry said went wrong
+#[derive(Debug)]
+pub struct RegistryError {
+    pub status: StatusCode,
+    pub errors: Vec<ErrorDetail>,
+}
+
+impl RegistryError {
+    /// Reads the error payload out of a failed response, which not every registry sends
+    pub fn from_response(response: Response) -> RegistryError {
+        let status = response.status();
+        let errors = response
+            .bytes()
+            .ok()
+            .and_then(|body| serde_json::from_slice::<ErrorResponse>(&body).ok())
+            .map(|body| body.errors)
+            .unwrap_or_default();
+        RegistryError { status, errors }
+    }
+
+    pub fn has_code(&self, code: &str) -> bool {
+        self.errors.iter().any(|e| e.code == code)
+    }
+
+    /// A message for the error that a person can act on, `what` says what we were after
+    pub fn describe(&self, image: &ImageReference, what: &str) -> String {
+        let repository = format!("{}/{}", image.registry, image.repository);
+        if self.has_code(NAME_UNKNOWN) {
+            format!("Repository {} not found", repository)
+        } else if self.has_code(MANIFEST_UNKNOWN) {
+            format!("Image {} not found", image)
+        } else if self.has_code(BLOB_UNKNOWN) {
+            format!("{} not found in {}", what, repository)
+        } else if self.has_code(UNAUTHORIZED)
+            || self.has_code(DENIED)
+            || self.status == StatusCode::UNAUTHORIZED
+            || self.status == StatusCode::FORBIDDEN
+        {
+            format!(
+                "Access to {} was denied, it may not exist or may need you to run login first",
+                repository
+            )
+        } else if self.has_code(TOOMANYREQUESTS) || self.status == StatusCode::TOO_MANY_REQUESTS {
+            format!("Rate limited by {} while fetching {}", image.registry, what)
+        } else if self.status == StatusCode::NOT_FOUND {
+            format!("{} not found in {}", what, repository)
+        } else {
+            format!("Registry failed to serve {} for {}", what, image)
+        }
+    }
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "registry responded with {}", self.status)?;
+        for error in &self.errors {
+            match error.message.is_empty() {
+                true => write!(f, ", {}", error.code)?,
+                false => write!(f, ", {}: {}", error.code, error.message)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RegistryError {}
@@ -0,0 +1,59 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use crate::containers::Container;
+use crate::store::Store;
+
+/// Writes a container's filesystem as one tar, its image's layers and what it changed on top of
+/// them flattened together, like `docker export`
+///
+/// What it is now is what's archived, whether it's still running or not. Like `import` takes,
+/// it has no whiteouts and no image config, and like `cp` sees, none of the container's own
+/// mounts (volumes, tmpfs, `/dev`, `/proc`, and `/sys`).
+///
+/// See: https://docs.docker.com/reference/cli/docker/container/export/
+pub fn export_container(store: &Store, container: &Container, output: impl Write) -> Result<()> {
+    let rootfs = container.rootfs(store)?;
+    let mut builder = tar::Builder::new(output);
+    builder.follow_symlinks(false);
+    append_entries(&mut builder, &rootfs, Path::new(""))
+        .with_context(|| format!("Tried to archive {}", rootfs.display()))?;
+    builder
+        .into_inner()
+        .and_then(|mut output| output.flush())
+        .context("Tried to write the archive")?;
+    Ok(())
+}
+
+/// Appends what's in the directory at `relative` in `rootfs`, sorted so the same filesystem
+/// makes the same tar
+fn append_entries(
+    builder: &mut tar::Builder<impl Write>,
+    rootfs: &Path,
+    relative: &Path,
+) -> Result<()> {
+    let directory = rootfs.join(relative);
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&directory)? {
+        names.push(entry?.file_name());
+    }
+    names.sort();
+    for name in names {
+        let (path, name) = (directory.join(&name), relative.join(&name));
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        // Sockets only mean anything to whatever's listening on them, docker leaves them out too
+        if file_type.is_socket() {
+            continue;
+        }
+        builder
+            .append_path_with_name(&path, &name)
+            .with_context(|| format!("Tried to archive /{}", name.display()))?;
+        if file_type.is_dir() {
+            append_entries(builder, rootfs, &name)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use anyhow::Result;
+
+use crate::cli::HistoryOptions;
+use crate::image::{History, ImageConfig};
+use crate::images::{parse_timestamp, render, time_ago};
+use crate::manifest::ImageManifest;
+use crate::table::{format_size, print_table, short_digest};
+
+/// How much of a step's command the table shows, like `docker history` without `--no-trunc`
+const CREATED_BY_WIDTH: usize = 45;
+
+/// One build step of an image, the way `history` lists it
+#[derive(Debug)]
+pub struct HistoryRow {
+    /// The image's ID, on its newest step only since pulled images don't have IDs for the steps
+    /// under it
+    pub id: Option<String>,
+    pub history: History,
+    /// The compressed size of the step's layer, uncompressed sizes aren't known until it's
+    /// extracted
+    pub size: u64,
+}
+
+impl HistoryRow {
+    /// Values for `--format`, named like the docker CLI names them
+    fn fields(&self, no_trunc: bool) -> Vec<(&'static str, String)> {
+        let created_since = match self.history.created.as_deref().and_then(parse_timestamp) {
+            Some(created) => time_ago(created),
+            None => String::from("N/A"),
+        };
+        let created_by = self.history.created_by.clone().unwrap_or_default();
+        let created_by = match no_trunc || created_by.chars().count() <= CREATED_BY_WIDTH {
+            true => created_by,
+            false => {
+                let truncated: String = created_by.chars().take(CREATED_BY_WIDTH - 1).collect();
+                format!("{}…", truncated)
+            }
+        };
+        vec![
+            (
+                "ID",
+                match (&self.id, no_trunc) {
+                    (Some(id), true) => id.clone(),
+                    (Some(id), false) => short_digest(id).to_string(),
+                    (None, _) => String::from("<missing>"),
+                },
+            ),
+            (
+                "CreatedAt",
+                self.history.created.clone().unwrap_or_default(),
+            ),
+            ("CreatedSince", created_since),
+            ("CreatedBy", created_by),
+            ("Size", format_size(self.size)),
+            ("Comment", self.history.comment.clone().unwrap_or_default()),
+        ]
+    }
+}
+
+/// Pairs each step of an image's history with the layer it made, newest first
+///
+/// Images without any history (like ones built by tools that don't record it) get a step with
+/// nothing but a size for each layer.
+pub fn image_history(manifest: &ImageManifest, config: &ImageConfig) -> Vec<HistoryRow> {
+    let history = match config.history.is_empty() {
+        true => vec![History::default(); manifest.layers.len()],
+        false => config.history.clone(),
+    };
+    let mut layers = manifest.layers.iter();
+    let mut rows: Vec<HistoryRow> = history
+        .into_iter()
+        .map(|history| {
+            let size = match history.empty_layer {
+                true => 0,
+                false => layers.next().map_or(0, |layer| layer.size),
+            };
+            HistoryRow {
+                id: None,
+                history,
+                size,
+            }
+        })
+        .collect();
+    rows.reverse();
+    if let Some(newest) = rows.first_mut() {
+        newest.id = Some(manifest.config.digest.clone());
+    }
+    rows
+}
+
+/// Prints history as a table, one JSON object per line for `--format json`, or through a
+/// template like `{{.CreatedBy}}`
+///
+/// See: https://docs.docker.com/reference/cli/docker/image/history/
+pub fn print_history(rows: &[HistoryRow], options: &HistoryOptions) -> Result<()> {
+    if options.quiet {
+        for row in rows {
+            println!("{}", row.fields(options.no_trunc)[0].1);
+        }
+        return Ok(());
+    }
+    match options.format.as_deref() {
+        None => {
+            let table: Vec<Vec<String>> = rows
+                .iter()
+                .map(|row| {
+                    let fields = row.fields(options.no_trunc);
+                    ["ID", "CreatedSince", "CreatedBy", "Size", "Comment"]
+                        .iter()
+                        .map(|name| {
+                            fields
+                                .iter()
+                                .find(|(field, _)| field == name)
+                                .map(|(_, value)| value.clone())
+                                .unwrap_or_default()
+                        })
+                        .collect()
+                })
+                .collect();
+            print_table(
+                &["IMAGE", "CREATED", "CREATED BY", "SIZE", "COMMENT"],
+                &table,
+            );
+        }
+        Some("json") => {
+            for row in rows {
+                let object: serde_json::Map<String, serde_json::Value> = row
+                    .fields(options.no_trunc)
+                    .into_iter()
+                    .map(|(field, value)| (field.to_string(), value.into()))
+                    .collect();
+                println!("{}", serde_json::Value::Object(object));
+            }
+        }
+        Some(template) => {
+            for row in rows {
+                println!("{}", render(template, &row.fields(options.no_trunc))?);
+            }
+        }
+    }
+    Ok(())
+}
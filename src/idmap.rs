@@ -0,0 +1,213 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// One line of a `uid_map` or `gid_map`, ids from `inside` on in our user namespace are the
+/// ones from `outside` on in its parent's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+impl IdRange {
+    /// Parses `container:host:count` from `--uidmap` and `--gidmap`, like podman's
+    ///
+    /// See: https://docs.podman.io/en/latest/markdown/podman-run.1.html#uidmap-flags-container-uid-from-uid-amount
+    pub fn parse(spec: &str) -> Result<IdRange> {
+        let fields: Vec<u32> = match spec.split(':').map(str::parse).collect() {
+            Ok(fields) => fields,
+            Err(_) => bail!(
+                "Invalid id mapping '{}', expected container-id:host-id:count",
+                spec
+            ),
+        };
+        match fields[..] {
+            [inside, outside, count] if count > 0 && inside.checked_add(count).is_some() => {
+                Ok(IdRange {
+                    inside,
+                    outside,
+                    count,
+                })
+            }
+            _ => bail!(
+                "Invalid id mapping '{}', expected container-id:host-id:count",
+                spec
+            ),
+        }
+    }
+}
+
+/// Which ids our user namespace has, which are the only ones files can be given in it
+///
+/// Outside of any user namespace that's all of them. Inside one, ownership set to an id the
+/// namespace maps is stored as the id it maps to, so extracted layers are shifted into the
+/// mapped range by just setting the ids they say, and anything on disk owned by an id it doesn't
+/// map shows up as the overflow id (`nobody`).
+///
+/// See: https://man7.org/linux/man-pages/man7/user_namespaces.7.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdMap {
+    ranges: Vec<IdRange>,
+}
+
+impl IdMap {
+    pub fn new(ranges: Vec<IdRange>) -> IdMap {
+        IdMap { ranges }
+    }
+
+    /// Parses `/proc/self/uid_map` (or `gid_map`)
+    pub fn load(path: &str) -> Result<IdMap> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Tried to read {}", path))?;
+        let mut ranges = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<u32> = line
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .with_context(|| format!("Invalid line '{}' in {}", line, path))?;
+            match fields[..] {
+                [inside, outside, count] => ranges.push(IdRange {
+                    inside,
+                    outside,
+                    count,
+                }),
+                _ => bail!("Invalid line '{}' in {}", line, path),
+            }
+        }
+        Ok(IdMap { ranges })
+    }
+
+    /// Whether files can be given `id`
+    pub fn contains(&self, id: u32) -> bool {
+        self.ranges
+            .iter()
+            .any(|range| id >= range.inside && id - range.inside < range.count)
+    }
+
+    /// What `id` is in the parent namespace, if it's mapped
+    pub fn outside(&self, id: u32) -> Option<u32> {
+        self.ranges
+            .iter()
+            .find(|range| id >= range.inside && id - range.inside < range.count)
+            .map(|range| range.outside + (id - range.inside))
+    }
+
+    /// In the format `uid_map` and `gid_map` are written in
+    fn to_map(&self) -> String {
+        self.ranges
+            .iter()
+            .map(|range| format!("{} {} {}\n", range.inside, range.outside, range.count))
+            .collect()
+    }
+}
+
+/// Our user namespace's uid and gid maps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdMappings {
+    pub uids: IdMap,
+    pub gids: IdMap,
+}
+
+impl IdMappings {
+    pub fn current() -> Result<IdMappings> {
+        Ok(IdMappings {
+            uids: IdMap::load("/proc/self/uid_map")?,
+            gids: IdMap::load("/proc/self/gid_map")?,
+        })
+    }
+
+    /// What `uid` and `gid` from a layer become, ids the namespace doesn't map are left to
+    /// whoever extracts them (`None`)
+    pub fn shift(&self, uid: u32, gid: u32) -> (Option<u32>, Option<u32>) {
+        (
+            Some(uid).filter(|&uid| self.uids.contains(uid)),
+            Some(gid).filter(|&gid| self.gids.contains(gid)),
+        )
+    }
+}
+
+/// A user namespace of our own making, kept alive by holding on to it
+///
+/// Containers run with `--uidmap` and `--gidmap` get one. Mounts are idmapped through it, so
+/// files stay on disk with the ids the image gave them and the same layers can be shared by
+/// containers with different mappings, and the container's first process joins it before exec.
+pub struct UserNamespace {
+    file: File,
+}
+
+impl UserNamespace {
+    /// Creates a user namespace with `mappings`, from a child that unshares one and waits for us
+    /// to write its maps and open it before it's killed
+    pub fn create(mappings: &IdMappings) -> Result<UserNamespace> {
+        let mut ready = [0 as RawFd; 2];
+        if unsafe { libc::pipe2(ready.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error()).context("Tried to create a pipe");
+        }
+        let (reader, writer) =
+            unsafe { (File::from_raw_fd(ready[0]), File::from_raw_fd(ready[1])) };
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error()).context("Tried to fork");
+        }
+        if pid == 0 {
+            // Only async-signal-safe calls from here on, we may have been forked from threads
+            unsafe {
+                if libc::unshare(libc::CLONE_NEWUSER) == 0 {
+                    libc::write(writer.as_raw_fd(), b"x".as_ptr() as *const libc::c_void, 1);
+                    loop {
+                        libc::pause();
+                    }
+                }
+                libc::_exit(1);
+            }
+        }
+        drop(writer);
+        let namespace = Self::open(pid, reader, mappings);
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+        namespace
+    }
+
+    fn open(pid: libc::pid_t, mut ready: File, mappings: &IdMappings) -> Result<UserNamespace> {
+        let mut byte = [0; 1];
+        match io::Read::read(&mut ready, &mut byte) {
+            Ok(1) => {}
+            _ => bail!("Tried to create a user namespace for the container, but couldn't"),
+        }
+        for (name, map) in [("uid_map", &mappings.uids), ("gid_map", &mappings.gids)] {
+            let path = format!("/proc/{}/{}", pid, name);
+            fs::write(&path, map.to_map())
+                .with_context(|| format!("Tried to write the container's {}", name))?;
+        }
+        let path = format!("/proc/{}/ns/user", pid);
+        let file = File::open(&path).with_context(|| format!("Tried to open {}", path))?;
+        Ok(UserNamespace { file })
+    }
+
+    /// Moves the calling process into the namespace, as root in it (if mapped) with no
+    /// supplementary groups, the host's don't mean anything in there
+    ///
+    /// For the container's first process between fork and exec, it has to be single threaded.
+    pub fn enter(&self) -> io::Result<()> {
+        unsafe {
+            if libc::setns(self.file.as_raw_fd(), libc::CLONE_NEWUSER) != 0
+                || libc::setgroups(0, std::ptr::null()) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for UserNamespace {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::digest::sha256_digest;
+
+/// An image's config blob, the part of it we care about at least
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/config.md
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageConfig {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default)]
+    pub config: ContainerConfig,
+    pub rootfs: RootFs,
+    /// How each layer was made, oldest first, including steps that didn't make one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<History>,
+}
+
+/// One build step of an image
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/config.md#properties
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct History {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// The command that made the layer, like a Dockerfile instruction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Steps like `ENV` that only changed the config have no layer of their own
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub empty_layer: bool,
+}
+
+/// The uncompressed layers that make up an image's filesystem
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/config.md#layer-diffid
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootFs {
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    /// Digests of each layer's uncompressed tar, in manifest order
+    pub diff_ids: Vec<String>,
+}
+
+impl RootFs {
+    /// The chain ID of each stack of layers, from just the first one up to all of them
+    ///
+    /// A chain ID names a layer along with everything under it, so stacks that start out the same
+    /// have the same chain IDs no matter which image they're from.
+    ///
+    /// See: https://github.com/opencontainers/image-spec/blob/main/config.md#layer-chainid
+    pub fn chain_ids(&self) -> Vec<String> {
+        let mut chain_ids: Vec<String> = Vec::new();
+        for diff_id in &self.diff_ids {
+            let chain_id = match chain_ids.last() {
+                Some(parent) => sha256_digest(format!("{} {}", parent, diff_id).as_bytes()),
+                None => diff_id.clone(),
+            };
+            chain_ids.push(chain_id);
+        }
+        chain_ids
+    }
+}
+
+/// Defaults for containers started from an image
+///
+/// Docker writes `null` rather than leaving fields out, hence all the `Option`s.
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/config.md#properties
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    /// Paths that get an anonymous volume of their own, written as `{"/data": {}}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<BTreeMap<String, serde_json::Value>>,
+}
@@ -0,0 +1,334 @@
+use anyhow::{bail, Result};
+use regex::{Captures, Regex};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cli::ImagesOptions;
+use crate::image::ImageConfig;
+use crate::reference::{familiar_name, ImageReference};
+use crate::store::{ImageRecord, Store};
+use crate::table::{format_size, print_table, short_digest};
+
+/// A stored image the way `images` lists it
+#[derive(Debug)]
+pub struct ImageSummary {
+    pub repository: String,
+    /// `<none>` for images that were pulled by digest
+    pub tag: String,
+    pub digest: String,
+    /// The config's digest, which is what docker calls an image's ID
+    pub id: String,
+    pub created: Option<String>,
+    /// Blobs on disk, the manifest and config included
+    pub size: u64,
+    /// Digests and sizes of the blobs counted in `size`
+    pub blobs: Vec<(String, u64)>,
+}
+
+impl ImageSummary {
+    /// Values for `--format`, named like the docker CLI names them
+    fn fields(&self, no_trunc: bool) -> Vec<(&'static str, String)> {
+        let created_since = match self.created.as_deref().and_then(parse_timestamp) {
+            Some(created) => time_ago(created),
+            None => String::from("N/A"),
+        };
+        vec![
+            ("Repository", self.repository.clone()),
+            ("Tag", self.tag.clone()),
+            ("Digest", self.digest.clone()),
+            (
+                "ID",
+                match no_trunc {
+                    true => self.id.clone(),
+                    false => short_digest(&self.id).to_string(),
+                },
+            ),
+            ("CreatedAt", self.created.clone().unwrap_or_default()),
+            ("CreatedSince", created_since),
+            ("Size", format_size(self.size)),
+        ]
+    }
+}
+
+/// Lists stored images, newest first, optionally only those matching `repository[:tag]`
+pub fn list_images(store: &Store, filter: Option<&str>) -> Result<Vec<ImageSummary>> {
+    let filter = filter.map(ImageReference::parse).transpose()?;
+    let repositories = store.repositories()?;
+
+    let mut images = Vec::new();
+    for (name, references) in &repositories.repositories {
+        let (registry, repository) = match name.split_once('/') {
+            Some(split) => split,
+            None => continue,
+        };
+        if let Some(filter) = &filter {
+            if filter.registry != registry || filter.repository != repository {
+                continue;
+            }
+        }
+        for (reference, stored) in references {
+            if filter
+                .as_ref()
+                .and_then(|f| f.tag.as_ref())
+                .is_some_and(|tag| tag != reference)
+            {
+                continue;
+            }
+            // Images whose manifest went missing can't be run or listed
+            let record = match repositories.images.get(&stored.manifest) {
+                Some(record) => record.clone(),
+                None => match store.image_manifest(stored) {
+                    Ok(manifest) => ImageRecord::from_manifest(&manifest),
+                    Err(_) => continue,
+                },
+            };
+            let config: Option<ImageConfig> = store
+                .read_blob(&record.config)
+                .ok()
+                .and_then(|raw_data| serde_json::from_slice(&raw_data).ok());
+
+            let mut size = 0;
+            let mut blobs = Vec::new();
+            for digest in [&stored.manifest, &record.config]
+                .into_iter()
+                .chain(&record.layers)
+            {
+                if let Ok(metadata) = fs::metadata(store.blob_path(digest)?) {
+                    size += metadata.len();
+                    blobs.push((digest.clone(), metadata.len()));
+                }
+            }
+
+            images.push(ImageSummary {
+                repository: familiar_name(registry, repository),
+                tag: match reference.starts_with("sha256:") {
+                    true => String::from("<none>"),
+                    false => reference.clone(),
+                },
+                digest: stored.digest.clone(),
+                id: record.config.clone(),
+                created: config.and_then(|config| config.created),
+                size,
+                blobs,
+            });
+        }
+    }
+
+    images.sort_by(|a, b| {
+        let created = |image: &ImageSummary| image.created.as_deref().and_then(parse_timestamp);
+        created(b)
+            .cmp(&created(a))
+            .then_with(|| a.repository.cmp(&b.repository))
+            .then_with(|| a.tag.cmp(&b.tag))
+    });
+    Ok(images)
+}
+
+/// Prints images as a table, one JSON object per line for `--format json`, or through a template
+/// like `{{.Repository}}:{{.Tag}}`
+///
+/// See: https://docs.docker.com/reference/cli/docker/image/ls/#format
+pub fn print_images(images: &[ImageSummary], options: &ImagesOptions) -> Result<()> {
+    match options.format.as_deref() {
+        None => {
+            let mut header = vec!["REPOSITORY", "TAG"];
+            if options.digests {
+                header.push("DIGEST");
+            }
+            header.extend(["IMAGE ID", "CREATED", "SIZE"]);
+            let rows: Vec<Vec<String>> = images
+                .iter()
+                .map(|image| {
+                    let fields = image.fields(options.no_trunc);
+                    let value = |name| {
+                        fields
+                            .iter()
+                            .find(|(field, _)| *field == name)
+                            .map(|(_, value)| value.clone())
+                            .unwrap_or_default()
+                    };
+                    let mut row = vec![value("Repository"), value("Tag")];
+                    if options.digests {
+                        row.push(value("Digest"));
+                    }
+                    row.extend([value("ID"), value("CreatedSince"), value("Size")]);
+                    row
+                })
+                .collect();
+            print_table(&header, &rows);
+        }
+        Some("json") => {
+            for image in images {
+                let object: serde_json::Map<String, serde_json::Value> = image
+                    .fields(options.no_trunc)
+                    .into_iter()
+                    .map(|(field, value)| (field.to_string(), value.into()))
+                    .collect();
+                println!("{}", serde_json::Value::Object(object));
+            }
+        }
+        Some(template) => {
+            for image in images {
+                println!("{}", render(template, &image.fields(options.no_trunc))?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fills in a template's `{{.Field}}` placeholders, along with `\t` and `\n` escapes
+pub fn render(template: &str, fields: &[(&str, String)]) -> Result<String> {
+    let placeholder = Regex::new(r"\{\{\s*\.(\w+)\s*\}\}").unwrap();
+    let mut unknown = None;
+    let rendered = placeholder.replace_all(template, |captures: &Captures| {
+        match fields.iter().find(|(field, _)| *field == &captures[1]) {
+            Some((_, value)) => value.clone(),
+            None => {
+                unknown.get_or_insert_with(|| captures[1].to_string());
+                String::new()
+            }
+        }
+    });
+    if let Some(field) = unknown {
+        let known: Vec<&str> = fields.iter().map(|(field, _)| *field).collect();
+        bail!(
+            "Unknown field '{}' in --format, expected one of {}",
+            field,
+            known.join(", ")
+        );
+    }
+    Ok(rendered.replace("\\t", "\t").replace("\\n", "\n"))
+}
+
+/// Fills in a template's `{{.Path.To.Field}}` and `{{json .Path}}` placeholders from a JSON
+/// document, along with `\t` and `\n` escapes
+///
+/// Values are printed the way Go's templates print them, `{{.}}` is the whole document, and
+/// fields that aren't there are `<no value>`.
+pub fn render_value(template: &str, document: &serde_json::Value) -> String {
+    let placeholder = Regex::new(r"\{\{\s*(json\s+)?\.([\w.]*)\s*\}\}").unwrap();
+    let rendered = placeholder.replace_all(template, |captures: &Captures| {
+        let value = captures[2]
+            .split('.')
+            .filter(|field| !field.is_empty())
+            .try_fold(document, |value, field| value.get(field));
+        match (value, captures.get(1).is_some()) {
+            (Some(value), true) => value.to_string(),
+            (Some(value), false) => go_format(value),
+            (None, _) => String::from("<no value>"),
+        }
+    });
+    rendered.replace("\\t", "\t").replace("\\n", "\n")
+}
+
+/// Formats a value like Go's `%v`, which is what templates print values with
+fn go_format(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::from("<nil>"),
+        serde_json::Value::String(string) => string.clone(),
+        serde_json::Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(go_format).collect();
+            format!("[{}]", values.join(" "))
+        }
+        serde_json::Value::Object(fields) => {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(key, value)| format!("{}:{}", key, go_format(value)))
+                .collect();
+            format!("map[{}]", fields.join(" "))
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Parses an RFC 3339 timestamp like `2024-01-02T03:04:05.678Z` into seconds since the epoch
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let number = |range: std::ops::Range<usize>| timestamp.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+
+    // The offset comes after any fractional seconds
+    let rest = timestamp.get(19..)?;
+    let rest = rest.trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match rest.chars().next() {
+        Some('Z') | Some('z') | None => 0,
+        Some(sign @ ('+' | '-')) => {
+            let hours: i64 = rest.get(1..3)?.parse().ok()?;
+            let minutes: i64 = rest.get(4..6)?.parse().ok()?;
+            let offset = hours * 3600 + minutes * 60;
+            if sign == '+' {
+                offset
+            } else {
+                -offset
+            }
+        }
+        Some(_) => return None,
+    };
+
+    // Days since the epoch from a proleptic Gregorian date
+    // See: https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(seconds).ok()
+}
+
+/// Formats seconds since the epoch as an RFC 3339 timestamp in UTC, like `2024-01-02T03:04:05Z`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = ((timestamp / 86400) as i64, timestamp % 86400);
+
+    // The inverse of parse_timestamp's days_from_civil
+    // See: https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// How long ago a timestamp was, roughly the way docker says it
+pub fn time_ago(timestamp: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let seconds = now.saturating_sub(timestamp);
+    let (minutes, hours, days) = (seconds / 60, seconds / 3600, seconds / 86400);
+    let ago = match seconds {
+        0 => String::from("Less than a second"),
+        s if s < 60 => format!("{} seconds", s),
+        s if s < 120 => String::from("About a minute"),
+        s if s < 3600 => format!("{} minutes", minutes),
+        s if s < 2 * 3600 => String::from("About an hour"),
+        s if s < 48 * 3600 => format!("{} hours", hours),
+        s if s < 14 * 86400 => format!("{} days", days),
+        s if s < 60 * 86400 => format!("{} weeks", days / 7),
+        s if s < 730 * 86400 => format!("{} months", days / 30),
+        _ => format!("{} years", days / 365),
+    };
+    format!("{} ago", ago)
+}
@@ -0,0 +1,188 @@
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+
+use crate::archive::{write_manifest, LoadedImage};
+use crate::cli::ImportOptions;
+use crate::digest::{sha256_digest, HashingReader};
+use crate::image::{ContainerConfig, History, ImageConfig, RootFs};
+use crate::images::format_timestamp;
+use crate::manifest::{Descriptor, DOCKER_CONFIG_V1, DOCKER_LAYER_GZIP};
+use crate::reference::ImageReference;
+use crate::store::Store;
+
+/// Wraps a plain rootfs tarball (like debootstrap or buildroot make) into a single layer image,
+/// like `docker import`
+///
+/// Uncompressed tarballs are gzipped on the way in, gzipped ones are stored as they are. The
+/// config is generated, with `--change` instructions applied to it and `--message` recorded in
+/// its history.
+///
+/// See: https://docs.docker.com/reference/cli/docker/image/import/
+pub fn import_rootfs(
+    store: &Store,
+    input: impl Read,
+    options: &ImportOptions,
+) -> Result<LoadedImage> {
+    let reference = options
+        .reference
+        .as_deref()
+        .map(ImageReference::parse)
+        .transpose()?;
+    let mut container_config = ContainerConfig::default();
+    for change in &options.changes {
+        apply_change(&mut container_config, change)?;
+    }
+
+    let _blobs = store.share_blobs()?;
+    let (layer, diff_id) = store_layer(store, input).context("Tried to store the tarball")?;
+
+    let created = format_timestamp(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    );
+    let config = ImageConfig {
+        architecture: options.platform.architecture.clone(),
+        os: options.platform.os.clone(),
+        variant: options.platform.variant.clone(),
+        created: Some(created.clone()),
+        config: container_config,
+        rootfs: RootFs {
+            fs_type: String::from("layers"),
+            diff_ids: vec![diff_id],
+        },
+        history: vec![History {
+            created: Some(created),
+            created_by: Some(String::from("import")),
+            comment: options.message.clone(),
+            ..History::default()
+        }],
+    };
+    let config_descriptor = write_config(store, &config)?;
+
+    let id = config_descriptor.digest.clone();
+    let stored = write_manifest(store, config_descriptor, vec![layer], &config)?;
+    let mut tags = Vec::new();
+    if let Some(image) = reference {
+        store.tag(&image, &stored)?;
+        tags.push(image);
+    }
+    Ok(LoadedImage { id, tags })
+}
+
+/// Stores a layer tarball as a blob, gzipping it unless it already is, and returns its
+/// descriptor and diff ID
+///
+/// Takes the blobs lock being held, so the blob isn't collected before an image refers to it.
+pub fn store_layer(store: &Store, input: impl Read) -> Result<(Descriptor, String)> {
+    let downloads = store.root().join("downloads");
+    let mut file = NamedTempFile::new_in(&downloads)
+        .with_context(|| format!("Tried to create a file in {}", downloads.display()))?;
+    let mut input = BufReader::new(input);
+    let compressed = input
+        .fill_buf()
+        .context("Tried to read the layer")?
+        .starts_with(&[0x1f, 0x8b]);
+    let diff_id = match compressed {
+        true => {
+            io::copy(&mut input, &mut file).context("Tried to read the layer")?;
+            None
+        }
+        false => {
+            let mut reader = HashingReader::new(input);
+            let mut encoder = GzEncoder::new(file.as_file_mut(), flate2::Compression::default());
+            io::copy(&mut reader, &mut encoder).context("Tried to compress the layer")?;
+            encoder.finish().context("Tried to compress the layer")?;
+            Some(reader.finalize())
+        }
+    };
+
+    let mut reader = HashingReader::new(File::open(file.path())?);
+    let size = io::copy(&mut reader, &mut io::sink())?;
+    let layer = Descriptor {
+        media_type: DOCKER_LAYER_GZIP.to_string(),
+        digest: reader.finalize(),
+        size,
+        urls: Vec::new(),
+        platform: None,
+        annotations: BTreeMap::new(),
+    };
+    if !store.has_blob(&layer.digest)? {
+        store.link_blob(&layer.digest, file.path())?;
+    }
+    let diff_id = match diff_id {
+        Some(diff_id) => diff_id,
+        None => store
+            .diff_id(&layer)
+            .context("Tried to decompress the layer")?,
+    };
+    Ok((layer, diff_id))
+}
+
+/// Stores an image config as a blob, and returns its descriptor
+pub fn write_config(store: &Store, config: &ImageConfig) -> Result<Descriptor> {
+    let raw_config = serde_json::to_vec(config)?;
+    let config_descriptor = Descriptor {
+        media_type: DOCKER_CONFIG_V1.to_string(),
+        digest: sha256_digest(&raw_config),
+        size: raw_config.len() as u64,
+        urls: Vec::new(),
+        platform: None,
+        annotations: BTreeMap::new(),
+    };
+    store.write_blob(&config_descriptor.digest, &raw_config)?;
+    Ok(config_descriptor)
+}
+
+/// Applies one of the Dockerfile instructions that only touch the config
+///
+/// See: https://docs.docker.com/reference/dockerfile/
+pub fn apply_change(config: &mut ContainerConfig, change: &str) -> Result<()> {
+    let change = change.trim();
+    let (instruction, value) = change.split_once([' ', '\t']).unwrap_or((change, ""));
+    let value = value.trim();
+    match instruction.to_uppercase().as_str() {
+        "CMD" => config.cmd = Some(command(value)?),
+        "ENTRYPOINT" => config.entrypoint = Some(command(value)?),
+        "ENV" => {
+            // Both `ENV KEY=value` and the legacy `ENV KEY value`
+            let variable = match value.split_once('=') {
+                Some(_) => value.to_string(),
+                None => match value.split_once([' ', '\t']) {
+                    Some((key, value)) => format!("{}={}", key, value.trim()),
+                    None => bail!("ENV needs a value, got '{}'", change),
+                },
+            };
+            let key = variable.split('=').next().unwrap_or_default();
+            let env = config.env.get_or_insert_with(Vec::new);
+            env.retain(|existing| existing.split('=').next() != Some(key));
+            env.push(variable);
+        }
+        "USER" => config.user = Some(value.to_string()),
+        "WORKDIR" => config.working_dir = Some(value.to_string()),
+        other => bail!(
+            "Unsupported --change instruction '{}', expected CMD, ENTRYPOINT, ENV, USER, or WORKDIR",
+            other
+        ),
+    }
+    Ok(())
+}
+
+/// A `CMD` or `ENTRYPOINT`, either a JSON array or a string for the shell to run
+fn command(value: &str) -> Result<Vec<String>> {
+    match value.starts_with('[') {
+        true => serde_json::from_str(value)
+            .with_context(|| format!("Tried to parse '{}' as a JSON array of strings", value)),
+        false => Ok(vec![
+            String::from("/bin/sh"),
+            String::from("-c"),
+            value.to_string(),
+        ]),
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use std::fs;
+
+use crate::images::format_timestamp;
+use crate::reference::familiar_name;
+use crate::store::{Store, StoredImage};
+
+/// Describes a stored image, shaped like `docker image inspect` describes them
+///
+/// The config's `config` object is passed through as it is, so everything the image sets (like
+/// `ExposedPorts`, `Volumes`, and `Labels`) shows up whether or not we use it. `Size` counts the
+/// blobs on disk, which is the compressed size of the layers.
+///
+/// See: https://docs.docker.com/reference/cli/docker/image/inspect/
+pub fn inspect_image(store: &Store, stored: &StoredImage) -> Result<serde_json::Value> {
+    let manifest = store.image_manifest(stored)?;
+    let raw_config = store.read_blob(&manifest.config.digest)?;
+    // Through Value rather than ImageConfig so nothing we don't model gets dropped
+    let config: serde_json::Value = serde_json::from_slice(&raw_config)
+        .with_context(|| format!("Tried to parse image config {}", manifest.config.digest))?;
+
+    let mut repo_tags = Vec::new();
+    let mut repo_digests = Vec::new();
+    for reference in store.references_to(&manifest.config.digest)? {
+        let name = familiar_name(&reference.registry, &reference.repository);
+        if let Some(tag) = &reference.tag {
+            repo_tags.push(format!("{}:{}", name, tag));
+        }
+        if let Some(stored) = store.lookup(&reference)? {
+            let repo_digest = format!("{}@{}", name, stored.digest);
+            if !repo_digests.contains(&repo_digest) {
+                repo_digests.push(repo_digest);
+            }
+        }
+    }
+
+    let mut size = 0;
+    for digest in [&stored.manifest, &manifest.config.digest]
+        .into_iter()
+        .chain(manifest.layers.iter().map(|layer| &layer.digest))
+    {
+        if let Ok(metadata) = fs::metadata(store.blob_path(digest)?) {
+            size += metadata.len();
+        }
+    }
+    let last_used = store
+        .repositories()?
+        .images
+        .get(&stored.manifest)
+        .and_then(|record| record.last_used)
+        .map(format_timestamp);
+
+    Ok(serde_json::json!({
+        "Id": manifest.config.digest,
+        "RepoTags": repo_tags,
+        "RepoDigests": repo_digests,
+        "Created": config.get("created"),
+        "Author": config.get("author"),
+        "Architecture": config.get("architecture"),
+        "Os": config.get("os"),
+        "Variant": config.get("variant"),
+        "Platform": stored.platform,
+        "Config": config.get("config"),
+        "Size": size,
+        "Digest": stored.digest,
+        "ManifestDigest": stored.manifest,
+        "Layers": manifest
+            .layers
+            .iter()
+            .map(|layer| serde_json::json!({
+                "Digest": layer.digest,
+                "MediaType": layer.media_type,
+                "Size": layer.size,
+            }))
+            .collect::<Vec<_>>(),
+        "RootFS": {
+            "Type": "layers",
+            "Layers": config.pointer("/rootfs/diff_ids"),
+        },
+        "Metadata": {
+            "LastUsedTime": last_used,
+        },
+    }))
+}
@@ -0,0 +1,289 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+use crate::archive::LoadedImage;
+use crate::digest::{check_digest, sha256_digest, validate_digest};
+use crate::manifest::{
+    Descriptor, ImageIndex, Manifest, PlatformSpec, DOCKER_MANIFEST_LIST_V2, OCI_INDEX_V1,
+    OCI_MANIFEST_V1,
+};
+use crate::platform::Platform;
+use crate::reference::{familiar_name, ImageReference};
+use crate::registry::select_platform_manifest;
+use crate::store::{write_atomically, Store, StoredImage};
+
+/// The version of the image layout this writes
+static IMAGE_LAYOUT_VERSION: &str = "1.0.0";
+
+/// Names an index entry, either a tag or a full reference
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/annotations.md#pre-defined-annotation-keys
+static REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// The full reference containerd (and `docker save` since 25.0) put next to `REF_NAME`
+static CONTAINERD_IMAGE_NAME: &str = "io.containerd.image.name";
+
+/// The `oci-layout` file that marks a directory as an image layout
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/image-layout.md#oci-layout-file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciLayout {
+    image_layout_version: String,
+}
+
+/// Writes stored images into an OCI image layout directory, which skopeo (`oci:dir:tag`),
+/// podman, and umoci can read
+///
+/// Blobs are copied as they are, so the manifest digests stay the ones the registry served.
+/// Each tag's entry in `index.json` is named with both the bare tag, which is what skopeo and
+/// umoci look up, and the full reference for containerd. A layout that's already there is added
+/// to, replacing entries with the same name.
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+pub fn save_oci_layout(store: &Store, images: &[ImageReference], directory: &Path) -> Result<()> {
+    let _blobs = store.share_blobs()?;
+    let mut stored_images = Vec::new();
+    for image in images {
+        let stored = store
+            .lookup(image)?
+            .with_context(|| format!("No such image: {}, pull it first", image))?;
+        stored_images.push((image, stored));
+    }
+
+    let blobs = directory.join("blobs/sha256");
+    fs::create_dir_all(&blobs).with_context(|| format!("Tried to create {}", blobs.display()))?;
+    if !is_layout(directory)? {
+        let layout = OciLayout {
+            image_layout_version: IMAGE_LAYOUT_VERSION.to_string(),
+        };
+        write_atomically(&directory.join("oci-layout"), &serde_json::to_vec(&layout)?)?;
+    }
+    let mut index = read_index(directory)?.unwrap_or_else(|| ImageIndex {
+        schema_version: 2,
+        media_type: Some(OCI_INDEX_V1.to_string()),
+        manifests: Vec::new(),
+    });
+
+    for (image, stored) in stored_images {
+        let manifest = store.image_manifest(&stored)?;
+        let raw_manifest = store.read_blob(&stored.manifest)?;
+        for blob in [&manifest.config].into_iter().chain(&manifest.layers) {
+            // Non-distributable layers are allowed to be missing from a layout
+            if blob.is_foreign() && !store.has_blob(&blob.digest)? {
+                continue;
+            }
+            copy_blob(store, &blob.digest, directory)
+                .with_context(|| format!("Tried to save {} of {}", blob.digest, image))?;
+        }
+        copy_blob(store, &stored.manifest, directory)?;
+
+        let platform = Platform::parse(&stored.platform)?;
+        let mut entry = Descriptor {
+            media_type: manifest
+                .media_type
+                .clone()
+                .unwrap_or_else(|| OCI_MANIFEST_V1.to_string()),
+            digest: stored.manifest.clone(),
+            size: raw_manifest.len() as u64,
+            urls: Vec::new(),
+            platform: Some(PlatformSpec {
+                os: platform.os,
+                architecture: platform.architecture,
+                variant: platform.variant,
+            }),
+            annotations: BTreeMap::new(),
+        };
+        match &image.digest {
+            Some(_) => {
+                if index.manifests.iter().any(|m| m.digest == entry.digest) {
+                    continue;
+                }
+            }
+            None => {
+                let tag = image.tag().to_string();
+                index
+                    .manifests
+                    .retain(|m| m.annotations.get(REF_NAME) != Some(&tag));
+                entry.annotations.insert(
+                    CONTAINERD_IMAGE_NAME.to_string(),
+                    format!(
+                        "{}:{}",
+                        familiar_name(&image.registry, &image.repository),
+                        tag
+                    ),
+                );
+                entry.annotations.insert(REF_NAME.to_string(), tag);
+            }
+        }
+        index.manifests.push(entry);
+    }
+
+    // Last, so the layout never lists blobs that aren't there
+    write_atomically(&directory.join("index.json"), &serde_json::to_vec(&index)?)
+}
+
+/// Imports every image an OCI image layout's `index.json` lists into the store
+///
+/// Entries are tagged with their `io.containerd.image.name` annotation, or their
+/// `org.opencontainers.image.ref.name` one if it's a full reference. A bare tag goes under a
+/// repository named after the directory, the way `skopeo copy oci:alpine:3.19` would name it.
+/// Entries that are themselves indexes import the host's platform. Every blob is checked against
+/// its digest as it's copied in.
+pub fn load_oci_layout(store: &Store, directory: &Path) -> Result<Vec<LoadedImage>> {
+    let _blobs = store.share_blobs()?;
+    if !is_layout(directory)? {
+        bail!(
+            "{} has no oci-layout file, only OCI image layouts can be loaded from a directory",
+            directory.display()
+        );
+    }
+    let index = read_index(directory)?
+        .with_context(|| format!("{} has no index.json", directory.display()))?;
+
+    let mut loaded = Vec::new();
+    for entry in &index.manifests {
+        let mut manifest_digest = entry.digest.clone();
+        if entry.media_type == OCI_INDEX_V1 || entry.media_type == DOCKER_MANIFEST_LIST_V2 {
+            let raw_index = read_layout_blob(directory, &entry.digest)?;
+            let nested: ImageIndex = serde_json::from_slice(&raw_index)
+                .with_context(|| format!("Tried to parse image index {}", entry.digest))?;
+            manifest_digest = select_platform_manifest(&nested, &Platform::host())?;
+        }
+
+        import_blob(store, directory, &manifest_digest)?;
+        let raw_manifest = store.read_blob(&manifest_digest)?;
+        let manifest = match Manifest::parse(None, &raw_manifest)? {
+            Manifest::Image(manifest) => manifest,
+            _ => bail!("{} isn't an image manifest", manifest_digest),
+        };
+        import_blob(store, directory, &manifest.config.digest)?;
+        for layer in &manifest.layers {
+            if layer.is_foreign() && !layout_blob_path(directory, &layer.digest)?.exists() {
+                continue;
+            }
+            import_blob(store, directory, &layer.digest)?;
+        }
+        let config = store.image_config(&manifest)?;
+
+        let stored = StoredImage {
+            digest: entry.digest.clone(),
+            manifest: manifest_digest,
+            platform: Platform {
+                os: config.os.clone(),
+                architecture: config.architecture.clone(),
+                variant: config.variant.clone(),
+            }
+            .to_string(),
+        };
+        let mut tags = Vec::new();
+        if let Some(name) = entry_name(entry, directory) {
+            let image = ImageReference::parse(&name)
+                .with_context(|| format!("Tried to parse {} from index.json", name))?;
+            store.tag(&image, &stored)?;
+            tags.push(image);
+        }
+        loaded.push(LoadedImage {
+            id: manifest.config.digest.clone(),
+            tags,
+        });
+    }
+    Ok(loaded)
+}
+
+/// What an index entry should be tagged as, if anything
+fn entry_name(entry: &Descriptor, directory: &Path) -> Option<String> {
+    if let Some(name) = entry.annotations.get(CONTAINERD_IMAGE_NAME) {
+        return Some(name.clone());
+    }
+    let name = entry.annotations.get(REF_NAME)?;
+    // `registry/repository:tag`, as opposed to just a tag, which can't have slashes or colons
+    if name.contains(['/', ':']) {
+        return Some(name.clone());
+    }
+    let directory = fs::canonicalize(directory).ok()?;
+    let repository = directory.file_name()?.to_string_lossy().to_lowercase();
+    Some(format!("{}:{}", repository, name))
+}
+
+/// Whether a directory has an `oci-layout` file, failing if it's for a version we can't read
+fn is_layout(directory: &Path) -> Result<bool> {
+    let path = directory.join("oci-layout");
+    let raw_layout = match fs::read(&path) {
+        Ok(raw_layout) => raw_layout,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Tried to read {}", path.display())),
+    };
+    let layout: OciLayout = serde_json::from_slice(&raw_layout)
+        .with_context(|| format!("Tried to parse {}", path.display()))?;
+    if layout.image_layout_version != IMAGE_LAYOUT_VERSION {
+        bail!(
+            "{} is an image layout version {}, only {} is supported",
+            directory.display(),
+            layout.image_layout_version,
+            IMAGE_LAYOUT_VERSION
+        );
+    }
+    Ok(true)
+}
+
+fn read_index(directory: &Path) -> Result<Option<ImageIndex>> {
+    let path = directory.join("index.json");
+    match fs::read(&path) {
+        Ok(raw_index) => serde_json::from_slice(&raw_index)
+            .map(Some)
+            .with_context(|| format!("Tried to parse {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Tried to read {}", path.display())),
+    }
+}
+
+fn read_layout_blob(directory: &Path, digest: &str) -> Result<Vec<u8>> {
+    let path = layout_blob_path(directory, digest)?;
+    let data = fs::read(&path).with_context(|| format!("Tried to read {}", path.display()))?;
+    check_digest(&sha256_digest(&data), digest)
+        .with_context(|| format!("{} doesn't match its digest", path.display()))?;
+    Ok(data)
+}
+
+/// Where a layout keeps a blob, `blobs/<algorithm>/<hex>`
+fn layout_blob_path(directory: &Path, digest: &str) -> Result<PathBuf> {
+    validate_digest(digest)?;
+    Ok(directory
+        .join("blobs/sha256")
+        .join(digest.trim_start_matches("sha256:")))
+}
+
+/// Copies a blob from the store into a layout, unless the layout already has it
+fn copy_blob(store: &Store, digest: &str, directory: &Path) -> Result<()> {
+    let path = layout_blob_path(directory, digest)?;
+    if path.exists() {
+        return Ok(());
+    }
+    let mut blob = store.open_blob(digest)?;
+    let blobs = path.parent().unwrap();
+    let mut file = NamedTempFile::new_in(blobs)
+        .with_context(|| format!("Tried to create a file in {}", blobs.display()))?;
+    io::copy(&mut blob, &mut file).with_context(|| format!("Tried to copy {}", digest))?;
+    file.persist(&path)
+        .with_context(|| format!("Tried to move {} into place", path.display()))?;
+    Ok(())
+}
+
+/// Copies a blob from a layout into the store, checking it against its digest on the way
+fn import_blob(store: &Store, directory: &Path, digest: &str) -> Result<()> {
+    if store.has_blob(digest)? {
+        return Ok(());
+    }
+    let path = layout_blob_path(directory, digest)?;
+    let source =
+        fs::File::open(&path).with_context(|| format!("Tried to open {}", path.display()))?;
+    store
+        .copy_blob(source, digest)
+        .with_context(|| format!("{} doesn't match its digest", path.display()))
+}
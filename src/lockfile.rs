@@ -0,0 +1,65 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::reference::ImageReference;
+
+/// Where `lock` writes to and `run --locked` reads from unless told otherwise
+pub static DEFAULT_LOCKFILE: &str = "minidocker.lock";
+
+/// The digests a list of image references resolved to when they were locked
+///
+/// ```json
+/// {
+///   "images": {
+///     "registry-1.docker.io/library/alpine:3.19": "sha256:..."
+///   }
+/// }
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lockfile {
+    /// Digests keyed by the normalized reference, so `alpine` and `docker.io/library/alpine`
+    /// share an entry
+    #[serde(default)]
+    pub images: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    /// Reads a lockfile, which is empty if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Lockfile> {
+        match fs::read_to_string(path) {
+            Ok(raw_data) => serde_json::from_str(&raw_data)
+                .with_context(|| format!("Tried to parse {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Lockfile::default()),
+            Err(e) => Err(e).with_context(|| format!("Tried to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut raw_data = serde_json::to_string_pretty(self)?;
+        raw_data.push('\n');
+        fs::write(path, raw_data).with_context(|| format!("Tried to write {}", path.display()))
+    }
+
+    pub fn lock(&mut self, image: &ImageReference, digest: &str) {
+        self.images.insert(image.to_string(), digest.to_string());
+    }
+
+    /// Refuses images that aren't in the lockfile or that resolved to something else
+    pub fn check(&self, image: &ImageReference, digest: &str) -> Result<()> {
+        match self.images.get(&image.to_string()) {
+            Some(locked) if locked == digest => Ok(()),
+            Some(locked) => bail!(
+                "{} resolved to {} but is locked to {}",
+                image,
+                digest,
+                locked
+            ),
+            None => bail!("{} isn't in the lockfile, run lock to add it", image),
+        }
+    }
+}
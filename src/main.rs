@@ -1,166 +1,1275 @@
-use anyhow::{Context, Result};
-use flate2::read::GzDecoder;
-use serde_json::Value;
+use anyhow::{bail, Context, Result};
 use std::fs;
-use std::os::unix::fs::chroot;
+use std::io;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use tempfile::tempdir;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
-static DOCKER_HUB: &str = "registry.hub.docker.com";
+mod archive;
+mod auth;
+mod base64;
+mod cgroup;
+mod changes;
+mod cli;
+mod commit;
+mod compression;
+mod config;
+mod console;
+mod containers;
+mod copy;
+mod credentials;
+mod df;
+mod digest;
+mod driver;
+mod endpoint;
+mod errors;
+mod export;
+mod history;
+mod idmap;
+mod image;
+mod images;
+mod import;
+mod inspect;
+mod layout;
+mod lockfile;
+mod manifest;
+mod mounts;
+mod platform;
+mod policy;
+mod process;
+mod progress;
+mod push;
+mod quota;
+mod recompress;
+mod reference;
+mod registry;
+mod retry;
+mod rootfs;
+mod sbom;
+mod search;
+mod signature;
+mod store;
+mod table;
+mod throttle;
+mod untar;
+mod verify;
+mod volumes;
 
-// Usage: your_docker.sh run <image> <command> <arg1> <arg2> ...
+use auth::{validate_credentials, RepositoryAuth};
+use cli::{
+    Command, CommitOptions, CpOptions, DfOptions, DiffOptions, ExportOptions, HistoryOptions,
+    ImagesOptions, ImportOptions, InspectOptions, LoadOptions, LockOptions, LoginOptions,
+    LogoutOptions, PrefetchOptions, PruneOptions, PsOptions, PullOptions, PullPolicy, PushOptions,
+    RecompressOptions, RepositoryOptions, RmOptions, RmiOptions, RunOptions, SaveFormat,
+    SaveOptions, SbomOptions, SearchOptions, TagOptions, VerifyOptions, VolumeCreateOptions,
+    VolumeInspectOptions, VolumeLsOptions, VolumeRmOptions, VolumeSource,
+};
+use config::Config;
+use credentials::Credentials;
+use endpoint::Endpoint;
+use idmap::UserNamespace;
+use lockfile::Lockfile;
+use policy::Policy;
+use process::ProcessSpec;
+use progress::{Progress, ProgressMode};
+use push::{mount_sources, push_image};
+use reference::{familiar_name, ImageReference};
+use registry::{fetch_image_blobs, fetch_image_manifest, list_tags, ResolvedManifest};
+use retry::RetryPolicy;
+use signature::verify_image;
+use store::{Store, StoredImage};
+use table::format_size;
+
+// Usage: your_docker.sh <pull|run|login|logout> [options] ...
 fn main() -> Result<()> {
     let args: Vec<_> = std::env::args().collect();
-
-    let image = &args[2];
-    let image_parts: Vec<&str> = image.split(':').collect();
-    let image_name = image_parts.first().unwrap();
-    let mut image_tag = "latest";
-    if image_parts.len() > 1 {
-        image_tag = image_parts.get(1).unwrap();
+    let (global, args) = cli::parse_global(&args)?;
+    if let Some(data_root) = &global.data_root {
+        // Before any threads are started, and so every Store::open picks it up
+        std::env::set_var(store::DATA_ROOT_ENV, data_root);
+    }
+    if let Some(max_cache_size) = global.max_cache_size {
+        std::env::set_var(store::MAX_CACHE_SIZE_ENV, max_cache_size.to_string());
+    }
+    if let Some(storage_driver) = &global.storage_driver {
+        std::env::set_var(driver::STORAGE_DRIVER_ENV, storage_driver);
     }
+    if let Some(xattrs) = &global.xattrs {
+        std::env::set_var(untar::XATTRS_ENV, xattrs);
+    }
+    match cli::parse(&args)? {
+        Command::Pull(options) => pull(options),
+        Command::Run(options) => run(*options),
+        Command::Login(options) => login(options),
+        Command::Logout(options) => logout(options),
+        Command::Tags(options) => tags(options),
+        Command::Push(options) => push(options),
+        Command::ManifestInspect(options) => manifest_inspect(options),
+        Command::Search(options) => search(options),
+        Command::Lock(options) => lock(options),
+        Command::Prefetch(options) => prefetch(options),
+        Command::Images(options) => images(options),
+        Command::Rmi(options) => rmi(options),
+        Command::Prune(options) => prune(options),
+        Command::SystemDf(options) => system_df(options),
+        Command::Save(options) => save(options),
+        Command::Load(options) => load(options),
+        Command::Import(options) => import_rootfs(options),
+        Command::History(options) => image_history(options),
+        Command::ImageInspect(options) => image_inspect(options),
+        Command::Sbom(options) => image_sbom(options),
+        Command::Tag(options) => tag(options),
+        Command::StoreVerify(options) => store_verify(options),
+        Command::StoreRecompress(options) => store_recompress(options),
+        Command::VolumeCreate(options) => volume_create(options),
+        Command::VolumeLs(options) => volume_ls(options),
+        Command::VolumeInspect(options) => volume_inspect(options),
+        Command::VolumeRm(options) => volume_rm(options),
+        Command::Ps(options) => ps(options),
+        Command::Rm(options) => rm(options),
+        Command::Diff(options) => diff(options),
+        Command::Commit(options) => commit_container(options),
+        Command::Cp(options) => cp(options),
+        Command::Export(options) => export(options),
+    }
+}
 
-    let auth_token = get_auth_token(image_name)?;
-    let layers = fetch_image_manifest(image_name, image_tag, &auth_token)?;
+/// Validates credentials against a registry and saves them for later pulls
+fn login(options: LoginOptions) -> Result<()> {
+    let endpoint = Endpoint::new(&options.registry, &options.config)?;
+    validate_credentials(&endpoint, &options.credentials, &options.retry)?;
+    let location = credentials::store(
+        &options.registry,
+        &options.credentials,
+        options.credential_helper.as_deref(),
+    )?;
+    println!("Login Succeeded (credentials saved to {})", location);
+    Ok(())
+}
 
-    let tmp_dir = tempdir().with_context(|| "Tried to create temporary directory".to_string())?;
+fn logout(options: LogoutOptions) -> Result<()> {
+    if credentials::erase(&options.registry)? {
+        println!("Removing login credentials for {}", options.registry);
+    } else {
+        println!("Not logged in to {}", options.registry);
+    }
+    Ok(())
+}
 
-    fetch_image_layers(layers, image_name, &auth_token, tmp_dir.path())?;
+/// Fetches the image's manifest, trying any configured mirrors before the image's own registry
+///
+/// A mirror that fails for any reason (including not having the image) is skipped with a warning.
+/// Mirrors get their own credentials, if any, never the ones meant for the upstream registry.
+fn resolve_manifest(
+    image: &ImageReference,
+    options: &PullOptions,
+    store: &Store,
+) -> Result<(RepositoryAuth, ResolvedManifest)> {
+    for mirror in Endpoint::mirrors(&image.registry, &options.config)? {
+        let host = mirror.host.clone();
+        let attempt = credentials::for_registry(&host).and_then(|credentials| {
+            let auth = RepositoryAuth::new(image, mirror, credentials, &options.retry)?;
+            let manifest = fetch_image_manifest(image, &auth, store, options)?;
+            Ok((auth, manifest))
+        });
+        match attempt {
+            Ok(resolved) => return Ok(resolved),
+            Err(e) => eprintln!(
+                "Warning: mirror {} failed, trying the next source: {:#}",
+                host, e
+            ),
+        }
+    }
 
-    let command = &args[3];
-    let target_chroot_path = tmp_dir
-        .path()
-        .join(command.strip_prefix('/').unwrap_or(command));
-    fs::create_dir_all(target_chroot_path.parent().unwrap()).with_context(|| {
-        "Tried to create directories to contain executable inside the chroot".to_string()
-    })?;
-    fs::copy(command, target_chroot_path)
-        .with_context(|| "Tried to copy executable into chroot".to_string())?;
+    let auth = registry_auth(
+        image,
+        options.credentials.as_ref(),
+        &options.config,
+        &options.retry,
+    )?;
+    let manifest = fetch_image_manifest(image, &auth, store, options)?;
+    Ok((auth, manifest))
+}
 
-    // /dev/null might already exist depending on the layers we pull, fail silently
-    let _ = fs::create_dir(tmp_dir.path().join("dev"));
-    let _ = fs::write(tmp_dir.path().join("dev/null"), b"");
+/// Authenticates against an image's own registry, looking up stored credentials unless some
+/// were given on the command line
+fn registry_auth(
+    image: &ImageReference,
+    credentials: Option<&Credentials>,
+    config: &Config,
+    retry: &RetryPolicy,
+) -> Result<RepositoryAuth> {
+    let credentials = match credentials {
+        Some(credentials) => Some(credentials.clone()),
+        None => credentials::for_registry(&image.registry)?,
+    };
+    let endpoint = Endpoint::new(&image.registry, config)?;
+    RepositoryAuth::new(image, endpoint, credentials, retry)
+}
 
-    chroot(tmp_dir.path())
-        .with_context(|| format!("Tried to chroot into {}", tmp_dir.path().display()))?;
+/// Prints an image's manifest (and the manifest list it came from) without pulling any blobs
+fn manifest_inspect(options: PullOptions) -> Result<()> {
+    let image = ImageReference::parse(&options.image)?;
+    let store = Store::open()?;
+    let (_, resolved) = resolve_manifest(&image, &options, &store)?;
 
-    unsafe {
-        libc::unshare(libc::CLONE_NEWPID);
+    // Go through Value rather than our own types so nothing we don't model gets dropped
+    let mut document = serde_json::json!({
+        "ref": image.to_string(),
+        "digest": resolved.digest,
+        "manifestDigest": resolved.manifest_digest,
+        "manifest": serde_json::from_slice::<serde_json::Value>(&resolved.raw_manifest)?,
+    });
+    if let Some(raw_index) = &resolved.raw_index {
+        document["index"] = serde_json::from_slice(raw_index)?;
     }
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
 
-    // Run the command
-    let command_args = &args[4..];
-    let output = std::process::Command::new(command)
-        .args(command_args)
-        .output()
-        .with_context(|| {
-            format!(
-                "Tried to run '{}' with arguments {:?}",
-                command, command_args
-            )
-        })?;
+/// Pushes an image from the store to its repository
+fn push(options: PushOptions) -> Result<()> {
+    let image = ImageReference::parse(&options.image)?;
+    let store = Store::open()?;
+    let stored = store
+        .lookup(&image)?
+        .with_context(|| format!("Image {} isn't in the local store", image))?;
 
-    // Cleanup
-    // fs::remove_dir_all(tmp_dir.path())
-    //     .with_context(|| "Tried to cleanup temporary directory".to_string())?;
-    // drop(tmp_dir);
+    let credentials = match &options.credentials {
+        Some(credentials) => Some(credentials.clone()),
+        None => credentials::for_registry(&image.registry)?,
+    };
+    let mount_sources = mount_sources(&image, &store, &stored)?;
+    let mut mount_from: Vec<String> = mount_sources.values().cloned().collect();
+    mount_from.sort();
+    mount_from.dedup();
 
-    let status_code = output.status.code().unwrap_or_default();
-    let std_out = std::str::from_utf8(&output.stdout)?;
-    print!("{}", std_out);
-    let std_err = std::str::from_utf8(&output.stderr)?;
-    eprint!("{}", std_err);
-    std::process::exit(status_code);
+    let endpoint = Endpoint::new(&image.registry, &options.config)?;
+    let auth =
+        RepositoryAuth::for_push(&image, &mount_from, endpoint, credentials, &options.retry)?;
+
+    let digest = push_image(&image, &auth, &store, &stored, &mount_sources, &options)?;
+    println!("{}: digest: {}", image.manifest_reference(), digest);
+    Ok(())
 }
 
-/// Retrieves an auth token from dockerhub
-///
-/// This implementation is limited to using dockerhub (hostname s not configurable) and only grabs
-/// a token with the pull scope.
-///
-/// See: https://distribution.github.io/distribution/spec/auth/jwt/
-fn get_auth_token(image_name: &str) -> Result<String, anyhow::Error> {
-    let auth_response = reqwest::blocking::get(format!(
-        "https://auth.docker.io/token?service=registry.docker.io&scope=repository:library/{}:pull",
-        image_name
-    ))
-    .context("Tried to request an auth token")?;
-    let raw_data = auth_response.text().unwrap();
-    let parsed_response: Value = serde_json::from_str(raw_data.as_str())
-        .context("Tried to parse docker registry's auth response")?;
-    Ok(String::from(parsed_response["token"].as_str().unwrap()))
-}
-
-/// Retrieves an image's manifest
+/// Prints every tag in an image's repository
+fn tags(options: RepositoryOptions) -> Result<()> {
+    let image = ImageReference::parse(&options.image)?;
+    let auth = registry_auth(
+        &image,
+        options.credentials.as_ref(),
+        &options.config,
+        &options.retry,
+    )?;
+    for tag in list_tags(&image, &auth)? {
+        println!("{}", tag);
+    }
+    Ok(())
+}
+
+/// Searches Docker Hub and prints what it found
+fn search(options: SearchOptions) -> Result<()> {
+    let results = search::search(&options)?;
+    search::print_results(&results, options.no_trunc);
+    Ok(())
+}
+
+/// Lists the images in the store
+fn images(options: ImagesOptions) -> Result<()> {
+    let store = Store::open()?;
+    let images = images::list_images(&store, options.repository.as_deref())
+        .context("Tried to list stored images")?;
+    images::print_images(&images, &options)
+}
+
+/// Removes images from the store, printing each reference that was untagged and each blob that
+/// was deleted along with it
 ///
-/// See: https://distribution.github.io/distribution/spec/api/#pulling-an-image-manifest
-fn fetch_image_manifest(
-    image_name: &str,
-    image_tag: &str,
-    token: &str,
-) -> Result<Vec<String>, anyhow::Error> {
-    let client = reqwest::blocking::Client::new();
-
-    let manifest_response = client
-        .get(format!(
-            "https://{}/v2/library/{}/manifests/{}",
-            DOCKER_HUB, image_name, image_tag
-        ))
-        .bearer_auth(token)
-        .header(
-            "Accept",
-            "application/vnd.docker.distribution.manifest.v2+json",
-        )
-        .send()
-        .context("Tried fetching image manifest")?;
-    let raw_data = manifest_response.text().unwrap();
-    let parsed_response: Value =
-        serde_json::from_str(&raw_data).context("Tried to parsed docker's manifest response")?;
-
-    let mut layers: Vec<String> = Vec::new();
-    let layers_arr = parsed_response["layers"]
-        .as_array()
-        .expect("No layers found in manifest response");
-    layers.extend(
-        layers_arr
+/// Every image is attempted even if some of them can't be removed.
+fn rmi(options: RmiOptions) -> Result<()> {
+    let store = Store::open()?;
+    let mut failures = 0;
+    for name in &options.images {
+        if let Err(e) = remove_image(name, options.force, &store) {
+            eprintln!("Error: {:#}", e);
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        bail!(
+            "Failed to remove {} of {} images",
+            failures,
+            options.images.len()
+        );
+    }
+    Ok(())
+}
+
+/// Prints what's known about images, as a JSON array or one line per image through `--format`
+fn image_inspect(options: InspectOptions) -> Result<()> {
+    let store = Store::open()?;
+    let mut documents = Vec::new();
+    for name in &options.images {
+        let (_, stored) = store
+            .find(name)?
+            .with_context(|| format!("No such image: {}", name))?;
+        documents.push(inspect::inspect_image(&store, &stored)?);
+    }
+    match &options.format {
+        Some(template) => {
+            for document in &documents {
+                println!("{}", images::render_value(template, document));
+            }
+        }
+        None => println!("{}", serde_json::to_string_pretty(&documents)?),
+    }
+    Ok(())
+}
+
+/// Writes the packages an image has installed to stdout, or a file with `-o`
+fn image_sbom(options: SbomOptions) -> Result<()> {
+    let store = Store::open()?;
+    let (image, stored) = store
+        .find(&options.image)?
+        .with_context(|| format!("No such image: {}, pull it first", options.image))?;
+    let document = sbom::image_sbom(&store, &image, &stored, options.format)?;
+    let document = serde_json::to_string_pretty(&document)?;
+    match &options.output {
+        Some(path) => fs::write(path, document + "\n")
+            .with_context(|| format!("Tried to write {}", path.display())),
+        None => {
+            println!("{}", document);
+            Ok(())
+        }
+    }
+}
+
+/// Prints how each of an image's layers was made
+fn image_history(options: HistoryOptions) -> Result<()> {
+    let store = Store::open()?;
+    let (_, stored) = store
+        .find(&options.image)?
+        .with_context(|| format!("No such image: {}", options.image))?;
+    let manifest = store.image_manifest(&stored)?;
+    let config = store.image_config(&manifest)?;
+    history::print_history(&history::image_history(&manifest, &config), &options)
+}
+
+/// Gives a stored image another reference, without touching the registry
+fn tag(options: TagOptions) -> Result<()> {
+    let target = ImageReference::parse(&options.target)?;
+    if target.digest.is_some() {
+        bail!(
+            "Refusing to create a tag with a digest reference: {}",
+            target
+        );
+    }
+    let store = Store::open()?;
+    let (_, stored) = store
+        .find(&options.source)?
+        .with_context(|| format!("No such image: {}", options.source))?;
+    store.alias(&target, &stored)
+}
+
+/// Removes an image by reference or, if no reference by that name is stored, by ID
+fn remove_image(name: &str, force: bool, store: &Store) -> Result<()> {
+    let references = match ImageReference::parse(name) {
+        Ok(image) if store.lookup(&image)?.is_some() => vec![image],
+        parsed => match (store.references_to(name)?, parsed) {
+            (references, _) if !references.is_empty() => references,
+            (_, Ok(image)) => vec![image],
+            (_, Err(_)) => bail!("No such image: {}", name),
+        },
+    };
+    if references.len() > 1 && !force {
+        let names: Vec<String> = references.iter().map(ToString::to_string).collect();
+        bail!(
+            "Image {} is referenced by {}, use -f to remove them all",
+            name,
+            names.join(", ")
+        );
+    }
+    // What a container was made from stays in the store either way, but like docker that takes -f
+    if !force {
+        let containers = containers::list_containers(store)?;
+        for image in &references {
+            let stored = match store.lookup(image)? {
+                Some(stored) => stored,
+                None => continue,
+            };
+            let user = containers
+                .iter()
+                .find(|container| container.record.stored.manifest == stored.manifest);
+            if let Some(container) = user {
+                let state = match container.running {
+                    true => "running",
+                    false => "stopped",
+                };
+                bail!(
+                    "Image {} is being used by {} container {}, use -f to remove it anyway",
+                    image,
+                    state,
+                    container.id.chars().take(12).collect::<String>()
+                );
+            }
+        }
+    }
+    for image in &references {
+        let deleted = store.untag(image)?;
+        println!("Untagged: {}", image);
+        for digest in deleted {
+            println!("Deleted: {}", digest);
+        }
+    }
+    Ok(())
+}
+
+/// Removes whatever in the store no image needs, after asking unless forced
+fn prune(options: PruneOptions) -> Result<()> {
+    if !options.force {
+        eprint!(
+            "WARNING! This will remove all blobs and extracted layers not used by a stored image.\n\
+             Are you sure you want to continue? [y/N] "
+        );
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .context("Tried to read an answer from stdin")?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Ok(());
+        }
+    }
+
+    let pruned = Store::open()?.prune().context("Tried to prune the store")?;
+    for digest in &pruned.blobs {
+        println!("Deleted: {}", digest);
+    }
+    for digest in &pruned.layers {
+        println!("Deleted layer: {}", digest);
+    }
+    for chain_id in &pruned.snapshots {
+        println!("Deleted snapshot: {}", chain_id);
+    }
+    println!("Total reclaimed space: {}", format_size(pruned.reclaimed));
+    Ok(())
+}
+
+/// Shows how much space the store takes up
+fn system_df(options: DfOptions) -> Result<()> {
+    let usage = df::disk_usage_of(&Store::open()?).context("Tried to measure the store")?;
+    df::print_disk_usage(&usage, options.verbose);
+    Ok(())
+}
+
+/// Checks every blob and extracted layer in the store, removing corrupted ones with `--repair`
+fn store_verify(options: VerifyOptions) -> Result<()> {
+    let verified = verify::verify_store(&Store::open()?, options.repair)
+        .context("Tried to verify the store")?;
+    for corruption in &verified.corrupted {
+        println!(
+            "Corrupted {} {}: {}",
+            corruption.kind, corruption.digest, corruption.problem
+        );
+    }
+    println!(
+        "Checked {} blobs and {} extracted layers",
+        verified.blobs, verified.layers
+    );
+    if verified.corrupted.is_empty() {
+        return Ok(());
+    }
+    match options.repair {
+        true => {
+            for corruption in &verified.corrupted {
+                println!("Removed {}: {}", corruption.kind, corruption.digest);
+            }
+            for chain_id in &verified.snapshots {
+                println!("Removed snapshot: {}", chain_id);
+            }
+            if !verified.affected.is_empty() {
+                println!("Pull these again to restore them:");
+                for image in &verified.affected {
+                    println!("  {}", image);
+                }
+            }
+            Ok(())
+        }
+        false => bail!(
+            "Found {} corrupted entries, run store verify --repair to remove them",
+            verified.corrupted.len()
+        ),
+    }
+}
+
+/// Shrinks the store by rewriting layer blobs with zstd
+fn store_recompress(options: RecompressOptions) -> Result<()> {
+    let store = Store::open()?;
+    let images = match options.images.is_empty() {
+        true => {
+            let mut images: Vec<StoredImage> = Vec::new();
+            for stored in store
+                .repositories()?
+                .repositories
+                .into_values()
+                .flat_map(|r| r.into_values())
+            {
+                if !images.iter().any(|image| image.manifest == stored.manifest) {
+                    images.push(stored);
+                }
+            }
+            images
+        }
+        false => options
+            .images
             .iter()
-            .map(|l| String::from(l["digest"].as_str().unwrap())),
+            .map(|name| {
+                store
+                    .find(name)?
+                    .map(|(_, stored)| stored)
+                    .with_context(|| format!("No such image: {}", name))
+            })
+            .collect::<Result<_>>()?,
+    };
+    let recompressed = recompress::recompress_layers(&store, &images, options.level)?;
+    for layer in &recompressed {
+        println!(
+            "Recompressed {}: {} -> {}",
+            layer.digest,
+            format_size(layer.before),
+            format_size(layer.after)
+        );
+    }
+    let saved: u64 = recompressed.iter().map(|l| l.before - l.after).sum();
+    println!("Total reclaimed space: {}", format_size(saved));
+    Ok(())
+}
+
+/// Creates a volume and prints its name, which is made up if none was given
+fn volume_create(options: VolumeCreateOptions) -> Result<()> {
+    let volume = volumes::create_volume(&Store::open()?, options.name.as_deref())?;
+    println!("{}", volume.name);
+    Ok(())
+}
+
+/// Lists named volumes, like `docker volume ls`
+fn volume_ls(options: VolumeLsOptions) -> Result<()> {
+    let volumes = volumes::list_volumes(&Store::open()?)?;
+    if options.quiet {
+        for volume in &volumes {
+            println!("{}", volume.name);
+        }
+        return Ok(());
+    }
+    let rows: Vec<Vec<String>> = volumes
+        .iter()
+        .map(|volume| vec![String::from("local"), volume.name.clone()])
+        .collect();
+    table::print_table(&["DRIVER", "VOLUME NAME"], &rows);
+    Ok(())
+}
+
+/// Prints what's known about volumes, as a JSON array or one line per volume through `--format`
+fn volume_inspect(options: VolumeInspectOptions) -> Result<()> {
+    let store = Store::open()?;
+    let mut documents = Vec::new();
+    for name in &options.names {
+        documents.push(volumes::find_volume(&store, name)?.inspect());
+    }
+    match &options.format {
+        Some(template) => {
+            for document in &documents {
+                println!("{}", images::render_value(template, document));
+            }
+        }
+        None => println!("{}", serde_json::to_string_pretty(&documents)?),
+    }
+    Ok(())
+}
+
+/// Removes volumes, printing the name of each one that was
+fn volume_rm(options: VolumeRmOptions) -> Result<()> {
+    let store = Store::open()?;
+    let mut failures = 0;
+    for name in &options.names {
+        if options.force && volumes::find_volume(&store, name).is_err() {
+            continue;
+        }
+        match volumes::remove_volume(&store, name) {
+            Ok(()) => println!("{}", name),
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        bail!(
+            "Failed to remove {} of {} volumes",
+            failures,
+            options.names.len()
+        );
+    }
+    Ok(())
+}
+
+/// Lists containers, like `docker ps`
+fn ps(options: PsOptions) -> Result<()> {
+    let containers: Vec<_> = containers::list_containers(&Store::open()?)?
+        .into_iter()
+        .filter(|container| options.all || container.running)
+        .collect();
+    let id = |container: &containers::Container| match options.no_trunc {
+        true => container.id.clone(),
+        false => container.id.chars().take(12).collect(),
+    };
+    if options.quiet {
+        for container in &containers {
+            println!("{}", id(container));
+        }
+        return Ok(());
+    }
+    let rows: Vec<Vec<String>> = containers
+        .iter()
+        .map(|container| {
+            let command = container.record.command.join(" ");
+            let command = match (options.no_trunc, command.chars().count() > 20) {
+                (false, true) => format!("{}…", command.chars().take(19).collect::<String>()),
+                _ => command,
+            };
+            vec![
+                id(container),
+                container.record.image.clone(),
+                format!("\"{}\"", command),
+                images::time_ago(container.record.created),
+                container.status(),
+            ]
+        })
+        .collect();
+    table::print_table(
+        &["CONTAINER ID", "IMAGE", "COMMAND", "CREATED", "STATUS"],
+        &rows,
     );
+    Ok(())
+}
+
+/// Removes containers that have exited, printing the ID each was given by
+fn rm(options: RmOptions) -> Result<()> {
+    let store = Store::open()?;
+    let mut failures = 0;
+    for id in &options.containers {
+        let removed = containers::find_container(&store, id)
+            .and_then(|container| store.remove_container(&container.id));
+        match removed {
+            Ok(()) => println!("{}", id),
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        bail!(
+            "Failed to remove {} of {} containers",
+            failures,
+            options.containers.len()
+        );
+    }
+    Ok(())
+}
 
-    Ok(layers)
+/// Lists what a container added (`A`), changed (`C`), and deleted (`D`) of its image
+fn diff(options: DiffOptions) -> Result<()> {
+    let store = Store::open()?;
+    let container = containers::find_container(&store, &options.container)?;
+    for change in container.changes(&store)?.changes {
+        println!("{} {}", change.kind.letter(), change.path.display());
+    }
+    Ok(())
+}
+
+/// Copies files between a container and the host
+fn cp(options: CpOptions) -> Result<()> {
+    let store = Store::open()?;
+    let container = containers::find_container(&store, &options.container)?;
+    copy::copy(&store, &container, &options)
+}
+
+/// Writes a container's filesystem to a tar, which `import` can make an image of again
+fn export(options: ExportOptions) -> Result<()> {
+    let store = Store::open()?;
+    let container = containers::find_container(&store, &options.container)?;
+    match &options.output {
+        Some(path) => {
+            let file = fs::File::create(path)
+                .with_context(|| format!("Tried to create {}", path.display()))?;
+            export::export_container(&store, &container, io::BufWriter::new(file))
+        }
+        None => {
+            if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+                bail!("Refusing to write an archive to a terminal, use -o or redirect stdout");
+            }
+            export::export_container(&store, &container, io::stdout().lock())
+        }
+    }
+}
+
+/// Makes an image of a container's changes, which can be run, tagged, and pushed like any other
+fn commit_container(options: CommitOptions) -> Result<()> {
+    let store = Store::open()?;
+    let container = containers::find_container(&store, &options.container)?;
+    let committed = commit::commit_container(&store, &container, &options)
+        .with_context(|| format!("Tried to commit container {}", container.id))?;
+    enforce_cache_size(&store)?;
+
+    if committed.tags.is_empty() {
+        eprintln!(
+            "Warning: {} wasn't given a tag, it can't be run and will be pruned",
+            committed.id
+        );
+    }
+    println!("{}", committed.id);
+    Ok(())
+}
+
+/// Writes stored images to a docker-archive tar, which `docker load` can read
+fn save(options: SaveOptions) -> Result<()> {
+    let images = options
+        .images
+        .iter()
+        .map(|name| ImageReference::parse(name))
+        .collect::<Result<Vec<_>>>()?;
+    let store = Store::open()?;
+    match &options.output {
+        Some(path) if options.format == SaveFormat::Oci => {
+            layout::save_oci_layout(&store, &images, path)
+        }
+        Some(path) => {
+            let file = fs::File::create(path)
+                .with_context(|| format!("Tried to create {}", path.display()))?;
+            archive::save_docker_archive(&store, &images, io::BufWriter::new(file))
+        }
+        None => {
+            if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+                bail!("Refusing to write an archive to a terminal, use -o or redirect stdout");
+            }
+            archive::save_docker_archive(&store, &images, io::stdout().lock())
+        }
+    }
+}
+
+/// Imports a docker-archive tar or an OCI image layout directory into the store, printing what
+/// was loaded like `docker load`
+fn load(options: LoadOptions) -> Result<()> {
+    let store = Store::open()?;
+    let loaded = match &options.input {
+        Some(path) if path.is_dir() => layout::load_oci_layout(&store, path),
+        Some(path) => {
+            let file = fs::File::open(path)
+                .with_context(|| format!("Tried to open {}", path.display()))?;
+            archive::load_docker_archive(&store, io::BufReader::new(file))
+        }
+        None => archive::load_docker_archive(&store, io::stdin().lock()),
+    }
+    .context("Tried to load images")?;
+    enforce_cache_size(&store)?;
+
+    for image in loaded {
+        if image.tags.is_empty() {
+            // Nothing refers to it, so the next prune cleans it up
+            eprintln!(
+                "Warning: {} has no tags in the archive, it can't be run and will be pruned",
+                image.id
+            );
+        }
+        if !options.quiet {
+            match image.tags.is_empty() {
+                true => println!("Loaded image ID: {}", image.id),
+                false => {
+                    for tag in image.tags {
+                        let name = familiar_name(&tag.registry, &tag.repository);
+                        println!("Loaded image: {}:{}", name, tag.tag());
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Turns a rootfs tarball into an image, printing its ID like `docker import`
+fn import_rootfs(options: ImportOptions) -> Result<()> {
+    let store = Store::open()?;
+    let imported = match options.source.as_str() {
+        "-" => import::import_rootfs(&store, io::stdin().lock(), &options),
+        path => {
+            let file = fs::File::open(path).with_context(|| format!("Tried to open {}", path))?;
+            import::import_rootfs(&store, file, &options)
+        }
+    }
+    .context("Tried to import the tarball")?;
+    enforce_cache_size(&store)?;
+
+    if imported.tags.is_empty() {
+        eprintln!(
+            "Warning: {} wasn't given a tag, it can't be run and will be pruned",
+            imported.id
+        );
+    }
+    println!("{}", imported.id);
+    Ok(())
+}
+
+/// Evicts the least recently used images if the store has grown past `max-cache-size`
+///
+/// Nothing can be holding a shared lock on the store's blobs, since eviction needs an exclusive
+/// one.
+fn enforce_cache_size(store: &Store) -> Result<()> {
+    let max_size = match store::max_cache_size()? {
+        Some(max_size) => max_size,
+        None => return Ok(()),
+    };
+    for image in store.evict(max_size)? {
+        eprintln!(
+            "Evicted {} to keep the store under {}",
+            image,
+            format_size(max_size)
+        );
+    }
+    Ok(())
+}
+
+/// Refuses images the policy doesn't allow, and makes sure signatures get checked if the policy
+/// requires them
+fn enforce_policy(image: &ImageReference, options: &mut PullOptions) -> Result<()> {
+    let policy = Policy::load()?;
+    if let Some(public_key) = policy.check(image)? {
+        // The policy's key is the one that counts, whatever was passed with --verify-key
+        options.verify_key = Some(public_key.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Pulls an image without running anything, printing the digest it resolved to
+fn pull(mut options: PullOptions) -> Result<()> {
+    let image = ImageReference::parse(&options.image)?;
+    enforce_policy(&image, &mut options)?;
+    let store = Store::open()?;
+    let stored = fetch_image(&image, &options, &store)?;
+    enforce_cache_size(&store)?;
+    options
+        .progress
+        .status(&format!("Digest: {}", stored.digest));
+    options
+        .progress
+        .status(&format!("Status: Downloaded image for {}", image));
+    // Like docker, a quiet pull only prints what was pulled
+    if options.progress.mode() == ProgressMode::Quiet {
+        println!("{}", image);
+        return Ok(());
+    }
+    println!("Digest: {}", stored.digest);
+    println!("Pulled {}", image);
+    Ok(())
+}
+
+/// Resolves images to digests and records them in the lockfile, without pulling any blobs
+fn lock(mut options: LockOptions) -> Result<()> {
+    let mut lockfile = Lockfile::load(&options.lockfile)?;
+    let images: Vec<String> = match options.images.is_empty() {
+        true => lockfile.images.keys().cloned().collect(),
+        false => options.images.clone(),
+    };
+    if images.is_empty() {
+        bail!(
+            "No images given to lock and {} doesn't have any to update",
+            options.lockfile.display()
+        );
+    }
+
+    let store = Store::open()?;
+    let verify_key = options.pull.verify_key.clone();
+    for name in &images {
+        let image = ImageReference::parse(name)?;
+        // Policies may require a key for some images and not others
+        options.pull.verify_key = verify_key.clone();
+        enforce_policy(&image, &mut options.pull)?;
+        let (auth, resolved) = resolve_manifest(&image, &options.pull, &store)?;
+        if let Some(public_key) = &options.pull.verify_key {
+            verify_image(&image, &auth, &resolved.digest, public_key, &store)?;
+        }
+        println!("{}: {}", image, resolved.digest);
+        lockfile.lock(&image, &resolved.digest);
+    }
+    lockfile.save(&options.lockfile)?;
+    println!(
+        "Locked {} images in {}",
+        images.len(),
+        options.lockfile.display()
+    );
+    Ok(())
 }
 
-/// Fetch the images and save them to disk
+/// Pulls a list of images into the store, a few at a time, to warm a cache
 ///
-/// See: https://distribution.github.io/distribution/spec/api/#pulling-a-layer
-fn fetch_image_layers(
-    layers: Vec<String>,
-    image_name: &str,
-    token: &str,
-    destination: &Path,
-) -> Result<()> {
-    let client = reqwest::blocking::Client::new();
-
-    // TODO: Make this async
-    for layer in layers {
-        let blob_response = client
-            .get(format!(
-                "https://{}/v2/library/{}/blobs/{}",
-                DOCKER_HUB, image_name, layer
-            ))
-            .bearer_auth(token)
-            .send()
-            .with_context(|| format!("Tried fetching layer {}", layer))?;
-        let gzipped_tar_data = blob_response.bytes()?;
-
-        let tar_data = GzDecoder::new(&gzipped_tar_data[..]);
-        let mut archive = tar::Archive::new(tar_data);
-        archive.set_preserve_permissions(true);
-        archive.set_unpack_xattrs(true);
-        archive
-            .unpack(destination)
-            .context(format!("Unable to unpack to {}", destination.display()))?;
+/// Progress goes to stdout as one JSON object per line, `pulling` when an image is started and
+/// then `pulled` (with its digest) or `failed` (with the error). Every image is attempted even
+/// if some of them fail.
+fn prefetch(options: PrefetchOptions) -> Result<()> {
+    let mut images = options.images.clone();
+    for path in &options.files {
+        let list = fs::read_to_string(path)
+            .with_context(|| format!("Tried to read {}", path.display()))?;
+        images.extend(
+            list.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from),
+        );
+    }
+    if images.is_empty() {
+        bail!("No images to prefetch");
     }
 
+    let store = Store::open()?;
+    let next_image = AtomicUsize::new(0);
+    let failures = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..options.jobs.min(images.len()) {
+            scope.spawn(|| {
+                while let Some(name) = images.get(next_image.fetch_add(1, Ordering::SeqCst)) {
+                    println!("{}", serde_json::json!({"image": name, "status": "pulling"}));
+                    let event = match prefetch_image(name, &options.pull, &store) {
+                        Ok(stored) => {
+                            serde_json::json!({"image": name, "status": "pulled", "digest": stored.digest})
+                        }
+                        Err(e) => {
+                            failures.fetch_add(1, Ordering::SeqCst);
+                            serde_json::json!({"image": name, "status": "failed", "error": format!("{:#}", e)})
+                        }
+                    };
+                    println!("{}", event);
+                }
+            });
+        }
+    });
+    enforce_cache_size(&store)?;
+
+    let failures = failures.into_inner();
+    if failures > 0 {
+        bail!("Failed to prefetch {} of {} images", failures, images.len());
+    }
     Ok(())
 }
+
+fn prefetch_image(name: &str, options: &PullOptions, store: &Store) -> Result<StoredImage> {
+    let image = ImageReference::parse(name)?;
+    let mut options = options.clone();
+    options.image = name.to_string();
+    enforce_policy(&image, &mut options)?;
+    fetch_image(&image, &options, store)
+}
+
+/// Fetches an image's manifest, config, and layers into the store
+fn fetch_image(
+    image: &ImageReference,
+    options: &PullOptions,
+    store: &Store,
+) -> Result<StoredImage> {
+    // Nothing this pulls is tagged until the end, which prune would otherwise see as unused
+    let _blobs = store.share_blobs()?;
+    let (auth, resolved) = resolve_manifest(image, options, store)?;
+    // Nothing gets downloaded for images we'd refuse to run anyway
+    if let Some(public_key) = &options.verify_key {
+        verify_image(image, &auth, &resolved.digest, public_key, store)?;
+    }
+    fetch_image_blobs(&resolved.manifest, image, &auth, store, options)?;
+
+    // The ref goes in last so a stored ref always means its blobs are there too, the manifest
+    // itself may already be in the store from an earlier conditional fetch
+    store.write_blob(&resolved.manifest_digest, &resolved.raw_manifest)?;
+    let stored = StoredImage {
+        digest: resolved.digest,
+        manifest: resolved.manifest_digest,
+        platform: options.platform.to_string(),
+    };
+    store.tag(image, &stored)?;
+    if options.config.recompress_layers {
+        let recompressed = recompress::recompress_layers(
+            store,
+            std::slice::from_ref(&stored),
+            recompress::DEFAULT_ZSTD_LEVEL,
+        );
+        // The image is usable as it is, this only saves space
+        if let Err(e) = recompressed {
+            eprintln!("Warning: couldn't recompress {}: {:#}", image, e);
+        }
+    }
+    Ok(stored)
+}
+
+/// Runs a command inside an image, pulling it first as the pull policy says
+fn run(mut options: RunOptions) -> Result<()> {
+    let image = ImageReference::parse(&options.pull.image)?;
+    enforce_policy(&image, &mut options.pull)?;
+    let lockfile = match &options.locked {
+        Some(path) => Some(Lockfile::load(path)?),
+        None => None,
+    };
+    let store = Store::open()?;
+
+    let (stored, fetched) = match options.pull_policy {
+        PullPolicy::Always => (fetch_image(&image, &options.pull, &store)?, true),
+        PullPolicy::Missing => match store.resolve(&image, &options.pull.platform)? {
+            Some(stored) => (stored, false),
+            None => (fetch_image(&image, &options.pull, &store)?, true),
+        },
+        PullPolicy::Never => store
+            .resolve(&image, &options.pull.platform)?
+            .map(|stored| (stored, false))
+            .with_context(|| {
+                let reason = match options.offline {
+                    true => "running with --offline",
+                    false => "--pull=never was given",
+                };
+                format!(
+                    "Image {} is not cached for {} ({})",
+                    image, options.pull.platform, reason
+                )
+            })?,
+    };
+    if let Some(lockfile) = &lockfile {
+        lockfile.check(&image, &stored.digest)?;
+    }
+    // Images that were already in the store still need their signature checked against the
+    // registry, signatures can be revoked by deleting them
+    if let (Some(public_key), false) = (&options.pull.verify_key, fetched) {
+        if options.pull_policy == PullPolicy::Never {
+            bail!(
+                "Can't verify the signature of {} without reaching the registry",
+                image
+            );
+        }
+        let auth = registry_auth(
+            &image,
+            options.pull.credentials.as_ref(),
+            &options.pull.config,
+            &options.pull.retry,
+        )?;
+        verify_image(&image, &auth, &stored.digest, public_key, &store)?;
+    }
+    let manifest = store.image_manifest(&stored)?;
+    let image_config = store.image_config(&manifest)?;
+
+    let driver = driver::configured_driver(options.link_layers)?;
+    if options.link_layers && driver.name() != "vfs" {
+        eprintln!(
+            "Warning: --link-layers only applies to the vfs storage driver, not {}",
+            driver.name()
+        );
+    }
+    if options.storage_size.is_some() {
+        match (driver.name(), options.link_layers) {
+            ("btrfs", _) => bail!("The btrfs storage driver can't limit a container's storage"),
+            // They'd have to be counted towards it, and can't be linked in from outside of it
+            (_, true) => bail!("--storage-opt size can't be combined with --link-layers"),
+            _ => {}
+        }
+    }
+    // The lock is held until we exit, so the rootfs is removed by whoever opens the store next
+    let (container, _container_lock) =
+        store.create_container(&stored, driver.name(), options.storage_size)?;
+    store.mark_used(&stored)?;
+    enforce_cache_size(&store)?;
+    // Extraction is only worth reporting as part of a pull, cached images unpack silently
+    let quiet = Progress::new(ProgressMode::Quiet);
+    let progress = match fetched {
+        true => &*options.pull.progress,
+        false => &quiet,
+    };
+    let layers = driver::ImageLayers {
+        manifest: &manifest,
+        config: &image_config,
+        progress,
+    };
+    let rootfs = driver.create_rootfs(&store, &layers, &container)?;
+    let process = ProcessSpec::new(&options, &image_config.config, &rootfs)?;
+    containers::record_container(&container, &options.pull.image, &stored, &process.args)?;
+
+    // Commands that only exist on the host (like the explorer binary the tests use) are copied
+    // into the chroot, anything the image ships is used as is
+    let command = &process.args[0];
+    let target_chroot_path = rootfs.join(command.strip_prefix('/').unwrap_or(command));
+    let host_only = command.starts_with('/')
+        && fs::symlink_metadata(&target_chroot_path).is_err()
+        && Path::new(command).is_file();
+    if host_only {
+        fs::create_dir_all(target_chroot_path.parent().unwrap()).with_context(|| {
+            "Tried to create directories to contain executable inside the chroot".to_string()
+        })?;
+        fs::copy(command, target_chroot_path)
+            .with_context(|| "Tried to copy executable into chroot".to_string())?;
+    }
+
+    // Images don't always have a /proc to mount procfs on, or a /sys for sysfs
+    let _ = fs::create_dir(rootfs.join("proc"));
+    if !options.no_sysfs {
+        let _ = fs::create_dir(rootfs.join("sys"));
+    }
+
+    mounts::unshare_mounts(&rootfs, &options.volumes)?;
+    // Layers stay on disk with the image's ids, the container sees them as its own through an
+    // idmapped mount. Kernels and filesystems that can't do that only leave chowning a copy
+    // nothing else shares, before anything is mounted in it.
+    let namespace = match &options.id_mappings {
+        Some(mappings) => {
+            let namespace = UserNamespace::create(mappings)?;
+            let idmapped = mounts::can_idmap(&rootfs, &namespace)?;
+            match (idmapped, driver.name() == "vfs" && !options.link_layers) {
+                (true, _) => {}
+                (false, true) => rootfs::shift_ownership(&rootfs, mappings)?,
+                (false, false) => bail!(
+                    "Can't idmap the container's rootfs with the {} storage driver (that needs \
+                     Linux 5.12 or later, and overlayfs can't be idmapped), --uidmap and --gidmap \
+                     only work with the vfs driver without --link-layers then",
+                    driver.name()
+                ),
+            }
+            Some((namespace, idmapped))
+        }
+        None => None,
+    };
+    mounts::mount_dev(&rootfs, options.shm_size)?;
+    let mut device_rules = cgroup::default_device_rules();
+    device_rules.extend(mounts::create_devices(&rootfs, &options.devices)?);
+    // Hosts without cgroups we can make leave containers able to use any device, as they
+    // always could, which only matters to mention when they were given some
+    let cgroup = match cgroup::Cgroup::create(&container, &device_rules) {
+        Ok(cgroup) => Some(cgroup),
+        Err(_) if options.devices.is_empty() => None,
+        Err(e) => {
+            eprintln!(
+                "Warning: couldn't create a device cgroup, the container can use every device: \
+                 {:#}",
+                e
+            );
+            None
+        }
+    };
+    let cgroup_procs = cgroup.as_ref().map(cgroup::Cgroup::procs).transpose()?;
+    let anonymous = volumes::anonymous_volumes(&image_config.config, &options.volumes);
+    if options.remove {
+        let names: Vec<&str> = anonymous
+            .iter()
+            .filter_map(|volume| match &volume.source {
+                VolumeSource::Named(name) => Some(name.as_str()),
+                VolumeSource::Host(_) => None,
+            })
+            .collect();
+        store.remove_with_container(&container, &names)?;
+    }
+    // Held until we exit, so the volumes can't be removed while they're mounted
+    let mut _volume_locks = Vec::new();
+    for volume in options.volumes.iter().chain(&anonymous) {
+        let source = match &volume.source {
+            VolumeSource::Host(path) => path.clone(),
+            VolumeSource::Named(name) => {
+                let (path, lock) = volumes::use_volume(&store, name, &rootfs, &volume.destination)?;
+                _volume_locks.push(lock);
+                path
+            }
+        };
+        let namespace = namespace.as_ref().map(|(namespace, _)| namespace);
+        mounts::bind_volume(&rootfs, &source, volume, namespace)?;
+    }
+    for tmpfs in &options.tmpfs {
+        mounts::mount_tmpfs(&rootfs, tmpfs)?;
+    }
+    // Last, everything mounted on it needed somewhere to go first
+    if options.read_only {
+        mounts::remount_read_only(&rootfs)?;
+    }
+    if let Some((namespace, true)) = &namespace {
+        mounts::idmap_rootfs(&rootfs, namespace)?;
+    }
+    let exit_code = containers::ExitCodeFile::create(&container)?;
+    mounts::pivot_root(&rootfs)?;
+    if !options.no_sysfs {
+        mounts::mount_sysfs()?;
+    }
+    let console = match options.tty {
+        true => Some(console::Console::open()?),
+        false => None,
+    };
+
+    unsafe {
+        libc::unshare(libc::CLONE_NEWPID);
+    }
+
+    // Run the command
+    let command_args = &process.args[1..];
+    let (uid, gid) = (process.uid, process.gid);
+    let mut child = std::process::Command::new(command);
+    child
+        .args(command_args)
+        .env_clear()
+        .envs(process.env.iter().map(|(key, value)| (key, value)))
+        .current_dir(&process.working_dir);
+    if let Some(console) = &console {
+        child
+            .stdin(console.open_slave()?)
+            .stdout(console.open_slave()?)
+            .stderr(console.open_slave()?);
+    }
+    let tty = console.is_some();
+    // The first process of the PID namespace, and the only one that can mount its /proc
+    unsafe {
+        child.pre_exec(move || {
+            // Before it can open anything, and while we're still allowed to move it there
+            if let Some(procs) = &cgroup_procs {
+                procs.join()?;
+            }
+            if tty {
+                console::take_controlling_terminal()?;
+            }
+            mounts::mount_proc()?;
+            mounts::mask_paths()?;
+            if let Some((namespace, _)) = &namespace {
+                namespace.enter()?;
+            }
+            process::switch_user(uid, gid)
+        });
+    }
+    let run_error = || {
+        format!(
+            "Tried to run '{}' with arguments {:?}",
+            command, command_args
+        )
+    };
+
+    if let Some(console) = console {
+        let mut running = child.spawn().with_context(run_error)?;
+        // Our copies of the container's end, or reading ours would never see it closed
+        drop(child);
+        console.relay()?;
+        let status = running.wait().with_context(run_error)?;
+        exit_code.write(status.code().unwrap_or_default());
+        if let Some(cgroup) = cgroup {
+            cgroup.remove();
+        }
+        std::process::exit(status.code().unwrap_or_default());
+    }
+    let output = child.output().with_context(run_error)?;
+
+    let status_code = output.status.code().unwrap_or_default();
+    exit_code.write(status_code);
+    if let Some(cgroup) = cgroup {
+        cgroup.remove();
+    }
+    let std_out = std::str::from_utf8(&output.stdout)?;
+    print!("{}", std_out);
+    let std_err = std::str::from_utf8(&output.stderr)?;
+    eprint!("{}", std_err);
+    std::process::exit(status_code);
+}
@@ -0,0 +1,279 @@
+use anyhow::{bail, Context, Result};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::base64;
+
+pub static DOCKER_MANIFEST_V2: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub static DOCKER_MANIFEST_LIST_V2: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+pub static OCI_MANIFEST_V1: &str = "application/vnd.oci.image.manifest.v1+json";
+pub static OCI_INDEX_V1: &str = "application/vnd.oci.image.index.v1+json";
+
+/// The long deprecated schema 1 manifests some old registries and mirrors still serve
+///
+/// See: https://distribution.github.io/distribution/spec/deprecated-schema-v1/
+pub static DOCKER_MANIFEST_V1: &str = "application/vnd.docker.distribution.manifest.v1+json";
+pub static DOCKER_MANIFEST_V1_SIGNED: &str =
+    "application/vnd.docker.distribution.manifest.v1+prettyjws";
+
+/// Every manifest media type we know how to handle, in order of preference
+pub static ACCEPTED_MANIFEST_TYPES: [&str; 6] = [
+    OCI_INDEX_V1,
+    DOCKER_MANIFEST_LIST_V2,
+    OCI_MANIFEST_V1,
+    DOCKER_MANIFEST_V2,
+    DOCKER_MANIFEST_V1_SIGNED,
+    DOCKER_MANIFEST_V1,
+];
+
+pub static DOCKER_CONFIG_V1: &str = "application/vnd.docker.container.image.v1+json";
+
+pub static DOCKER_LAYER_TAR: &str = "application/vnd.docker.image.rootfs.diff.tar";
+pub static DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+pub static OCI_LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
+pub static OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+pub static OCI_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+
+/// Layers that registries aren't allowed to serve (e.g. Windows base layers), these can only be
+/// fetched from the URLs listed in their descriptor
+pub static DOCKER_FOREIGN_LAYER_GZIP: &str =
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+pub static OCI_NONDISTRIBUTABLE_LAYER_TAR: &str =
+    "application/vnd.oci.image.layer.nondistributable.v1.tar";
+pub static OCI_NONDISTRIBUTABLE_LAYER_GZIP: &str =
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip";
+pub static OCI_NONDISTRIBUTABLE_LAYER_ZSTD: &str =
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd";
+
+/// Annotation on eStargz layers with the digest of their table of contents
+///
+/// eStargz layers are regular gzipped tars with a few extra entries, so they can be pulled and
+/// unpacked like any other layer. Lazily fetching their files on access would need a FUSE backed
+/// layer, which we don't have.
+///
+/// See: https://github.com/containerd/stargz-snapshotter/blob/main/docs/estargz.md
+pub static ESTARGZ_TOC_DIGEST: &str = "containerd.io/snapshot/stargz/toc.digest";
+
+/// Entries eStargz adds to a layer's tar that aren't part of the image's filesystem
+pub static ESTARGZ_METADATA: [&str; 3] = [
+    "stargz.index.json",
+    ".prefetch.landmark",
+    ".no.prefetch.landmark",
+];
+
+/// A reference to a piece of content (manifest, config, or layer) stored in a registry
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/descriptor.md
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<PlatformSpec>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl Descriptor {
+    /// Whether this is a foreign (non-distributable) layer
+    ///
+    /// See: https://github.com/opencontainers/image-spec/blob/main/layer.md#non-distributable-layers
+    pub fn is_foreign(&self) -> bool {
+        [
+            DOCKER_FOREIGN_LAYER_GZIP,
+            OCI_NONDISTRIBUTABLE_LAYER_TAR,
+            OCI_NONDISTRIBUTABLE_LAYER_GZIP,
+            OCI_NONDISTRIBUTABLE_LAYER_ZSTD,
+        ]
+        .contains(&self.media_type.as_str())
+    }
+}
+
+/// The platform an index entry was built for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlatformSpec {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+/// A single-platform image manifest, either Docker schema 2 or OCI
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/manifest.md
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+impl ImageManifest {
+    /// Layers we can actually get, foreign layers without any URLs are only on the publisher's
+    /// machines
+    pub fn fetchable_layers(&self) -> impl Iterator<Item = &Descriptor> {
+        self.layers
+            .iter()
+            .filter(|layer| !(layer.is_foreign() && layer.urls.is_empty()))
+    }
+}
+
+/// A multi-platform manifest list (Docker) or image index (OCI)
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/image-index.md
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>,
+}
+
+/// A schema 1 manifest, whose layers and history are listed from the top layer down
+///
+/// See: https://distribution.github.io/distribution/spec/deprecated-schema-v1/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schema1Manifest {
+    #[serde(default)]
+    pub architecture: String,
+    pub fs_layers: Vec<FsLayer>,
+    pub history: Vec<Schema1History>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsLayer {
+    #[serde(rename = "blobSum")]
+    pub blob_sum: String,
+}
+
+/// The v1 image JSON for a layer, which is all schema 1 has in the way of an image config
+#[derive(Debug, Clone, Deserialize)]
+pub struct Schema1History {
+    #[serde(rename = "v1Compatibility")]
+    pub v1_compatibility: String,
+}
+
+/// The JWS signature docker signed schema 1 manifests with, kept in the manifest itself
+#[derive(Debug, Deserialize)]
+struct Schema1Signatures {
+    #[serde(default)]
+    signatures: Vec<Schema1Signature>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Schema1Signature {
+    protected: String,
+}
+
+/// The protected header says how to get back to the bytes that were signed
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Schema1ProtectedHeader {
+    format_length: usize,
+    format_tail: String,
+}
+
+/// The part of a signed schema 1 manifest its digest is computed over, which is the manifest
+/// without its `signatures`
+///
+/// See: https://github.com/distribution/distribution/blob/release/2.8/docs/spec/manifest-v2-1.md#signed-manifests
+pub fn schema1_payload(body: &[u8]) -> Result<Vec<u8>> {
+    let signed: Schema1Signatures =
+        serde_json::from_slice(body).context("Tried to parse schema 1 manifest signatures")?;
+    let signature = match signed.signatures.first() {
+        Some(signature) => signature,
+        None => return Ok(body.to_vec()),
+    };
+    let header = base64::decode(&base64url_to_standard(&signature.protected))
+        .context("Tried to decode schema 1 manifest signature")?;
+    let header: Schema1ProtectedHeader = serde_json::from_slice(&header)
+        .context("Tried to parse schema 1 manifest signature header")?;
+    let tail = base64::decode(&base64url_to_standard(&header.format_tail))
+        .context("Tried to decode schema 1 manifest signature")?;
+    let head = body
+        .get(..header.format_length)
+        .context("Schema 1 manifest is shorter than its signature says")?;
+    Ok([head, &tail].concat())
+}
+
+fn base64url_to_standard(encoded: &str) -> String {
+    encoded.replace('-', "+").replace('_', "/")
+}
+
+/// Just enough of a manifest to tell which kind it is before parsing it for real
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestShape {
+    #[serde(default)]
+    schema_version: Option<u32>,
+    #[serde(default)]
+    media_type: Option<String>,
+    #[serde(default)]
+    manifests: Option<IgnoredAny>,
+}
+
+/// How a manifest body should be parsed
+enum ManifestKind {
+    Image,
+    Index,
+    Schema1,
+}
+
+/// Anything the manifests endpoint can hand back to us
+#[derive(Debug, Clone)]
+pub enum Manifest {
+    Image(ImageManifest),
+    Index(ImageIndex),
+    Schema1(Schema1Manifest),
+}
+
+impl Manifest {
+    /// Parses a manifest response body
+    ///
+    /// The media type comes from the response's `Content-Type` header, falling back to the
+    /// document's own `mediaType` field (which OCI makes optional) and finally its shape.
+    pub fn parse(content_type: Option<&str>, body: &[u8]) -> Result<Manifest> {
+        let shape: ManifestShape =
+            serde_json::from_slice(body).context("Tried to parse manifest as JSON")?;
+
+        let media_type = content_type
+            .filter(|t| ACCEPTED_MANIFEST_TYPES.contains(t))
+            .or(shape.media_type.as_deref());
+
+        let kind = match media_type {
+            Some(t) if t == OCI_INDEX_V1 || t == DOCKER_MANIFEST_LIST_V2 => ManifestKind::Index,
+            Some(t) if t == OCI_MANIFEST_V1 || t == DOCKER_MANIFEST_V2 => ManifestKind::Image,
+            Some(t) if t == DOCKER_MANIFEST_V1 || t == DOCKER_MANIFEST_V1_SIGNED => {
+                ManifestKind::Schema1
+            }
+            Some(t) => bail!("Unsupported manifest media type '{}'", t),
+            None if shape.schema_version == Some(1) => ManifestKind::Schema1,
+            None if shape.manifests.is_some() => ManifestKind::Index,
+            None => ManifestKind::Image,
+        };
+
+        // serde's errors name the missing or mistyped field along with where it was expected
+        match kind {
+            ManifestKind::Index => serde_json::from_slice(body)
+                .map(Manifest::Index)
+                .context("Tried to parse image index"),
+            ManifestKind::Image => serde_json::from_slice(body)
+                .map(Manifest::Image)
+                .context("Tried to parse image manifest"),
+            ManifestKind::Schema1 => serde_json::from_slice(body)
+                .map(Manifest::Schema1)
+                .context("Tried to parse schema 1 manifest"),
+        }
+    }
+}
@@ -0,0 +1,826 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::{CStr, CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{lchown, symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use crate::cgroup::{DeviceRule, DeviceType};
+use crate::cli::{Device, Propagation, Tmpfs, Volume};
+use crate::idmap::{IdMappings, UserNamespace};
+use crate::rootfs::resolve_in_rootfs;
+
+/// From `linux/mount.h`, for the mount API idmapped mounts need, which the libc crate doesn't have
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+/// From `linux/fcntl.h`
+const AT_RECURSIVE: libc::c_int = 0x8000;
+
+/// `struct mount_attr`, what `mount_setattr` changes
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// The devices in every container's `/dev`, with their major and minor numbers
+static DEVICES: &[(&str, u32, u32)] = &[
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+/// What in procfs and sysfs tells about (or lets a container poke at) the host, hidden behind
+/// `/dev/null` for files and an empty read-only tmpfs for directories
+///
+/// These, and `READ_ONLY_PATHS`, are docker's defaults for the OCI runtime spec.
+///
+/// See: https://github.com/moby/moby/blob/master/oci/defaults.go
+static MASKED_PATHS: &[&CStr] = &[
+    c"/proc/asound",
+    c"/proc/acpi",
+    c"/proc/interrupts",
+    c"/proc/kcore",
+    c"/proc/keys",
+    c"/proc/latency_stats",
+    c"/proc/timer_list",
+    c"/proc/timer_stats",
+    c"/proc/sched_debug",
+    c"/proc/scsi",
+    c"/sys/firmware",
+    c"/sys/devices/virtual/powercap",
+];
+
+/// What in procfs the container may read but, since it changes the host's kernel, not write
+static READ_ONLY_PATHS: &[&CStr] = &[
+    c"/proc/bus",
+    c"/proc/fs",
+    c"/proc/irq",
+    c"/proc/sys",
+    c"/proc/sysrq-trigger",
+];
+
+/// Symlinks in every container's `/dev`, to where they point
+static DEV_SYMLINKS: &[(&str, &str)] = &[
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+    // Terminals are only ever allocated from the container's own devpts
+    ("ptmx", "pts/ptmx"),
+];
+
+/// Moves us into a mount namespace of our own, with `rootfs` bind mounted onto itself
+///
+/// Mounts made from then on (like those on `/dev` under `rootfs`) don't propagate back to the
+/// host, and are carried along by `pivot_root`. Our namespace stays as connected to the host's
+/// as `volumes` need, like docker makes the container's root `rshared` for `shared` ones and
+/// `rslave` for `slave` ones, and `rprivate` otherwise.
+pub fn unshare_mounts(rootfs: &Path, volumes: &[Volume]) -> Result<()> {
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        return Err(io::Error::last_os_error())
+            .context("Tried to create a mount namespace for the container");
+    }
+    let wants = |propagations: &[Propagation]| {
+        volumes
+            .iter()
+            .any(|volume| propagations.contains(&volume.propagation))
+    };
+    let propagation = match (
+        wants(&[Propagation::Shared, Propagation::RShared]),
+        wants(&[Propagation::Slave, Propagation::RSlave]),
+    ) {
+        (true, _) => libc::MS_SHARED,
+        (false, true) => libc::MS_SLAVE,
+        (false, false) => libc::MS_PRIVATE,
+    };
+    // Mounts are shared with the host's namespace by default on systemd, unmounting the old root
+    // would unmount it on the host too
+    mount(None, Path::new("/"), None, libc::MS_REC | propagation, None)?;
+    // Where the rootfs is mounted from can't be, so nothing mounted in it shows up on the host
+    // and `pivot_root` doesn't refuse to move onto a shared mount
+    if propagation != libc::MS_PRIVATE {
+        let parent = mount_of(rootfs)?;
+        mount(None, &parent.mount_point, None, libc::MS_PRIVATE, None)?;
+    }
+    // The new root has to be a mount point, which a plain directory in the store isn't
+    mount(
+        Some(rootfs),
+        rootfs,
+        None,
+        libc::MS_BIND | libc::MS_REC,
+        None,
+    )
+}
+
+/// Makes `rootfs` the root of our mount namespace, leaving none of the host's mounts behind in
+/// it, once `unshare_mounts` has made it a mount point
+///
+/// Unlike `chroot`, which only changes where paths are resolved from and can be escaped by
+/// anyone who can chroot again, this swaps the root mount itself, the way runc does, and then
+/// unmounts the host's.
+///
+/// See: https://man7.org/linux/man-pages/man2/pivot_root.2.html
+pub fn pivot_root(rootfs: &Path) -> Result<()> {
+    let old_root = fs::File::open("/").context("Tried to open /")?;
+    std::env::set_current_dir(rootfs)
+        .with_context(|| format!("Tried to change directory to {}", rootfs.display()))?;
+    // Pivoting onto the current directory stacks the old root on top of the new one, so it can
+    // be unmounted without needing a directory in the rootfs to put it in
+    let current = CString::new(".")?;
+    if unsafe { libc::syscall(libc::SYS_pivot_root, current.as_ptr(), current.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Tried to pivot_root into {}", rootfs.display()));
+    }
+    // `.` is the new root until we're in the old one, which is only reachable through what we
+    // opened of it
+    if unsafe { libc::fchdir(old_root.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .context("Tried to change directory to the host's root");
+    }
+    // Like runc and LXC, so unmounting it can't unmount anything on the host through the mounts
+    // it may still share with it
+    mount(
+        None,
+        Path::new("."),
+        None,
+        libc::MS_SLAVE | libc::MS_REC,
+        None,
+    )?;
+    if unsafe { libc::umount2(current.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(io::Error::last_os_error()).context("Tried to unmount the host's root");
+    }
+    std::env::set_current_dir("/").context("Tried to change directory to /")
+}
+
+/// Mounts a tmpfs on the rootfs' `/dev` with the devices every container gets in it, along with
+/// the usual symlinks and a `/dev/pts` and a `/dev/shm` (of up to `shm_size` bytes) of its own
+///
+/// Devices are made with `mknod` where we may, and bind mounted from the host's `/dev` where we
+/// may not (like in a user namespace). Call before `pivot_root`, while the host's `/dev` is
+/// still there.
+///
+/// See: https://github.com/opencontainers/runtime-spec/blob/main/config-linux.md#default-devices
+pub fn mount_dev(rootfs: &Path, shm_size: u64) -> Result<()> {
+    let dev = rootfs.join("dev");
+    if !dev.is_dir() {
+        fs::create_dir(&dev).with_context(|| format!("Tried to create {}", dev.display()))?;
+    }
+    mount(
+        Some(Path::new("tmpfs")),
+        &dev,
+        Some("tmpfs"),
+        libc::MS_NOSUID | libc::MS_STRICTATIME,
+        Some("mode=755,size=65536k"),
+    )?;
+    for &(name, major, minor) in DEVICES {
+        create_device(
+            &dev.join(name),
+            &Path::new("/dev").join(name),
+            libc::S_IFCHR | 0o666,
+            unsafe { libc::makedev(major, minor) },
+            None,
+        )
+        .with_context(|| format!("Tried to create /dev/{}", name))?;
+    }
+    for &(name, target) in DEV_SYMLINKS {
+        symlink(target, dev.join(name))
+            .with_context(|| format!("Tried to create /dev/{}", name))?;
+    }
+
+    let shm = dev.join("shm");
+    let pts = dev.join("pts");
+    for directory in [&shm, &pts] {
+        fs::create_dir(directory)
+            .with_context(|| format!("Tried to create {}", directory.display()))?;
+    }
+    mount(
+        Some(Path::new("shm")),
+        &shm,
+        Some("tmpfs"),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        Some(&format!("mode=1777,size={}", shm_size)),
+    )?;
+    // A devpts of its own, so the container doesn't see (or get to open) the host's terminals.
+    // Group 5 is `tty` by convention, in user namespaces that don't map it terminals stay in
+    // the group of whoever opens them.
+    let options = match IdMappings::current()?.gids.contains(5) {
+        true => "newinstance,ptmxmode=0666,mode=0620,gid=5",
+        false => "newinstance,ptmxmode=0666,mode=0620",
+    };
+    mount(
+        Some(Path::new("devpts")),
+        &pts,
+        Some("devpts"),
+        libc::MS_NOSUID | libc::MS_NOEXEC,
+        Some(options),
+    )
+}
+
+/// Makes a device node, owned by `owner` if given, or binds `host` (the same device) to it where
+/// we aren't allowed to (in a user namespace, say). One that's already there is kept.
+fn create_device(
+    path: &Path,
+    host: &Path,
+    mode: libc::mode_t,
+    device: libc::dev_t,
+    owner: Option<(u32, u32)>,
+) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mknod(c_path.as_ptr(), mode, device) } == 0 {
+        // Whatever the umask took away
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+            .with_context(|| format!("Tried to change the permissions of {}", path.display()))?;
+        if let Some((uid, gid)) = owner {
+            lchown(path, Some(uid), Some(gid))
+                .with_context(|| format!("Tried to change the owner of {}", path.display()))?;
+        }
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EEXIST) => return Ok(()),
+        Some(libc::EPERM) => {}
+        _ => return Err(error).with_context(|| format!("Tried to create {}", path.display())),
+    }
+    fs::File::create(path).with_context(|| format!("Tried to create {}", path.display()))?;
+    bind(host, path)
+}
+
+/// Makes the `--device` nodes in the container, like the host's (which they're bound to if
+/// they can't be made), returning the rules its device cgroup needs to let it use them. Call
+/// after `mount_dev`.
+pub fn create_devices(rootfs: &Path, devices: &[Device]) -> Result<Vec<DeviceRule>> {
+    let mut rules = Vec::new();
+    for device in devices {
+        add_device(
+            rootfs,
+            &device.host,
+            &device.container,
+            device,
+            true,
+            &mut rules,
+        )
+        .with_context(|| format!("Tried to add device {}", device.host.display()))?;
+    }
+    Ok(rules)
+}
+
+/// `given` is whether `host` is what `--device` was given, rather than something found in a
+/// directory it was given, which only has its devices added (not its symlinks to them)
+fn add_device(
+    rootfs: &Path,
+    host: &Path,
+    destination: &Path,
+    device: &Device,
+    given: bool,
+    rules: &mut Vec<DeviceRule>,
+) -> Result<()> {
+    let metadata = match given {
+        true => fs::metadata(host),
+        false => fs::symlink_metadata(host),
+    }
+    .with_context(|| format!("Tried to read {}", host.display()))?;
+    let file_type = metadata.file_type();
+    let kind = match (file_type.is_char_device(), file_type.is_block_device()) {
+        (true, _) => DeviceType::Char,
+        (_, true) => DeviceType::Block,
+        _ if metadata.is_dir() => {
+            let mut entries = fs::read_dir(host)
+                .with_context(|| format!("Tried to list {}", host.display()))?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort();
+            for name in entries {
+                add_device(
+                    rootfs,
+                    &host.join(&name),
+                    &destination.join(&name),
+                    device,
+                    false,
+                    rules,
+                )?;
+            }
+            return Ok(());
+        }
+        _ if given => bail!("{} isn't a device", host.display()),
+        _ => return Ok(()),
+    };
+    let path = resolve_in_rootfs(rootfs, destination)?;
+    let parent = path.parent().unwrap();
+    fs::create_dir_all(parent).with_context(|| format!("Tried to create {}", parent.display()))?;
+    create_device(
+        &path,
+        host,
+        metadata.mode(),
+        metadata.rdev(),
+        Some((metadata.uid(), metadata.gid())),
+    )?;
+    let (major, minor) = unsafe { (libc::major(metadata.rdev()), libc::minor(metadata.rdev())) };
+    rules.push(DeviceRule {
+        kind: Some(kind),
+        major: Some(major),
+        minor: Some(minor),
+        access: device.permissions.clone(),
+    });
+    Ok(())
+}
+
+/// Mounts a read-only sysfs on `/sys`, and a read-only cgroup2 filesystem on `/sys/fs/cgroup`
+/// in a cgroup namespace of the container's own
+///
+/// The cgroup namespace makes the cgroup we're in look like the root of the hierarchy, so
+/// software reading its limits from there (like the JVM and Go's runtime) finds them, without
+/// seeing anything of the host's other cgroups. Call after `pivot_root`.
+///
+/// See: https://man7.org/linux/man-pages/man7/cgroup_namespaces.7.html
+pub fn mount_sysfs() -> Result<()> {
+    let flags = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+    mount(
+        Some(Path::new("sysfs")),
+        Path::new("/sys"),
+        Some("sysfs"),
+        flags,
+        None,
+    )?;
+    if unsafe { libc::unshare(libc::CLONE_NEWCGROUP) } != 0 {
+        return Err(io::Error::last_os_error())
+            .context("Tried to create a cgroup namespace for the container");
+    }
+    mount(
+        Some(Path::new("cgroup2")),
+        Path::new("/sys/fs/cgroup"),
+        Some("cgroup2"),
+        flags,
+        None,
+    )
+}
+
+/// Mounts a procfs of the container's own PID namespace on `/proc`
+///
+/// procfs shows the PID namespace of whoever mounts it, and only the processes started after
+/// `unshare(CLONE_NEWPID)` are in the container's, so this is called from the container's first
+/// process, between fork and exec, where nothing may allocate.
+pub fn mount_proc() -> io::Result<()> {
+    let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+    let result = unsafe {
+        libc::mount(
+            c"proc".as_ptr(),
+            c"/proc".as_ptr(),
+            c"proc".as_ptr(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Masks `MASKED_PATHS` and makes `READ_ONLY_PATHS` read-only, once `mount_proc` has mounted
+/// the procfs they're in
+///
+/// Like `mount_proc`, this runs between fork and exec, so nothing here allocates. Paths the
+/// kernel doesn't have are skipped.
+pub fn mask_paths() -> io::Result<()> {
+    for path in MASKED_PATHS {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::stat(path.as_ptr(), &mut stat) } != 0 {
+            continue;
+        }
+        let result = match stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            true => unsafe {
+                libc::mount(
+                    c"tmpfs".as_ptr(),
+                    path.as_ptr(),
+                    c"tmpfs".as_ptr(),
+                    libc::MS_RDONLY,
+                    std::ptr::null(),
+                )
+            },
+            false => unsafe {
+                libc::mount(
+                    c"/dev/null".as_ptr(),
+                    path.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND,
+                    std::ptr::null(),
+                )
+            },
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // Bind mounted onto themselves first, only a mount can be made read-only. The remount keeps
+    // the flags procfs was mounted with.
+    let flags = libc::MS_BIND | libc::MS_REC;
+    let remount = flags
+        | libc::MS_REMOUNT
+        | libc::MS_RDONLY
+        | libc::MS_NOSUID
+        | libc::MS_NODEV
+        | libc::MS_NOEXEC;
+    for path in READ_ONLY_PATHS {
+        let bound = unsafe {
+            libc::mount(
+                path.as_ptr(),
+                path.as_ptr(),
+                std::ptr::null(),
+                flags,
+                std::ptr::null(),
+            )
+        };
+        if bound != 0 {
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::NotFound => continue,
+                e => return Err(e),
+            }
+        }
+        let remounted = unsafe {
+            libc::mount(
+                std::ptr::null(),
+                path.as_ptr(),
+                std::ptr::null(),
+                remount,
+                std::ptr::null(),
+            )
+        };
+        if remounted != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Bind mounts `source` (a host path, or the contents of a named volume) on where `volume` goes
+/// in the rootfs, creating what it goes on if the image doesn't have it
+///
+/// Directories are bind mounted along with whatever is mounted under them, and what's mounted
+/// under them later propagates as the volume says, which needs `source` to be on a shared mount
+/// on the host for anything to propagate at all (`mount --make-shared`). With `namespace`
+/// (from `--uidmap`) the mount is idmapped like the rootfs, where the kernel can. Call before
+/// `pivot_root`, while the host's paths are still there.
+pub fn bind_volume(
+    rootfs: &Path,
+    source: &Path,
+    volume: &Volume,
+    namespace: Option<&UserNamespace>,
+) -> Result<()> {
+    // Like docker with -v, rather than failing
+    if !source.exists() {
+        fs::create_dir_all(source)
+            .with_context(|| format!("Tried to create {}", source.display()))?;
+    }
+    let target = resolve_in_rootfs(rootfs, Path::new(&volume.destination))?;
+    let is_dir = source.is_dir();
+    match fs::symlink_metadata(&target) {
+        Ok(metadata) if metadata.is_dir() != is_dir => bail!(
+            "Can't mount {} on {} in the container, only a {} can go there",
+            source.display(),
+            volume.destination,
+            match metadata.is_dir() {
+                true => "directory",
+                false => "file",
+            }
+        ),
+        Ok(_) => {}
+        Err(_) if is_dir => fs::create_dir_all(&target)
+            .with_context(|| format!("Tried to create {}", target.display()))?,
+        Err(_) => {
+            fs::create_dir_all(target.parent().unwrap())
+                .and_then(|()| fs::File::create(&target).map(|_| ()))
+                .with_context(|| format!("Tried to create {}", target.display()))?;
+        }
+    }
+
+    let propagates = match volume.propagation {
+        Propagation::Shared | Propagation::RShared => &["shared:"][..],
+        Propagation::Slave | Propagation::RSlave => &["shared:", "master:"][..],
+        Propagation::Private | Propagation::RPrivate => &[][..],
+    };
+    let on_shared_mount = mount_of(source)?.optional.iter().any(|field| {
+        propagates
+            .iter()
+            .any(|propagation| field.starts_with(propagation))
+    });
+    if !propagates.is_empty() && !on_shared_mount {
+        eprintln!(
+            "Warning: {} isn't on a shared mount, mounts under it won't propagate",
+            source.display()
+        );
+    }
+
+    let tree = match namespace {
+        Some(namespace) => idmapped_tree(source, namespace, true)?,
+        None => None,
+    };
+    if let Some(tree) = &tree {
+        move_mount(tree, source, &target)?;
+    } else {
+        if namespace.is_some() {
+            eprintln!(
+                "Warning: Can't idmap the mount of {}, its files keep their ids in the container",
+                source.display()
+            );
+        }
+        mount(
+            Some(source),
+            &target,
+            None,
+            libc::MS_BIND | libc::MS_REC,
+            None,
+        )?;
+    }
+    let propagation = match volume.propagation {
+        Propagation::Shared => libc::MS_SHARED,
+        Propagation::Slave => libc::MS_SLAVE,
+        Propagation::Private => libc::MS_PRIVATE,
+        Propagation::RShared => libc::MS_SHARED | libc::MS_REC,
+        Propagation::RSlave => libc::MS_SLAVE | libc::MS_REC,
+        Propagation::RPrivate => libc::MS_PRIVATE | libc::MS_REC,
+    };
+    mount(None, &target, None, propagation, None)?;
+    if volume.read_only {
+        remount_read_only(&target)?;
+    }
+    Ok(())
+}
+
+/// Mounts a tmpfs where `tmpfs` goes in the rootfs, creating the directory if the image doesn't
+/// have it
+pub fn mount_tmpfs(rootfs: &Path, tmpfs: &Tmpfs) -> Result<()> {
+    let target = resolve_in_rootfs(rootfs, Path::new(&tmpfs.destination))?;
+    fs::create_dir_all(&target).with_context(|| format!("Tried to create {}", target.display()))?;
+    let mut flags = 0;
+    for (set, flag) in [
+        (tmpfs.read_only, libc::MS_RDONLY),
+        (!tmpfs.exec, libc::MS_NOEXEC),
+        (!tmpfs.suid, libc::MS_NOSUID),
+        (!tmpfs.dev, libc::MS_NODEV),
+    ] {
+        if set {
+            flags |= flag;
+        }
+    }
+    mount(
+        Some(Path::new("tmpfs")),
+        &target,
+        Some("tmpfs"),
+        flags,
+        Some(&tmpfs.data.join(",")),
+    )
+}
+
+/// Makes a bind mount read-only, keeping the flags it already had
+///
+/// A remount replaces all of them, and the ones users in a user namespace can't clear
+/// (like `nosuid` on what the host mounted with it) make it fail if they're left out. Only
+/// `target` itself turns read-only, what's mounted under it (like the rootfs's `/dev` and
+/// volumes) stays as it was.
+pub fn remount_read_only(target: &Path) -> Result<()> {
+    let c_target = CString::new(target.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_target.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Tried to statvfs {}", target.display()));
+    }
+    let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+    for (kept, flag) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & kept != 0 {
+            flags |= flag;
+        }
+    }
+    mount(None, target, None, flags, None)
+}
+
+/// Whether the kernel (5.12 or later) and the filesystem `path` is on can idmap its mount
+pub fn can_idmap(path: &Path, namespace: &UserNamespace) -> Result<bool> {
+    Ok(idmapped_tree(path, namespace, false)?.is_some())
+}
+
+/// Stacks an idmapped copy of the rootfs's mount (and what's mounted under it) on top of it, so
+/// files on disk with the image's ids show up in the container as their own
+///
+/// Call once everything is mounted on it and it's been made read-only if it should be. Only the
+/// rootfs's own mount is idmapped, the copies of volumes keep theirs and the others (like `/dev`)
+/// stay as they are, nothing can be created through an idmapped mount by ids it doesn't map (like
+/// our root).
+pub fn idmap_rootfs(rootfs: &Path, namespace: &UserNamespace) -> Result<()> {
+    let tree = idmapped_tree(rootfs, namespace, false)?
+        .with_context(|| format!("Tried to idmap the mount of {}", rootfs.display()))?;
+    move_mount(&tree, rootfs, rootfs)
+}
+
+/// A copy of the mount `source` is on (along with what's mounted under it), not mounted
+/// anywhere yet, with its ids shifted through `namespace`'s maps (and those of the mounts under
+/// it too, if `recursive`)
+///
+/// `None` when the kernel (before 5.12) or the filesystem can't idmap mounts.
+///
+/// See: https://man7.org/linux/man-pages/man2/mount_setattr.2.html
+fn idmapped_tree(
+    source: &Path,
+    namespace: &UserNamespace,
+    recursive: bool,
+) -> Result<Option<fs::File>> {
+    let c_source = CString::new(source.as_os_str().as_bytes())?;
+    let unsupported = |error: &io::Error| {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::ENOSYS) | Some(libc::EINVAL) | Some(libc::EOPNOTSUPP)
+        )
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            c_source.as_ptr(),
+            OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint | AT_RECURSIVE as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return match io::Error::last_os_error() {
+            e if unsupported(&e) => Ok(None),
+            e => {
+                Err(e).with_context(|| format!("Tried to clone the mount of {}", source.display()))
+            }
+        };
+    }
+    let tree = unsafe { fs::File::from_raw_fd(fd as libc::c_int) };
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: namespace.as_raw_fd() as u64,
+    };
+    let flags = match recursive {
+        true => libc::AT_EMPTY_PATH | AT_RECURSIVE,
+        false => libc::AT_EMPTY_PATH,
+    };
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            flags as libc::c_uint,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        )
+    };
+    if result != 0 {
+        return match io::Error::last_os_error() {
+            e if unsupported(&e) => Ok(None),
+            e => {
+                Err(e).with_context(|| format!("Tried to idmap the mount of {}", source.display()))
+            }
+        };
+    }
+    Ok(Some(tree))
+}
+
+/// Mounts a tree from `idmapped_tree` (copied from `source`) on `target`
+fn move_mount(tree: &fs::File, source: &Path, target: &Path) -> Result<()> {
+    let c_target = CString::new(target.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_FDCWD,
+            c_target.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| {
+            format!(
+                "Tried to mount {} on {}",
+                source.display(),
+                target.display()
+            )
+        });
+    }
+    Ok(())
+}
+
+/// A mount, from `/proc/self/mountinfo`
+struct MountInfo {
+    mount_point: PathBuf,
+    /// Like `shared:1` for mounts in peer group 1, and `master:1` for slaves of it, which is how
+    /// a mount's propagation shows
+    optional: Vec<String>,
+}
+
+/// The mount `path` is on, the last one mounted on the longest mount point it's under
+///
+/// See: https://man7.org/linux/man-pages/man5/proc_pid_mountinfo.5.html
+fn mount_of(path: &Path) -> Result<MountInfo> {
+    let path =
+        fs::canonicalize(path).with_context(|| format!("Tried to resolve {}", path.display()))?;
+    let mountinfo =
+        fs::read_to_string("/proc/self/mountinfo").context("Tried to read /proc/self/mountinfo")?;
+    let mut found: Option<MountInfo> = None;
+    for line in mountinfo.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (mount_point, optional) = match fields.iter().position(|&field| field == "-") {
+            Some(separator) if separator >= 6 => (unescape(fields[4]), &fields[6..separator]),
+            _ => bail!("Invalid line '{}' in /proc/self/mountinfo", line),
+        };
+        let longer = match &found {
+            Some(found) => mount_point.as_os_str().len() >= found.mount_point.as_os_str().len(),
+            None => true,
+        };
+        if path.starts_with(&mount_point) && longer {
+            found = Some(MountInfo {
+                mount_point,
+                optional: optional.iter().map(|field| field.to_string()).collect(),
+            });
+        }
+    }
+    found.with_context(|| format!("{} isn't on any mount", path.display()))
+}
+
+/// Undoes the octal escapes (like `\040` for a space) mountinfo has in paths
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                unescaped.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(unescaped))
+}
+
+/// Bind mounts `source` on `target`
+pub fn bind(source: &Path, target: &Path) -> Result<()> {
+    mount(Some(source), target, None, libc::MS_BIND, None)
+}
+
+/// Calls `mount(2)`, with `source`, `fstype`, and `data` left out where they aren't given
+fn mount(
+    source: Option<&Path>,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> Result<()> {
+    let c_source = source
+        .map(|source| CString::new(source.as_os_str().as_bytes()))
+        .transpose()?;
+    let c_target = CString::new(target.as_os_str().as_bytes())?;
+    let c_fstype = fstype.map(CString::new).transpose()?;
+    let c_data = data.map(CString::new).transpose()?;
+    let result = unsafe {
+        libc::mount(
+            c_source.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            c_target.as_ptr(),
+            c_fstype.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            flags,
+            c_data
+                .as_ref()
+                .map_or(std::ptr::null(), |s| s.as_ptr() as *const libc::c_void),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| match source {
+            Some(source) => format!(
+                "Tried to mount {} on {}",
+                source.display(),
+                target.display()
+            ),
+            None => format!("Tried to change how {} is mounted", target.display()),
+        });
+    }
+    Ok(())
+}
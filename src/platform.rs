@@ -0,0 +1,73 @@
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// An image platform in the `os/architecture[/variant]` form used by manifest indexes
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/image-index.md#image-index-property-descriptions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Parses a platform string like `linux/arm64` or `linux/arm/v7`
+    pub fn parse(platform: &str) -> Result<Platform> {
+        let parts: Vec<&str> = platform.split('/').collect();
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|p| p.is_empty()) {
+            bail!(
+                "Invalid platform '{}', expected os/architecture[/variant]",
+                platform
+            );
+        }
+
+        Ok(Platform {
+            os: parts[0].to_string(),
+            architecture: parts[1].to_string(),
+            variant: parts.get(2).map(|v| v.to_string()),
+        })
+    }
+
+    /// The platform of the machine we're running on, using GOARCH style architecture names
+    pub fn host() -> Platform {
+        let (architecture, variant) = match std::env::consts::ARCH {
+            "x86_64" => ("amd64", None),
+            "x86" => ("386", None),
+            "aarch64" => ("arm64", Some("v8")),
+            "arm" => ("arm", Some("v7")),
+            "powerpc64" => ("ppc64le", None),
+            "loongarch64" => ("loong64", None),
+            arch => (arch, None),
+        };
+
+        Platform {
+            os: String::from(std::env::consts::OS),
+            architecture: String::from(architecture),
+            variant: variant.map(String::from),
+        }
+    }
+
+    /// Checks whether an index entry's platform can run on this platform
+    ///
+    /// A missing variant on either side is treated as a wildcard since many indexes omit it.
+    pub fn matches(&self, os: &str, architecture: &str, variant: Option<&str>) -> bool {
+        if self.os != os || self.architecture != architecture {
+            return false;
+        }
+        match (self.variant.as_deref(), variant) {
+            (Some(ours), Some(theirs)) => ours == theirs,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
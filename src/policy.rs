@@ -0,0 +1,131 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::config_dir;
+use crate::reference::{normalize_registry, ImageReference};
+
+/// A system wide policy, which takes precedence over the user's so they can't loosen it
+static SYSTEM_POLICY: &str = "/etc/minidocker/policy.json";
+
+/// Which images `pull` and `run` may use, from `policy.json`
+///
+/// Patterns match `registry/repository` (e.g. `docker.io/library/alpine`), where `*` matches
+/// anything including slashes. Without any `allowed` patterns every repository is allowed, and
+/// `denied` wins over `allowed`.
+///
+/// ```json
+/// {
+///   "allowed": ["docker.io/library/*", "ghcr.io/my-org/*"],
+///   "denied": ["docker.io/library/ubuntu"],
+///   "requirements": [
+///     {"match": "ghcr.io/my-org/*", "require-digest": true, "signed-by": "/etc/keys/cosign.pub"}
+///   ]
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    allowed: Vec<String>,
+    #[serde(default)]
+    denied: Vec<String>,
+    #[serde(default)]
+    requirements: Vec<Requirement>,
+}
+
+/// What images from matching repositories need before they can be used
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Requirement {
+    #[serde(rename = "match")]
+    pattern: String,
+    /// References have to pin a digest, tags can be moved to point at something else
+    #[serde(default)]
+    require_digest: bool,
+    /// Public key the image's cosign signature has to verify against
+    #[serde(default)]
+    signed_by: Option<PathBuf>,
+}
+
+impl Policy {
+    /// Loads the system policy if there is one and the user's otherwise, without either
+    /// everything is allowed
+    pub fn load() -> Result<Policy> {
+        for path in [
+            PathBuf::from(SYSTEM_POLICY),
+            config_dir()?.join("policy.json"),
+        ] {
+            match fs::read_to_string(&path) {
+                Ok(raw_data) => {
+                    return serde_json::from_str(&raw_data)
+                        .with_context(|| format!("Tried to parse {}", path.display()))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Tried to read {}", path.display()))
+                }
+            }
+        }
+        Ok(Policy::default())
+    }
+
+    /// Refuses references the policy doesn't allow, returning the key the image's signature
+    /// must verify against if the policy requires one
+    pub fn check(&self, image: &ImageReference) -> Result<Option<&Path>> {
+        let name = format!("{}/{}", image.registry, image.repository);
+        let matches = |pattern: &String| glob_matches(&normalize_pattern(pattern), &name);
+
+        if self.denied.iter().any(matches) {
+            bail!("Policy denies images from {}", name);
+        }
+        if !self.allowed.is_empty() && !self.allowed.iter().any(matches) {
+            bail!("Policy doesn't allow images from {}", name);
+        }
+
+        let mut signed_by = None;
+        for requirement in self.requirements.iter().filter(|r| matches(&r.pattern)) {
+            if requirement.require_digest && image.digest.is_none() {
+                bail!(
+                    "Policy requires images from {} to be pinned by digest (name@sha256:...)",
+                    name
+                );
+            }
+            signed_by = signed_by.or(requirement.signed_by.as_deref());
+        }
+        Ok(signed_by)
+    }
+}
+
+/// Lets patterns name Docker Hub the way people usually do (`docker.io/...`)
+fn normalize_pattern(pattern: &str) -> String {
+    match pattern.split_once('/') {
+        Some((host, rest)) => format!("{}/{}", normalize_registry(host), rest),
+        None => pattern.to_string(),
+    }
+}
+
+/// Matches `*` wildcards, which may match any run of characters (slashes included)
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // No wildcards at all
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
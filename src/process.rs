@@ -0,0 +1,197 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cli::RunOptions;
+use crate::image::ContainerConfig;
+
+/// The `PATH` docker gives containers whose image doesn't set one
+static DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Everything needed to start the container's process
+#[derive(Debug)]
+pub struct ProcessSpec {
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub working_dir: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl ProcessSpec {
+    /// Combines the image's defaults with the command line the way `docker run` does
+    ///
+    /// A command given on the command line replaces the image's `Cmd` but is still passed to its
+    /// `Entrypoint`, unless `--entrypoint` replaces that too (which also drops the image's `Cmd`).
+    ///
+    /// See: https://docs.docker.com/reference/dockerfile/#understand-how-cmd-and-entrypoint-interact
+    pub fn new(options: &RunOptions, config: &ContainerConfig, rootfs: &Path) -> Result<Self> {
+        let entrypoint = match &options.entrypoint {
+            Some(entrypoint) if entrypoint.is_empty() => Vec::new(),
+            Some(entrypoint) => vec![entrypoint.clone()],
+            None => config.entrypoint.clone().unwrap_or_default(),
+        };
+        let cmd = match (&options.command, &options.entrypoint) {
+            (Some(command), _) => [command.clone()]
+                .into_iter()
+                .chain(options.args.iter().cloned())
+                .collect(),
+            (None, Some(_)) => Vec::new(),
+            (None, None) => config.cmd.clone().unwrap_or_default(),
+        };
+        let args: Vec<String> = entrypoint.into_iter().chain(cmd).collect();
+        if args.is_empty() {
+            bail!("No command given and the image doesn't specify one either");
+        }
+
+        let user = options
+            .user
+            .as_deref()
+            .or(config.user.as_deref())
+            .filter(|user| !user.is_empty())
+            .unwrap_or("root");
+        let (uid, gid, home) = resolve_user(user, rootfs)
+            .with_context(|| format!("Tried to resolve user {}", user))?;
+
+        let mut env = Vec::new();
+        for variable in config.env.iter().flatten() {
+            let (key, value) = variable.split_once('=').unwrap_or((variable, ""));
+            set_env(&mut env, key, value);
+        }
+        for variable in &options.env {
+            match variable.split_once('=') {
+                Some((key, value)) => set_env(&mut env, key, value),
+                // Like docker, a bare name passes the variable through only if we have it
+                None => {
+                    if let Ok(value) = std::env::var(variable) {
+                        set_env(&mut env, variable, &value);
+                    }
+                }
+            }
+        }
+        if !env.iter().any(|(key, _)| key == "PATH") {
+            set_env(&mut env, "PATH", DEFAULT_PATH);
+        }
+        if !env.iter().any(|(key, _)| key == "HOME") {
+            set_env(&mut env, "HOME", home.as_deref().unwrap_or("/"));
+        }
+        // Like docker, so programs know what the terminal they're on can do
+        if options.tty && !env.iter().any(|(key, _)| key == "TERM") {
+            set_env(&mut env, "TERM", "xterm");
+        }
+
+        let working_dir = options
+            .working_dir
+            .as_deref()
+            .or(config.working_dir.as_deref())
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or("/")
+            .to_string();
+        if !working_dir.starts_with('/') {
+            bail!("The working directory '{}' must be absolute", working_dir);
+        }
+
+        Ok(ProcessSpec {
+            args,
+            env,
+            working_dir,
+            uid,
+            gid,
+        })
+    }
+}
+
+/// Becomes `uid` and `gid`, dropping supplementary groups, from between fork and exec
+///
+/// That's what `Command::uid` and `Command::gid` do too, but before any `pre_exec` hook runs,
+/// and the hooks setting the container up still need root. User namespaces made without
+/// privileges (like `unshare --map-root-user` makes them) don't allow `setgroups`, and have no
+/// supplementary groups to drop either.
+pub fn switch_user(uid: u32, gid: u32) -> io::Result<()> {
+    unsafe {
+        if libc::getuid() == 0 && libc::setgroups(0, std::ptr::null()) != 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EPERM) {
+                return Err(error);
+            }
+        }
+        if libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_env(env: &mut Vec<(String, String)>, key: &str, value: &str) {
+    match env.iter_mut().find(|(k, _)| k == key) {
+        Some((_, v)) => *v = value.to_string(),
+        None => env.push((key.to_string(), value.to_string())),
+    }
+}
+
+/// Resolves `user[:group]` (names or ids) using the image's `/etc/passwd` and `/etc/group`,
+/// returning the uid, gid, and home directory
+fn resolve_user(user: &str, rootfs: &Path) -> Result<(u32, u32, Option<String>)> {
+    let (user, group) = match user.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (user, None),
+    };
+
+    // passwd lines look like name:password:uid:gid:gecos:home:shell
+    let passwd = read_database(&rootfs.join("etc/passwd"))?;
+    let entry = passwd
+        .iter()
+        .find(|fields| fields[0] == user || fields.get(2).map(String::as_str) == Some(user));
+    let (uid, primary_gid, home) = match (entry, user.parse::<u32>()) {
+        (Some(fields), _) if fields.len() >= 6 => (
+            fields[2]
+                .parse()
+                .with_context(|| format!("Invalid uid for {} in /etc/passwd", user))?,
+            fields[3]
+                .parse()
+                .with_context(|| format!("Invalid gid for {} in /etc/passwd", user))?,
+            Some(fields[5].clone()),
+        ),
+        (_, Ok(uid)) => (uid, uid, None),
+        // Images without an /etc/passwd still get to run as root
+        (_, Err(_)) if user == "root" => (0, 0, Some(String::from("/root"))),
+        (_, Err(_)) => bail!("No user named {} in the image's /etc/passwd", user),
+    };
+
+    let gid = match group {
+        None => primary_gid,
+        Some(group) => resolve_group(group, rootfs)?,
+    };
+    Ok((uid, gid, home))
+}
+
+fn resolve_group(group: &str, rootfs: &Path) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    // group lines look like name:password:gid:members
+    let groups = read_database(&rootfs.join("etc/group"))?;
+    let fields = groups
+        .iter()
+        .find(|fields| fields[0] == group && fields.len() >= 3)
+        .with_context(|| format!("No group named {} in the image's /etc/group", group))?;
+    fields[2]
+        .parse()
+        .with_context(|| format!("Invalid gid for {} in /etc/group", group))
+}
+
+/// Reads a colon separated database like `/etc/passwd`, a missing file has no entries
+fn read_database(path: &Path) -> Result<Vec<Vec<String>>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Tried to read {}", path.display())),
+    };
+    Ok(contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(String::from).collect())
+        .collect())
+}
@@ -0,0 +1,329 @@
+use anyhow::{bail, Result};
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::table::{format_size, short_digest};
+
+/// How often progress bars are redrawn while bytes trickle in, phase changes always redraw
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How wide the `[=====>    ]` part of a progress bar is
+const BAR_WIDTH: usize = 30;
+
+/// How pull progress is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Nothing at all
+    Quiet,
+    /// A line per layer that's redrawn in place, for terminals
+    Bars,
+    /// A line whenever a layer changes phase, for logs that can't be redrawn
+    Lines,
+    /// Newline delimited JSON events shaped like the docker API's pull progress, for tools
+    ///
+    /// See: https://docs.docker.com/reference/api/engine/version/v1.47/#tag/Image/operation/ImageCreate
+    Json,
+}
+
+impl ProgressMode {
+    /// Parses `--progress`, where `auto` picks bars or lines depending on stderr
+    pub fn parse(mode: &str) -> Result<ProgressMode> {
+        match mode {
+            "auto" => Ok(ProgressMode::detect()),
+            "tty" => Ok(ProgressMode::Bars),
+            "plain" => Ok(ProgressMode::Lines),
+            "json" => Ok(ProgressMode::Json),
+            "quiet" => Ok(ProgressMode::Quiet),
+            other => bail!(
+                "Unknown progress mode '{}', expected auto, tty, plain, json, or quiet",
+                other
+            ),
+        }
+    }
+
+    /// Bars if stderr is a terminal and lines otherwise
+    pub fn detect() -> ProgressMode {
+        match unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 {
+            true => ProgressMode::Bars,
+            false => ProgressMode::Lines,
+        }
+    }
+}
+
+/// Where a layer is at, named like `docker pull` names them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Waiting,
+    Downloading,
+    /// Downloaded and verified, which is as far as `pull` takes a layer
+    Downloaded,
+    Extracting,
+    Done,
+    /// The layer was already in the store
+    Exists,
+}
+
+impl Phase {
+    fn describe(&self) -> &'static str {
+        match self {
+            Phase::Waiting => "Waiting",
+            Phase::Downloading => "Downloading",
+            Phase::Downloaded => "Download complete",
+            Phase::Extracting => "Extracting",
+            Phase::Done => "Pull complete",
+            Phase::Exists => "Already exists",
+        }
+    }
+}
+
+/// Reports what each layer of a pull is doing on stderr, where it can't get mixed up with
+/// anything a container prints
+///
+/// Downloads happen on several threads at once, which all report through the same `Progress`.
+/// Only layers added with `add_layer` are shown, updates about other blobs (like the config) are
+/// ignored.
+#[derive(Debug)]
+pub struct Progress {
+    mode: ProgressMode,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    layers: Vec<Layer>,
+    started: Instant,
+    drawn: Option<Instant>,
+    /// How many lines the last redraw left on screen, which the next one draws over
+    drawn_lines: usize,
+}
+
+#[derive(Debug)]
+struct Layer {
+    digest: String,
+    size: u64,
+    downloaded: u64,
+    phase: Phase,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode) -> Progress {
+        Progress {
+            mode,
+            state: Mutex::new(State {
+                layers: Vec::new(),
+                started: Instant::now(),
+                drawn: None,
+                drawn_lines: 0,
+            }),
+        }
+    }
+
+    pub fn mode(&self) -> ProgressMode {
+        self.mode
+    }
+
+    pub fn add_layer(&self, digest: &str, size: u64, phase: Phase) {
+        self.update(digest, |state| {
+            if !state.layers.iter().any(|l| l.digest == digest) {
+                state.layers.push(Layer {
+                    digest: digest.to_string(),
+                    size,
+                    downloaded: 0,
+                    phase,
+                });
+            }
+            true
+        });
+    }
+
+    pub fn set_phase(&self, digest: &str, phase: Phase) {
+        self.update(digest, |state| match state.layer(digest) {
+            Some(layer) if layer.phase != phase => {
+                layer.phase = phase;
+                true
+            }
+            _ => false,
+        });
+    }
+
+    /// Counts `bytes` more of a layer as downloaded
+    pub fn advance(&self, digest: &str, bytes: u64) {
+        self.update(digest, |state| {
+            if let Some(layer) = state.layer(digest) {
+                layer.downloaded = (layer.downloaded + bytes).min(layer.size);
+                layer.phase = Phase::Downloading;
+            }
+            false
+        });
+    }
+
+    /// Forgets what was downloaded of a layer, for when it has to be downloaded again
+    pub fn reset(&self, digest: &str) {
+        self.update(digest, |state| {
+            if let Some(layer) = state.layer(digest) {
+                layer.downloaded = 0;
+            }
+            false
+        });
+    }
+
+    /// Reports something about the pull as a whole, only as a JSON event since the other modes
+    /// leave that to whatever the command prints when it's done
+    pub fn status(&self, message: &str) {
+        if self.mode == ProgressMode::Json {
+            eprintln!("{}", serde_json::json!({"status": message}));
+        }
+    }
+
+    /// Wraps a reader so everything read from it counts towards a layer's download
+    pub fn reader<'a, R: Read>(&'a self, digest: &'a str, inner: R) -> ProgressReader<'a, R> {
+        ProgressReader {
+            inner,
+            progress: self,
+            digest,
+        }
+    }
+
+    /// Applies `change` and redraws, right away if it returns true and eventually otherwise
+    fn update(&self, digest: &str, change: impl FnOnce(&mut State) -> bool) {
+        if self.mode == ProgressMode::Quiet {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let phase_changed = change(&mut state);
+        let due = state
+            .drawn
+            .map_or(true, |drawn| drawn.elapsed() >= REDRAW_INTERVAL);
+        match self.mode {
+            ProgressMode::Bars if phase_changed || due => state.draw_bars(),
+            ProgressMode::Json if phase_changed || due => {
+                if let Some(layer) = state.layers.iter().find(|l| l.digest == digest) {
+                    eprintln!("{}", layer.event());
+                }
+                state.drawn = Some(Instant::now());
+            }
+            ProgressMode::Lines if phase_changed => {
+                if let Some(layer) = state.layers.iter().find(|l| l.digest == digest) {
+                    eprintln!(
+                        "{}: {}",
+                        short_digest(&layer.digest),
+                        layer.phase.describe()
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl State {
+    fn layer(&mut self, digest: &str) -> Option<&mut Layer> {
+        self.layers.iter_mut().find(|l| l.digest == digest)
+    }
+
+    fn draw_bars(&mut self) {
+        let mut lines: Vec<String> = self.layers.iter().map(Layer::bar).collect();
+        lines.push(self.summary());
+
+        let mut stderr = io::stderr().lock();
+        if self.drawn_lines > 0 {
+            let _ = write!(stderr, "\x1b[{}A", self.drawn_lines);
+        }
+        for line in &lines {
+            let _ = writeln!(stderr, "\r\x1b[2K{}", line);
+        }
+        let _ = stderr.flush();
+        self.drawn_lines = lines.len();
+        self.drawn = Some(Instant::now());
+    }
+
+    /// Total bytes downloaded and, once there's a rate to go by, how long the rest should take
+    fn summary(&self) -> String {
+        let downloading = self.layers.iter().filter(|l| l.phase != Phase::Exists);
+        let total: u64 = downloading.clone().map(|l| l.size).sum();
+        let downloaded: u64 = downloading
+            .map(|l| match l.phase {
+                Phase::Waiting | Phase::Downloading => l.downloaded,
+                _ => l.size,
+            })
+            .sum();
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let eta = match (downloaded < total, downloaded > 0 && elapsed > 0.0) {
+            (true, true) => {
+                let rate = downloaded as f64 / elapsed;
+                format!(
+                    ", ETA {}s",
+                    ((total - downloaded) as f64 / rate).ceil() as u64
+                )
+            }
+            _ => String::new(),
+        };
+        format!(
+            "Total: {}/{}{}",
+            format_size(downloaded),
+            format_size(total),
+            eta
+        )
+    }
+}
+
+impl Layer {
+    /// The layer's state as one of the docker API's progress messages
+    fn event(&self) -> serde_json::Value {
+        let mut event = serde_json::json!({
+            "status": self.phase.describe(),
+            "id": short_digest(&self.digest),
+            "digest": self.digest,
+        });
+        if self.phase == Phase::Downloading {
+            event["progressDetail"] =
+                serde_json::json!({"current": self.downloaded, "total": self.size});
+        }
+        event
+    }
+
+    fn bar(&self) -> String {
+        let name = short_digest(&self.digest);
+        if self.phase != Phase::Downloading {
+            return format!("{}: {}", name, self.phase.describe());
+        }
+        let filled = match self.size {
+            0 => BAR_WIDTH,
+            size => (self.downloaded as f64 / size as f64 * BAR_WIDTH as f64) as usize,
+        };
+        let bar = match filled {
+            0 => " ".repeat(BAR_WIDTH),
+            filled if filled >= BAR_WIDTH => "=".repeat(BAR_WIDTH),
+            filled => format!(
+                "{}>{}",
+                "=".repeat(filled - 1),
+                " ".repeat(BAR_WIDTH - filled)
+            ),
+        };
+        format!(
+            "{}: {} [{}] {}/{}",
+            name,
+            self.phase.describe(),
+            bar,
+            format_size(self.downloaded),
+            format_size(self.size)
+        )
+    }
+}
+
+/// Counts bytes read towards a layer's download
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+    digest: &'a str,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.advance(self.digest, read as u64);
+        Ok(read)
+    }
+}
@@ -0,0 +1,307 @@
+use anyhow::{bail, Context, Result};
+use reqwest::blocking::{Body, Client, Response};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE};
+use reqwest::{StatusCode, Url};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::auth::RepositoryAuth;
+use crate::cli::PushOptions;
+use crate::errors::RegistryError;
+use crate::manifest::{Descriptor, OCI_MANIFEST_V1};
+use crate::reference::ImageReference;
+use crate::registry::DOCKER_CONTENT_DIGEST;
+use crate::retry::{with_retries, RetryableStatus};
+use crate::store::{Store, StoredImage};
+
+/// Finds other repositories on the same registry that we know have the image's blobs, so they
+/// can be mounted instead of uploaded again
+///
+/// Returns the repository to mount each blob from, keyed by digest.
+pub fn mount_sources(
+    image: &ImageReference,
+    store: &Store,
+    stored: &StoredImage,
+) -> Result<HashMap<String, String>> {
+    let manifest = store.image_manifest(stored)?;
+    let mut sources = HashMap::new();
+    for (repository, other) in store.registry_images(&image.registry)? {
+        if repository == image.repository {
+            continue;
+        }
+        let other_manifest = match store.image_manifest(&other) {
+            Ok(other_manifest) => other_manifest,
+            Err(_) => continue,
+        };
+        for blob in [&other_manifest.config]
+            .into_iter()
+            .chain(&other_manifest.layers)
+        {
+            let needed = blob.digest == manifest.config.digest
+                || manifest.layers.iter().any(|l| l.digest == blob.digest);
+            if needed {
+                sources
+                    .entry(blob.digest.clone())
+                    .or_insert_with(|| repository.clone());
+            }
+        }
+    }
+    Ok(sources)
+}
+
+/// Pushes a stored image's config, layers, and manifest to the image's repository
+///
+/// Blobs the registry already has are skipped, blobs in `mount_sources` are mounted from another
+/// repository if the registry lets us, and foreign layers are never uploaded since registries
+/// aren't allowed to distribute them. Returns the pushed manifest's digest.
+///
+/// See: https://distribution.github.io/distribution/spec/api/#pushing-an-image
+pub fn push_image(
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    store: &Store,
+    stored: &StoredImage,
+    mount_sources: &HashMap<String, String>,
+    options: &PushOptions,
+) -> Result<String> {
+    let manifest = store.image_manifest(stored)?;
+    let client = auth.endpoint().client()?;
+
+    for blob in [&manifest.config].into_iter().chain(&manifest.layers) {
+        let short_digest = &blob.digest["sha256:".len()..][..12];
+        if blob.is_foreign() {
+            println!("{}: Skipped foreign layer", short_digest);
+        } else if blob_exists(client, image, auth, &blob.digest)? {
+            println!("{}: Layer already exists", short_digest);
+        } else {
+            let mount_from = mount_sources.get(&blob.digest).map(String::as_str);
+            let mounted = upload_blob(client, image, auth, store, blob, mount_from, options)
+                .with_context(|| format!("Tried to push blob {}", blob.digest))?;
+            match (mounted, mount_from) {
+                (true, Some(repository)) => {
+                    println!("{}: Mounted from {}", short_digest, repository)
+                }
+                _ => println!("{}: Pushed", short_digest),
+            }
+        }
+    }
+
+    let raw_manifest = store.read_blob(&stored.manifest)?;
+    let media_type = manifest.media_type.as_deref().unwrap_or(OCI_MANIFEST_V1);
+    let response = auth
+        .send("Pushing manifest", || {
+            client
+                .put(auth.endpoint().url(&format!(
+                    "/v2/{}/manifests/{}",
+                    image.repository,
+                    image.manifest_reference()
+                )))
+                .header(CONTENT_TYPE, media_type)
+                .body(raw_manifest.clone())
+        })
+        .context("Tried to push manifest")?;
+    let response = expect_status(response, StatusCode::CREATED, "pushing the manifest")?;
+
+    let digest = response
+        .headers()
+        .get(DOCKER_CONTENT_DIGEST)
+        .and_then(|d| d.to_str().ok())
+        .unwrap_or(&stored.manifest);
+    if digest != stored.manifest {
+        bail!(
+            "Registry stored the manifest as {} but we pushed {}",
+            digest,
+            stored.manifest
+        );
+    }
+    Ok(stored.manifest.clone())
+}
+
+/// Checks whether the repository already has a blob
+///
+/// See: https://distribution.github.io/distribution/spec/api/#existing-layers
+fn blob_exists(
+    client: &Client,
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    digest: &str,
+) -> Result<bool> {
+    let response = auth.send("Checking for blob", || {
+        client.head(
+            auth.endpoint()
+                .url(&format!("/v2/{}/blobs/{}", image.repository, digest)),
+        )
+    })?;
+    match response.status() {
+        StatusCode::OK => Ok(true),
+        StatusCode::NOT_FOUND => Ok(false),
+        _ => Err(RegistryError::from_response(response))
+            .with_context(|| format!("Tried to check for blob {}", digest)),
+    }
+}
+
+/// Uploads a blob once the registry has given us an upload session
+///
+/// Blobs up to the chunk size go up in a single request, anything bigger is uploaded in chunks
+/// so a failure only costs us the chunk it happened in. If `mount_from` is given the registry is
+/// first asked to mount the blob from that repository, which it may refuse by starting a regular
+/// upload instead. Returns whether the blob was mounted.
+///
+/// See: https://distribution.github.io/distribution/spec/api/#monolithic-upload
+/// See: https://distribution.github.io/distribution/spec/api/#cross-repository-blob-mount
+fn upload_blob(
+    client: &Client,
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    store: &Store,
+    blob: &Descriptor,
+    mount_from: Option<&str>,
+    options: &PushOptions,
+) -> Result<bool> {
+    let mut start_url = Url::parse(
+        &auth
+            .endpoint()
+            .url(&format!("/v2/{}/blobs/uploads/", image.repository)),
+    )?;
+    if let Some(repository) = mount_from {
+        start_url
+            .query_pairs_mut()
+            .append_pair("mount", &blob.digest)
+            .append_pair("from", repository);
+    }
+    let response = auth.send("Starting blob upload", || {
+        client.post(start_url.clone()).header(CONTENT_LENGTH, 0)
+    })?;
+    if mount_from.is_some() && response.status() == StatusCode::CREATED {
+        return Ok(true);
+    }
+    let response = expect_status(response, StatusCode::ACCEPTED, "starting the upload")?;
+
+    let mut upload_url = upload_location(auth, &response)?;
+    // Opened once, a recompressed blob is rebuilt every time it is
+    let blob_file = store.open_blob(&blob.digest)?;
+    if blob.size <= options.chunk_size {
+        upload_url
+            .query_pairs_mut()
+            .append_pair("digest", &blob.digest);
+        let response = auth.try_send("Uploading blob", || {
+            let mut blob_file = blob_file.try_clone()?;
+            blob_file.seek(SeekFrom::Start(0))?;
+            Ok(client
+                .put(upload_url.clone())
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .body(Body::sized(blob_file, blob.size)))
+        })?;
+        expect_status(response, StatusCode::CREATED, "uploading the blob")?;
+        return Ok(false);
+    }
+
+    let mut offset = 0;
+    while offset < blob.size {
+        let chunk_end = (offset + options.chunk_size).min(blob.size);
+        let mut resuming = false;
+        with_retries(
+            &options.retry,
+            &format!("Uploading chunk of {}", blob.digest),
+            || {
+                // After a failure the registry may have kept none, some, or all of the chunk
+                if resuming {
+                    (upload_url, offset) = upload_status(client, auth, &upload_url)?;
+                }
+                resuming = true;
+                if offset < chunk_end {
+                    upload_url =
+                        upload_chunk(client, auth, &blob_file, &upload_url, offset, chunk_end)?;
+                }
+                offset = chunk_end;
+                Ok(())
+            },
+        )?;
+    }
+
+    upload_url
+        .query_pairs_mut()
+        .append_pair("digest", &blob.digest);
+    let response = auth.send("Finishing blob upload", || {
+        client.put(upload_url.clone()).header(CONTENT_LENGTH, 0)
+    })?;
+    expect_status(response, StatusCode::CREATED, "finishing the upload")?;
+    Ok(false)
+}
+
+/// Sends the `[start, end)` range of a blob, returning where the next chunk should go
+///
+/// See: https://distribution.github.io/distribution/spec/api/#chunked-upload
+fn upload_chunk(
+    client: &Client,
+    auth: &RepositoryAuth,
+    blob: &File,
+    upload_url: &Url,
+    start: u64,
+    end: u64,
+) -> Result<Url> {
+    let mut blob_file = blob.try_clone()?;
+    blob_file.seek(SeekFrom::Start(start))?;
+    let request = client
+        .patch(upload_url.clone())
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CONTENT_RANGE, format!("{}-{}", start, end - 1))
+        .header(CONTENT_LENGTH, end - start)
+        .body(Body::sized(blob_file.take(end - start), end - start));
+    let response = auth.authorize(request)?.send()?;
+    auth.check_unauthorized(&response)?;
+
+    match response.status() {
+        StatusCode::ACCEPTED => upload_location(auth, &response),
+        // The registry disagrees about how much it has, which the status request sorts out
+        s if s.is_server_error()
+            || s == StatusCode::TOO_MANY_REQUESTS
+            || s == StatusCode::RANGE_NOT_SATISFIABLE =>
+        {
+            Err(RetryableStatus::from_response(&response).into())
+        }
+        _ => Err(RegistryError::from_response(response)).context("Tried uploading a chunk"),
+    }
+}
+
+/// Asks the registry how much of an upload it has, returning the upload's URL and where to
+/// continue from
+///
+/// See: https://distribution.github.io/distribution/spec/api/#upload-progress
+fn upload_status(client: &Client, auth: &RepositoryAuth, upload_url: &Url) -> Result<(Url, u64)> {
+    let response = auth.send("Checking upload progress", || {
+        client.get(upload_url.clone())
+    })?;
+    let response = expect_status(response, StatusCode::NO_CONTENT, "checking upload progress")?;
+
+    // The Range header is inclusive, `0-0` is what registries send when they have nothing
+    let received = response
+        .headers()
+        .get(RANGE)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| r.split_once('-'))
+        .and_then(|(_, end)| end.parse::<u64>().ok())
+        .map_or(0, |end| if end == 0 { 0 } else { end + 1 });
+    Ok((upload_location(auth, &response)?, received))
+}
+
+/// Where the registry wants the rest of an upload sent, which may be relative to the registry
+fn upload_location(auth: &RepositoryAuth, response: &Response) -> Result<Url> {
+    let location = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .context("Registry didn't say where to upload the blob to")?;
+    Url::parse(&auth.endpoint().url("/"))?
+        .join(location)
+        .with_context(|| format!("Invalid upload location {}", location))
+}
+
+fn expect_status(response: Response, expected: StatusCode, action: &str) -> Result<Response> {
+    if response.status() != expected {
+        return Err(RegistryError::from_response(response))
+            .with_context(|| format!("Tried {}", action));
+    }
+    Ok(response)
+}
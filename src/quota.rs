@@ -0,0 +1,248 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+
+/// Where the project ID of a container's quota is kept, so it can be lifted once the container
+/// is removed
+static PROJECT_RECORD: &str = "quota";
+
+/// From `asm/unistd.h`, Linux 5.14's, which the libc crate doesn't have
+const SYS_QUOTACTL_FD: libc::c_long = 443;
+
+/// From `linux/quota.h` and `linux/dqblk_xfs.h`, `QCMD(Q_XSETQLIM, PRJQUOTA)`, which ext4 takes
+/// as well as XFS
+const Q_XSETQLIM_PROJECT: libc::c_int = (0x5804 << 8) | 2;
+const FS_DQUOT_VERSION: i8 = 1;
+const FS_PROJ_QUOTA: i8 = 2;
+const FS_DQ_BSOFT: u16 = 1 << 2;
+const FS_DQ_BHARD: u16 = 1 << 3;
+
+/// From `linux/fs.h`
+const FS_IOC_FSGETXATTR: libc::c_ulong = 0x801c_581f;
+const FS_IOC_FSSETXATTR: libc::c_ulong = 0x401c_5820;
+const FS_XFLAG_PROJINHERIT: u32 = 0x0000_0200;
+
+/// From `linux/loop.h`
+const LOOP_SET_FD: libc::c_ulong = 0x4c00;
+const LOOP_CLR_FD: libc::c_ulong = 0x4c01;
+const LOOP_SET_STATUS64: libc::c_ulong = 0x4c04;
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4c82;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+/// `struct fs_disk_quota`, with only the block limits this sets named
+#[repr(C)]
+struct DiskQuota {
+    version: i8,
+    flags: i8,
+    field_mask: u16,
+    id: u32,
+    /// In 512 byte blocks
+    block_hard_limit: u64,
+    block_soft_limit: u64,
+    rest: [u64; 11],
+}
+
+/// `struct fsxattr`
+#[repr(C)]
+struct FsXattr {
+    xflags: u32,
+    extsize: u32,
+    nextents: u32,
+    projid: u32,
+    cowextsize: u32,
+    pad: [u8; 8],
+}
+
+/// `struct loop_info64`, with only the flags this sets named
+#[repr(C)]
+struct LoopInfo {
+    rest: [u64; 5],
+    number: u32,
+    encrypt_type: u32,
+    encrypt_key_size: u32,
+    flags: u32,
+    names_and_key: [u8; 160],
+    init: [u64; 2],
+}
+
+/// Keeps what's written to a container's directory (its rootfs, or overlay's upper directory)
+/// under `size` bytes, call right after creating it, before anything is put in it
+///
+/// Where the store is on XFS or ext4 with project quotas enabled (`prjquota`), the directory
+/// gets a project of its own to count with, the way docker's overlay2 driver does it. Anywhere
+/// else it's the mount point of an ext4 filesystem of that size, made with `mkfs.ext4` in
+/// `disk` and loop mounted, which stays mounted until the container is removed.
+///
+/// See: https://docs.docker.com/reference/cli/docker/container/run/#storage-opt
+pub fn limit_storage(container: &Path, disk: &Path, size: u64) -> Result<()> {
+    let project = project_id(container);
+    if set_project_quota(container, project, size).is_ok() {
+        return fs::write(container.join(PROJECT_RECORD), project.to_string())
+            .context("Tried to record the container's quota");
+    }
+    mount_disk(container, disk, size)
+}
+
+/// Undoes `limit_storage`, if the container was given a limit, call before removing its
+/// directory
+pub fn remove_storage_limit(container: &Path, disk: &Path) -> Result<()> {
+    if let Ok(project) = fs::read_to_string(container.join(PROJECT_RECORD)) {
+        // Best effort, a stale limit on an unused project doesn't get in anyone's way
+        if let Ok(project) = project.trim().parse() {
+            let _ = set_quota(container, project, 0);
+        }
+    }
+    if !disk.exists() {
+        return Ok(());
+    }
+    let path = CString::new(container.as_os_str().as_bytes())?;
+    if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } != 0 {
+        match io::Error::last_os_error() {
+            // The host was rebooted since, or the container never got that far
+            e if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOENT)) => {}
+            e => {
+                return Err(e).with_context(|| format!("Tried to unmount {}", container.display()))
+            }
+        }
+    }
+    fs::remove_file(disk).with_context(|| format!("Tried to remove {}", disk.display()))
+}
+
+/// The container's project, which is taken from its ID, any two (still around) having the same
+/// one is as unlikely as their IDs starting the same
+fn project_id(container: &Path) -> u32 {
+    let id = container.file_name().unwrap().to_string_lossy();
+    let start: String = id.chars().take(8).collect();
+    u32::from_str_radix(&start, 16).unwrap_or_default().max(1)
+}
+
+fn set_project_quota(directory: &Path, project: u32, size: u64) -> Result<()> {
+    // Fails where quotas aren't enabled, before anything was changed
+    set_quota(directory, project, size)?;
+    let file =
+        File::open(directory).with_context(|| format!("Tried to open {}", directory.display()))?;
+    let mut attributes: FsXattr = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR, &mut attributes) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Tried to read the attributes of {}", directory.display()));
+    }
+    // Everything made in it is in the project too, and everything made in that
+    attributes.projid = project;
+    attributes.xflags |= FS_XFLAG_PROJINHERIT;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSSETXATTR, &attributes) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Tried to set the project of {}", directory.display()));
+    }
+    Ok(())
+}
+
+/// Limits how much the project (on the filesystem `path` is on) may take up, 0 for no limit
+fn set_quota(path: &Path, project: u32, size: u64) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Tried to open {}", path.display()))?;
+    let mut quota: DiskQuota = unsafe { std::mem::zeroed() };
+    quota.version = FS_DQUOT_VERSION;
+    quota.flags = FS_PROJ_QUOTA;
+    quota.field_mask = FS_DQ_BSOFT | FS_DQ_BHARD;
+    quota.id = project;
+    quota.block_hard_limit = size.div_ceil(512);
+    quota.block_soft_limit = quota.block_hard_limit;
+    let result = unsafe {
+        libc::syscall(
+            SYS_QUOTACTL_FD,
+            file.as_raw_fd(),
+            Q_XSETQLIM_PROJECT,
+            project,
+            &quota as *const DiskQuota,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Tried to set the quota of project {}", project));
+    }
+    Ok(())
+}
+
+/// Makes an ext4 filesystem of `size` bytes in `disk` (which takes up no more space than is
+/// written to it) and mounts it on `target`
+fn mount_disk(target: &Path, disk: &Path, size: u64) -> Result<()> {
+    let file = File::create(disk).with_context(|| format!("Tried to create {}", disk.display()))?;
+    file.set_len(size)
+        .with_context(|| format!("Tried to resize {}", disk.display()))?;
+    // No blocks reserved for root, the container's root is who'd use them
+    let output = Command::new("mkfs.ext4")
+        .args(["-q", "-F", "-m", "0"])
+        .arg(disk)
+        .output()
+        .context("Tried to run mkfs.ext4, is e2fsprogs installed?")?;
+    if !output.status.success() {
+        bail!(
+            "mkfs.ext4 failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    // Kept open until it's mounted, or it would detach itself then and there
+    let (device, _device_file) = attach_loop_device(&file)?;
+    let source = CString::new(device.as_bytes())?;
+    let c_target = CString::new(target.as_os_str().as_bytes())?;
+    let mounted = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            c_target.as_ptr(),
+            c"ext4".as_ptr(),
+            0,
+            std::ptr::null(),
+        )
+    };
+    if mounted != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Tried to mount {} on {}", device, target.display()));
+    }
+    Ok(())
+}
+
+/// Attaches a free loop device to `file`, returning its path and the device opened, which
+/// detaches itself once it's unmounted and closed
+///
+/// See: https://man7.org/linux/man-pages/man4/loop.4.html
+fn attach_loop_device(file: &File) -> Result<(String, File)> {
+    let control = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/loop-control")
+        .context("Tried to open /dev/loop-control")?;
+    // Another process can take the free one before we do
+    for _ in 0..10 {
+        let number = unsafe { libc::ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE) };
+        if number < 0 {
+            return Err(io::Error::last_os_error()).context("Tried to find a free loop device");
+        }
+        let path = format!("/dev/loop{}", number);
+        let device = File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Tried to open {}", path))?;
+        if unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_FD, file.as_raw_fd()) } != 0 {
+            match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(libc::EBUSY) => continue,
+                e => return Err(e).with_context(|| format!("Tried to set up {}", path)),
+            }
+        }
+        let mut info: LoopInfo = unsafe { std::mem::zeroed() };
+        info.flags = LO_FLAGS_AUTOCLEAR;
+        if unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_STATUS64, &info) } != 0 {
+            let error = io::Error::last_os_error();
+            unsafe {
+                libc::ioctl(device.as_raw_fd(), LOOP_CLR_FD);
+            }
+            return Err(error).with_context(|| format!("Tried to set up {}", path));
+        }
+        return Ok((path, device));
+    }
+    bail!("Couldn't find a free loop device")
+}
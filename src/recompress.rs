@@ -0,0 +1,159 @@
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use tempfile::NamedTempFile;
+
+use crate::compression::Compression;
+use crate::digest::{check_digest, HashingReader};
+use crate::manifest::Descriptor;
+use crate::store::{RecompressedBlob, Store, StoredImage};
+
+/// High enough to beat gzip by a fair margin, without `--ultra`'s memory use when decompressing
+pub const DEFAULT_ZSTD_LEVEL: usize = 19;
+
+/// A layer blob that was swapped for a smaller zstd one
+#[derive(Debug)]
+pub struct RecompressedLayer {
+    pub digest: String,
+    pub before: u64,
+    pub after: u64,
+}
+
+/// Rewrites the layer blobs of stored images with zstd where that makes them smaller
+///
+/// Blobs keep their original digest, which is what manifests refer to them by, and are
+/// decompressed as zstd from then on. Every layer is checked against its diff ID as it's
+/// recompressed, so a blob is only ever replaced by one with the same tar in it. Layers that
+/// already are zstd, or that come out no smaller, are left as they are.
+///
+/// Pushing an image or saving it into an OCI layout takes the bytes its manifest has the
+/// digests of, so only gzip layers flate2 makes again byte for byte (like the ones `import`
+/// makes) are recompressed, and rebuilt whenever those are needed. Most registries' layers
+/// weren't gzipped by flate2 and are left as they are.
+pub fn recompress_layers(
+    store: &Store,
+    images: &[StoredImage],
+    level: usize,
+) -> Result<Vec<RecompressedLayer>> {
+    let _blobs = store.share_blobs()?;
+    let mut seen = HashSet::new();
+    let mut recompressed = Vec::new();
+    for stored in images {
+        let manifest = store.image_manifest(stored)?;
+        let config = store.image_config(&manifest)?;
+        for (layer, diff_id) in manifest.layers.iter().zip(&config.rootfs.diff_ids) {
+            if !seen.insert(layer.digest.clone())
+                || Compression::from_media_type(&layer.media_type)? == Compression::Zstd
+                || !store.has_blob(&layer.digest)?
+                || store.recompressed(&layer.digest)?.is_some()
+            {
+                continue;
+            }
+            let layer = recompress_layer(store, layer, diff_id, level)
+                .with_context(|| format!("Tried to recompress layer {}", layer.digest))?;
+            recompressed.extend(layer);
+        }
+    }
+    Ok(recompressed)
+}
+
+fn recompress_layer(
+    store: &Store,
+    layer: &Descriptor,
+    diff_id: &str,
+    level: usize,
+) -> Result<Option<RecompressedLayer>> {
+    let gzip_level = match rebuild_level(store, layer)? {
+        Some(level) => level,
+        None => return Ok(None),
+    };
+    let before = fs::metadata(store.blob_path(&layer.digest)?)?.len();
+    let downloads = store.root().join("downloads");
+    let output = NamedTempFile::new_in(&downloads)
+        .with_context(|| format!("Tried to create a file in {}", downloads.display()))?;
+
+    let mut child = Command::new("zstd")
+        .args(["--quiet", "--stdout", "-T0", &format!("-{}", level)])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(output.reopen()?))
+        .spawn()
+        .context("Tried to run zstd to recompress a layer, is it installed?")?;
+    let mut decoder = HashingReader::new(store.open_layer(layer)?);
+    let mut stdin = child.stdin.take().unwrap();
+    let copied = io::copy(&mut decoder, &mut stdin);
+    drop(stdin);
+    let status = child.wait().context("Tried to wait for zstd")?;
+    copied.context("Tried to decompress the layer into zstd")?;
+    if !status.success() {
+        bail!("zstd failed to compress the layer ({})", status);
+    }
+    let (decoder, actual_diff_id) = decoder.into_inner();
+    decoder.finish()?;
+    check_digest(&actual_diff_id, diff_id).context("Layer doesn't match its diff ID")?;
+
+    let mut reader = HashingReader::new(File::open(output.path())?);
+    let after = io::copy(&mut reader, &mut io::sink())?;
+    if after >= before {
+        return Ok(None);
+    }
+    let record = RecompressedBlob {
+        zstd_digest: reader.finalize(),
+        size: before,
+        gzip_level: Some(gzip_level),
+    };
+    let output = output.into_temp_path();
+    store.insert_recompressed(&layer.digest, &output, &record)?;
+    // It's been moved into the store, there's nothing left to clean up
+    let _ = output.keep();
+    Ok(Some(RecompressedLayer {
+        digest: layer.digest.clone(),
+        before,
+        after,
+    }))
+}
+
+/// The flate2 level that gzips a layer's tar into its blob as it is, if there's one
+///
+/// Trying a level stops at the first byte that comes out different, which for the wrong ones is
+/// rarely far into the blob.
+fn rebuild_level(store: &Store, layer: &Descriptor) -> Result<Option<u32>> {
+    if Compression::from_media_type(&layer.media_type)? != Compression::Gzip {
+        return Ok(None);
+    }
+    for level in 0..=9 {
+        let original = Matching(BufReader::new(store.open_blob(&layer.digest)?));
+        let mut encoder = GzEncoder::new(original, flate2::Compression::new(level));
+        let mut decoder = store.open_layer(layer)?;
+        let rebuilt = io::copy(&mut decoder, &mut encoder).and_then(|_| encoder.finish());
+        if let Ok(Matching(mut original)) = rebuilt {
+            if original.fill_buf()?.is_empty() {
+                return Ok(Some(level));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Fails writes of anything else than what's next in the original
+struct Matching(BufReader<File>);
+
+impl Write for Matching {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut original = vec![0; buf.len()];
+        self.0.read_exact(&mut original)?;
+        if original != buf {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "doesn't match the original",
+            ));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{bail, Context, Result};
+use std::fmt;
+
+use crate::digest::validate_digest;
+
+/// Registry used when an image reference doesn't name one explicitly
+pub static DOCKER_HUB: &str = "registry.hub.docker.com";
+
+static DEFAULT_TAG: &str = "latest";
+
+/// A parsed image reference such as `alpine`, `alejandro/myapp:v2`, or
+/// `ghcr.io/org/tool@sha256:...`
+///
+/// References without a registry are resolved against Docker Hub and single component
+/// repositories are placed under the `library/` namespace, matching the docker CLI.
+///
+/// See: https://github.com/distribution/reference/blob/main/reference.go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageReference {
+    /// Parses a reference of the form `[registry/]repository[:tag][@digest]`
+    pub fn parse(reference: &str) -> Result<ImageReference> {
+        if reference.is_empty() {
+            bail!("Image reference cannot be empty");
+        }
+
+        let (remainder, digest) = match reference.split_once('@') {
+            Some((remainder, digest)) => {
+                validate_digest(digest)
+                    .with_context(|| format!("Invalid image reference '{}'", reference))?;
+                (remainder, Some(digest.to_string()))
+            }
+            None => (reference, None),
+        };
+
+        // A colon only denotes a tag if it appears after the last slash, otherwise it's part
+        // of a registry host (e.g. localhost:5000/myapp)
+        let last_slash = remainder.rfind('/').map(|i| i + 1).unwrap_or(0);
+        let (name, tag) = match remainder[last_slash..].rfind(':') {
+            Some(i) => (
+                &remainder[..last_slash + i],
+                Some(remainder[last_slash + i + 1..].to_string()),
+            ),
+            None => (remainder, None),
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (normalize_registry(host), path.to_string())
+            }
+            _ => (String::from(DOCKER_HUB), name.to_string()),
+        };
+
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        if repository.is_empty() || tag.as_deref() == Some("") {
+            bail!("Invalid image reference '{}'", reference);
+        }
+        if repository
+            .chars()
+            .any(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || "._-/".contains(c)))
+        {
+            bail!(
+                "Invalid repository name '{}' in image reference '{}'",
+                repository,
+                reference
+            );
+        }
+
+        if let Some(tag) = &tag {
+            if !is_valid_tag(tag) {
+                bail!("Invalid tag '{}' in image reference '{}'", tag, reference);
+            }
+        }
+
+        Ok(ImageReference {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+
+    /// The tag this reference points to, defaulting to `latest`
+    pub fn tag(&self) -> &str {
+        self.tag.as_deref().unwrap_or(DEFAULT_TAG)
+    }
+
+    /// What to ask the manifests endpoint for, digests take precedence over tags
+    pub fn manifest_reference(&self) -> &str {
+        self.digest.as_deref().unwrap_or_else(|| self.tag())
+    }
+}
+
+/// Whether a tag matches `[A-Za-z0-9_][A-Za-z0-9_.-]{0,127}`, which also keeps it from adding a
+/// query or fragment to the manifest URLs it goes into
+///
+/// See: https://github.com/opencontainers/distribution-spec/blob/main/spec.md#pulling-manifests
+fn is_valid_tag(tag: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    match tag.chars().next() {
+        Some(first) if allowed(first) => {}
+        _ => return false,
+    }
+    tag.len() <= 128 && tag.chars().all(|c| allowed(c) || c == '.' || c == '-')
+}
+
+impl fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        match (&self.tag, &self.digest) {
+            (tag, Some(digest)) => {
+                if let Some(tag) = tag {
+                    write!(f, ":{}", tag)?;
+                }
+                write!(f, "@{}", digest)
+            }
+            (_, None) => write!(f, ":{}", self.tag()),
+        }
+    }
+}
+
+/// How people usually write a repository, leaving out Docker Hub and its `library/` namespace
+pub fn familiar_name(registry: &str, repository: &str) -> String {
+    match registry == DOCKER_HUB {
+        true => repository
+            .strip_prefix("library/")
+            .unwrap_or(repository)
+            .to_string(),
+        false => format!("{}/{}", registry, repository),
+    }
+}
+
+/// Maps Docker Hub's various aliases onto the host that actually serves the registry API
+pub fn normalize_registry(host: &str) -> String {
+    match host {
+        "docker.io" | "index.docker.io" => String::from(DOCKER_HUB),
+        _ => host.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_validated() {
+        for reference in [
+            "alpine:3.19",
+            "alpine:latest_1-rc.2",
+            "localhost:5000/app:_x",
+        ] {
+            assert!(ImageReference::parse(reference).is_ok(), "{}", reference);
+        }
+        let too_long = format!("alpine:{}", "a".repeat(129));
+        for reference in [
+            "alpine:latest?x=1",
+            "alpine:a#b",
+            "alpine:a/b@",
+            "alpine:.hidden",
+            "alpine:-x",
+            "alpine:",
+            "alpine:a%2F",
+            too_long.as_str(),
+        ] {
+            let error = ImageReference::parse(reference).unwrap_err().to_string();
+            assert!(error.starts_with("Invalid"), "{}: {}", reference, error);
+        }
+        assert!(ImageReference::parse(&format!("alpine:{}", "a".repeat(128))).is_ok());
+    }
+}
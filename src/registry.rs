@@ -0,0 +1,951 @@
+use anyhow::{bail, Context, Result};
+use reqwest::blocking::Response;
+use reqwest::header::{
+    ACCEPT, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LINK, LOCATION, RANGE,
+};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use crate::auth::RepositoryAuth;
+use crate::cli::PullOptions;
+use crate::compression::Compression;
+use crate::digest::{check_digest, sha256_digest, validate_digest, HashingReader};
+use crate::errors::{RegistryError, MANIFEST_UNKNOWN};
+use crate::image::{ContainerConfig, History, ImageConfig, RootFs};
+use crate::manifest::{
+    schema1_payload, Descriptor, ImageIndex, ImageManifest, Manifest, Schema1Manifest,
+    ACCEPTED_MANIFEST_TYPES, DOCKER_CONFIG_V1, DOCKER_LAYER_GZIP, DOCKER_MANIFEST_V1_SIGNED,
+    DOCKER_MANIFEST_V2,
+};
+use crate::platform::Platform;
+use crate::progress::Phase;
+use crate::reference::ImageReference;
+use crate::retry::{with_retries, RetryableStatus};
+use crate::store::{CachedManifest, Store};
+use crate::throttle::ThrottledReader;
+
+/// Header registries use to tell us the canonical digest of a manifest
+///
+/// See: https://distribution.github.io/distribution/spec/api/#digest-header
+pub static DOCKER_CONTENT_DIGEST: &str = "Docker-Content-Digest";
+
+/// An image manifest along with the exact bytes it was served as
+pub struct ResolvedManifest {
+    /// What the reference resolved to, which is the manifest list's digest for multi-arch images
+    pub digest: String,
+    /// The digest of the platform specific manifest
+    pub manifest_digest: String,
+    pub raw_manifest: Vec<u8>,
+    pub manifest: ImageManifest,
+    /// The manifest list the manifest was picked from, for multi-arch images
+    pub raw_index: Option<Vec<u8>>,
+}
+
+/// Retrieves an image's manifest
+///
+/// If the registry responds with a manifest list (or OCI index) the entry matching the requested
+/// platform is selected and its manifest is fetched instead. Schema 1 manifests are converted
+/// into schema 2 ones, which means downloading their layers.
+///
+/// See: https://distribution.github.io/distribution/spec/api/#pulling-an-image-manifest
+pub fn fetch_image_manifest(
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    store: &Store,
+    options: &PullOptions,
+) -> Result<ResolvedManifest> {
+    let platform = &options.platform;
+    let (manifest, digest, raw_data) =
+        fetch_manifest(image, image.manifest_reference(), auth, store)?;
+    match manifest {
+        Manifest::Image(manifest) => Ok(ResolvedManifest {
+            digest: digest.clone(),
+            manifest_digest: digest,
+            raw_manifest: raw_data,
+            manifest,
+            raw_index: None,
+        }),
+        Manifest::Index(index) => {
+            let manifest_digest =
+                select_platform_manifest(&index, platform).with_context(|| {
+                    format!("Tried to find a manifest for {} in {}", platform, image)
+                })?;
+            match fetch_manifest(image, &manifest_digest, auth, store)? {
+                (Manifest::Image(manifest), _, raw_manifest) => Ok(ResolvedManifest {
+                    digest,
+                    manifest_digest,
+                    raw_manifest,
+                    manifest,
+                    raw_index: Some(raw_data),
+                }),
+                (Manifest::Index(_), _, _) => {
+                    bail!("Manifest list entry {} is itself a list", manifest_digest)
+                }
+                (Manifest::Schema1(_), _, _) => {
+                    bail!(
+                        "Manifest list entry {} is a schema 1 manifest",
+                        manifest_digest
+                    )
+                }
+            }
+        }
+        Manifest::Schema1(manifest) => convert_schema1(image, auth, store, options, &manifest)
+            .map(|(manifest, raw_manifest)| ResolvedManifest {
+                digest,
+                manifest_digest: sha256_digest(&raw_manifest),
+                raw_manifest,
+                manifest,
+                raw_index: None,
+            })
+            .with_context(|| format!("Tried to convert schema 1 manifest for {}", image)),
+    }
+}
+
+/// The parts of a layer's v1 image JSON that end up in the converted config
+///
+/// See: https://github.com/moby/moby/blob/master/image/spec/v1.md
+#[derive(Debug, Deserialize)]
+struct V1Compatibility {
+    #[serde(default)]
+    created: Option<String>,
+    #[serde(default)]
+    os: Option<String>,
+    #[serde(default)]
+    architecture: Option<String>,
+    #[serde(default)]
+    config: Option<ContainerConfig>,
+    /// The config of the container the layer was built in, whose `Cmd` is what built it
+    #[serde(default)]
+    container_config: Option<ContainerConfig>,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    comment: Option<String>,
+    /// Layers docker recorded for history only, usually empty tars from metadata instructions
+    #[serde(default)]
+    throwaway: bool,
+}
+
+/// Converts a schema 1 manifest into a schema 2 manifest and config the way docker does
+///
+/// Schema 1 has no config blob, so one is put together from the v1 image JSON of the top layer.
+/// Its diff IDs can only be worked out from the layers themselves, so they're downloaded into
+/// the store first. Returns the converted manifest along with its serialized form.
+///
+/// See: https://github.com/moby/moby/blob/master/distribution/pull_v2.go
+fn convert_schema1(
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    store: &Store,
+    options: &PullOptions,
+    schema1: &Schema1Manifest,
+) -> Result<(ImageManifest, Vec<u8>)> {
+    if schema1.fs_layers.len() != schema1.history.len() {
+        bail!(
+            "Manifest has {} layers but {} history entries",
+            schema1.fs_layers.len(),
+            schema1.history.len()
+        );
+    }
+    let history = schema1
+        .history
+        .iter()
+        .map(|h| serde_json::from_str(&h.v1_compatibility))
+        .collect::<Result<Vec<V1Compatibility>, _>>()
+        .context("Tried to parse the manifest's v1 image JSON")?;
+    let top = history
+        .first()
+        .context("Manifest doesn't have any layers")?;
+
+    let os = top.os.clone().unwrap_or_else(|| String::from("linux"));
+    let architecture = top
+        .architecture
+        .clone()
+        .unwrap_or_else(|| schema1.architecture.clone());
+    if !options.platform.matches(&os, &architecture, None) {
+        bail!(
+            "Image is {}/{} but {} was requested",
+            os,
+            architecture,
+            options.platform
+        );
+    }
+
+    let client = auth.endpoint().client()?;
+    let mut layers = Vec::new();
+    for (fs_layer, entry) in schema1.fs_layers.iter().zip(&history).rev() {
+        if entry.throwaway {
+            continue;
+        }
+        validate_digest(&fs_layer.blob_sum)?;
+        let size = blob_size(client, image, auth, &fs_layer.blob_sum)?;
+        layers.push(Descriptor {
+            media_type: String::from(DOCKER_LAYER_GZIP),
+            digest: fs_layer.blob_sum.clone(),
+            size,
+            urls: Vec::new(),
+            platform: None,
+            annotations: BTreeMap::new(),
+        });
+    }
+
+    let mut missing: Vec<&Descriptor> = Vec::new();
+    for layer in &layers {
+        if !store.has_blob(&layer.digest)? && !missing.iter().any(|m| m.digest == layer.digest) {
+            missing.push(layer);
+        }
+    }
+    fetch_blobs(&missing, image, auth, store, options)?;
+
+    let diff_ids = layers
+        .iter()
+        .map(|layer| store.diff_id(layer))
+        .collect::<Result<Vec<_>>>()?;
+    let config = ImageConfig {
+        architecture,
+        os,
+        variant: None,
+        created: top.created.clone(),
+        config: top.config.clone().unwrap_or_default(),
+        rootfs: RootFs {
+            fs_type: String::from("layers"),
+            diff_ids,
+        },
+        // Schema 1 lists layers from the top down, history goes oldest first
+        history: history
+            .iter()
+            .rev()
+            .map(|entry| History {
+                created: entry.created.clone(),
+                created_by: entry
+                    .container_config
+                    .as_ref()
+                    .and_then(|config| config.cmd.as_ref())
+                    .map(|cmd| cmd.join(" ")),
+                author: entry.author.clone(),
+                comment: entry.comment.clone(),
+                empty_layer: entry.throwaway,
+            })
+            .collect(),
+    };
+    let raw_config = serde_json::to_vec(&config)?;
+    let config_digest = sha256_digest(&raw_config);
+    store.write_blob(&config_digest, &raw_config)?;
+
+    let manifest = ImageManifest {
+        schema_version: 2,
+        media_type: Some(String::from(DOCKER_MANIFEST_V2)),
+        config: Descriptor {
+            media_type: String::from(DOCKER_CONFIG_V1),
+            digest: config_digest,
+            size: raw_config.len() as u64,
+            urls: Vec::new(),
+            platform: None,
+            annotations: BTreeMap::new(),
+        },
+        layers,
+    };
+    let raw_manifest = serde_json::to_vec(&manifest)?;
+    Ok((manifest, raw_manifest))
+}
+
+/// Asks the registry how big a blob is, which schema 1 manifests don't say
+fn blob_size(
+    client: &reqwest::blocking::Client,
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    digest: &str,
+) -> Result<u64> {
+    let response = auth.send("Checking blob size", || {
+        client.head(
+            auth.endpoint()
+                .url(&format!("/v2/{}/blobs/{}", image.repository, digest)),
+        )
+    })?;
+    if !response.status().is_success() {
+        let error = RegistryError::from_response(response);
+        let description = error.describe(image, &format!("blob {}", digest));
+        return Err(anyhow::Error::new(error).context(description));
+    }
+    response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse().ok())
+        .with_context(|| format!("Registry didn't say how big blob {} is", digest))
+}
+
+/// Fetches the manifest (or manifest list) for a tag or digest
+///
+/// When fetching by digest the response body is checked against it, since that's the whole
+/// point of pinning an image by digest. Returns the parsed manifest along with its digest and
+/// the raw bytes it was computed over.
+///
+/// Manifests served with an `ETag` are cached in the store and revalidated with
+/// `If-None-Match`, so an unchanged manifest costs a `304` instead of a download.
+///
+/// See: https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/If-None-Match
+pub fn fetch_manifest(
+    image: &ImageReference,
+    reference: &str,
+    auth: &RepositoryAuth,
+    store: &Store,
+) -> Result<(Manifest, String, Vec<u8>)> {
+    let client = auth.endpoint().client()?;
+    let host = &auth.endpoint().host;
+    let cached = store.cached_manifest(host, &image.repository, reference)?;
+
+    let manifest_response = auth
+        .send("Fetching manifest", || {
+            let request = client
+                .get(
+                    auth.endpoint()
+                        .url(&format!("/v2/{}/manifests/{}", image.repository, reference)),
+                )
+                .header(ACCEPT, ACCEPTED_MANIFEST_TYPES.join(", "));
+            match &cached {
+                Some(cached) => request.header(IF_NONE_MATCH, &cached.etag),
+                None => request,
+            }
+        })
+        .context("Tried fetching image manifest")?;
+
+    if let (StatusCode::NOT_MODIFIED, Some(cached)) = (manifest_response.status(), &cached) {
+        let raw_data = store.read_blob(&cached.digest)?;
+        let manifest = Manifest::parse(cached.content_type.as_deref(), &raw_data)
+            .context("Tried to parse cached manifest")?;
+        return Ok((manifest, cached.digest.clone(), raw_data));
+    }
+    if !manifest_response.status().is_success() {
+        let error = RegistryError::from_response(manifest_response);
+        let mut description = error.describe(image, &format!("manifest {}", reference));
+        if error.has_code(MANIFEST_UNKNOWN) && !reference.contains(':') {
+            if let Some(suggestion) = suggest_tag(image, reference, auth) {
+                description = format!("{}, did you mean {}?", description, suggestion);
+            }
+        }
+        return Err(anyhow::Error::new(error).context(description));
+    }
+
+    let content_type = manifest_response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or(v).trim().to_string());
+    let advertised_digest = manifest_response
+        .headers()
+        .get(DOCKER_CONTENT_DIGEST)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let etag = manifest_response
+        .headers()
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let raw_data = manifest_response
+        .bytes()
+        .context("Tried to read manifest response")?;
+
+    // The digest is computed over the exact bytes we received, re-serializing the parsed JSON
+    // would not round trip. Signed schema 1 manifests are digested without their signatures.
+    let digest = match content_type.as_deref() {
+        Some(t) if t == DOCKER_MANIFEST_V1_SIGNED => sha256_digest(&schema1_payload(&raw_data)?),
+        _ => sha256_digest(&raw_data),
+    };
+    if let Some(advertised) = advertised_digest {
+        if advertised != digest {
+            bail!(
+                "Integrity error: manifest for {} hashed to {} but registry advertised {} in {}",
+                image,
+                digest,
+                advertised,
+                DOCKER_CONTENT_DIGEST
+            );
+        }
+    }
+    if reference.contains(':') && reference != digest {
+        bail!(
+            "Integrity error: requested manifest {} for {} but received content hashed to {}",
+            reference,
+            image.repository,
+            digest
+        );
+    }
+
+    let manifest = Manifest::parse(content_type.as_deref(), &raw_data)
+        .context("Tried to parse docker's manifest response")?;
+    if let Some(etag) = etag {
+        let cached = CachedManifest {
+            etag,
+            digest: digest.clone(),
+            content_type,
+        };
+        store.cache_manifest(host, &image.repository, reference, &cached, &raw_data)?;
+    }
+    Ok((manifest, digest, raw_data.to_vec()))
+}
+
+/// Most edits a tag can be away from the one asked for to be suggested instead
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// Finds the repository's tag closest to a tag that doesn't exist, in case it was a typo
+fn suggest_tag(image: &ImageReference, tag: &str, auth: &RepositoryAuth) -> Option<String> {
+    let tags = list_tags(image, auth).ok()?;
+    tags.into_iter()
+        .map(|candidate| (edit_distance(tag, &candidate), candidate))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE && *distance < tag.len())
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between two strings
+///
+/// See: https://en.wikipedia.org/wiki/Levenshtein_distance#Iterative_with_two_matrix_rows
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// How many tags to ask for per page
+const TAGS_PAGE_SIZE: usize = 100;
+
+/// A page of tags from the tags endpoint
+#[derive(Debug, Deserialize)]
+struct TagList {
+    /// Registries return `null` for repositories without any tags
+    tags: Option<Vec<String>>,
+}
+
+/// Lists every tag in an image's repository, following the registry's pagination
+///
+/// Registries point at the next page with a `Link` header, but since that's optional we fall back
+/// to asking for whatever comes after the last tag of a full page ourselves.
+///
+/// See: https://distribution.github.io/distribution/spec/api/#listing-image-tags
+pub fn list_tags(image: &ImageReference, auth: &RepositoryAuth) -> Result<Vec<String>> {
+    let client = auth.endpoint().client()?;
+    let first_page = auth.endpoint().url(&format!(
+        "/v2/{}/tags/list?n={}",
+        image.repository, TAGS_PAGE_SIZE
+    ));
+    let mut page_url = Some(Url::parse(&first_page)?);
+
+    let mut tags = Vec::new();
+    while let Some(url) = page_url.take() {
+        let response = auth
+            .send("Listing tags", || client.get(url.clone()))
+            .with_context(|| format!("Tried to list tags for {}", image.repository))?;
+        if !response.status().is_success() {
+            let error = RegistryError::from_response(response);
+            let description = error.describe(image, "tags");
+            return Err(anyhow::Error::new(error).context(description));
+        }
+        let next_link = response
+            .headers()
+            .get(LINK)
+            .and_then(|l| l.to_str().ok())
+            .and_then(next_page_link)
+            .map(|link| url.join(&link))
+            .transpose()
+            .context("Invalid Link header in tags response")?;
+        let page: TagList = response
+            .json()
+            .context("Tried to parse the registry's tag list")?;
+        let page = page.tags.unwrap_or_default();
+
+        page_url = match (next_link, page.last()) {
+            (Some(next_link), _) => Some(next_link),
+            (None, Some(last)) if page.len() >= TAGS_PAGE_SIZE => {
+                let mut next = url.clone();
+                next.query_pairs_mut()
+                    .clear()
+                    .append_pair("n", &TAGS_PAGE_SIZE.to_string())
+                    .append_pair("last", last);
+                Some(next)
+            }
+            _ => None,
+        };
+        tags.extend(page);
+    }
+    Ok(tags)
+}
+
+/// Extracts the target of a `Link: <url>; rel="next"` header
+///
+/// See: https://datatracker.ietf.org/doc/html/rfc5988#section-5
+fn next_page_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (target, params) = link.split_once(';')?;
+        let is_next = params
+            .split(';')
+            .any(|param| param.trim().replace(' ', "") == "rel=\"next\"");
+        let target = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+        is_next.then(|| target.to_string())
+    })
+}
+
+/// Picks the digest of the manifest list entry that matches the requested platform
+///
+/// See: https://distribution.github.io/distribution/spec/manifest-v2-2/#manifest-list
+pub fn select_platform_manifest(index: &ImageIndex, platform: &Platform) -> Result<String> {
+    let entry = index
+        .manifests
+        .iter()
+        .find(|m| match &m.platform {
+            Some(p) => platform.matches(&p.os, &p.architecture, p.variant.as_deref()),
+            None => false,
+        })
+        .with_context(|| {
+            let available: Vec<String> = index
+                .manifests
+                .iter()
+                .filter_map(|m| m.platform.as_ref())
+                .map(|p| format!("{}/{}", p.os, p.architecture))
+                .collect();
+            format!(
+                "No manifest for platform {} (available: {})",
+                platform,
+                available.join(", ")
+            )
+        })?;
+
+    Ok(entry.digest.clone())
+}
+
+/// How many times a blob is downloaded before giving up on it not matching its digest
+const BLOB_ATTEMPTS: usize = 3;
+
+/// Blobs at least this big are split into ranges that are downloaded concurrently
+const RANGED_DOWNLOAD_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Downloads a blob into the store, verifying it against the digest it was requested by
+///
+/// The blob is streamed to a partial file on disk so memory use doesn't depend on the size of the
+/// layer, and so a download that dies midway can be resumed with a `Range` request next time. A
+/// mismatch usually means the download was corrupted in transit, so the partial file is thrown
+/// away and the blob is downloaded again a few times before failing the pull. Large blobs are
+/// downloaded as `download_ranges` ranges at once when the registry supports it.
+///
+/// See: https://distribution.github.io/distribution/spec/api/#fetch-blob-part
+fn fetch_blob(
+    client: &reqwest::blocking::Client,
+    image: &ImageReference,
+    blob: &Descriptor,
+    auth: &RepositoryAuth,
+    store: &Store,
+    options: &PullOptions,
+) -> Result<()> {
+    // Another image being pulled alongside this one may be downloading the same blob
+    let progress = &options.progress;
+    let _claim = match store.claim_download(&blob.digest)? {
+        Some(claim) => claim,
+        None => {
+            progress.set_phase(&blob.digest, Phase::Downloaded);
+            return Ok(());
+        }
+    };
+    if store.copy_from_additional(&blob.digest)? {
+        progress.set_phase(&blob.digest, Phase::Exists);
+        return Ok(());
+    }
+    progress.set_phase(&blob.digest, Phase::Downloading);
+    let partial_path = store.partial_blob_path(&blob.digest)?;
+
+    let mut attempt = 1;
+    loop {
+        let ranged = options.download_ranges > 1
+            && blob.size >= RANGED_DOWNLOAD_THRESHOLD
+            && fetch_blob_ranges(client, image, blob, auth, &partial_path, options)?;
+        if !ranged {
+            // Any ranges that did get downloaded were thrown away
+            progress.reset(&blob.digest);
+            // Every retry resumes from whatever the previous attempt managed to download
+            with_retries(
+                &options.retry,
+                &format!("Downloading blob {}", blob.digest),
+                || {
+                    download_range(
+                        client,
+                        image,
+                        blob,
+                        auth,
+                        &partial_path,
+                        0..blob.size,
+                        options,
+                    )
+                    .map(|_| ())
+                },
+            )?;
+        }
+
+        let blob_file = File::open(&partial_path)
+            .with_context(|| format!("Tried to open {}", partial_path.display()))?;
+        let mut hashing_reader = HashingReader::new(blob_file);
+        io::copy(&mut hashing_reader, &mut io::sink())
+            .with_context(|| format!("Tried to hash {}", partial_path.display()))?;
+        let digest = hashing_reader.finalize();
+
+        match check_digest(&digest, &blob.digest) {
+            Ok(()) => {
+                store.insert_blob(&blob.digest, &partial_path)?;
+                progress.set_phase(&blob.digest, Phase::Downloaded);
+                return Ok(());
+            }
+            Err(e) if attempt < BLOB_ATTEMPTS => {
+                let _ = fs::remove_file(&partial_path);
+                progress.reset(&blob.digest);
+                eprintln!(
+                    "Blob {} failed verification (attempt {}/{}), retrying: {}",
+                    blob.digest, attempt, BLOB_ATTEMPTS, e
+                );
+                attempt += 1;
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&partial_path);
+                return Err(e).with_context(|| {
+                    format!(
+                        "Blob {} failed verification after {} attempts",
+                        blob.digest, BLOB_ATTEMPTS
+                    )
+                });
+            }
+        }
+    }
+}
+
+/// Downloads a blob as `ranges` concurrent ranges and reassembles them into `partial_path`
+///
+/// Each range gets its own partial file next to `partial_path` so it can be resumed on its own.
+/// Returns false, leaving the download to be done in one go, if the registry ignores `Range`.
+fn fetch_blob_ranges(
+    client: &reqwest::blocking::Client,
+    image: &ImageReference,
+    blob: &Descriptor,
+    auth: &RepositoryAuth,
+    partial_path: &Path,
+    options: &PullOptions,
+) -> Result<bool> {
+    // An earlier attempt may have already finished and reassembled the blob
+    if fs::metadata(partial_path).is_ok_and(|m| m.len() == blob.size) {
+        return Ok(true);
+    }
+
+    let range_size = blob.size.div_ceil(options.download_ranges as u64);
+    let parts: Vec<(PathBuf, Range<u64>)> = (0..blob.size)
+        .step_by(range_size as usize)
+        .enumerate()
+        .map(|(i, start)| {
+            let mut part_path = partial_path.as_os_str().to_owned();
+            part_path.push(format!(".part{}", i));
+            (
+                PathBuf::from(part_path),
+                start..(start + range_size).min(blob.size),
+            )
+        })
+        .collect();
+
+    let supported = thread::scope(|scope| {
+        let workers: Vec<_> = parts
+            .iter()
+            .map(|(part_path, range)| {
+                scope.spawn(move || {
+                    let description = format!(
+                        "Downloading bytes {}-{} of blob {}",
+                        range.start, range.end, blob.digest
+                    );
+                    with_retries(&options.retry, &description, || {
+                        download_range(client, image, blob, auth, part_path, range.clone(), options)
+                    })
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Blob range download thread panicked"))
+            .collect::<Result<Vec<bool>>>()
+    })?;
+
+    let supported = supported.into_iter().all(|supported| supported);
+    if supported {
+        let mut partial_file = File::create(partial_path)
+            .with_context(|| format!("Tried to create {}", partial_path.display()))?;
+        for (part_path, _) in &parts {
+            let mut part_file = File::open(part_path)
+                .with_context(|| format!("Tried to open {}", part_path.display()))?;
+            io::copy(&mut part_file, &mut partial_file)
+                .with_context(|| format!("Tried to reassemble {}", partial_path.display()))?;
+        }
+    }
+    for (part_path, _) in &parts {
+        let _ = fs::remove_file(part_path);
+    }
+    Ok(supported)
+}
+
+/// Most redirects we'll follow for a single blob request
+const MAX_REDIRECTS: usize = 10;
+
+/// Requests a blob, following redirects by hand
+///
+/// Registries commonly redirect blob downloads to object storage or a CDN which reject (or worse,
+/// log) our bearer token, so the `Authorization` header is only sent to the registry's own
+/// origin. Requests without `auth` never carry a token. Returns the final response and whether
+/// we were redirected to get it.
+///
+/// See: https://distribution.github.io/distribution/spec/api/#pulling-a-layer
+fn send_blob_request(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    auth: Option<&RepositoryAuth>,
+    range: Option<&str>,
+) -> Result<(Response, bool)> {
+    let registry_url = Url::parse(url).with_context(|| format!("Invalid blob URL {}", url))?;
+    let mut url = registry_url.clone();
+
+    for _ in 0..MAX_REDIRECTS {
+        let mut request = client.get(url.clone());
+        if let Some(auth) = auth.filter(|_| url.origin() == registry_url.origin()) {
+            request = auth.authorize(request)?;
+        }
+        if let Some(range) = range {
+            request = request.header(RANGE, range);
+        }
+        let response = request.send()?;
+
+        if !response.status().is_redirection() {
+            return Ok((response, url != registry_url));
+        }
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .with_context(|| format!("Redirect from {} has no Location header", url))?;
+        url = url
+            .join(location)
+            .with_context(|| format!("Invalid redirect location {}", location))?;
+    }
+
+    bail!("Too many redirects while fetching {}", registry_url)
+}
+
+/// Downloads `range` of a blob into `partial_path`, resuming from whatever is already there
+///
+/// Returns false if the registry ignored our `Range` header (or sent some other range) for a
+/// range that isn't the whole blob, in which case nothing was downloaded.
+fn download_range(
+    client: &reqwest::blocking::Client,
+    image: &ImageReference,
+    blob: &Descriptor,
+    auth: &RepositoryAuth,
+    partial_path: &Path,
+    range: Range<u64>,
+    options: &PullOptions,
+) -> Result<bool> {
+    let mut partial_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(partial_path)
+        .with_context(|| format!("Tried to open {}", partial_path.display()))?;
+    let mut have = partial_file.metadata()?.len();
+    let wanted = range.end - range.start;
+    if have > 0 && have == wanted {
+        return Ok(true);
+    }
+    // Left over from a download split up differently, none of it can be trusted to line up
+    if have > wanted {
+        partial_file
+            .set_len(0)
+            .with_context(|| format!("Tried to truncate {}", partial_path.display()))?;
+        have = 0;
+    }
+    let whole_blob = range == (0..blob.size);
+
+    // Foreign layers live outside the registry, which must never see our token
+    let (url, blob_auth) = match blob.urls.first() {
+        Some(url) if blob.is_foreign() => (url.clone(), None),
+        _ => (
+            auth.endpoint()
+                .url(&format!("/v2/{}/blobs/{}", image.repository, blob.digest)),
+            Some(auth),
+        ),
+    };
+    let range_header = match (whole_blob, have) {
+        (true, 0) => None,
+        (true, _) => Some(format!("bytes={}-", have)),
+        (false, _) => Some(format!("bytes={}-{}", range.start + have, range.end - 1)),
+    };
+    let (blob_response, redirected) =
+        send_blob_request(client, &url, blob_auth, range_header.as_deref())
+            .with_context(|| format!("Tried fetching blob {}", blob.digest))?;
+    // A 401 from wherever we were redirected to has nothing to do with our token
+    if let (Some(auth), false) = (blob_auth, redirected) {
+        auth.check_unauthorized(&blob_response)?;
+    }
+
+    match blob_response.status() {
+        StatusCode::PARTIAL_CONTENT => match content_range_start(&blob_response) {
+            Some(start) if start == range.start + have => {}
+            // Like when the Range header is ignored, what we had doesn't line up with it
+            Some(0) if whole_blob => partial_file.set_len(0)?,
+            start => {
+                drop(partial_file);
+                let _ = fs::remove_file(partial_path);
+                if whole_blob {
+                    bail!(
+                        "Registry sent blob {} from byte {} when asked for it from byte {}",
+                        blob.digest,
+                        start.map_or(String::from("?"), |start| start.to_string()),
+                        have
+                    );
+                }
+                return Ok(false);
+            }
+        },
+        s if s.is_server_error() || s == StatusCode::TOO_MANY_REQUESTS => {
+            return Err(RetryableStatus::from_response(&blob_response))
+                .with_context(|| format!("Tried fetching blob {}", blob.digest));
+        }
+        // Anything we already had is useless if the registry ignored our Range header
+        StatusCode::OK if whole_blob => partial_file.set_len(0)?,
+        StatusCode::OK => {
+            drop(partial_file);
+            let _ = fs::remove_file(partial_path);
+            return Ok(false);
+        }
+        // We already have everything there is to have, the digest check will sort it out
+        StatusCode::RANGE_NOT_SATISFIABLE => return Ok(true),
+        _ => {
+            let error = RegistryError::from_response(blob_response);
+            let description = error.describe(image, &format!("blob {}", blob.digest));
+            return Err(anyhow::Error::new(error).context(description));
+        }
+    }
+
+    // Never write past the end of the range, in case the registry sends more than we asked for
+    let already = partial_file.metadata()?.len();
+    options.progress.advance(&blob.digest, already);
+    let body = options
+        .progress
+        .reader(&blob.digest, blob_response.take(wanted - already));
+    let mut body = ThrottledReader::new(body, options.limit_rate.as_deref());
+    io::copy(&mut body, &mut partial_file)
+        .with_context(|| format!("Tried reading blob {}", blob.digest))?;
+    Ok(true)
+}
+
+/// Where the part of the blob a 206 response has starts, from its `Content-Range` header, like
+/// `bytes 100-199/1000`
+///
+/// See: https://www.rfc-editor.org/rfc/rfc9110#field.content-range
+fn content_range_start(response: &Response) -> Option<u64> {
+    let content_range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = content_range.strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Downloads an image's config and layers into the store, skipping blobs it already has
+///
+/// Up to `max_concurrent_downloads` blobs are downloaded at once, each of which may be split into
+/// `download_ranges` ranges if it's large, all of them together staying under `limit_rate`.
+/// Foreign layers without any URLs to fetch them from are skipped.
+///
+/// See: https://distribution.github.io/distribution/spec/api/#pulling-a-layer
+pub fn fetch_image_blobs(
+    manifest: &ImageManifest,
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    store: &Store,
+    options: &PullOptions,
+) -> Result<()> {
+    for layer in &manifest.layers {
+        if layer.is_foreign() && layer.urls.is_empty() {
+            eprintln!(
+                "Skipping foreign layer {} which has no URLs to fetch it from",
+                layer.digest
+            );
+        }
+    }
+
+    // Don't bother downloading anything if we won't be able to unpack it
+    for layer in manifest.fetchable_layers() {
+        Compression::from_media_type(&layer.media_type)
+            .with_context(|| format!("Layer {} can't be unpacked", layer.digest))?;
+    }
+
+    for layer in manifest.fetchable_layers() {
+        let phase = match store.has_blob(&layer.digest)? {
+            true => Phase::Exists,
+            false => Phase::Waiting,
+        };
+        options.progress.add_layer(&layer.digest, layer.size, phase);
+    }
+
+    let mut blobs = Vec::new();
+    for blob in [&manifest.config]
+        .into_iter()
+        .chain(manifest.fetchable_layers())
+    {
+        if !store.has_blob(&blob.digest)? {
+            blobs.push(blob);
+        }
+    }
+    fetch_blobs(&blobs, image, auth, store, options)
+}
+
+/// Downloads blobs into the store, up to `max_concurrent_downloads` at once
+fn fetch_blobs(
+    blobs: &[&Descriptor],
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    store: &Store,
+    options: &PullOptions,
+) -> Result<()> {
+    // Redirects are followed by send_blob_request so it can decide who gets our token
+    let client = auth.endpoint().no_redirects_client()?;
+    let next_blob = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..options
+            .max_concurrent_downloads
+            .clamp(1, blobs.len().max(1)))
+            .map(|_| {
+                let (next_blob, failed) = (&next_blob, &failed);
+                scope.spawn(move || -> Result<()> {
+                    // Stop handing out work once anything failed, the pull is done for
+                    while !failed.load(Ordering::SeqCst) {
+                        let blob = match blobs.get(next_blob.fetch_add(1, Ordering::SeqCst)) {
+                            Some(blob) => blob,
+                            None => break,
+                        };
+                        if let Err(e) = fetch_blob(client, image, blob, auth, store, options) {
+                            failed.store(true, Ordering::SeqCst);
+                            return Err(e);
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("Blob download thread panicked"))
+    })
+}
@@ -0,0 +1,233 @@
+use anyhow::Result;
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How transient registry failures are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of tries, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for every retry after that
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Randomize delays so concurrent downloads don't retry in lockstep
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry` (starting at 1)
+    fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        if !self.jitter {
+            return exponential;
+        }
+
+        // Good enough randomness for spreading out retries without pulling in a rand crate
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        let fraction = 0.5 + (nanos % 1000) as f64 / 2000.0;
+        exponential.mul_f64(fraction)
+    }
+}
+
+/// Longest `Retry-After` we're willing to sit through, anything beyond is better reported
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Docker Hub's view of how many pulls we have left, from its `ratelimit-*` headers
+///
+/// See: https://docs.docker.com/docker-hub/download-rate-limit/
+#[derive(Debug)]
+pub struct RateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+    pub window: Option<Duration>,
+}
+
+impl RateLimit {
+    /// Parses headers of the form `ratelimit-remaining: 76;w=21600`
+    pub fn from_headers(headers: &HeaderMap) -> Option<RateLimit> {
+        let parse = |name: &str| -> Option<(u64, Option<Duration>)> {
+            let value = headers.get(name)?.to_str().ok()?;
+            let mut parts = value.split(';');
+            let count = parts.next()?.trim().parse().ok()?;
+            let window = parts
+                .find_map(|p| p.trim().strip_prefix("w="))
+                .and_then(|w| w.parse().ok())
+                .map(Duration::from_secs);
+            Some((count, window))
+        };
+
+        let (limit, window) = parse("ratelimit-limit")?;
+        let (remaining, _) = parse("ratelimit-remaining")?;
+        Some(RateLimit {
+            limit,
+            remaining,
+            window,
+        })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} pulls remaining", self.remaining, self.limit)?;
+        if let Some(window) = self.window {
+            write!(f, " per {}h", window.as_secs() / 3600)?;
+        }
+        Ok(())
+    }
+}
+
+/// A registry response that's worth retrying (server errors, rate limiting, and the like)
+#[derive(Debug)]
+pub struct RetryableStatus {
+    pub status: StatusCode,
+    /// How long the registry asked us to wait via `Retry-After`
+    pub retry_after: Option<Duration>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl RetryableStatus {
+    pub fn from_response(response: &Response) -> RetryableStatus {
+        // Retry-After may also be an HTTP date, we only bother with the delay-seconds form that
+        // registries actually send
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs);
+
+        RetryableStatus {
+            status: response.status(),
+            retry_after,
+            rate_limit: RateLimit::from_headers(response.headers()),
+        }
+    }
+}
+
+impl fmt::Display for RetryableStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.status != StatusCode::TOO_MANY_REQUESTS {
+            return write!(f, "registry responded with {}", self.status);
+        }
+
+        write!(f, "registry rate limit exceeded")?;
+        if let Some(rate_limit) = &self.rate_limit {
+            write!(f, " ({})", rate_limit)?;
+        }
+        write!(
+            f,
+            ", anonymous pulls have lower limits so consider authenticating with the registry"
+        )
+    }
+}
+
+impl std::error::Error for RetryableStatus {}
+
+/// Warns when a response indicates we're close to being rate limited
+pub fn warn_on_low_rate_limit(response: &Response) {
+    if let Some(rate_limit) = RateLimit::from_headers(response.headers()) {
+        if rate_limit.remaining * 10 <= rate_limit.limit {
+            eprintln!("Warning: registry reports {}", rate_limit);
+        }
+    }
+}
+
+/// Whether an error is transient, permanent errors (bad credentials, unknown images, ...) won't
+/// get any better by asking again
+fn is_retryable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if cause.is::<RetryableStatus>() {
+            return true;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout() || e.is_connect() || e.is_body() || e.is_request();
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            return matches!(
+                e.kind(),
+                ConnectionReset | ConnectionAborted | BrokenPipe | TimedOut | UnexpectedEof
+            );
+        }
+        false
+    })
+}
+
+/// Runs `operation` until it succeeds, fails with a permanent error, or runs out of attempts
+pub fn with_retries<T>(
+    policy: &RetryPolicy,
+    description: &str,
+    mut operation: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.attempts && is_retryable(&e) => {
+                let requested = e
+                    .chain()
+                    .find_map(|cause| cause.downcast_ref::<RetryableStatus>())
+                    .and_then(|status| status.retry_after);
+                if requested.is_some_and(|delay| delay > MAX_RETRY_AFTER) {
+                    return Err(e);
+                }
+                let delay = policy.backoff(attempt).max(requested.unwrap_or_default());
+                eprintln!(
+                    "{} failed (attempt {}/{}), retrying in {:.1}s: {:#}",
+                    description,
+                    attempt,
+                    policy.attempts,
+                    delay.as_secs_f64(),
+                    e
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sends a request, retrying transient failures
+///
+/// `request` is called for every attempt since a `RequestBuilder` can only be sent once.
+/// Responses with permanent error statuses are handed back to the caller to interpret.
+pub fn send_with_retries(
+    policy: &RetryPolicy,
+    description: &str,
+    request: impl Fn() -> Result<RequestBuilder>,
+) -> Result<Response> {
+    with_retries(policy, description, || {
+        let response = request()?.send()?;
+        let status = response.status();
+        if status.is_server_error()
+            || status == StatusCode::REQUEST_TIMEOUT
+            || status == StatusCode::TOO_MANY_REQUESTS
+        {
+            return Err(RetryableStatus::from_response(&response).into());
+        }
+        warn_on_low_rate_limit(&response);
+        Ok(response)
+    })
+}
@@ -0,0 +1,502 @@
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+
+use crate::idmap::IdMappings;
+
+/// `FICLONE` from `linux/fs.h`, which the libc crate doesn't have
+const FICLONE: libc::c_ulong = 0x40049409;
+
+/// What the names of files marking paths a layer deleted start with, followed by the name of
+/// what was deleted
+///
+/// See: https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+pub static WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Marks a directory whose contents in the layers below are all deleted
+pub static OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// The xattr overlayfs marks opaque directories with, set to `y`
+///
+/// See: https://docs.kernel.org/filesystems/overlayfs.html#whiteouts-and-opaque-directories
+static OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+/// What overlayfs mounted with `userxattr` (and fuse-overlayfs) reads opaque directories from
+/// instead, which users without `CAP_SYS_ADMIN` can set
+static USER_OVERLAY_OPAQUE_XATTR: &str = "user.overlay.opaque";
+
+/// How a layer's regular files end up in a rootfs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileCopy {
+    /// Reflinked on filesystems that can share extents between files (like btrfs and XFS) and
+    /// copied on the rest, either way writes to them stay in the rootfs
+    Clone,
+    /// Hardlinked to the extracted layer, which is instant and takes no space but means anything
+    /// the container writes to those files changes the store too, so it's only for containers
+    /// that can't write to them. Falls back to `Clone` where a link can't be made.
+    Hardlink,
+}
+
+/// What's done with the whiteouts in a layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Whiteouts {
+    /// Delete what they name from the destination
+    Apply,
+    /// Turn them into overlayfs's own, for a lower directory of an overlay
+    Overlay,
+    /// There aren't any, what's being copied isn't a layer
+    Copy,
+}
+
+/// Copies an extracted layer onto a rootfs, the way overlayfs would show it on top
+///
+/// Directories found in both are merged, anything else in the layer replaces whatever the rootfs
+/// had at that path. Whiteouts delete what they name from the rootfs instead of being copied, and
+/// an opaque whiteout empties its directory before the layer's own files go in. Symlinks in the
+/// rootfs are never followed, so a layer can't write outside of it. Ownership, permissions, and
+/// timestamps are copied along with the contents.
+pub fn copy_layer(layer_dir: &Path, rootfs: &Path, files: FileCopy) -> Result<()> {
+    copy_entries(layer_dir, rootfs, files, Whiteouts::Apply, None).with_context(|| {
+        format!(
+            "Tried to copy layer {} into {}",
+            layer_dir.display(),
+            rootfs.display()
+        )
+    })
+}
+
+/// Copies a file, or a whole directory, to `to` for `cp`
+///
+/// A directory already at `to` is merged with, anything else there is replaced. Symlinks are
+/// copied as they are rather than followed, in what's copied and in where it's copied to alike,
+/// so neither side's can point the copy somewhere else. Permissions, timestamps, and xattrs are
+/// copied along with the contents, and so are ownership and file capabilities unless `owner`
+/// says who gets it instead. Xattrs that can't be set where the copy goes are warned about.
+pub fn copy_path(from: &Path, to: &Path, owner: Option<(u32, u32)>) -> Result<()> {
+    copy_entry(from, to, FileCopy::Clone, Whiteouts::Copy, owner)
+        .with_context(|| format!("Tried to copy {} to {}", from.display(), to.display()))
+}
+
+/// Hardlinks an extracted layer into an empty directory that overlayfs can use as a lower
+/// directory
+///
+/// Whiteouts become character devices numbered 0/0 named after what they delete, and opaque
+/// whiteouts become the `trusted.overlay.opaque` xattr on their directory, which is how
+/// overlayfs expects to find them. Setting that xattr takes `CAP_SYS_ADMIN`, without it they get
+/// `user.overlay.opaque` instead, which only rootless overlays read.
+pub fn link_overlay_layer(layer_dir: &Path, destination: &Path) -> Result<()> {
+    copy_entries(
+        layer_dir,
+        destination,
+        FileCopy::Hardlink,
+        Whiteouts::Overlay,
+        None,
+    )
+    .with_context(|| {
+        format!(
+            "Tried to link layer {} into {}",
+            layer_dir.display(),
+            destination.display()
+        )
+    })
+}
+
+fn copy_entries(
+    source: &Path,
+    destination: &Path,
+    files: FileCopy,
+    whiteouts: Whiteouts,
+    owner: Option<(u32, u32)>,
+) -> io::Result<()> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(source)? {
+        names.push(entry?.file_name());
+    }
+    match whiteouts {
+        Whiteouts::Apply => {}
+        Whiteouts::Overlay => return link_overlay_entries(source, destination, names),
+        Whiteouts::Copy => {
+            for name in names {
+                let (from, to) = (source.join(&name), destination.join(&name));
+                copy_entry(&from, &to, files, whiteouts, owner)?;
+            }
+            return Ok(());
+        }
+    }
+
+    // Whiteouts are about what the layers below had, so they go before anything is copied
+    if names.iter().any(|name| name == OPAQUE_WHITEOUT) {
+        for entry in fs::read_dir(destination)? {
+            remove_path(&entry?.path())?;
+        }
+    }
+    for name in &names {
+        let hidden = match name.as_bytes().strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+            Some(hidden) if name != OPAQUE_WHITEOUT => OsStr::from_bytes(hidden),
+            _ => continue,
+        };
+        // Anything else would be a path that isn't in this directory
+        if hidden.is_empty() || hidden == "." || hidden == ".." {
+            continue;
+        }
+        remove_path(&destination.join(hidden))?;
+    }
+
+    for name in names {
+        if name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes()) {
+            continue;
+        }
+        copy_entry(
+            &source.join(&name),
+            &destination.join(&name),
+            files,
+            whiteouts,
+            owner,
+        )?;
+    }
+    Ok(())
+}
+
+fn link_overlay_entries(source: &Path, destination: &Path, names: Vec<OsString>) -> io::Result<()> {
+    for name in names {
+        if name == OPAQUE_WHITEOUT {
+            match set_xattr(destination, OVERLAY_OPAQUE_XATTR, b"y") {
+                Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                    set_xattr(destination, USER_OVERLAY_OPAQUE_XATTR, b"y")?
+                }
+                set => set?,
+            }
+            continue;
+        }
+        let hidden = match name.as_bytes().strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+            Some(hidden) => OsStr::from_bytes(hidden),
+            None => {
+                let (from, to) = (source.join(&name), destination.join(&name));
+                copy_entry(&from, &to, FileCopy::Hardlink, Whiteouts::Overlay, None)?;
+                continue;
+            }
+        };
+        if hidden.is_empty() || hidden == "." || hidden == ".." {
+            continue;
+        }
+        let whiteout = destination.join(hidden);
+        remove_path(&whiteout)?;
+        let path = CString::new(whiteout.as_os_str().as_bytes())?;
+        if unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Sets an xattr on a file, or on the symlink itself for symlinks
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    let result = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Copies the xattrs a file (or the symlink itself) has, `security.capability` only with
+/// `capabilities`
+///
+/// With `best_effort` the ones that can't be set here, like a host's `security.selinux` or
+/// `trusted.*` ones without root, or any on a filesystem that doesn't have them, are warned
+/// about rather than failing the copy.
+fn copy_xattrs(from: &Path, to: &Path, best_effort: bool, capabilities: bool) -> io::Result<()> {
+    let path = CString::new(from.as_os_str().as_bytes())?;
+    let names = match xattr_list(|buffer, size| unsafe {
+        libc::llistxattr(path.as_ptr(), buffer as *mut libc::c_char, size)
+    }) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        names => names?,
+    };
+    for name in names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let name = CString::new(name)?;
+        let name = name.to_string_lossy();
+        if name == "security.capability" && !capabilities {
+            continue;
+        }
+        let c_name = CString::new(name.as_bytes())?;
+        let value = xattr_list(|buffer, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buffer, size)
+        })?;
+        match set_xattr(to, &name, &value) {
+            Err(e)
+                if best_effort
+                    && matches!(e.raw_os_error(), Some(libc::EPERM) | Some(libc::ENOTSUP)) =>
+            {
+                eprintln!(
+                    "Warning: couldn't copy xattr {} to {}: {}",
+                    name,
+                    to.display(),
+                    e
+                );
+            }
+            set => set?,
+        }
+    }
+    Ok(())
+}
+
+/// Whether an overlay directory replaces what the layers under it have there, going by either
+/// of the xattrs overlayfs marks that with
+pub fn is_opaque(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    for name in [OVERLAY_OPAQUE_XATTR, USER_OVERLAY_OPAQUE_XATTR] {
+        let c_name = CString::new(name)?;
+        match xattr_list(|buffer, size| unsafe {
+            libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buffer, size)
+        }) {
+            Ok(value) if value == b"y" => return Ok(true),
+            Ok(_) => {}
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENODATA) | Some(libc::ENOTSUP)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// Calls `get` (like `llistxattr`) once for the size and again with a buffer that big
+fn xattr_list(get: impl Fn(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+    let size = get(std::ptr::null_mut(), 0);
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buffer = vec![0u8; size as usize];
+    let size = get(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buffer.truncate(size as usize);
+    Ok(buffer)
+}
+
+/// Shifts the ownership of everything in a rootfs into the host ids `mappings` maps the
+/// container's to, for containers with `--uidmap` where the kernel can't idmap the rootfs's mount
+///
+/// Only for rootfses nothing else shares (what the vfs driver copies), the files themselves are
+/// changed. Ids the mappings leave out are left as they are. File capabilities are set again
+/// after, changing ownership clears them along with setuid and setgid bits.
+pub fn shift_ownership(rootfs: &Path, mappings: &IdMappings) -> Result<()> {
+    let mut pending = vec![rootfs.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let metadata = fs::symlink_metadata(&path)
+            .with_context(|| format!("Tried to look at {}", path.display()))?;
+        if metadata.is_dir() {
+            for entry in
+                fs::read_dir(&path).with_context(|| format!("Tried to list {}", path.display()))?
+            {
+                pending.push(entry?.path());
+            }
+        }
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let capability = xattr_list(|buffer, size| unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                c"security.capability".as_ptr(),
+                buffer,
+                size,
+            )
+        })
+        .ok();
+        lchown(
+            &path,
+            mappings.uids.outside(metadata.uid()),
+            mappings.gids.outside(metadata.gid()),
+        )
+        .with_context(|| format!("Tried to change the owner of {}", path.display()))?;
+        if !metadata.file_type().is_symlink() {
+            fs::set_permissions(&path, metadata.permissions())?;
+        }
+        if let Some(capability) = capability {
+            set_xattr(&path, "security.capability", &capability)
+                .with_context(|| format!("Tried to set the capabilities of {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a path in the container to where it is under `rootfs`, following symlinks as if
+/// `rootfs` were `/`, so nothing in the image can make it resolve to somewhere outside of it
+///
+/// What doesn't exist yet is taken as it is, so the result is also where to create it.
+pub fn resolve_in_rootfs(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    let mut pending: VecDeque<OsString> = components(path);
+    let mut resolved = PathBuf::new();
+    let mut symlinks = 0;
+    while let Some(component) = pending.pop_front() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&component);
+        let link = match fs::symlink_metadata(rootfs.join(&candidate)) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                fs::read_link(rootfs.join(&candidate))?
+            }
+            _ => {
+                resolved = candidate;
+                continue;
+            }
+        };
+        // Linux gives up after as many
+        symlinks += 1;
+        if symlinks > 40 {
+            bail!("Too many levels of symlinks in {}", path.display());
+        }
+        if link.is_absolute() {
+            resolved = PathBuf::new();
+        }
+        for component in components(&link).into_iter().rev() {
+            pending.push_front(component);
+        }
+    }
+    Ok(rootfs.join(resolved))
+}
+
+/// The names and `..`s a path is made of, leaving out `/` and `.`
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Removes a file or a whole directory, if there's anything there
+fn remove_path(path: &Path) -> io::Result<()> {
+    let removed = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+    match removed {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        removed => removed,
+    }
+}
+
+fn copy_entry(
+    from: &Path,
+    to: &Path,
+    files: FileCopy,
+    whiteouts: Whiteouts,
+    owner: Option<(u32, u32)>,
+) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    let file_type = metadata.file_type();
+    let merge = match fs::symlink_metadata(to) {
+        Ok(existing) if existing.is_dir() && file_type.is_dir() => true,
+        Ok(existing) if existing.is_dir() => {
+            fs::remove_dir_all(to)?;
+            false
+        }
+        Ok(_) => {
+            fs::remove_file(to)?;
+            false
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e),
+    };
+
+    if file_type.is_dir() {
+        if !merge {
+            fs::create_dir(to)?;
+        }
+        copy_entries(from, to, files, whiteouts, owner)?;
+    } else if file_type.is_symlink() {
+        symlink(fs::read_link(from)?, to)?;
+    } else if file_type.is_file() {
+        // A link shares the layer's ownership, permissions, and timestamps already
+        if files == FileCopy::Hardlink && fs::hard_link(from, to).is_ok() {
+            return Ok(());
+        }
+        clone_file(from, to)?;
+    } else {
+        // Device nodes, FIFOs, and sockets, whose mode says which they are
+        let path = CString::new(to.as_os_str().as_bytes())?;
+        if unsafe { libc::mknod(path.as_ptr(), metadata.mode(), metadata.rdev()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // Ownership first, changing it clears setuid and setgid bits, and file capabilities. Ids
+    // our user namespace doesn't map (which show up as the overflow id) can't be set, the copy
+    // stays ours then.
+    let (uid, gid) = owner.unwrap_or((metadata.uid(), metadata.gid()));
+    match lchown(to, Some(uid), Some(gid)) {
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+        changed => changed?,
+    }
+    // A layer only has what it was extracted with, which can be set again, while `cp` copies
+    // whatever host files have. Like chown, a copy given someone else's ownership loses its
+    // capabilities, which would otherwise be the caller's to run with.
+    let copying = whiteouts == Whiteouts::Copy;
+    copy_xattrs(from, to, copying, owner.is_none())?;
+    if !file_type.is_symlink() {
+        fs::set_permissions(to, metadata.permissions())?;
+    }
+    set_times(to, &metadata)
+}
+
+/// Reflinks a file where the filesystem can, and copies it where it can't
+fn clone_file(from: &Path, to: &Path) -> io::Result<()> {
+    let source = File::open(from)?;
+    let destination = File::create(to)?;
+    if unsafe { libc::ioctl(destination.as_raw_fd(), FICLONE, source.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+    // Across filesystems, or on ones without reflinks
+    drop(destination);
+    fs::copy(from, to).map(|_| ())
+}
+
+/// Copies access and modification times, of the symlink itself for symlinks
+fn set_times(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -0,0 +1,642 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cli::SbomFormat;
+use crate::digest::sha256_digest;
+use crate::images::format_timestamp;
+use crate::progress::{Progress, ProgressMode};
+use crate::reference::ImageReference;
+use crate::store::{Store, StoredImage};
+
+/// Where apk keeps the packages it installed
+///
+/// See: https://wiki.alpinelinux.org/wiki/Apk_spec#Installed_Database_V2
+static APK_INSTALLED: &str = "lib/apk/db/installed";
+
+/// Where dpkg keeps the packages it knows about
+static DPKG_STATUS: &str = "var/lib/dpkg/status";
+
+/// One file per package, which distroless images have instead of `DPKG_STATUS`
+static DPKG_STATUS_DIR: &str = "var/lib/dpkg/status.d";
+
+/// Either the Berkeley DB or, since rpm 4.16, the sqlite database
+static RPM_DB: &str = "var/lib/rpm";
+
+/// What the names of the properties we add to CycloneDX components start with
+static PROPERTY_NAMESPACE: &str = "minidocker";
+
+/// The kinds of packages we find, each with its package URL type
+///
+/// See: https://github.com/package-url/purl-spec/blob/master/PURL-TYPES.rst
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ecosystem {
+    Apk,
+    Deb,
+    Rpm,
+    Npm,
+    Pypi,
+    Gem,
+}
+
+impl Ecosystem {
+    fn purl_type(self) -> &'static str {
+        match self {
+            Ecosystem::Apk => "apk",
+            Ecosystem::Deb => "deb",
+            Ecosystem::Rpm => "rpm",
+            Ecosystem::Npm => "npm",
+            Ecosystem::Pypi => "pypi",
+            Ecosystem::Gem => "gem",
+        }
+    }
+
+    /// Whether the image's distribution installed it, as opposed to a language package manager
+    fn is_os(self) -> bool {
+        matches!(self, Ecosystem::Apk | Ecosystem::Deb | Ecosystem::Rpm)
+    }
+}
+
+/// A package found in an image's filesystem
+#[derive(Debug)]
+struct Package {
+    ecosystem: Ecosystem,
+    name: String,
+    version: String,
+    arch: Option<String>,
+    license: Option<String>,
+    /// The file it was found in, relative to the root of the image
+    location: String,
+}
+
+/// The distribution an image is built on, from `/etc/os-release`
+///
+/// See: https://www.freedesktop.org/software/systemd/man/latest/os-release.html
+#[derive(Debug, Default)]
+struct Distro {
+    id: Option<String>,
+    version_id: Option<String>,
+}
+
+impl Package {
+    /// The package URL identifying it, like `pkg:apk/alpine/musl@1.2.4-r2?arch=x86_64`
+    ///
+    /// See: https://github.com/package-url/purl-spec/blob/master/PURL-SPECIFICATION.rst
+    fn purl(&self, distro: &Distro) -> String {
+        let namespace = match self.ecosystem {
+            Ecosystem::Apk => Some(distro.id.as_deref().unwrap_or("alpine")),
+            Ecosystem::Deb => Some(distro.id.as_deref().unwrap_or("debian")),
+            Ecosystem::Rpm => distro.id.as_deref(),
+            _ => None,
+        };
+        // Scoped npm packages, like `@types/node`, keep their scope as the namespace
+        let name = match self.ecosystem {
+            Ecosystem::Pypi => self.name.to_lowercase().replace('_', "-"),
+            Ecosystem::Npm => self.name.replace('@', "%40"),
+            _ => self.name.clone(),
+        };
+        let mut purl = format!("pkg:{}/", self.ecosystem.purl_type());
+        if let Some(namespace) = namespace {
+            purl.push_str(namespace);
+            purl.push('/');
+        }
+        purl.push_str(&name);
+        purl.push('@');
+        purl.push_str(&self.version.replace('+', "%2B"));
+        let mut qualifiers = Vec::new();
+        if let Some(arch) = &self.arch {
+            qualifiers.push(format!("arch={}", arch));
+        }
+        if self.ecosystem.is_os() {
+            if let (Some(id), Some(version)) = (&distro.id, &distro.version_id) {
+                qualifiers.push(format!("distro={}-{}", id, version));
+            }
+        }
+        if !qualifiers.is_empty() {
+            purl.push('?');
+            purl.push_str(&qualifiers.join("&"));
+        }
+        purl
+    }
+}
+
+/// Lists the packages installed in an image, as a CycloneDX or SPDX document
+///
+/// The image's layers are extracted (if they aren't already) and the filesystem they make up is
+/// searched for the databases of apk, dpkg, and rpm, and for what npm, pip, and gem install.
+/// Reading rpm's database takes the `rpm` binary, images with one are skipped with a warning
+/// when it isn't installed.
+///
+/// See: https://cyclonedx.org/docs/1.5/json/
+/// See: https://spdx.github.io/spdx-spec/v2.3/
+pub fn image_sbom(
+    store: &Store,
+    image: &ImageReference,
+    stored: &StoredImage,
+    format: SbomFormat,
+) -> Result<serde_json::Value> {
+    let manifest = store.image_manifest(stored)?;
+    let config = store.image_config(&manifest)?;
+
+    let _blobs = store.share_blobs()?;
+    let quiet = Progress::new(ProgressMode::Quiet);
+    let (distro, packages) = match store.image_snapshot(&manifest, &config, &quiet)? {
+        Some(root) => (read_distro(&root), find_packages(&root)?),
+        None => (Distro::default(), Vec::new()),
+    };
+
+    let name = image.to_string();
+    let created = format_timestamp(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    );
+    let uuid = document_uuid(&stored.manifest);
+    Ok(match format {
+        SbomFormat::CycloneDx => cyclonedx(&name, stored, &distro, &packages, created, uuid),
+        SbomFormat::Spdx => spdx(&name, stored, &distro, &packages, created, uuid),
+    })
+}
+
+fn cyclonedx(
+    name: &str,
+    stored: &StoredImage,
+    distro: &Distro,
+    packages: &[Package],
+    created: String,
+    uuid: String,
+) -> serde_json::Value {
+    let mut components = Vec::new();
+    if let Some(id) = &distro.id {
+        components.push(serde_json::json!({
+            "type": "operating-system",
+            "name": id,
+            "version": distro.version_id,
+        }));
+    }
+    for (i, package) in packages.iter().enumerate() {
+        // The same package can be installed in several places, so purls aren't unique
+        let mut component = serde_json::json!({
+            "type": "library",
+            "bom-ref": format!("{}-{}", package.ecosystem.purl_type(), i + 1),
+            "name": package.name,
+            "version": package.version,
+            "purl": package.purl(distro),
+            "properties": [
+                {
+                    "name": format!("{}:package:type", PROPERTY_NAMESPACE),
+                    "value": package.ecosystem.purl_type(),
+                },
+                {
+                    "name": format!("{}:location", PROPERTY_NAMESPACE),
+                    "value": package.location,
+                },
+            ],
+        });
+        if let Some(license) = &package.license {
+            component["licenses"] = match spdx_expression(license) {
+                true => serde_json::json!([{ "expression": license }]),
+                false => serde_json::json!([{ "license": { "name": license } }]),
+            };
+        }
+        components.push(component);
+    }
+
+    serde_json::json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", uuid),
+        "version": 1,
+        "metadata": {
+            "timestamp": created,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                }],
+            },
+            "component": {
+                "type": "container",
+                "bom-ref": stored.manifest,
+                "name": name,
+                "version": stored.digest,
+            },
+        },
+        "components": components,
+    })
+}
+
+fn spdx(
+    name: &str,
+    stored: &StoredImage,
+    distro: &Distro,
+    packages: &[Package],
+    created: String,
+    uuid: String,
+) -> serde_json::Value {
+    let image_id = "SPDXRef-Image";
+    let mut spdx_packages = vec![serde_json::json!({
+        "name": name,
+        "SPDXID": image_id,
+        "versionInfo": stored.digest,
+        "downloadLocation": "NOASSERTION",
+        "filesAnalyzed": false,
+        "primaryPackagePurpose": "CONTAINER",
+    })];
+    let mut relationships = vec![serde_json::json!({
+        "spdxElementId": "SPDXRef-DOCUMENT",
+        "relationshipType": "DESCRIBES",
+        "relatedSpdxElement": image_id,
+    })];
+    for (i, package) in packages.iter().enumerate() {
+        let id = format!(
+            "SPDXRef-Package-{}-{}",
+            package.ecosystem.purl_type(),
+            i + 1
+        );
+        // Anything that isn't an SPDX license expression would make the document invalid
+        let license = match &package.license {
+            Some(license) if spdx_expression(license) => license.as_str(),
+            _ => "NOASSERTION",
+        };
+        spdx_packages.push(serde_json::json!({
+            "name": package.name,
+            "SPDXID": id,
+            "versionInfo": package.version,
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+            "licenseConcluded": "NOASSERTION",
+            "licenseDeclared": license,
+            "sourceInfo": format!("found in {}", package.location),
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": package.purl(distro),
+            }],
+        }));
+        relationships.push(serde_json::json!({
+            "spdxElementId": image_id,
+            "relationshipType": "CONTAINS",
+            "relatedSpdxElement": id,
+        }));
+    }
+
+    serde_json::json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!(
+            "https://spdx.org/spdxdocs/{}-{}",
+            env!("CARGO_PKG_NAME"),
+            uuid
+        ),
+        "creationInfo": {
+            "created": created,
+            "creators": [format!("Tool: {}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": spdx_packages,
+        "relationships": relationships,
+    })
+}
+
+/// Whether a license is something like `MIT` or `GPL-2.0-or-later AND BSD-3-Clause`, rather
+/// than a free form name like `Apache License, Version 2.0`
+fn spdx_expression(license: &str) -> bool {
+    let id = r"\(?[A-Za-z0-9][A-Za-z0-9.\-]*\+?\)?";
+    Regex::new(&format!(r"^{id}(\s+(AND|OR|WITH)\s+{id})*$", id = id))
+        .unwrap()
+        .is_match(license)
+}
+
+/// A random looking version 4 UUID, different for every document
+fn document_uuid(seed: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let hash = sha256_digest(format!("{}{}{}", seed, nanos, process::id()).as_bytes());
+    let hex = hash.trim_start_matches("sha256:");
+    format!(
+        "{}-{}-4{}-{:x}{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[13..16],
+        (u8::from_str_radix(&hex[16..17], 16).unwrap_or_default() & 0x3) | 0x8,
+        &hex[17..20],
+        &hex[20..32]
+    )
+}
+
+fn read_distro(root: &Path) -> Distro {
+    let mut distro = Distro::default();
+    let contents = match fs::read_to_string(root.join("etc/os-release")) {
+        Ok(contents) => contents,
+        Err(_) => match fs::read_to_string(root.join("usr/lib/os-release")) {
+            Ok(contents) => contents,
+            Err(_) => return distro,
+        },
+    };
+    for line in contents.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().trim_matches(['"', '\'']).to_string();
+            match key.trim() {
+                "ID" => distro.id = Some(value),
+                "VERSION_ID" => distro.version_id = Some(value),
+                _ => {}
+            }
+        }
+    }
+    distro
+}
+
+fn find_packages(root: &Path) -> Result<Vec<Package>> {
+    let mut packages = Vec::new();
+    if let Some(contents) = read_optional(&root.join(APK_INSTALLED))? {
+        packages.extend(parse_apk(&contents));
+    }
+    if let Some(contents) = read_optional(&root.join(DPKG_STATUS))? {
+        packages.extend(parse_dpkg(&contents, DPKG_STATUS));
+    }
+    if let Ok(entries) = fs::read_dir(root.join(DPKG_STATUS_DIR)) {
+        for entry in entries.flatten() {
+            let location = format!(
+                "{}/{}",
+                DPKG_STATUS_DIR,
+                entry.file_name().to_string_lossy()
+            );
+            if let Some(contents) = read_optional(&entry.path())? {
+                packages.extend(parse_dpkg(&contents, &location));
+            }
+        }
+    }
+    if root.join(RPM_DB).is_dir() {
+        packages.extend(query_rpm(root));
+    }
+    walk(root, root, &mut packages)?;
+    Ok(packages)
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(String::from_utf8_lossy(&contents).into_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Tried to read {}", path.display())),
+    }
+}
+
+/// Blank line separated blocks of `K:value` lines, one for each package
+fn parse_apk(contents: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    for block in contents.split("\n\n") {
+        let mut package = Package {
+            ecosystem: Ecosystem::Apk,
+            name: String::new(),
+            version: String::new(),
+            arch: None,
+            license: None,
+            location: APK_INSTALLED.to_string(),
+        };
+        for line in block.lines() {
+            match line.split_once(':') {
+                Some(("P", name)) => package.name = name.to_string(),
+                Some(("V", version)) => package.version = version.to_string(),
+                Some(("A", arch)) => package.arch = Some(arch.to_string()),
+                Some(("L", license)) => package.license = Some(license.to_string()),
+                _ => {}
+            }
+        }
+        if !package.name.is_empty() {
+            packages.push(package);
+        }
+    }
+    packages
+}
+
+/// Blank line separated paragraphs of `Field: value`, including packages that were removed but
+/// not purged, which are skipped
+///
+/// See: https://man7.org/linux/man-pages/man5/deb-control.5.html
+fn parse_dpkg(contents: &str, location: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    for paragraph in contents.split("\n\n") {
+        let fields = |name: &str| {
+            paragraph.lines().find_map(|line| {
+                let (field, value) = line.split_once(':')?;
+                match field == name {
+                    true => Some(value.trim().to_string()),
+                    false => None,
+                }
+            })
+        };
+        let (name, version) = match (fields("Package"), fields("Version")) {
+            (Some(name), Some(version)) => (name, version),
+            _ => continue,
+        };
+        // Files in status.d don't have a status, they're all installed
+        if let Some(status) = fields("Status") {
+            if !status.ends_with(" installed") {
+                continue;
+            }
+        }
+        packages.push(Package {
+            ecosystem: Ecosystem::Deb,
+            name,
+            version,
+            arch: fields("Architecture"),
+            license: None,
+            location: location.to_string(),
+        });
+    }
+    packages
+}
+
+/// Asks `rpm` to read the image's database, since neither format is something to parse by hand
+fn query_rpm(root: &Path) -> Vec<Package> {
+    let output = process::Command::new("rpm")
+        .arg("--dbpath")
+        .arg(root.join(RPM_DB))
+        .args([
+            "-qa",
+            "--queryformat",
+            "%{NAME}\\t%{EPOCH}:%{VERSION}-%{RELEASE}\\t%{ARCH}\\t%{LICENSE}\\n",
+        ])
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            eprintln!(
+                "Warning: Couldn't read the image's rpm database, skipping it: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return Vec::new();
+        }
+        Err(e) => {
+            eprintln!(
+                "Warning: The image has an rpm database but rpm couldn't be run, skipping it: {}",
+                e
+            );
+            return Vec::new();
+        }
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let name = fields.next()?.to_string();
+            let version = fields.next()?;
+            let version = version.strip_prefix("(none):").unwrap_or(version);
+            let arch = fields.next().filter(|arch| *arch != "(none)");
+            let license = fields.next().filter(|license| *license != "(none)");
+            Some(Package {
+                ecosystem: Ecosystem::Rpm,
+                name,
+                version: version.to_string(),
+                arch: arch.map(str::to_string),
+                license: license.map(str::to_string),
+                location: RPM_DB.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Looks through every directory for what language package managers install, without following
+/// symlinks out of the image
+fn walk(root: &Path, directory: &Path, packages: &mut Vec<Package>) -> Result<()> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        // Directories we can't read, like ones only their owner can, don't stop the rest
+        Err(_) => return Ok(()),
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let location = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned();
+        if file_type.is_dir() {
+            // Python packages are in `<name>-<version>.dist-info/METADATA` (or egg-info)
+            if name.ends_with(".dist-info") || name.ends_with(".egg-info") {
+                for metadata in ["METADATA", "PKG-INFO"] {
+                    if let Some(contents) = read_optional(&path.join(metadata))? {
+                        let location = format!("{}/{}", location, metadata);
+                        packages.extend(parse_python(&contents, location));
+                        break;
+                    }
+                }
+                continue;
+            }
+            walk(root, &path, packages)?;
+        } else if file_type.is_file() {
+            let parent = directory
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned());
+            match name.as_str() {
+                "package.json" if in_node_modules(directory) => {
+                    if let Some(contents) = read_optional(&path)? {
+                        packages.extend(parse_npm(&contents, location));
+                    }
+                }
+                _ if name.ends_with(".gemspec") && parent.as_deref() == Some("specifications") => {
+                    packages.extend(parse_gemspec_name(&name, location));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a directory is a package under `node_modules`, either `node_modules/<name>` or
+/// `node_modules/@<scope>/<name>`
+fn in_node_modules(directory: &Path) -> bool {
+    let parent = directory.parent().and_then(Path::file_name);
+    let grandparent = directory
+        .parent()
+        .and_then(Path::parent)
+        .and_then(Path::file_name);
+    match parent.map(|p| p.to_string_lossy()) {
+        Some(p) if p == "node_modules" => true,
+        Some(p) if p.starts_with('@') => grandparent.is_some_and(|g| g == "node_modules"),
+        _ => false,
+    }
+}
+
+fn parse_npm(contents: &str, location: String) -> Option<Package> {
+    let manifest: serde_json::Value = serde_json::from_str(contents).ok()?;
+    let license = match manifest.get("license") {
+        Some(serde_json::Value::String(license)) => Some(license.clone()),
+        // The deprecated `{"type": "MIT", "url": ...}`
+        Some(license) => license
+            .get("type")
+            .and_then(|l| l.as_str())
+            .map(str::to_string),
+        None => None,
+    };
+    Some(Package {
+        ecosystem: Ecosystem::Npm,
+        name: manifest.get("name")?.as_str()?.to_string(),
+        version: manifest.get("version")?.as_str()?.to_string(),
+        arch: None,
+        license,
+        location,
+    })
+}
+
+/// The headers of Python's core metadata, which end at the first blank line
+///
+/// See: https://packaging.python.org/en/latest/specifications/core-metadata/
+fn parse_python(contents: &str, location: String) -> Option<Package> {
+    let headers = contents.split("\n\n").next().unwrap_or_default();
+    let header = |name: &str| {
+        headers.lines().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            match header.eq_ignore_ascii_case(name) {
+                true => Some(value.trim().to_string()),
+                false => None,
+            }
+        })
+    };
+    Some(Package {
+        ecosystem: Ecosystem::Pypi,
+        name: header("Name")?,
+        version: header("Version")?,
+        arch: None,
+        license: header("License-Expression")
+            .or_else(|| header("License"))
+            .filter(|license| !license.is_empty() && license != "UNKNOWN"),
+        location,
+    })
+}
+
+/// Gems are installed with a `specifications/<name>-<version>.gemspec`, and since the gemspec is
+/// Ruby its name is easier to go by than its contents
+fn parse_gemspec_name(file_name: &str, location: String) -> Option<Package> {
+    let stem = file_name.strip_suffix(".gemspec")?;
+    // Names can have dashes too (`net-http-0.4.1`), the version is after the last one followed by
+    // a digit
+    let split = stem
+        .rmatch_indices('-')
+        .find(|(i, _)| stem[i + 1..].starts_with(|c: char| c.is_ascii_digit()))?
+        .0;
+    Some(Package {
+        ecosystem: Ecosystem::Gem,
+        name: stem[..split].to_string(),
+        version: stem[split + 1..].to_string(),
+        arch: None,
+        license: None,
+        location,
+    })
+}
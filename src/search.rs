@@ -0,0 +1,107 @@
+use anyhow::{Context, Result};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::cli::{SearchFilter, SearchOptions};
+use crate::endpoint::Endpoint;
+use crate::errors::RegistryError;
+use crate::retry::send_with_retries;
+use crate::table::print_table;
+
+/// Docker Hub's v1 index, which is still where search lives
+static DOCKER_HUB_INDEX: &str = "index.docker.io";
+
+/// Descriptions longer than this are cut off unless `--no-trunc` is given, like the docker CLI
+const DESCRIPTION_WIDTH: usize = 45;
+
+/// A page of results from the search endpoint
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchResult {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub star_count: u64,
+    #[serde(default)]
+    pub is_official: bool,
+    #[serde(default)]
+    pub is_automated: bool,
+}
+
+impl SearchFilter {
+    fn matches(&self, result: &SearchResult) -> bool {
+        self.stars.map_or(true, |stars| result.star_count >= stars)
+            && self.is_official.map_or(true, |o| result.is_official == o)
+            && self.is_automated.map_or(true, |a| result.is_automated == a)
+    }
+}
+
+/// Searches Docker Hub for repositories matching a term
+///
+/// Filters are applied to the results Docker Hub returns, so fewer than `limit` results may be
+/// left over, which is what the docker daemon does too.
+///
+/// See: https://docs.docker.com/reference/cli/docker/search/
+pub fn search(options: &SearchOptions) -> Result<Vec<SearchResult>> {
+    let endpoint = Endpoint::new(DOCKER_HUB_INDEX, &options.config)?;
+    let client = endpoint.client()?;
+    let mut url = Url::parse(&endpoint.url("/v1/search"))?;
+    url.query_pairs_mut()
+        .append_pair("q", &options.term)
+        .append_pair("n", &options.limit.to_string());
+
+    let response = send_with_retries(&options.retry, "Searching Docker Hub", || {
+        Ok(client.get(url.clone()))
+    })
+    .with_context(|| format!("Tried to search Docker Hub for '{}'", options.term))?;
+    if !response.status().is_success() {
+        return Err(RegistryError::from_response(response))
+            .with_context(|| format!("Tried to search Docker Hub for '{}'", options.term));
+    }
+    let page: SearchResponse = response
+        .json()
+        .context("Tried to parse Docker Hub's search results")?;
+
+    Ok(page
+        .results
+        .into_iter()
+        .filter(|result| options.filter.matches(result))
+        .take(options.limit)
+        .collect())
+}
+
+/// Prints search results as a table like `docker search` does
+pub fn print_results(results: &[SearchResult], no_trunc: bool) {
+    let rows: Vec<Vec<String>> = results
+        .iter()
+        .map(|result| {
+            // Descriptions can contain newlines, which would break the table
+            let description = result.description.replace(['\n', '\r'], " ");
+            let description = match no_trunc || description.chars().count() <= DESCRIPTION_WIDTH {
+                true => description,
+                false => {
+                    let cut: String = description.chars().take(DESCRIPTION_WIDTH - 1).collect();
+                    format!("{}…", cut)
+                }
+            };
+            let flag = |set: bool| String::from(if set { "[OK]" } else { "" });
+            vec![
+                result.name.clone(),
+                description,
+                result.star_count.to_string(),
+                flag(result.is_official),
+                flag(result.is_automated),
+            ]
+        })
+        .collect();
+    print_table(
+        &["NAME", "DESCRIPTION", "STARS", "OFFICIAL", "AUTOMATED"],
+        &rows,
+    );
+}
@@ -0,0 +1,165 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tempfile::tempdir;
+
+use crate::auth::RepositoryAuth;
+use crate::base64;
+use crate::digest::{check_digest, sha256_digest};
+use crate::errors::{RegistryError, MANIFEST_UNKNOWN};
+use crate::manifest::Manifest;
+use crate::reference::ImageReference;
+use crate::registry::fetch_manifest;
+use crate::store::Store;
+
+/// Media type of the layers cosign stores its signed payloads in
+static COSIGN_SIMPLESIGNING: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+
+/// Annotation holding the base64 encoded signature over a layer's payload
+static COSIGN_SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// The payload cosign signs, which names the manifest digest the signature is for
+///
+/// See: https://github.com/containers/image/blob/main/docs/containers-signature.5.md#json-data-format
+#[derive(Debug, Deserialize)]
+struct SimpleSigning {
+    critical: SimpleSigningCritical,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleSigningCritical {
+    image: SimpleSigningImage,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleSigningImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+/// Checks that an image has a cosign signature for `digest` made with the key at `public_key`
+///
+/// Cosign pushes signatures to the image's repository under a tag derived from the digest they
+/// sign, as a manifest whose layers are signed payloads. Any one valid signature is enough.
+/// Signatures are checked with the `openssl` binary since none of our dependencies do ECDSA.
+///
+/// See: https://github.com/sigstore/cosign/blob/main/specs/SIGNATURE_SPEC.md
+pub fn verify_image(
+    image: &ImageReference,
+    auth: &RepositoryAuth,
+    digest: &str,
+    public_key: &Path,
+    store: &Store,
+) -> Result<()> {
+    let signature_tag = format!("{}.sig", digest.replace(':', "-"));
+    let signatures = ImageReference {
+        tag: Some(signature_tag.clone()),
+        digest: None,
+        ..image.clone()
+    };
+    let manifest = match fetch_manifest(&signatures, &signature_tag, auth, store) {
+        Ok((Manifest::Image(manifest), _, _)) => manifest,
+        Ok(_) => bail!(
+            "Signature {} for {} isn't an image manifest",
+            signature_tag,
+            image
+        ),
+        Err(e)
+            if e.chain().any(|cause| {
+                cause
+                    .downcast_ref::<RegistryError>()
+                    .is_some_and(|error| error.has_code(MANIFEST_UNKNOWN))
+            }) =>
+        {
+            bail!("Image {} ({}) is not signed", image, digest)
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Tried to fetch the signatures for {}", image))
+        }
+    };
+
+    let client = auth.endpoint().client()?;
+    let mut failures = Vec::new();
+    for layer in &manifest.layers {
+        if layer.media_type != COSIGN_SIMPLESIGNING {
+            continue;
+        }
+        let signature = match layer.annotations.get(COSIGN_SIGNATURE_ANNOTATION) {
+            Some(signature) => signature,
+            None => continue,
+        };
+        let response = auth.send("Fetching signature payload", || {
+            client.get(
+                auth.endpoint()
+                    .url(&format!("/v2/{}/blobs/{}", image.repository, layer.digest)),
+            )
+        })?;
+        if !response.status().is_success() {
+            return Err(RegistryError::from_response(response))
+                .with_context(|| format!("Tried to fetch signature payload {}", layer.digest));
+        }
+        let payload = response
+            .bytes()
+            .context("Tried to read signature payload")?;
+        check_digest(&sha256_digest(&payload), &layer.digest)?;
+
+        match check_signature(&payload, signature, digest, public_key) {
+            Ok(()) => return Ok(()),
+            Err(e) => failures.push(format!("{}: {:#}", layer.digest, e)),
+        }
+    }
+
+    if failures.is_empty() {
+        bail!("No cosign signatures found for {} ({})", image, digest);
+    }
+    bail!(
+        "No valid signature for {} ({}) from {}:\n  {}",
+        image,
+        digest,
+        public_key.display(),
+        failures.join("\n  ")
+    )
+}
+
+/// Checks one signed payload, which has to both name `digest` and verify against the key
+fn check_signature(payload: &[u8], signature: &str, digest: &str, public_key: &Path) -> Result<()> {
+    let signed: SimpleSigning =
+        serde_json::from_slice(payload).context("Tried to parse signature payload")?;
+    if signed.critical.image.docker_manifest_digest != digest {
+        bail!(
+            "signature is for {} instead",
+            signed.critical.image.docker_manifest_digest
+        );
+    }
+
+    let signature = base64::decode(signature).context("Tried to decode signature")?;
+    let dir = tempdir().context("Tried to create a directory for signature checking")?;
+    let payload_path = dir.path().join("payload");
+    let signature_path = dir.path().join("signature");
+    fs::write(&payload_path, payload)?;
+    fs::write(&signature_path, signature)?;
+
+    let output = Command::new("openssl")
+        .arg("dgst")
+        .arg("-sha256")
+        .arg("-verify")
+        .arg(public_key)
+        .arg("-signature")
+        .arg(&signature_path)
+        .arg(&payload_path)
+        .stdin(Stdio::null())
+        .output()
+        .context("Tried to run openssl to check the signature, is it installed?")?;
+    if !output.status.success() {
+        let reason = String::from_utf8_lossy(&output.stdout);
+        let details = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "signature doesn't verify ({} {})",
+            reason.trim(),
+            details.trim()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,1879 @@
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+
+use crate::cgroup::CGROUP_RECORD;
+use crate::compression::{Compression, Decoder};
+use crate::config::Config;
+use crate::containers::CONTAINER_RECORD;
+use crate::digest::{check_digest, sha256_digest, validate_digest, HashingReader};
+use crate::driver::storage_driver;
+use crate::image::ImageConfig;
+use crate::manifest::{Descriptor, ImageManifest, Manifest, ESTARGZ_METADATA, ESTARGZ_TOC_DIGEST};
+use crate::platform::Platform;
+use crate::progress::{Phase, Progress};
+use crate::quota::{limit_storage, remove_storage_limit};
+use crate::reference::ImageReference;
+use crate::rootfs::{copy_layer, link_overlay_layer, FileCopy};
+use crate::table::parse_size;
+use crate::untar::{unpack_entry, Skipped, UnpackOptions};
+use crate::volumes::remove_volume;
+
+/// Overrides where the store is kept
+pub static DATA_ROOT_ENV: &str = "MINIDOCKER_ROOT";
+
+/// Caps how much space the store takes up, overriding `daemon.json`'s `max-cache-size`
+pub static MAX_CACHE_SIZE_ENV: &str = "MINIDOCKER_MAX_CACHE_SIZE";
+
+/// What zstd frames start with
+///
+/// See: https://datatracker.ietf.org/doc/html/rfc8878#section-3.1.1
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Colon separated stores to copy blobs and snapshots from, overriding `daemon.json`'s
+/// `additional-stores`
+pub static ADDITIONAL_STORES_ENV: &str = "MINIDOCKER_ADDITIONAL_STORES";
+
+/// What a reference resolved to when it was pulled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredImage {
+    /// The digest the reference resolved to, which is the manifest list's for multi-arch images
+    pub digest: String,
+    /// The digest of the platform specific manifest that was pulled
+    pub manifest: String,
+    pub platform: String,
+}
+
+/// Where a layer blob that `store recompress` rewrote with zstd came from, kept in
+/// `recompressed/sha256/<digest>`
+///
+/// The blob is still stored under its original digest (which manifests refer to it by), so this
+/// is what it can be checked against now, and how to rebuild what it was.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecompressedBlob {
+    /// The digest of the zstd data that replaced the original
+    pub zstd_digest: String,
+    /// The original blob's size
+    pub size: u64,
+    /// The flate2 level gzipping the layer's tar again makes the original at, byte for byte,
+    /// none for blobs recompressed before that was checked, whose original is gone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gzip_level: Option<u32>,
+}
+
+/// What a stored image's manifest is made of, so it can be answered without reading blobs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageRecord {
+    pub config: String,
+    pub layers: Vec<String>,
+    /// When the image was last pulled or run, in seconds since the epoch, which is what
+    /// `max-cache-size` evicts by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<u64>,
+}
+
+impl ImageRecord {
+    pub fn from_manifest(manifest: &ImageManifest) -> ImageRecord {
+        ImageRecord {
+            config: manifest.config.digest.clone(),
+            layers: manifest.layers.iter().map(|l| l.digest.clone()).collect(),
+            last_used: None,
+        }
+    }
+}
+
+/// The images in the store, kept in `repositories.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Repositories {
+    /// What each reference resolved to, by `registry/repository` and then by tag or digest
+    #[serde(default)]
+    pub repositories: BTreeMap<String, BTreeMap<String, StoredImage>>,
+    /// What each stored manifest is made of, by manifest digest
+    #[serde(default)]
+    pub images: BTreeMap<String, ImageRecord>,
+}
+
+impl Repositories {
+    /// Digests of every blob a stored reference needs, manifest lists included
+    pub fn in_use(&self) -> HashSet<String> {
+        let mut in_use = HashSet::new();
+        for stored in self.repositories.values().flat_map(|r| r.values()) {
+            in_use.insert(stored.digest.clone());
+            in_use.insert(stored.manifest.clone());
+            if let Some(record) = self.images.get(&stored.manifest) {
+                in_use.insert(record.config.clone());
+                in_use.extend(record.layers.iter().cloned());
+            }
+        }
+        in_use
+    }
+
+    /// Digests of every blob a stored reference or a container's image needs
+    ///
+    /// The overlay driver mounts layers straight from the store, so they can't go anywhere
+    /// while a container is using them, even once nothing refers to its image.
+    pub fn in_use_or_by_containers(&self, containers: &HashSet<String>) -> HashSet<String> {
+        let mut in_use = self.in_use();
+        for manifest in containers {
+            in_use.insert(manifest.clone());
+            if let Some(record) = self.images.get(manifest) {
+                in_use.insert(record.config.clone());
+                in_use.extend(record.layers.iter().cloned());
+            }
+        }
+        in_use
+    }
+}
+
+/// What `prune` removed from the store
+#[derive(Debug, Default)]
+pub struct Pruned {
+    pub blobs: Vec<String>,
+    /// Digests of the layer blobs whose extracted layers were removed
+    pub layers: Vec<String>,
+    /// Chain IDs of the snapshots that were removed
+    pub snapshots: Vec<String>,
+    pub reclaimed: u64,
+}
+
+/// A manifest we've fetched before, along with what the registry called that version of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedManifest {
+    pub etag: String,
+    pub digest: String,
+    pub content_type: Option<String>,
+}
+
+/// Pulled images, kept under `$XDG_DATA_HOME/minidocker` (or `~/.local/share/minidocker`)
+/// unless `--data-root`, `MINIDOCKER_ROOT`, or `data-root` in `daemon.json` put it elsewhere
+///
+/// Blobs (manifests, configs, and layers) are stored by digest under `blobs/sha256/` and survive
+/// across runs, and `downloads/` holds partial blobs so interrupted pulls can be resumed.
+/// `repositories.json` records what each reference resolved to and what every stored manifest is
+/// made of, it replaced the `refs/` directory older versions kept.
+/// `manifests/<host>/<repository>/<tag or digest>` remembers the `ETag` each manifest was served
+/// with, so fetching it again can be a conditional request. `layers/sha256/` holds each layer
+/// extracted on its own, keyed by the layer blob's digest, and `snapshots/sha256/` holds stacks
+/// of layers flattened together, keyed by chain ID, so runs only have to copy them.
+/// `containers/` holds each container's rootfs and record, from when it's run until it's removed
+/// with `rm` (or when it exits, with `run --rm`).
+///
+/// Several processes can share a store. Files are written next to where they go and renamed
+/// into place, and `locks/` holds the files they `flock` so only one of them downloads or
+/// extracts a given blob, or changes `repositories.json`, at a time. Pulls and runs hold a shared
+/// lock on `locks/blobs` while they use blobs, which `rmi` and `prune` wait for before deleting
+/// any.
+///
+/// Additional stores (like an NFS share or a CI cache volume that other machines pull into) are
+/// only ever read. Blobs, images, and snapshots missing from this store are copied from them the
+/// first time they're needed, before anything is downloaded.
+pub struct Store {
+    root: PathBuf,
+    /// Roots of the read-only stores to copy from, in the order they're tried
+    additional: Vec<PathBuf>,
+    /// Blobs being downloaded right now, so concurrent pulls sharing a layer download it once
+    downloading: Mutex<HashSet<String>>,
+    download_finished: Condvar,
+}
+
+/// Where the store is, `MINIDOCKER_ROOT` (which `--data-root` sets) first and then
+/// `daemon.json`'s `data-root`, falling back to `$XDG_DATA_HOME/minidocker`
+pub fn data_root() -> Result<PathBuf> {
+    if let Some(root) = std::env::var_os(DATA_ROOT_ENV).filter(|root| !root.is_empty()) {
+        return Ok(PathBuf::from(root));
+    }
+    if let Some(root) = Config::load()?.data_root {
+        return Ok(root);
+    }
+    let data_home = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            std::env::var_os("HOME").context("Neither XDG_DATA_HOME nor HOME are set")?,
+        )
+        .join(".local")
+        .join("share"),
+    };
+    Ok(data_home.join("minidocker"))
+}
+
+/// The read-only stores to copy from, `MINIDOCKER_ADDITIONAL_STORES` or else `daemon.json`'s
+/// `additional-stores`
+fn additional_stores() -> Result<Vec<PathBuf>> {
+    match std::env::var_os(ADDITIONAL_STORES_ENV) {
+        Some(roots) => Ok(std::env::split_paths(&roots)
+            .filter(|root| !root.as_os_str().is_empty())
+            .collect()),
+        None => Ok(Config::load()?.additional_stores),
+    }
+}
+
+/// Reads the `repositories.json` of the store at `root`
+fn read_repositories(root: &Path) -> Result<Repositories> {
+    let path = root.join("repositories.json");
+    match fs::read_to_string(&path) {
+        Ok(raw_data) => serde_json::from_str(&raw_data)
+            .with_context(|| format!("Tried to parse {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Repositories::default()),
+        Err(e) => Err(e).with_context(|| format!("Tried to read {}", path.display())),
+    }
+}
+
+/// How `repositories.json` names a repository, `registry/repository`
+fn repository_key(registry: &str, repository: &str) -> String {
+    format!("{}/{}", registry, repository)
+}
+
+/// Keeps other threads and processes from downloading the same blob until it's dropped
+pub struct DownloadClaim<'a> {
+    store: &'a Store,
+    digest: String,
+    /// The blob's lock, for other processes, closing it releases the lock
+    lock: Option<File>,
+}
+
+impl Drop for DownloadClaim<'_> {
+    fn drop(&mut self) {
+        let mut downloading = self
+            .store
+            .downloading
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        downloading.remove(&self.digest);
+        self.store.download_finished.notify_all();
+    }
+}
+
+impl Store {
+    pub fn open() -> Result<Store> {
+        let root = data_root()?;
+        // Runs pivot into paths under the root, which have to keep working wherever they are
+        let root = match root.is_absolute() {
+            true => root,
+            false => std::env::current_dir()
+                .context("Tried to find the current directory")?
+                .join(root),
+        };
+        let store = Store {
+            root,
+            additional: additional_stores()?,
+            downloading: Mutex::new(HashSet::new()),
+            download_finished: Condvar::new(),
+        };
+        for directory in [
+            "blobs/sha256",
+            "layers/sha256",
+            "snapshots/sha256",
+            "btrfs/sha256",
+            "overlay/sha256",
+            "recompressed/sha256",
+            "downloads",
+            "manifests",
+            "locks",
+            "containers",
+            "disks",
+            "volumes",
+        ] {
+            let directory = store.root.join(directory);
+            fs::create_dir_all(&directory)
+                .with_context(|| format!("Tried to create {}", directory.display()))?;
+        }
+        store.migrate_refs()?;
+        store.remove_orphaned_builds()?;
+        store.remove_orphaned_containers()?;
+        Ok(store)
+    }
+
+    /// The directory everything is kept under
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        validate_digest(digest)?;
+        Ok(self
+            .root
+            .join("blobs/sha256")
+            .join(digest.trim_start_matches("sha256:")))
+    }
+
+    pub fn has_blob(&self, digest: &str) -> Result<bool> {
+        Ok(self.blob_path(digest)?.exists())
+    }
+
+    /// Opens a blob to read it as it was fetched, which recompressed blobs are rebuilt into
+    pub fn open_blob(&self, digest: &str) -> Result<File> {
+        match self.recompressed(digest)? {
+            Some(record) => self.rebuild_blob(digest, &record),
+            None => self.open_raw_blob(digest),
+        }
+    }
+
+    /// Gzips a recompressed blob's tar again into an unnamed file in `downloads`, which
+    /// recompressing made sure comes out as the original did
+    fn rebuild_blob(&self, digest: &str, record: &RecompressedBlob) -> Result<File> {
+        let level = match record.gzip_level {
+            Some(level) => level,
+            None => bail!(
+                "Blob {} was recompressed with zstd, so the bytes its digest is of are gone. \
+                 Remove the image and pull it again to get them back.",
+                digest
+            ),
+        };
+        let downloads = self.root.join("downloads");
+        let file = tempfile::tempfile_in(&downloads)
+            .with_context(|| format!("Tried to create a file in {}", downloads.display()))?;
+        let mut decoder = Decoder::new(Compression::Zstd, self.open_raw_blob(digest)?)?;
+        let mut encoder = GzEncoder::new(&file, flate2::Compression::new(level));
+        io::copy(&mut decoder, &mut encoder)
+            .and_then(|_| encoder.finish())
+            .with_context(|| format!("Tried to rebuild blob {}", digest))?;
+        decoder.finish()?;
+
+        (&file).seek(SeekFrom::Start(0))?;
+        let mut reader = HashingReader::new(&file);
+        io::copy(&mut reader, &mut io::sink())?;
+        check_digest(&reader.finalize(), digest)
+            .with_context(|| format!("Rebuilt blob {} doesn't match its digest", digest))?;
+        (&file).seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+
+    fn open_raw_blob(&self, digest: &str) -> Result<File> {
+        let path = self.blob_path(digest)?;
+        File::open(&path).with_context(|| format!("Tried to open blob {}", path.display()))
+    }
+
+    /// Opens a layer blob and decompresses it, whether it's as it was fetched or was
+    /// recompressed since
+    pub fn open_layer(&self, layer: &Descriptor) -> Result<Decoder> {
+        let compression = match self.recompressed(&layer.digest)? {
+            Some(_) => Compression::Zstd,
+            None => Compression::from_media_type(&layer.media_type)?,
+        };
+        Decoder::new(compression, self.open_raw_blob(&layer.digest)?)
+    }
+
+    fn recompressed_path(&self, digest: &str) -> Result<PathBuf> {
+        validate_digest(digest)?;
+        Ok(self
+            .root
+            .join("recompressed/sha256")
+            .join(digest.trim_start_matches("sha256:")))
+    }
+
+    /// What a blob was recompressed from, if it was
+    ///
+    /// A record only counts while the blob is still zstd, so it doesn't matter if a blob was
+    /// removed and fetched again without the record being cleaned up yet.
+    pub fn recompressed(&self, digest: &str) -> Result<Option<RecompressedBlob>> {
+        let path = self.recompressed_path(digest)?;
+        let record: RecompressedBlob = match fs::read(&path) {
+            Ok(raw_record) => serde_json::from_slice(&raw_record)
+                .with_context(|| format!("Tried to parse {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Tried to read {}", path.display())),
+        };
+        let mut magic = [0; 4];
+        let is_zstd = match self.open_raw_blob(digest)?.read_exact(&mut magic) {
+            Ok(()) => magic == ZSTD_MAGIC,
+            Err(_) => false,
+        };
+        Ok(is_zstd.then_some(record))
+    }
+
+    /// Swaps a blob for a recompressed copy of it, which has to decompress to the same tar
+    pub fn insert_recompressed(
+        &self,
+        digest: &str,
+        zstd_path: &Path,
+        record: &RecompressedBlob,
+    ) -> Result<()> {
+        // The record goes first, a zstd blob without one would look like it's corrupted
+        write_atomically(
+            &self.recompressed_path(digest)?,
+            &serde_json::to_vec(record)?,
+        )?;
+        let path = self.blob_path(digest)?;
+        fs::rename(zstd_path, &path)
+            .with_context(|| format!("Tried to move recompressed blob into {}", path.display()))
+    }
+
+    /// Removes records of recompressed blobs that aren't anymore, or are gone entirely
+    fn prune_recompressed(&self) -> Result<()> {
+        let directory = self.root.join("recompressed/sha256");
+        let entries = fs::read_dir(&directory)
+            .with_context(|| format!("Tried to list {}", directory.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let digest = format!("sha256:{}", path.file_name().unwrap().to_string_lossy());
+            let stale = match self.has_blob(&digest)? {
+                true => self.recompressed(&digest)?.is_none(),
+                false => true,
+            };
+            if stale {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Tried to remove {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where a blob is downloaded to before it's been verified
+    pub fn partial_blob_path(&self, digest: &str) -> Result<PathBuf> {
+        validate_digest(digest)?;
+        Ok(self.root.join("downloads").join(digest.replace(':', "-")))
+    }
+
+    /// Claims a blob for downloading, waiting for any other thread or process already
+    /// downloading it
+    ///
+    /// Returns `None` if the blob turned up in the store while we waited.
+    pub fn claim_download(&self, digest: &str) -> Result<Option<DownloadClaim<'_>>> {
+        let mut downloading = self.downloading.lock().unwrap_or_else(|e| e.into_inner());
+        while downloading.contains(digest) {
+            downloading = self
+                .download_finished
+                .wait(downloading)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        if self.has_blob(digest)? {
+            return Ok(None);
+        }
+        downloading.insert(digest.to_string());
+        drop(downloading);
+
+        // Other threads wait on the claim, so they don't pile up on the lock as well
+        let mut claim = DownloadClaim {
+            store: self,
+            digest: digest.to_string(),
+            lock: None,
+        };
+        claim.lock = Some(self.lock(&digest.replace(':', "-"), libc::LOCK_EX)?);
+        if self.has_blob(digest)? {
+            return Ok(None);
+        }
+        Ok(Some(claim))
+    }
+
+    /// Keeps `rmi` and `prune` in any process from deleting blobs until the returned lock is
+    /// dropped
+    pub fn share_blobs(&self) -> Result<File> {
+        self.lock("blobs", libc::LOCK_SH)
+    }
+
+    /// Waits until nobody is using blobs, extracted layers, or snapshots, then keeps everybody
+    /// else from doing so until the returned lock is dropped
+    pub fn lock_blobs(&self) -> Result<File> {
+        self.lock("blobs", libc::LOCK_EX)
+    }
+
+    /// Where a named volume is kept, its contents are under `_data` like docker's
+    pub fn volume_path(&self, name: &str) -> Result<PathBuf> {
+        validate_volume_name(name)?;
+        Ok(self.root.join("volumes").join(name))
+    }
+
+    /// Keeps volumes from being created or removed by anyone else until the returned lock is
+    /// dropped
+    pub fn lock_volumes(&self) -> Result<File> {
+        self.lock("volumes", libc::LOCK_EX)
+    }
+
+    /// Marks a volume as in use by a container until the returned lock is dropped
+    pub fn share_volume(&self, name: &str) -> Result<File> {
+        self.lock(&volume_lock(name), libc::LOCK_SH)
+    }
+
+    /// Whether a running container has the volume mounted
+    pub fn volume_in_use(&self, name: &str) -> bool {
+        self.lock(&volume_lock(name), libc::LOCK_EX | libc::LOCK_NB)
+            .is_err()
+    }
+
+    /// Removes a volume's lock file once the volume is gone, call with `lock_volumes` held
+    pub fn forget_volume(&self, name: &str) -> Result<()> {
+        let path = self.root.join("locks").join(volume_lock(name));
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Tried to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Blocks until `operation` (`LOCK_SH` or `LOCK_EX`) can be taken on `locks/<name>`,
+    /// returning the file that holds it
+    fn lock(&self, name: &str, operation: libc::c_int) -> Result<File> {
+        let path = self.root.join("locks").join(name);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Tried to open {}", path.display()))?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(file);
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error).with_context(|| format!("Tried to lock {}", path.display()));
+            }
+        }
+    }
+
+    /// Moves a verified download into place
+    pub fn insert_blob(&self, digest: &str, verified_path: &Path) -> Result<()> {
+        let path = self.blob_path(digest)?;
+        fs::rename(verified_path, &path)
+            .with_context(|| format!("Tried to move blob into {}", path.display()))
+    }
+
+    /// Hardlinks a verified file into place, leaving it where it was too
+    pub fn link_blob(&self, digest: &str, verified_path: &Path) -> Result<()> {
+        let path = self.blob_path(digest)?;
+        match fs::hard_link(verified_path, &path) {
+            Ok(()) => Ok(()),
+            // Someone else stored the same blob in the meantime
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("Tried to link blob into {}", path.display())),
+        }
+    }
+
+    /// Stores a blob we already have in memory, like a manifest, whose digest was checked
+    pub fn write_blob(&self, digest: &str, data: &[u8]) -> Result<()> {
+        write_atomically(&self.blob_path(digest)?, data)
+    }
+
+    /// Where something about `registry/repository:reference` is kept under `directory`
+    fn reference_path(
+        &self,
+        directory: &str,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> Result<PathBuf> {
+        let traverses = [registry]
+            .into_iter()
+            .chain(repository.split('/'))
+            .chain([reference])
+            .any(|component| component == "." || component == ".." || component.contains('/'));
+        if traverses {
+            bail!(
+                "Image reference {}/{}:{} can't be stored",
+                registry,
+                repository,
+                reference
+            );
+        }
+        Ok(self
+            .root
+            .join(directory)
+            .join(registry)
+            .join(repository)
+            .join(reference))
+    }
+
+    /// Looks up the last version of a manifest `host` served for a tag or digest, as long as we
+    /// still have its blob
+    pub fn cached_manifest(
+        &self,
+        host: &str,
+        repository: &str,
+        reference: &str,
+    ) -> Result<Option<CachedManifest>> {
+        let path = self.reference_path("manifests", host, repository, reference)?;
+        let raw_data = match fs::read_to_string(&path) {
+            Ok(raw_data) => raw_data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Tried to read {}", path.display())),
+        };
+        // A broken cache entry only costs us a full request
+        let cached: CachedManifest = match serde_json::from_str(&raw_data) {
+            Ok(cached) => cached,
+            Err(_) => return Ok(None),
+        };
+        if !self.has_blob(&cached.digest)? {
+            return Ok(None);
+        }
+        Ok(Some(cached))
+    }
+
+    /// Stores a verified manifest and remembers the `ETag` it was served with
+    pub fn cache_manifest(
+        &self,
+        host: &str,
+        repository: &str,
+        reference: &str,
+        cached: &CachedManifest,
+        data: &[u8],
+    ) -> Result<()> {
+        self.write_blob(&cached.digest, data)?;
+        let path = self.reference_path("manifests", host, repository, reference)?;
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Tried to create {}", parent.display()))?;
+        let raw_data = serde_json::to_string(cached)?;
+        write_atomically(&path, raw_data.as_bytes())
+    }
+
+    /// Looks up what a reference resolved to the last time it was pulled for `platform`
+    pub fn resolve(
+        &self,
+        image: &ImageReference,
+        platform: &Platform,
+    ) -> Result<Option<StoredImage>> {
+        // Only the latest pull of a reference is remembered, possibly for another platform
+        match self
+            .lookup(image)?
+            .filter(|stored| stored.platform == platform.to_string())
+        {
+            Some(stored) => Ok(Some(stored)),
+            None => self.resolve_additional(image, platform),
+        }
+    }
+
+    /// Copies an image from the first additional store that has it for `platform`, and tags it
+    /// here too
+    fn resolve_additional(
+        &self,
+        image: &ImageReference,
+        platform: &Platform,
+    ) -> Result<Option<StoredImage>> {
+        let _blobs = self.share_blobs()?;
+        for root in &self.additional {
+            let stored = match read_repositories(root) {
+                Ok(mut repositories) => repositories
+                    .repositories
+                    .remove(&repository_key(&image.registry, &image.repository))
+                    .and_then(|mut references| references.remove(image.manifest_reference()))
+                    .filter(|stored| stored.platform == platform.to_string()),
+                Err(e) => {
+                    eprintln!("Warning: skipping additional store: {:#}", e);
+                    None
+                }
+            };
+            let stored = match stored {
+                Some(stored) => stored,
+                None => continue,
+            };
+
+            let copied = (|| -> Result<bool> {
+                if !self.copy_from_additional(&stored.manifest)? {
+                    return Ok(false);
+                }
+                let manifest = self.image_manifest(&stored)?;
+                for blob in [&manifest.config]
+                    .into_iter()
+                    .chain(manifest.fetchable_layers())
+                {
+                    if !self.copy_from_additional(&blob.digest)? {
+                        return Ok(false);
+                    }
+                }
+                // Manifest lists are nice to have, nothing needs them to run
+                if stored.digest != stored.manifest {
+                    self.copy_from_additional(&stored.digest)?;
+                }
+                Ok(true)
+            })()
+            .with_context(|| format!("Tried to copy {} from {}", image, root.display()))?;
+            if copied {
+                self.tag(image, &stored)?;
+                return Ok(Some(stored));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copies a blob into the store from the first additional store that has it, returning
+    /// whether one did
+    ///
+    /// Blobs are checked against their digest on the way in, one that doesn't match (or can't
+    /// be read) is skipped with a warning rather than failing whatever needed it.
+    pub fn copy_from_additional(&self, digest: &str) -> Result<bool> {
+        if self.has_blob(digest)? {
+            return Ok(true);
+        }
+        let hex = self.blob_path(digest)?.file_name().unwrap().to_os_string();
+        for root in &self.additional {
+            let source = root.join("blobs/sha256").join(&hex);
+            let file = match File::open(&source) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    eprintln!("Warning: couldn't open {}: {}", source.display(), e);
+                    continue;
+                }
+            };
+            match self.copy_blob(file, digest) {
+                Ok(()) => return Ok(true),
+                Err(e) => eprintln!("Warning: couldn't copy {}: {:#}", source.display(), e),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Copies a blob into the store from anywhere, checking it against its digest on the way
+    pub fn copy_blob(&self, source: impl io::Read, digest: &str) -> Result<()> {
+        let downloads = self.root.join("downloads");
+        let mut file = NamedTempFile::new_in(&downloads)
+            .with_context(|| format!("Tried to create a file in {}", downloads.display()))?;
+        let mut reader = HashingReader::new(source);
+        io::copy(&mut reader, &mut file).with_context(|| format!("Tried to copy {}", digest))?;
+        check_digest(&reader.finalize(), digest)?;
+        self.link_blob(digest, file.path())
+    }
+
+    /// Looks up what a reference resolved to the last time it was pulled, whatever the platform
+    pub fn lookup(&self, image: &ImageReference) -> Result<Option<StoredImage>> {
+        let stored = self
+            .repositories()?
+            .repositories
+            .remove(&repository_key(&image.registry, &image.repository))
+            .and_then(|mut references| references.remove(image.manifest_reference()));
+        match stored {
+            Some(stored) if self.has_blob(&stored.manifest)? => Ok(Some(stored)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Every image stored for a registry, by repository
+    pub fn registry_images(&self, registry: &str) -> Result<Vec<(String, StoredImage)>> {
+        let prefix = format!("{}/", registry);
+        let mut images = Vec::new();
+        for (name, references) in self.repositories()?.repositories {
+            if let Some(repository) = name.strip_prefix(&prefix) {
+                images.extend(
+                    references
+                        .into_values()
+                        .map(|stored| (repository.to_string(), stored)),
+                );
+            }
+        }
+        Ok(images)
+    }
+
+    /// Records what a reference resolved to, along with what its manifest is made of
+    pub fn tag(&self, image: &ImageReference, stored: &StoredImage) -> Result<()> {
+        let manifest = self.image_manifest(stored)?;
+        let mut record = ImageRecord::from_manifest(&manifest);
+        record.last_used = Some(now());
+        self.update_repositories(|repositories| {
+            repositories
+                .repositories
+                .entry(repository_key(&image.registry, &image.repository))
+                .or_default()
+                .insert(image.manifest_reference().to_string(), stored.clone());
+            repositories.images.insert(stored.manifest.clone(), record);
+        })
+    }
+
+    /// Points another reference at a stored image, like `docker tag`
+    ///
+    /// A reference that already pointed at a different image is untagged first, so that image's
+    /// blobs are removed if nothing else needs them. The image itself can't go away while it's
+    /// being tagged, since whatever it was found by still needs all of its blobs.
+    pub fn alias(&self, image: &ImageReference, stored: &StoredImage) -> Result<()> {
+        if let Some(previous) = self.lookup(image)? {
+            if previous.manifest != stored.manifest {
+                self.untag(image)?;
+            }
+        }
+        self.tag(image, stored)
+    }
+
+    /// Records that an image was just used, so it's the last to be evicted
+    pub fn mark_used(&self, stored: &StoredImage) -> Result<()> {
+        self.update_repositories(|repositories| {
+            if let Some(record) = repositories.images.get_mut(&stored.manifest) {
+                record.last_used = Some(now());
+            }
+        })
+    }
+
+    /// Looks up an image by reference or, if no reference by that name is stored, by ID
+    pub fn find(&self, name: &str) -> Result<Option<(ImageReference, StoredImage)>> {
+        if let Ok(image) = ImageReference::parse(name) {
+            if let Some(stored) = self.lookup(&image)? {
+                return Ok(Some((image, stored)));
+            }
+        }
+        for image in self.references_to(name)? {
+            if let Some(stored) = self.lookup(&image)? {
+                return Ok(Some((image, stored)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every reference to the image with this ID, which is its config's digest and may be
+    /// shortened to a prefix of its hex like `docker images` shows it
+    pub fn references_to(&self, id: &str) -> Result<Vec<ImageReference>> {
+        let prefix = id.trim_start_matches("sha256:");
+        if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(Vec::new());
+        }
+        self.references_matching(|_, record| {
+            record.is_some_and(|record| {
+                record
+                    .config
+                    .trim_start_matches("sha256:")
+                    .starts_with(prefix)
+            })
+        })
+    }
+
+    /// Every reference whose stored image (and its record, if there is one) `matches`
+    fn references_matching(
+        &self,
+        matches: impl Fn(&StoredImage, Option<&ImageRecord>) -> bool,
+    ) -> Result<Vec<ImageReference>> {
+        let repositories = self.repositories()?;
+        let mut references = Vec::new();
+        for (name, stored_references) in &repositories.repositories {
+            let (registry, repository) = match name.split_once('/') {
+                Some(split) => split,
+                None => continue,
+            };
+            for (reference, stored) in stored_references {
+                if !matches(stored, repositories.images.get(&stored.manifest)) {
+                    continue;
+                }
+                let is_digest = reference.starts_with("sha256:");
+                references.push(ImageReference {
+                    registry: registry.to_string(),
+                    repository: repository.to_string(),
+                    tag: (!is_digest).then(|| reference.clone()),
+                    digest: is_digest.then(|| reference.clone()),
+                });
+            }
+        }
+        Ok(references)
+    }
+
+    /// Forgets what a reference resolved to, then deletes every blob, extracted layer, and
+    /// snapshot that only it needed, returning the blobs' digests
+    ///
+    /// Blobs still needed by any other reference are kept, so removing one of several tags for
+    /// the same image only removes the tag.
+    pub fn untag(&self, image: &ImageReference) -> Result<Vec<String>> {
+        let _blobs = self.lock("blobs", libc::LOCK_EX)?;
+        let containers = self.container_images()?;
+        let key = repository_key(&image.registry, &image.repository);
+        let mut removed = None;
+        let mut unused = Vec::new();
+        self.update_repositories(|repositories| {
+            let references = match repositories.repositories.get_mut(&key) {
+                Some(references) => references,
+                None => return,
+            };
+            let stored = match references.remove(image.manifest_reference()) {
+                Some(stored) => stored,
+                None => return,
+            };
+            if references.is_empty() {
+                repositories.repositories.remove(&key);
+            }
+
+            let in_use = repositories.in_use_or_by_containers(&containers);
+            let mut candidates = vec![stored.digest.clone(), stored.manifest.clone()];
+            if let Some(record) = repositories.images.get(&stored.manifest) {
+                candidates.push(record.config.clone());
+                candidates.extend(record.layers.iter().cloned());
+            }
+            if !in_use.contains(&stored.manifest) {
+                repositories.images.remove(&stored.manifest);
+            }
+            for digest in candidates {
+                if !in_use.contains(&digest) && !unused.contains(&digest) {
+                    unused.push(digest);
+                }
+            }
+            removed = Some(stored);
+        })?;
+        if removed.is_none() {
+            bail!("No such image: {}", image);
+        }
+
+        self.remove_unused_snapshots()?;
+        let mut deleted = Vec::new();
+        for digest in unused {
+            for layer_path in [self.layer_path(&digest)?, self.overlay_layer_path(&digest)?] {
+                if layer_path.is_dir() {
+                    fs::remove_dir_all(&layer_path)
+                        .with_context(|| format!("Tried to remove {}", layer_path.display()))?;
+                }
+            }
+            let blob_path = self.blob_path(&digest)?;
+            match fs::remove_file(&blob_path) {
+                Ok(()) => deleted.push(digest),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Tried to remove {}", blob_path.display()))
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// How much space blobs, extracted layers, and snapshots take up, counting files that are
+    /// hardlinked between them once
+    pub fn size(&self) -> Result<u64> {
+        let mut seen = HashSet::new();
+        let mut size = 0;
+        for directory in [
+            "blobs/sha256",
+            "layers/sha256",
+            "overlay/sha256",
+            "snapshots/sha256",
+        ] {
+            size += unique_disk_usage(&self.root.join(directory), &mut seen)?;
+        }
+        Ok(size)
+    }
+
+    /// Removes the least recently used images until the store is no bigger than `max_size`,
+    /// returning the references that were removed
+    ///
+    /// Images that containers were started from are never evicted, even if that leaves
+    /// the store over the limit.
+    pub fn evict(&self, max_size: u64) -> Result<Vec<ImageReference>> {
+        let mut evicted = Vec::new();
+        if self.size()? <= max_size {
+            return Ok(evicted);
+        }
+        let containers = self.container_images()?;
+        let mut candidates: Vec<(u64, String)> = self
+            .repositories()?
+            .images
+            .into_iter()
+            .filter(|(manifest, _)| !containers.contains(manifest))
+            .map(|(manifest, record)| (record.last_used.unwrap_or(0), manifest))
+            .collect();
+        candidates.sort();
+
+        for (_, manifest) in candidates {
+            if self.size()? <= max_size {
+                break;
+            }
+            for reference in self.references_matching(|stored, _| stored.manifest == manifest)? {
+                self.untag(&reference)?;
+                evicted.push(reference);
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// Manifest digests of the images containers were started from, running or kept since
+    fn container_images(&self) -> Result<HashSet<String>> {
+        let locks = self.root.join("locks");
+        let entries =
+            fs::read_dir(&locks).with_context(|| format!("Tried to list {}", locks.display()))?;
+        let mut images = HashSet::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let id = match name.strip_prefix("container-") {
+                Some(id) => id,
+                None => continue,
+            };
+            // A container's lock is held for as long as it runs, and taken before its directory
+            // is made
+            let kept = self.root.join("containers").join(id).is_dir();
+            if !kept && self.lock(&name, libc::LOCK_SH | libc::LOCK_NB).is_ok() {
+                continue;
+            }
+            if let Ok(manifest) = fs::read_to_string(locks.join(&name)) {
+                images.insert(manifest.trim().to_string());
+            }
+        }
+        Ok(images)
+    }
+
+    /// Deletes every blob, extracted layer, and snapshot no stored reference needs, along with
+    /// cached manifests whose blob is gone
+    ///
+    /// That covers what's left behind by interrupted pulls, by manifests that were only
+    /// inspected, and by older versions of tags that were pulled again. Images containers were
+    /// started from are kept until the containers are removed, along with everything they're
+    /// made of.
+    pub fn prune(&self) -> Result<Pruned> {
+        let _blobs = self.lock("blobs", libc::LOCK_EX)?;
+        let containers = self.container_images()?;
+        let in_use = self.repositories()?.in_use_or_by_containers(&containers);
+        // Records of images that were only kept for a container that has been removed since
+        self.update_repositories(|repositories| {
+            repositories
+                .images
+                .retain(|manifest, _| in_use.contains(manifest))
+        })?;
+        let mut pruned = Pruned::default();
+        for name in ["blobs/sha256", "layers/sha256", "overlay/sha256"] {
+            // Overlay lower directories are extracted layers too, just linked differently
+            let removed = match name {
+                "blobs/sha256" => &mut pruned.blobs,
+                _ => &mut pruned.layers,
+            };
+            let directory = self.root.join(name);
+            let entries = fs::read_dir(&directory)
+                .with_context(|| format!("Tried to list {}", directory.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                let digest = format!("sha256:{}", path.file_name().unwrap().to_string_lossy());
+                if in_use.contains(&digest) {
+                    continue;
+                }
+                pruned.reclaimed += disk_usage(&path)?;
+                let removal = match path.is_dir() {
+                    true => fs::remove_dir_all(&path),
+                    false => fs::remove_file(&path),
+                };
+                removal.with_context(|| format!("Tried to remove {}", path.display()))?;
+                if !removed.contains(&digest) {
+                    removed.push(digest);
+                }
+            }
+        }
+        let (snapshots, reclaimed) = self.remove_unused_snapshots()?;
+        pruned.snapshots = snapshots;
+        pruned.reclaimed += reclaimed;
+        self.prune_cached_manifests(&self.root.join("manifests"))?;
+        self.prune_recompressed()?;
+
+        // Blob, layer, and snapshot locks are only taken while `locks/blobs` is shared, so nobody can be
+        // holding one right now
+        let locks = self.root.join("locks");
+        for entry in
+            fs::read_dir(&locks).with_context(|| format!("Tried to list {}", locks.display()))?
+        {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            let prefixes = ["sha256-", "layer-", "overlay-", "snapshot-", "subvolume-"];
+            if prefixes.iter().any(|prefix| name.starts_with(prefix)) {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Tried to remove {}", path.display()))?;
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Removes cache entries under `directory` that point to blobs which are gone
+    fn prune_cached_manifests(&self, directory: &Path) -> Result<()> {
+        let entries = fs::read_dir(directory)
+            .with_context(|| format!("Tried to list {}", directory.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.prune_cached_manifests(&path)?;
+                continue;
+            }
+            let cached: Option<CachedManifest> = fs::read_to_string(&path)
+                .ok()
+                .and_then(|raw_data| serde_json::from_str(&raw_data).ok());
+            let exists = match cached {
+                Some(cached) => self.has_blob(&cached.digest).unwrap_or(false),
+                None => false,
+            };
+            if !exists {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Tried to remove {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `repositories.json`, which doesn't exist until something's been pulled
+    pub fn repositories(&self) -> Result<Repositories> {
+        read_repositories(&self.root)
+    }
+
+    /// Changes `repositories.json`, which is locked so concurrent changes from other processes
+    /// aren't lost, and replaced rather than rewritten in place so it's never seen half written
+    pub fn update_repositories(&self, change: impl FnOnce(&mut Repositories)) -> Result<()> {
+        let _lock = self.lock("repositories", libc::LOCK_EX)?;
+        let mut repositories = self.repositories()?;
+        change(&mut repositories);
+        let raw_data = serde_json::to_string_pretty(&repositories)?;
+        write_atomically(&self.root.join("repositories.json"), raw_data.as_bytes())
+    }
+
+    /// Moves references out of the `refs/<registry>/<repository>/<reference>` files older
+    /// versions kept and into `repositories.json`
+    fn migrate_refs(&self) -> Result<()> {
+        let refs = self.root.join("refs");
+        if !refs.is_dir() {
+            return Ok(());
+        }
+        let mut references = Vec::new();
+        let mut directories = vec![refs.clone()];
+        while let Some(directory) = directories.pop() {
+            let entries = fs::read_dir(&directory)
+                .with_context(|| format!("Tried to list {}", directory.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                let raw_data = fs::read_to_string(&path)
+                    .with_context(|| format!("Tried to read {}", path.display()))?;
+                // A broken ref only costs a pull
+                let stored: StoredImage = match serde_json::from_str(&raw_data) {
+                    Ok(stored) => stored,
+                    Err(_) => continue,
+                };
+                let relative = path.strip_prefix(&refs)?;
+                let (name, reference) = match (relative.parent(), relative.file_name()) {
+                    (Some(name), Some(reference)) => (name, reference),
+                    _ => continue,
+                };
+                references.push((
+                    name.to_string_lossy().into_owned(),
+                    reference.to_string_lossy().into_owned(),
+                    stored,
+                ));
+            }
+        }
+
+        let mut records = BTreeMap::new();
+        for (_, _, stored) in &references {
+            if let Ok(manifest) = self.image_manifest(stored) {
+                records.insert(
+                    stored.manifest.clone(),
+                    ImageRecord::from_manifest(&manifest),
+                );
+            }
+        }
+        self.update_repositories(|repositories| {
+            for (name, reference, stored) in references {
+                repositories
+                    .repositories
+                    .entry(name)
+                    .or_default()
+                    .entry(reference)
+                    .or_insert(stored);
+            }
+            repositories.images.extend(records);
+        })?;
+        fs::remove_dir_all(&refs).with_context(|| format!("Tried to remove {}", refs.display()))
+    }
+
+    pub fn read_blob(&self, digest: &str) -> Result<Vec<u8>> {
+        let path = self.blob_path(digest)?;
+        fs::read(&path).with_context(|| format!("Tried to read blob {}", path.display()))
+    }
+
+    pub fn image_manifest(&self, stored: &StoredImage) -> Result<ImageManifest> {
+        let raw_data = self.read_blob(&stored.manifest)?;
+        match Manifest::parse(None, &raw_data)? {
+            Manifest::Image(manifest) => Ok(manifest),
+            Manifest::Index(_) => bail!("Stored manifest {} is a manifest list", stored.manifest),
+            Manifest::Schema1(_) => bail!(
+                "Stored manifest {} is a schema 1 manifest, which should have been converted",
+                stored.manifest
+            ),
+        }
+    }
+
+    pub fn image_config(&self, manifest: &ImageManifest) -> Result<ImageConfig> {
+        let raw_data = self.read_blob(&manifest.config.digest)?;
+        serde_json::from_slice(&raw_data)
+            .with_context(|| format!("Tried to parse image config {}", manifest.config.digest))
+    }
+
+    /// Computes a layer's diff ID, the digest of its uncompressed tar
+    pub fn diff_id(&self, layer: &Descriptor) -> Result<String> {
+        let mut decoder = HashingReader::new(self.open_layer(layer)?);
+        io::copy(&mut decoder, &mut io::sink())
+            .with_context(|| format!("Tried to decompress layer {}", layer.digest))?;
+        let (decoder, diff_id) = decoder.into_inner();
+        decoder
+            .finish()
+            .with_context(|| format!("Tried to decompress layer {}", layer.digest))?;
+        Ok(diff_id)
+    }
+
+    /// Where a layer is kept once it's been extracted
+    pub fn layer_path(&self, digest: &str) -> Result<PathBuf> {
+        validate_digest(digest)?;
+        Ok(self
+            .root
+            .join("layers/sha256")
+            .join(digest.trim_start_matches("sha256:")))
+    }
+
+    /// Extracts a layer into the store unless that's already been done, returning where it is
+    pub fn extract_layer(&self, layer: &Descriptor, diff_id: &str) -> Result<PathBuf> {
+        let path = self.layer_path(&layer.digest)?;
+        let _lock = self.lock(&layer_lock(&layer.digest), libc::LOCK_EX)?;
+        build_atomically(&path, |temp_path| {
+            self.unpack_layer(layer, diff_id, temp_path)
+        })?;
+        Ok(path)
+    }
+
+    /// Where the overlay driver keeps a layer, linked from the extracted one with its whiteouts
+    /// the way overlayfs wants them
+    pub fn overlay_layer_path(&self, digest: &str) -> Result<PathBuf> {
+        validate_digest(digest)?;
+        Ok(self
+            .root
+            .join("overlay/sha256")
+            .join(digest.trim_start_matches("sha256:")))
+    }
+
+    /// Extracts a layer and links it into a lower directory for overlayfs unless that's already
+    /// been done, returning where it is
+    ///
+    /// Like snapshots, those are shared and must never be written to, and callers hold a shared
+    /// lock on the store's blobs for as long as they use them.
+    pub fn overlay_layer(&self, layer: &Descriptor, diff_id: &str) -> Result<PathBuf> {
+        let path = self.overlay_layer_path(&layer.digest)?;
+        let _lock = self.lock(&overlay_lock(&layer.digest), libc::LOCK_EX)?;
+        build_atomically(&path, |temp_path| {
+            let layer_dir = self.extract_layer(layer, diff_id)?;
+            link_overlay_layer(&layer_dir, temp_path)
+        })?;
+        Ok(path)
+    }
+
+    /// Where the btrfs driver keeps a snapshot as a subvolume, which containers' rootfs are
+    /// snapshots of
+    pub fn subvolume_path(&self, chain_id: &str) -> Result<PathBuf> {
+        validate_digest(chain_id)?;
+        Ok(self
+            .root
+            .join("btrfs/sha256")
+            .join(chain_id.trim_start_matches("sha256:")))
+    }
+
+    /// Keeps other processes from building the same subvolume until the returned lock is dropped
+    pub fn lock_subvolume(&self, chain_id: &str) -> Result<File> {
+        self.lock(&subvolume_lock(chain_id), libc::LOCK_EX)
+    }
+
+    /// Where the flattened stack of layers a chain ID names is kept
+    pub fn snapshot_path(&self, chain_id: &str) -> Result<PathBuf> {
+        validate_digest(chain_id)?;
+        Ok(self
+            .root
+            .join("snapshots/sha256")
+            .join(chain_id.trim_start_matches("sha256:")))
+    }
+
+    /// Flattens a layer onto the snapshot of the layers under it, unless that's already been done,
+    /// returning where it is
+    ///
+    /// Snapshots are made of hardlinks to the extracted layers, so they cost next to no space,
+    /// and nothing ever writes to them once they're in place.
+    pub fn create_snapshot(
+        &self,
+        parent: Option<&Path>,
+        layer: &Descriptor,
+        diff_id: &str,
+        chain_id: &str,
+    ) -> Result<PathBuf> {
+        let path = self.snapshot_path(chain_id)?;
+        let _lock = self.lock(&snapshot_lock(chain_id), libc::LOCK_EX)?;
+        build_atomically(&path, |temp_path| {
+            if let Some(parent) = parent {
+                copy_layer(parent, temp_path, FileCopy::Hardlink)?;
+            }
+            // Foreign layers we can't download leave the snapshot as it was
+            if !(layer.is_foreign() && layer.urls.is_empty()) {
+                let layer_dir = self.extract_layer(layer, diff_id)?;
+                copy_layer(&layer_dir, temp_path, FileCopy::Hardlink)?;
+            }
+            Ok(())
+        })?;
+        Ok(path)
+    }
+
+    /// Copies a snapshot from the first additional store that has it, returning whether one did
+    ///
+    /// The copy is a full one (reflinked where the filesystem can), since the additional store's
+    /// files can't be hardlinked to across filesystems or relied on to stay put.
+    fn warm_snapshot(&self, chain_id: &str) -> Result<bool> {
+        let path = self.snapshot_path(chain_id)?;
+        let hex = path.file_name().unwrap().to_os_string();
+        for root in &self.additional {
+            let source = root.join("snapshots/sha256").join(&hex);
+            if !source.is_dir() {
+                continue;
+            }
+            let _lock = self.lock(&snapshot_lock(chain_id), libc::LOCK_EX)?;
+            match build_atomically(&path, |temp_path| {
+                copy_layer(&source, temp_path, FileCopy::Clone)
+            }) {
+                Ok(()) => return Ok(true),
+                Err(e) => eprintln!(
+                    "Warning: couldn't copy snapshot {}: {:#}",
+                    source.display(),
+                    e
+                ),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Removes layers and snapshots left half built by processes that were killed, skipping any
+    /// that are still being built
+    fn remove_orphaned_builds(&self) -> Result<()> {
+        for (directory, lock) in [
+            ("layers/sha256", layer_lock as fn(&str) -> String),
+            ("overlay/sha256", overlay_lock),
+            ("snapshots/sha256", snapshot_lock),
+            ("btrfs/sha256", subvolume_lock),
+        ] {
+            let directory = self.root.join(directory);
+            let entries = fs::read_dir(&directory)
+                .with_context(|| format!("Tried to list {}", directory.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                if path
+                    .extension()
+                    .map_or(true, |extension| extension != "tmp")
+                {
+                    continue;
+                }
+                let digest = format!("sha256:{}", path.file_stem().unwrap().to_string_lossy());
+                let _lock = match self.lock(&lock(&digest), libc::LOCK_EX | libc::LOCK_NB) {
+                    Ok(lock) => lock,
+                    Err(_) => continue,
+                };
+                remove_orphan(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Chain IDs of the snapshots stored images are made of
+    fn snapshots_in_use(&self, repositories: &Repositories) -> HashSet<String> {
+        let mut in_use = HashSet::new();
+        for stored in repositories.repositories.values().flat_map(|r| r.values()) {
+            let config: Option<ImageConfig> = repositories
+                .images
+                .get(&stored.manifest)
+                .and_then(|record| self.read_blob(&record.config).ok())
+                .and_then(|raw_data| serde_json::from_slice(&raw_data).ok());
+            if let Some(config) = config {
+                in_use.extend(config.rootfs.chain_ids());
+            }
+        }
+        in_use
+    }
+
+    /// Deletes snapshots (and the btrfs driver's subvolumes of them) no stored image is made
+    /// of, returning their chain IDs along with the space that freed
+    fn remove_unused_snapshots(&self) -> Result<(Vec<String>, u64)> {
+        let in_use = self.snapshots_in_use(&self.repositories()?);
+        let mut removed = Vec::new();
+        let mut reclaimed = 0;
+        for directory in ["snapshots/sha256", "btrfs/sha256"] {
+            let directory = self.root.join(directory);
+            let entries = fs::read_dir(&directory)
+                .with_context(|| format!("Tried to list {}", directory.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                let chain_id = format!("sha256:{}", path.file_name().unwrap().to_string_lossy());
+                if in_use.contains(&chain_id) {
+                    continue;
+                }
+                reclaimed += disk_usage(&path)?;
+                // Subvolumes are removed like directories once they're empty
+                fs::remove_dir_all(&path)
+                    .with_context(|| format!("Tried to remove {}", path.display()))?;
+                if !removed.contains(&chain_id) {
+                    removed.push(chain_id);
+                }
+            }
+        }
+        Ok((removed, reclaimed))
+    }
+
+    /// Creates an empty directory under `containers/` for a container, which is kept until the
+    /// process holding the returned lock has exited
+    ///
+    /// Keeping containers in the store puts their rootfs on the same filesystem as the extracted
+    /// layers, so layer files can be linked into them. Whoever opens the store next removes the
+    /// ones that are no longer locked, since a container's process can't remove its own rootfs
+    /// once it has pivoted into it. The storage driver is recorded so it's the one that takes the
+    /// rootfs apart.
+    pub fn create_container(
+        &self,
+        image: &StoredImage,
+        driver: &str,
+        storage_size: Option<u64>,
+    ) -> Result<(PathBuf, File)> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let id = sha256_digest(format!("{} {}", std::process::id(), nanos).as_bytes());
+        let id = id.trim_start_matches("sha256:");
+
+        // Locked before it exists, so it can't be mistaken for an orphan. The lock file says
+        // which image it's running, so that image isn't evicted from under it.
+        let mut lock = self.lock(&format!("container-{}", id), libc::LOCK_EX)?;
+        lock.write_all(image.manifest.as_bytes())
+            .context("Tried to record the container's image")?;
+        let path = self.root.join("containers").join(id);
+        fs::create_dir(&path).with_context(|| format!("Tried to create {}", path.display()))?;
+        if let Some(size) = storage_size {
+            limit_storage(&path, &self.disk_path(&path), size)
+                .context("Tried to limit the container's storage")?;
+        }
+        write_atomically(&path.join("driver"), driver.as_bytes())?;
+        Ok((path, lock))
+    }
+
+    /// Where the filesystem a container's storage is limited by is kept, when it isn't limited
+    /// with a quota (see `limit_storage`)
+    fn disk_path(&self, container: &Path) -> PathBuf {
+        self.root.join("disks").join(container.file_name().unwrap())
+    }
+
+    /// Has the container removed once it exits, along with `volumes`, for `run --rm`
+    pub fn remove_with_container(&self, container: &Path, volumes: &[&str]) -> Result<()> {
+        let mut names = volumes.join("\n");
+        names.push('\n');
+        write_atomically(&container.join("remove"), names.as_bytes())
+    }
+
+    /// Whether a container's process is still running
+    pub fn container_running(&self, id: &str) -> bool {
+        self.lock(&format!("container-{}", id), libc::LOCK_EX | libc::LOCK_NB)
+            .is_err()
+    }
+
+    /// Removes a container that has exited, its rootfs and whatever the container wrote to it
+    /// included, for `rm`
+    pub fn remove_container(&self, id: &str) -> Result<()> {
+        let name = format!("container-{}", id);
+        let _lock = match self.lock(&name, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(lock) => lock,
+            Err(_) => bail!("Container {} is still running", id),
+        };
+        self.remove_container_files(&self.root.join("containers").join(id), &name)
+    }
+
+    /// Removes containers whose process has exited and that aren't kept, which are those run
+    /// with `--rm` and those that never got far enough to be recorded
+    fn remove_orphaned_containers(&self) -> Result<()> {
+        let containers = self.root.join("containers");
+        let entries = fs::read_dir(&containers)
+            .with_context(|| format!("Tried to list {}", containers.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.join(CONTAINER_RECORD).exists() && !path.join("remove").exists() {
+                continue;
+            }
+            let name = format!("container-{}", path.file_name().unwrap().to_string_lossy());
+            let _lock = match self.lock(&name, libc::LOCK_EX | libc::LOCK_NB) {
+                Ok(lock) => lock,
+                Err(_) => continue,
+            };
+            if let Err(e) = self.remove_container_files(&path, &name) {
+                eprintln!("Warning: {:#}, leaving it", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a container's rootfs apart and removes its directory, along with the volumes it was
+    /// to take with it, call with its lock (`name`) held
+    fn remove_container_files(&self, path: &Path, name: &str) -> Result<()> {
+        // Containers from before there were drivers only have a `vfs` rootfs to remove
+        let driver =
+            fs::read_to_string(path.join("driver")).unwrap_or_else(|_| String::from("vfs"));
+        // Removing the directory while something is still mounted in it would remove what's
+        // mounted, like the layers under an overlay
+        storage_driver(driver.trim(), false)
+            .and_then(|driver| driver.remove_rootfs(self, path))
+            .with_context(|| format!("Couldn't take apart the rootfs of {}", path.display()))?;
+        remove_storage_limit(path, &self.disk_path(path))?;
+        let volumes = fs::read_to_string(path.join("remove")).unwrap_or_default();
+        for volume in volumes.lines().filter(|volume| !volume.is_empty()) {
+            // Unless someone removed it already
+            if !self.volume_path(volume)?.exists() {
+                continue;
+            }
+            if let Err(e) = remove_volume(self, volume) {
+                eprintln!("Warning: couldn't remove volume {}: {:#}", volume, e);
+            }
+        }
+        // Left behind if `run` didn't get to remove it, the container has exited either way
+        if let Ok(cgroup) = fs::read_to_string(path.join(CGROUP_RECORD)) {
+            let _ = fs::remove_dir(cgroup);
+        }
+        remove_orphan(path)?;
+        let _ = fs::remove_file(self.root.join("locks").join(name));
+        Ok(())
+    }
+
+    /// Returns the snapshot of all of an image's layers, building any snapshots and extracting
+    /// any layers that haven't been yet, or nothing for images without layers
+    ///
+    /// Images with common ancestry share the snapshots of the layers they have in common, the way
+    /// containerd's snapshotters key them by chain ID, so only the layers on top get flattened.
+    ///
+    /// Snapshots are shared and must never be written to. Callers hold a shared lock on the
+    /// store's blobs for as long as they use it, so it isn't pruned from under them.
+    pub fn image_snapshot(
+        &self,
+        manifest: &ImageManifest,
+        config: &ImageConfig,
+        progress: &Progress,
+    ) -> Result<Option<PathBuf>> {
+        if manifest.layers.len() != config.rootfs.diff_ids.len() {
+            bail!(
+                "Image has {} layers but its config lists {} diff IDs",
+                manifest.layers.len(),
+                config.rootfs.diff_ids.len()
+            );
+        }
+        let chain_ids = config.rootfs.chain_ids();
+        let mut built = 0;
+        for (i, chain_id) in chain_ids.iter().enumerate().rev() {
+            if self.snapshot_path(chain_id)?.is_dir() || self.warm_snapshot(chain_id)? {
+                built = i + 1;
+                break;
+            }
+        }
+        for layer in &manifest.layers[..built] {
+            progress.set_phase(&layer.digest, Phase::Done);
+        }
+
+        let mut snapshot = match built {
+            0 => None,
+            built => Some(self.snapshot_path(&chain_ids[built - 1])?),
+        };
+        let layers = manifest
+            .layers
+            .iter()
+            .zip(&config.rootfs.diff_ids)
+            .zip(&chain_ids)
+            .skip(built);
+        for ((layer, diff_id), chain_id) in layers {
+            progress.set_phase(&layer.digest, Phase::Extracting);
+            snapshot = Some(self.create_snapshot(snapshot.as_deref(), layer, diff_id, chain_id)?);
+            progress.set_phase(&layer.digest, Phase::Done);
+        }
+        Ok(snapshot)
+    }
+
+    /// Compares an extracted layer with the blob it was extracted from, returning what's wrong
+    /// with it if anything is
+    ///
+    /// Only what files contain (and what symlinks point to) is compared, not their metadata. The
+    /// blob is assumed to be intact, so check its digest first.
+    pub fn verify_layer(&self, layer: &Descriptor, diff_id: &str) -> Result<Option<String>> {
+        let path = self.layer_path(&layer.digest)?;
+        let decoder = HashingReader::new(self.open_layer(layer)?);
+        let mut archive = tar::Archive::new(decoder);
+        let read_error = || format!("Tried to read layer {}", layer.digest);
+
+        // By path, since a later entry for the same path replaces an earlier one
+        let mut problems = BTreeMap::new();
+        for entry in archive.entries().with_context(read_error)? {
+            let mut entry = entry.with_context(read_error)?;
+            let entry_path = entry.path().with_context(read_error)?.into_owned();
+            let name = entry_path
+                .to_string_lossy()
+                .trim_start_matches("./")
+                .to_string();
+            if name.is_empty()
+                || (layer.annotations.contains_key(ESTARGZ_TOC_DIGEST)
+                    && ESTARGZ_METADATA.contains(&name.as_str()))
+            {
+                continue;
+            }
+            let extracted = path.join(&name);
+            let metadata = fs::symlink_metadata(&extracted);
+            let problem = match (entry.header().entry_type(), metadata) {
+                (_, Err(_)) => Some(format!("{} is missing", name)),
+                (tar::EntryType::Regular | tar::EntryType::Continuous, Ok(metadata)) => {
+                    let mut expected = HashingReader::new(&mut entry);
+                    io::copy(&mut expected, &mut io::sink()).with_context(read_error)?;
+                    let expected = expected.finalize();
+                    match metadata.is_file() {
+                        true => {
+                            let mut actual = HashingReader::new(File::open(&extracted)?);
+                            io::copy(&mut actual, &mut io::sink()).with_context(|| {
+                                format!("Tried to read {}", extracted.display())
+                            })?;
+                            (actual.finalize() != expected)
+                                .then(|| format!("{} doesn't match the layer's blob", name))
+                        }
+                        false => Some(format!("{} isn't a file", name)),
+                    }
+                }
+                (tar::EntryType::Symlink, Ok(_)) => {
+                    let target = entry.link_name().with_context(read_error)?;
+                    match (fs::read_link(&extracted).ok(), target) {
+                        (Some(actual), Some(target)) if actual == *target => None,
+                        _ => Some(format!("{} doesn't point where it should", name)),
+                    }
+                }
+                (tar::EntryType::Directory, Ok(metadata)) => {
+                    (!metadata.is_dir()).then(|| format!("{} isn't a directory", name))
+                }
+                _ => None,
+            };
+            problems.insert(name, problem);
+        }
+
+        let mut decoder = archive.into_inner();
+        io::copy(&mut decoder, &mut io::sink()).with_context(read_error)?;
+        let (_, actual_diff_id) = decoder.into_inner();
+        if actual_diff_id != diff_id {
+            return Ok(Some(format!(
+                "its blob doesn't match its diff ID {}",
+                diff_id
+            )));
+        }
+        Ok(problems.into_values().flatten().next())
+    }
+
+    /// Unpacks a layer into `destination`, refusing entries that would escape it
+    ///
+    /// The blob's digest only covers the compressed bytes, so the uncompressed tar is checked
+    /// against the config's diff ID to catch registries or caches serving mismatched layers.
+    /// eStargz layers have their table of contents checked too, but it isn't unpacked.
+    fn unpack_layer(&self, layer: &Descriptor, diff_id: &str, destination: &Path) -> Result<()> {
+        let decoder = HashingReader::new(self.open_layer(layer)?);
+        let mut archive = tar::Archive::new(decoder);
+        archive.set_preserve_permissions(true);
+        // Ours are set by unpack_entry, which knows which of them can be
+        archive.set_unpack_xattrs(false);
+
+        let options = UnpackOptions::configured()?;
+        let mut skipped = Skipped::default();
+        let toc_digest = layer.annotations.get(ESTARGZ_TOC_DIGEST);
+        let mut actual_toc_digest = None;
+        // Like tar's own unpack, directories go last so their permissions can't get in the way
+        // of unpacking what's in them
+        let mut directories = Vec::new();
+        let unpack_error = || format!("Unable to unpack to {}", destination.display());
+        for entry in archive.entries().with_context(unpack_error)? {
+            let mut entry = entry.with_context(unpack_error)?;
+            let path = entry.path().with_context(unpack_error)?.into_owned();
+            let name = path.to_string_lossy();
+            let name = name.trim_start_matches("./");
+            if toc_digest.is_some() && ESTARGZ_METADATA.contains(&name) {
+                if name == ESTARGZ_METADATA[0] {
+                    let mut toc = HashingReader::new(&mut entry);
+                    io::copy(&mut toc, &mut io::sink()).with_context(unpack_error)?;
+                    actual_toc_digest = Some(toc.finalize());
+                }
+                continue;
+            }
+            if entry.header().entry_type() == tar::EntryType::Directory {
+                directories.push(entry);
+            } else {
+                unpack_entry(&mut entry, destination, &options, &mut skipped)
+                    .with_context(unpack_error)?;
+            }
+        }
+        for mut directory in directories {
+            unpack_entry(&mut directory, destination, &options, &mut skipped)
+                .with_context(unpack_error)?;
+        }
+        if skipped.xattrs > 0 {
+            eprintln!(
+                "Warning: skipped {} extended attributes (like file capabilities) in layer {} that only root can set, set xattrs to user to keep them as user.* ones",
+                skipped.xattrs, layer.digest
+            );
+        }
+        if skipped.owners > 0 {
+            eprintln!(
+                "Warning: {} files in layer {} are owned by ids our user namespace doesn't map, they're owned by its root instead",
+                skipped.owners, layer.digest
+            );
+        }
+        if let Some(toc_digest) = toc_digest {
+            let actual = actual_toc_digest.with_context(|| {
+                format!("eStargz layer {} has no table of contents", layer.digest)
+            })?;
+            check_digest(&actual, toc_digest).with_context(|| {
+                format!(
+                    "eStargz layer {} doesn't match its TOC digest",
+                    layer.digest
+                )
+            })?;
+        }
+
+        // tar stops reading at the end-of-archive marker, the padding after it counts too
+        let mut decoder = archive.into_inner();
+        io::copy(&mut decoder, &mut io::sink())
+            .with_context(|| format!("Tried to decompress layer {}", layer.digest))?;
+        let (decoder, actual_diff_id) = decoder.into_inner();
+        decoder
+            .finish()
+            .with_context(|| format!("Tried to decompress layer {}", layer.digest))?;
+        check_digest(&actual_diff_id, diff_id)
+            .with_context(|| format!("Layer {} doesn't match its diff ID", layer.digest))
+    }
+}
+
+/// Bytes taken up by a file, or by everything in a directory
+pub fn disk_usage(path: &Path) -> Result<u64> {
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("Tried to read metadata of {}", path.display()))?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    let entries =
+        fs::read_dir(path).with_context(|| format!("Tried to list {}", path.display()))?;
+    for entry in entries {
+        size += disk_usage(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Like `disk_usage`, but files already in `seen` are skipped and the rest added to it
+fn unique_disk_usage(path: &Path, seen: &mut HashSet<(u64, u64)>) -> Result<u64> {
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("Tried to read metadata of {}", path.display()))?;
+    if !metadata.is_dir() {
+        return match seen.insert((metadata.dev(), metadata.ino())) {
+            true => Ok(metadata.len()),
+            false => Ok(0),
+        };
+    }
+    let mut size = 0;
+    let entries =
+        fs::read_dir(path).with_context(|| format!("Tried to list {}", path.display()))?;
+    for entry in entries {
+        size += unique_disk_usage(&entry?.path(), seen)?;
+    }
+    Ok(size)
+}
+
+/// Seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// How big `max-cache-size` lets the store get, from `MINIDOCKER_MAX_CACHE_SIZE` (which
+/// `--max-cache-size` sets) or `daemon.json`, if it's capped at all
+pub fn max_cache_size() -> Result<Option<u64>> {
+    let max_size = match std::env::var(MAX_CACHE_SIZE_ENV) {
+        Ok(max_size) if !max_size.is_empty() => Some(max_size),
+        _ => Config::load()?.max_cache_size,
+    };
+    max_size
+        .map(|max_size| parse_size(&max_size).context("Tried to parse max-cache-size"))
+        .transpose()
+}
+
+/// The name of the lock held while a layer is extracted
+fn layer_lock(digest: &str) -> String {
+    format!("layer-{}", digest.replace(':', "-"))
+}
+
+/// The name of the lock held while a layer is linked for the overlay driver
+fn overlay_lock(digest: &str) -> String {
+    format!("overlay-{}", digest.replace(':', "-"))
+}
+
+/// The name of the lock held while a snapshot is built
+fn volume_lock(name: &str) -> String {
+    format!("volume-{}", name)
+}
+
+/// Volume names are what docker allows, which keeps them from being paths
+fn validate_volume_name(name: &str) -> Result<()> {
+    let mut characters = name.chars();
+    let valid = characters
+        .next()
+        .is_some_and(|first| first.is_ascii_alphanumeric())
+        && name.len() >= 2
+        && characters.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    match valid {
+        true => Ok(()),
+        false => bail!(
+            "Invalid volume name '{}', only [a-zA-Z0-9][a-zA-Z0-9_.-] are allowed",
+            name
+        ),
+    }
+}
+
+fn snapshot_lock(chain_id: &str) -> String {
+    format!("snapshot-{}", chain_id.replace(':', "-"))
+}
+
+/// The name of the lock held while the btrfs driver builds a subvolume
+fn subvolume_lock(chain_id: &str) -> String {
+    format!("subvolume-{}", chain_id.replace(':', "-"))
+}
+
+/// Builds a directory at `<path>.tmp` and only renames it to `path` once it's complete and
+/// synced to disk, so it's never seen half built even if we're killed halfway through
+///
+/// Does nothing if `path` is already there, callers hold a lock so nobody else builds it at the
+/// same time.
+fn build_atomically(path: &Path, build: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    if path.is_dir() {
+        return Ok(());
+    }
+    let temp_path = path.with_extension("tmp");
+    remove_orphan(&temp_path)?;
+    fs::create_dir(&temp_path)
+        .with_context(|| format!("Tried to create {}", temp_path.display()))?;
+    if let Err(e) = build(&temp_path).and_then(|()| sync_filesystem(&temp_path)) {
+        let _ = fs::remove_dir_all(&temp_path);
+        return Err(e);
+    }
+    fs::rename(&temp_path, path)
+        .with_context(|| format!("Tried to move {} into place", path.display()))?;
+    File::open(path.parent().unwrap())
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("Tried to sync {}", path.display()))
+}
+
+fn remove_orphan(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Tried to remove {}", path.display())),
+    }
+}
+
+/// Flushes everything written to the filesystem `path` is on, which is quicker than syncing
+/// every file of a layer one at a time
+fn sync_filesystem(path: &Path) -> Result<()> {
+    let directory =
+        File::open(path).with_context(|| format!("Tried to open {}", path.display()))?;
+    match unsafe { libc::syncfs(directory.as_raw_fd()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error())
+            .with_context(|| format!("Tried to sync {}", path.display())),
+    }
+}
+
+/// Writes a file next to `path` and renames it into place, so nothing ever sees it half written
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let directory = path.parent().unwrap();
+    let mut file = NamedTempFile::new_in(directory)
+        .with_context(|| format!("Tried to create a file in {}", directory.display()))?;
+    file.write_all(data)
+        .with_context(|| format!("Tried to write {}", file.path().display()))?;
+    file.persist(path)
+        .with_context(|| format!("Tried to move {} into place", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use anyhow::{bail, Context, Result};
+
+/// Prints rows as columns padded to line up under the header, like the docker CLI's tables
+pub fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = header.iter().map(|column| column.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let header: Vec<String> = header.iter().map(|column| column.to_string()).collect();
+    for row in [&header].into_iter().chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("   ").trim_end());
+    }
+}
+
+/// The first 12 hex digits of a digest, like docker shows them
+pub fn short_digest(digest: &str) -> &str {
+    let hex = digest.split_once(':').map_or(digest, |(_, hex)| hex);
+    &hex[..hex.len().min(12)]
+}
+
+/// Sizes in decimal units, like docker shows them
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1_000_000_000 => format!("{:.2}GB", b as f64 / 1e9),
+        b if b >= 1_000_000 => format!("{:.1}MB", b as f64 / 1e6),
+        b if b >= 1_000 => format!("{:.1}kB", b as f64 / 1e3),
+        b => format!("{}B", b),
+    }
+}
+
+/// Parses sizes like `10GB`, `512MiB`, or `1.5g`, in decimal units unless they say `iB`
+pub fn parse_size(size: &str) -> Result<u64> {
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid size '{}', expected a number like 10GB", size))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => bail!(
+            "Unknown unit '{}' in size '{}', expected B, kB, MB, GB, TB, or KiB through TiB",
+            unit,
+            size
+        ),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
@@ -0,0 +1,76 @@
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket capping how fast blobs are downloaded, shared by every download of a pull
+///
+/// The bucket holds up to a second's worth of bytes, so short bursts can go faster than the limit
+/// as long as the average doesn't.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may be read right away, negative when readers are waiting to pay off a debt
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Throttle {
+        Throttle {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Accounts for `bytes` that were just read, sleeping until the bucket can afford them
+    fn take(&self, bytes: usize) {
+        let rate = self.bytes_per_second as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+            bucket.refilled = now;
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / rate),
+                false => Duration::ZERO,
+            }
+        };
+        thread::sleep(wait);
+    }
+}
+
+/// Reads from `inner` no faster than the throttle allows, or as fast as it can without one
+pub struct ThrottledReader<'a, R> {
+    inner: R,
+    throttle: Option<&'a Throttle>,
+}
+
+impl<'a, R: Read> ThrottledReader<'a, R> {
+    pub fn new(inner: R, throttle: Option<&'a Throttle>) -> ThrottledReader<'a, R> {
+        ThrottledReader { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let throttle = match self.throttle {
+            Some(throttle) => throttle,
+            None => return self.inner.read(buf),
+        };
+        // Never read more than the bucket can hold, or one read could stall for ages
+        let limit = buf.len().min(throttle.bytes_per_second.max(1) as usize);
+        let read = self.inner.read(&mut buf[..limit])?;
+        throttle.take(read);
+        Ok(read)
+    }
+}
@@ -0,0 +1,660 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::{lchown, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::Config;
+use crate::idmap::IdMappings;
+use crate::rootfs::set_xattr;
+
+/// Overrides what's done with layers' extended attributes, which `--xattrs` sets
+pub static XATTRS_ENV: &str = "MINIDOCKER_XATTRS";
+
+/// Where tar's pax headers keep a file's extended attributes, followed by their names
+static PAX_XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+/// What's done with the extended attributes layers record for their files
+///
+/// Everything outside the `user.` namespace, file capabilities (`security.capability`) among
+/// them, takes root to set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Xattrs {
+    /// Set them as they are, failing if they can't be
+    Preserve,
+    /// Set the ones that take root under `user.` instead, like `user.security.capability`,
+    /// which keeps them around without them doing anything
+    User,
+    /// Only set the `user.` ones, skipping the rest with a warning
+    Skip,
+}
+
+impl Xattrs {
+    /// What `--xattrs` (or `MINIDOCKER_XATTRS`, or `daemon.json`'s `xattrs`) asks for
+    ///
+    /// That's `auto` unless set, which preserves them when we're root and skips the ones that
+    /// can't be set otherwise. Layers are only extracted once, so changing it doesn't change
+    /// layers that already are.
+    pub fn configured() -> Result<Xattrs> {
+        let name = match std::env::var(XATTRS_ENV) {
+            Ok(name) if !name.is_empty() => Some(name),
+            _ => Config::load()?.xattrs,
+        };
+        match name.as_deref().unwrap_or("auto") {
+            "auto" => match unsafe { libc::geteuid() } {
+                0 => Ok(Xattrs::Preserve),
+                _ => Ok(Xattrs::Skip),
+            },
+            "preserve" => Ok(Xattrs::Preserve),
+            "user" => Ok(Xattrs::User),
+            "skip" => Ok(Xattrs::Skip),
+            other => bail!(
+                "Unknown xattrs setting '{}', expected auto, preserve, user, or skip",
+                other
+            ),
+        }
+    }
+}
+
+/// How every entry of a layer is unpacked
+#[derive(Debug, Clone)]
+pub struct UnpackOptions {
+    /// Extract device nodes rather than refusing to
+    pub allow_devices: bool,
+    pub xattrs: Xattrs,
+    /// The user namespace ownership is set in, none if we can't set any
+    pub ids: Option<IdMappings>,
+}
+
+impl UnpackOptions {
+    /// What `daemon.json` and `--xattrs` ask for, with ownership set whenever we're root,
+    /// including root of a user namespace
+    pub fn configured() -> Result<UnpackOptions> {
+        let ids = match unsafe { libc::geteuid() } {
+            0 => Some(IdMappings::current()?),
+            _ => None,
+        };
+        Ok(UnpackOptions {
+            allow_devices: Config::load()?.allow_device_nodes,
+            xattrs: Xattrs::configured()?,
+            ids,
+        })
+    }
+}
+
+/// What a layer's entries had that couldn't be kept
+#[derive(Debug, Default)]
+pub struct Skipped {
+    /// Extended attributes `Xattrs` didn't let through
+    pub xattrs: usize,
+    /// Entries owned by a uid or gid our user namespace doesn't map, which are left owned by
+    /// its root instead
+    pub owners: usize,
+}
+
+/// Unpacks a layer's tar entry into `destination`, refusing anything that would end up outside
+/// of it, and counting what couldn't be kept in `skipped`
+///
+/// The tar crate already skips paths with `..` in them and won't follow symlinks out of the
+/// destination, but it skips such entries silently, still follows symlinks that stay inside it,
+/// and sets the permissions of a directory through a symlink that an earlier entry put in its
+/// place. Layers come from registries we don't control, so entries are checked before tar gets
+/// them:
+///
+/// - paths (and hardlink targets) are taken relative to the destination, and can't have `..`
+///   in them
+/// - nothing is unpacked through a symlink, not even one pointing elsewhere in the layer
+/// - a symlink an earlier entry left where this one goes is removed rather than written
+///   through
+/// - device nodes are refused unless `allow_devices`, a container shouldn't get to reach the
+///   host's devices just because a layer had a node for them
+///
+/// Symlink targets themselves are left alone, absolute ones are how layers point elsewhere in
+/// the image and nothing here ever follows them.
+///
+/// Ownership is set to what the entry says as seen from inside `options.ids`, which the kernel
+/// shifts into whatever range the namespace maps on disk, so the container sees the image's
+/// owners as long as it runs in the same namespace.
+pub fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    destination: &Path,
+    options: &UnpackOptions,
+    skipped: &mut Skipped,
+) -> Result<()> {
+    let path = entry
+        .path()
+        .context("Tried to read an entry's path")?
+        .into_owned();
+    let relative = relative_path(&path)?;
+    let entry_type = entry.header().entry_type();
+    if !options.allow_devices && matches!(entry_type, tar::EntryType::Char | tar::EntryType::Block)
+    {
+        bail!(
+            "{} is a device node, set allow-device-nodes in daemon.json to extract those",
+            path.display()
+        );
+    }
+    // That's the destination itself, which tar doesn't unpack anything for
+    if relative.as_os_str().is_empty() {
+        return Ok(());
+    }
+    check_parents(destination, &relative)
+        .with_context(|| format!("Refusing to unpack {}", path.display()))?;
+    let unpacked = destination.join(&relative);
+    if fs::symlink_metadata(&unpacked).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        fs::remove_file(&unpacked)
+            .with_context(|| format!("Tried to remove {}", unpacked.display()))?;
+    }
+    // Made here rather than by tar, which joins an absolute target onto the destination as it is
+    // rather than taking it from the layer's root. A hardlink's attributes are its target's,
+    // which were set when it was unpacked.
+    if entry_type == tar::EntryType::Link {
+        let target = entry
+            .link_name()
+            .context("Tried to read a hardlink's target")?
+            .with_context(|| format!("Hardlink {} has no target", path.display()))?;
+        let target = relative_path(&target)?;
+        check_parents(destination, &target)
+            .with_context(|| format!("Refusing to hardlink {}", path.display()))?;
+        if target == relative {
+            return Ok(());
+        }
+        // Like tar does for other entries, what an earlier one left there is replaced
+        if fs::symlink_metadata(&unpacked).is_ok_and(|metadata| !metadata.is_dir()) {
+            fs::remove_file(&unpacked)
+                .with_context(|| format!("Tried to remove {}", unpacked.display()))?;
+        }
+        if let Some(parent) = unpacked.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Tried to create {}", parent.display()))?;
+        }
+        // Doesn't follow a symlink at target, which links the symlink itself
+        return fs::hard_link(destination.join(&target), &unpacked)
+            .with_context(|| format!("Tried to hardlink {}", path.display()));
+    }
+    // Read before unpacking, which consumes the entry. Pax headers hold ids too big for the
+    // tar header's fields.
+    let header = entry.header();
+    let mut uid = header.uid().context("Tried to read an entry's uid")?;
+    let mut gid = header.gid().context("Tried to read an entry's gid")?;
+    let mode = header.mode().context("Tried to read an entry's mode")?;
+    let mut attributes = Vec::new();
+    if let Some(extensions) = entry
+        .pax_extensions()
+        .context("Tried to read an entry's pax headers")?
+    {
+        for extension in extensions {
+            let extension = extension.context("Tried to read an entry's pax headers")?;
+            let id = || {
+                std::str::from_utf8(extension.value_bytes())
+                    .ok()?
+                    .parse()
+                    .ok()
+            };
+            match extension.key_bytes() {
+                b"uid" => uid = id().unwrap_or(uid),
+                b"gid" => gid = id().unwrap_or(gid),
+                key => {
+                    if let Some(name) = key.strip_prefix(PAX_XATTR_PREFIX) {
+                        attributes.push((name.to_vec(), extension.value_bytes().to_vec()));
+                    }
+                }
+            }
+        }
+    }
+    entry
+        .unpack_in(destination)
+        .with_context(|| format!("Tried to unpack {}", path.display()))?;
+    if let Some(ids) = &options.ids {
+        let (uid, gid) = match (u32::try_from(uid), u32::try_from(gid)) {
+            (Ok(uid), Ok(gid)) => ids.shift(uid, gid),
+            _ => (None, None),
+        };
+        if uid.is_none() || gid.is_none() {
+            skipped.owners += 1;
+        }
+        lchown(&unpacked, uid, gid)
+            .with_context(|| format!("Tried to set the owner of {}", path.display()))?;
+        // Changing the owner clears setuid and setgid bits, which tar had already set
+        if !entry_type.is_symlink() {
+            fs::set_permissions(&unpacked, fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Tried to set the mode of {}", path.display()))?;
+        }
+    }
+    // Last, changing the owner clears file capabilities too
+    skipped.xattrs += set_xattrs(
+        &unpacked,
+        &attributes,
+        options.xattrs,
+        entry_type.is_symlink(),
+    )
+    .with_context(|| format!("Tried to set the xattrs of {}", path.display()))?;
+    Ok(())
+}
+
+/// Sets what `xattrs` lets through of `attributes`, returning how many it skipped
+fn set_xattrs(
+    path: &Path,
+    attributes: &[(Vec<u8>, Vec<u8>)],
+    xattrs: Xattrs,
+    symlink: bool,
+) -> io::Result<usize> {
+    let mut skipped = 0;
+    for (name, value) in attributes {
+        let name = match std::str::from_utf8(name) {
+            Ok(name) => name,
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        // Overlayfs's own bookkeeping, about how some overlay stored the layer rather than about
+        // the image, which would make our overlays show something else than what's in it
+        if name.starts_with("trusted.overlay.") || name.starts_with("user.overlay.") {
+            continue;
+        }
+        let name = match (name.starts_with("user."), xattrs) {
+            (true, _) | (false, Xattrs::Preserve) => name.to_string(),
+            (false, Xattrs::User) => format!("user.{}", name),
+            (false, Xattrs::Skip) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        // The kernel only has user xattrs on regular files and directories, so there's nowhere
+        // to keep them, root or not
+        if symlink && name.starts_with("user.") {
+            continue;
+        }
+        set_xattr(path, &name, value)?;
+    }
+    Ok(skipped)
+}
+
+/// Where a path from a layer goes relative to the layer's root, which leading `/`s and `.`s
+/// don't change
+fn relative_path(path: &Path) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => continue,
+            Component::ParentDir => bail!("{} escapes the layer", path.display()),
+            Component::Normal(part) => relative.push(part),
+        }
+    }
+    Ok(relative)
+}
+
+/// Fails if any of the directories `relative` is in was unpacked as something else than one
+fn check_parents(destination: &Path, relative: &Path) -> io::Result<()> {
+    let mut parent = destination.to_path_buf();
+    let components: Vec<_> = relative.components().collect();
+    for component in &components[..components.len().saturating_sub(1)] {
+        parent.push(component);
+        match fs::symlink_metadata(&parent) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is a symlink", parent.display()),
+                ))
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} isn't a directory", parent.display()),
+                ))
+            }
+            // Nothing's there yet, so nothing under it is either
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::TempDir;
+
+    /// What a layer's entry is, for `layer` to put in a tar archive
+    enum Entry<'a> {
+        File(&'a str, &'a str),
+        Directory(&'a str),
+        Symlink(&'a str, &'a str),
+        Hardlink(&'a str, &'a str),
+        Device(&'a str, tar::EntryType),
+    }
+
+    /// A tar archive of `entries`, with their paths written as they are, which tar's
+    /// `Header::set_path` wouldn't let us do for the ones that escape
+    fn layer(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for entry in entries {
+            let mut header = tar::Header::new_gnu();
+            let (path, contents) = match *entry {
+                Entry::File(path, contents) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    (path, contents)
+                }
+                Entry::Directory(path) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    (path, "")
+                }
+                Entry::Symlink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_link_name(target).unwrap();
+                    (path, "")
+                }
+                Entry::Hardlink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Link);
+                    header.set_link_name(target).unwrap();
+                    (path, "")
+                }
+                Entry::Device(path, entry_type) => {
+                    // /dev/null's
+                    header.set_entry_type(entry_type);
+                    header.set_device_major(1).unwrap();
+                    header.set_device_minor(3).unwrap();
+                    (path, "")
+                }
+            };
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_mode(match entry {
+                Entry::Directory(_) => 0o755,
+                _ => 0o644,
+            });
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Unpacks `layer` into `destination` the way `Store` does, stopping at the first entry
+    /// that's refused
+    fn unpack(layer: &[u8], destination: &Path, allow_devices: bool) -> Result<()> {
+        let mut archive = tar::Archive::new(layer);
+        archive.set_preserve_permissions(true);
+        let options = UnpackOptions {
+            allow_devices,
+            xattrs: Xattrs::Skip,
+            ids: None,
+        };
+        let mut skipped = Skipped::default();
+        for entry in archive.entries()? {
+            unpack_entry(&mut entry?, destination, &options, &mut skipped)?;
+        }
+        Ok(())
+    }
+
+    /// A destination to unpack into, and a directory next to it layers shouldn't reach
+    fn directories() -> (TempDir, PathBuf, PathBuf) {
+        let directory = TempDir::new().unwrap();
+        let destination = directory.path().join("rootfs");
+        let outside = directory.path().join("outside");
+        fs::create_dir(&destination).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("target"), "host").unwrap();
+        (directory, destination, outside)
+    }
+
+    #[test]
+    fn relative_path_drops_leading_slashes_and_dots() {
+        assert_eq!(
+            relative_path(Path::new("./usr/bin")).unwrap(),
+            Path::new("usr/bin")
+        );
+        assert_eq!(
+            relative_path(Path::new("/etc/passwd")).unwrap(),
+            Path::new("etc/passwd")
+        );
+        assert_eq!(relative_path(Path::new(".")).unwrap(), Path::new(""));
+        assert!(relative_path(Path::new("usr/../../etc")).is_err());
+    }
+
+    #[test]
+    fn parent_dir_paths_are_refused() {
+        let (_directory, destination, outside) = directories();
+        let layer = layer(&[Entry::File("../outside/escaped", "layer")]);
+        assert!(unpack(&layer, &destination, false).is_err());
+        assert!(!outside.join("escaped").exists());
+    }
+
+    #[test]
+    fn absolute_paths_unpack_inside_the_destination() {
+        let (_directory, destination, outside) = directories();
+        let path = format!("{}/escaped", outside.display());
+        unpack(&layer(&[Entry::File(&path, "layer")]), &destination, false).unwrap();
+        assert!(!outside.join("escaped").exists());
+        let unpacked = destination.join(relative_path(Path::new(&path)).unwrap());
+        assert_eq!(fs::read_to_string(unpacked).unwrap(), "layer");
+    }
+
+    #[test]
+    fn nothing_is_unpacked_through_a_symlinked_directory() {
+        let (_directory, destination, outside) = directories();
+        let target = outside.display().to_string();
+        let layer = layer(&[
+            Entry::Symlink("usr", &target),
+            Entry::File("usr/escaped", "layer"),
+        ]);
+        assert!(unpack(&layer, &destination, false).is_err());
+        assert!(!outside.join("escaped").exists());
+    }
+
+    #[test]
+    fn symlinks_are_replaced_rather_than_written_through() {
+        let (_directory, destination, outside) = directories();
+        let target = outside.join("target").display().to_string();
+        let layer = layer(&[
+            Entry::Symlink("passwd", &target),
+            Entry::File("passwd", "layer"),
+        ]);
+        unpack(&layer, &destination, false).unwrap();
+        assert_eq!(fs::read_to_string(outside.join("target")).unwrap(), "host");
+        let unpacked = destination.join("passwd");
+        assert!(fs::symlink_metadata(&unpacked).unwrap().is_file());
+        assert_eq!(fs::read_to_string(unpacked).unwrap(), "layer");
+    }
+
+    #[test]
+    fn hardlinks_dont_escape() {
+        let (_directory, destination, outside) = directories();
+        let absolute = outside.join("target").display().to_string();
+        let symlinked = outside.display().to_string();
+        for entries in [
+            vec![Entry::Hardlink("link", "../outside/target")],
+            vec![Entry::Hardlink("link", &absolute)],
+            vec![
+                Entry::Symlink("usr", &symlinked),
+                Entry::Hardlink("link", "usr/target"),
+            ],
+        ] {
+            assert!(unpack(&layer(&entries), &destination, false).is_err());
+            assert_eq!(fs::metadata(outside.join("target")).unwrap().nlink(), 1);
+            assert!(!destination.join("link").exists());
+        }
+    }
+
+    #[test]
+    fn hardlinks_inside_the_layer_unpack() {
+        let (_directory, destination, _outside) = directories();
+        let layer = layer(&[
+            Entry::File("usr/bin/python3.12", "layer"),
+            Entry::Hardlink("usr/bin/python3", "/usr/bin/python3.12"),
+        ]);
+        unpack(&layer, &destination, false).unwrap();
+        let linked = fs::metadata(destination.join("usr/bin/python3")).unwrap();
+        assert_eq!(linked.nlink(), 2);
+    }
+
+    #[test]
+    fn device_nodes_take_allow_devices() {
+        for entry_type in [tar::EntryType::Char, tar::EntryType::Block] {
+            let (_directory, destination, _outside) = directories();
+            let layer = layer(&[Entry::Device("null", entry_type)]);
+            let error = unpack(&layer, &destination, false).unwrap_err();
+            assert!(error.to_string().contains("allow-device-nodes"));
+            assert!(!destination.join("null").exists());
+
+            // Making them takes root, but they're only refused without allow_devices
+            match unpack(&layer, &destination, true) {
+                Ok(()) => assert!(fs::symlink_metadata(destination.join("null")).is_ok()),
+                Err(error) => assert!(!error.to_string().contains("allow-device-nodes")),
+            }
+        }
+    }
+
+    #[test]
+    fn symlinks_can_point_anywhere() {
+        let (_directory, destination, _outside) = directories();
+        let layer = layer(&[Entry::Symlink("cc", "/usr/bin/gcc")]);
+        unpack(&layer, &destination, false).unwrap();
+        assert_eq!(
+            fs::read_link(destination.join("cc")).unwrap(),
+            Path::new("/usr/bin/gcc")
+        );
+    }
+
+    #[test]
+    fn hardlinks_replace_what_was_there() {
+        let (_directory, destination, _outside) = directories();
+        let layer = layer(&[
+            Entry::File("usr/bin/python3.12", "layer"),
+            Entry::File("usr/bin/python3", "old"),
+            Entry::Hardlink("usr/bin/python3", "usr/bin/python3.12"),
+            Entry::Hardlink("usr/bin/python3", "usr/bin/python3.12"),
+            Entry::Hardlink("usr/bin/python3.12", "usr/bin/python3.12"),
+        ]);
+        unpack(&layer, &destination, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(destination.join("usr/bin/python3")).unwrap(),
+            "layer"
+        );
+        let linked = fs::metadata(destination.join("usr/bin/python3.12")).unwrap();
+        assert_eq!(linked.nlink(), 2);
+    }
+
+    /// A xorshift generator, random enough to mix up entries and reproducible from its seed
+    struct Generator(u64);
+
+    impl Generator {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.below(choices.len())]
+        }
+    }
+
+    /// Every file under `path`, as paths relative to it, with what regular files have in them
+    fn tree(path: &Path) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+        let mut tree = Vec::new();
+        let mut directories = vec![path.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            for entry in fs::read_dir(&directory).unwrap() {
+                let path_in = entry.unwrap().path();
+                let metadata = fs::symlink_metadata(&path_in).unwrap();
+                let relative = path_in.strip_prefix(path).unwrap().to_path_buf();
+                if metadata.is_dir() {
+                    directories.push(path_in);
+                    tree.push((relative, None));
+                } else if metadata.is_file() {
+                    tree.push((relative, Some(fs::read(&path_in).unwrap())));
+                } else {
+                    tree.push((relative, None));
+                }
+            }
+        }
+        tree.sort();
+        tree
+    }
+
+    /// Layers of made up entries, with paths and link targets mixing `..`, absolute paths,
+    /// symlinks to symlinks, and names reused as something else, never leave a trace outside
+    /// the destination, whichever entries are refused
+    #[test]
+    fn generated_layers_stay_in_the_destination() {
+        let directory = TempDir::new().unwrap();
+        // Nested so that symlinks with a couple of `..`s in them still land in the directory
+        let guard = directory.path().join("guard");
+        let outside = guard.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("target"), "host").unwrap();
+        let outside_path = outside.display().to_string();
+        let outside_target = outside.join("target").display().to_string();
+        let before = tree(directory.path());
+
+        let mut generator = Generator(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..300 {
+            let destination = guard.join("rootfs");
+            fs::create_dir(&destination).unwrap();
+            let mut paths = Vec::new();
+            let mut targets = Vec::new();
+            for _ in 0..1 + generator.below(8) {
+                let mut path = String::from(generator.pick(&["", "/", "./", "//"]));
+                for i in 0..1 + generator.below(3) {
+                    if i > 0 {
+                        path.push('/');
+                    }
+                    path.push_str(generator.pick(&["a", "b", "..", ".", "a", "b", "c"]));
+                }
+                paths.push(path);
+                targets.push(
+                    generator
+                        .pick(&[
+                            "a",
+                            "b",
+                            "a/b",
+                            "..",
+                            "../..",
+                            "../outside",
+                            "../outside/target",
+                            "/outside",
+                            "/",
+                            &outside_path,
+                            &outside_target,
+                        ])
+                        .to_string(),
+                );
+            }
+            let entries: Vec<Entry> = paths
+                .iter()
+                .zip(&targets)
+                .map(|(path, target)| match generator.below(4) {
+                    0 => Entry::File(path, "layer"),
+                    1 => Entry::Directory(path),
+                    2 => Entry::Symlink(path, target),
+                    _ => Entry::Hardlink(path, target),
+                })
+                .collect();
+
+            let layer = layer(&entries);
+            let mut archive = tar::Archive::new(&layer[..]);
+            archive.set_preserve_permissions(true);
+            let options = UnpackOptions {
+                allow_devices: false,
+                xattrs: Xattrs::Skip,
+                ids: None,
+            };
+            for entry in archive.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let _ = unpack_entry(&mut entry, &destination, &options, &mut Skipped::default());
+            }
+
+            assert_eq!(fs::metadata(outside.join("target")).unwrap().nlink(), 1);
+            fs::remove_dir_all(&destination).unwrap();
+            assert_eq!(tree(directory.path()), before, "unpacking {:?}", paths);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+
+use crate::digest::HashingReader;
+use crate::manifest::Descriptor;
+use crate::reference::ImageReference;
+use crate::store::Store;
+
+/// Something in the store that isn't what it should be
+#[derive(Debug)]
+pub struct Corruption {
+    /// `blob` or `layer`
+    pub kind: &'static str,
+    pub digest: String,
+    pub problem: String,
+}
+
+/// What `store verify` found, and what it removed with `--repair`
+#[derive(Debug, Default)]
+pub struct Verified {
+    pub blobs: usize,
+    pub layers: usize,
+    pub corrupted: Vec<Corruption>,
+    /// Chain IDs of the snapshots built from corrupted layers, which were removed along with them
+    pub snapshots: Vec<String>,
+    /// References to images that need a corrupted blob or layer, and have to be pulled again
+    pub affected: Vec<ImageReference>,
+}
+
+/// A layer some stored image needs, along with where it sits in each image that has it
+struct UsedLayer {
+    descriptor: Descriptor,
+    diff_id: String,
+    /// The chain IDs of that layer and of all the ones above it, in each image
+    snapshots: BTreeSet<String>,
+}
+
+/// Re-hashes every blob and compares every extracted layer with the blob it came from
+///
+/// With `repair`, whatever is corrupted is removed, along with the snapshots made from it, so
+/// that pulling the affected images again fetches and extracts it anew. The whole store is
+/// locked while this runs, since nothing can safely use it while it's being repaired.
+pub fn verify_store(store: &Store, repair: bool) -> Result<Verified> {
+    let _blobs = store.lock_blobs()?;
+    let mut verified = Verified::default();
+
+    let directory = store.root().join("blobs/sha256");
+    let entries = fs::read_dir(&directory)
+        .with_context(|| format!("Tried to list {}", directory.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let digest = format!("sha256:{}", path.file_name().unwrap().to_string_lossy());
+        let mut reader = HashingReader::new(
+            fs::File::open(&path).with_context(|| format!("Tried to open {}", path.display()))?,
+        );
+        io::copy(&mut reader, &mut io::sink())
+            .with_context(|| format!("Tried to read {}", path.display()))?;
+        let actual = reader.finalize();
+        verified.blobs += 1;
+        // Recompressed blobs are checked against the zstd data that replaced them
+        let expected = match store.recompressed(&digest)? {
+            Some(record) => record.zstd_digest,
+            None => digest.clone(),
+        };
+        if actual != expected {
+            verified.corrupted.push(Corruption {
+                kind: "blob",
+                digest,
+                problem: format!("its contents hash to {}", actual),
+            });
+        }
+    }
+    let corrupted_blobs: BTreeSet<String> = verified
+        .corrupted
+        .iter()
+        .map(|c| c.digest.clone())
+        .collect();
+
+    let used_layers = used_layers(store, &corrupted_blobs)?;
+    let directory = store.root().join("layers/sha256");
+    let entries = fs::read_dir(&directory)
+        .with_context(|| format!("Tried to list {}", directory.display()))?;
+    let mut corrupted_layers = BTreeSet::new();
+    for entry in entries {
+        let path = entry?.path();
+        // Half built layers are removed the next time the store is opened
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            continue;
+        }
+        let digest = format!("sha256:{}", path.file_name().unwrap().to_string_lossy());
+        // Layers nobody needs are left to `prune`
+        let layer = match used_layers.get(&digest) {
+            Some(layer) => layer,
+            None => continue,
+        };
+        // There's nothing to compare with, but it can't be trusted either
+        if corrupted_blobs.contains(&digest) {
+            corrupted_layers.insert(digest);
+            continue;
+        }
+        verified.layers += 1;
+        let problem = store
+            .verify_layer(&layer.descriptor, &layer.diff_id)
+            .with_context(|| format!("Tried to verify layer {}", digest))?;
+        if let Some(problem) = problem {
+            verified.corrupted.push(Corruption {
+                kind: "layer",
+                digest: digest.clone(),
+                problem,
+            });
+            corrupted_layers.insert(digest);
+        }
+    }
+
+    let corrupted: BTreeSet<&String> = verified.corrupted.iter().map(|c| &c.digest).collect();
+    let repositories = store.repositories()?;
+    for (name, stored_references) in &repositories.repositories {
+        let (registry, repository) = match name.split_once('/') {
+            Some(split) => split,
+            None => continue,
+        };
+        for (reference, stored) in stored_references {
+            let record = repositories.images.get(&stored.manifest);
+            let mut needed = [&stored.digest, &stored.manifest].into_iter().chain(
+                record
+                    .into_iter()
+                    .flat_map(|r| [&r.config].into_iter().chain(&r.layers)),
+            );
+            if !needed.any(|digest| corrupted.contains(digest)) {
+                continue;
+            }
+            let is_digest = reference.starts_with("sha256:");
+            verified.affected.push(ImageReference {
+                registry: registry.to_string(),
+                repository: repository.to_string(),
+                tag: (!is_digest).then(|| reference.clone()),
+                digest: is_digest.then(|| reference.clone()),
+            });
+        }
+    }
+
+    if repair {
+        for digest in &corrupted_blobs {
+            let path = store.blob_path(digest)?;
+            fs::remove_file(&path)
+                .with_context(|| format!("Tried to remove {}", path.display()))?;
+        }
+        let mut snapshots = BTreeSet::new();
+        for digest in &corrupted_layers {
+            let path = store.layer_path(digest)?;
+            fs::remove_dir_all(&path)
+                .with_context(|| format!("Tried to remove {}", path.display()))?;
+            snapshots.extend(used_layers[digest].snapshots.iter().cloned());
+        }
+        for chain_id in snapshots {
+            let path = store.snapshot_path(&chain_id)?;
+            if path.is_dir() {
+                fs::remove_dir_all(&path)
+                    .with_context(|| format!("Tried to remove {}", path.display()))?;
+                verified.snapshots.push(chain_id);
+            }
+        }
+    }
+    Ok(verified)
+}
+
+/// Every layer of every stored image, by digest, skipping images whose manifest or config is
+/// corrupted since there's no telling what their layers should be
+fn used_layers(
+    store: &Store,
+    corrupted_blobs: &BTreeSet<String>,
+) -> Result<BTreeMap<String, UsedLayer>> {
+    let mut layers: BTreeMap<String, UsedLayer> = BTreeMap::new();
+    let repositories = store.repositories()?;
+    for stored in repositories.repositories.values().flat_map(|r| r.values()) {
+        if corrupted_blobs.contains(&stored.manifest) {
+            continue;
+        }
+        let manifest = match store.image_manifest(stored) {
+            Ok(manifest) => manifest,
+            Err(_) => continue,
+        };
+        if corrupted_blobs.contains(&manifest.config.digest) {
+            continue;
+        }
+        let config = match store.image_config(&manifest) {
+            Ok(config) => config,
+            Err(_) => continue,
+        };
+        let chain_ids = config.rootfs.chain_ids();
+        for (i, (descriptor, diff_id)) in manifest
+            .layers
+            .iter()
+            .zip(&config.rootfs.diff_ids)
+            .enumerate()
+        {
+            let layer = layers
+                .entry(descriptor.digest.clone())
+                .or_insert_with(|| UsedLayer {
+                    descriptor: descriptor.clone(),
+                    diff_id: diff_id.clone(),
+                    snapshots: BTreeSet::new(),
+                });
+            layer.snapshots.extend(chain_ids[i..].iter().cloned());
+        }
+    }
+    Ok(layers)
+}
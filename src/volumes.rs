@@ -0,0 +1,170 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cli::{Propagation, Volume, VolumeSource};
+use crate::digest::sha256_digest;
+use crate::image::ContainerConfig;
+use crate::images::format_timestamp;
+use crate::rootfs::{copy_layer, resolve_in_rootfs, FileCopy};
+use crate::store::{disk_usage, Store};
+
+/// A named volume, kept under `volumes/<name>/_data` in the store
+#[derive(Debug)]
+pub struct NamedVolume {
+    pub name: String,
+    /// Where its contents are, what gets mounted in containers
+    pub mountpoint: PathBuf,
+    pub created: u64,
+    /// Whether a running container has it mounted
+    pub in_use: bool,
+}
+
+impl NamedVolume {
+    /// Shaped like `docker volume inspect` describes them
+    ///
+    /// See: https://docs.docker.com/reference/cli/docker/volume/inspect/
+    pub fn inspect(&self) -> serde_json::Value {
+        serde_json::json!({
+            "CreatedAt": format_timestamp(self.created),
+            "Driver": "local",
+            "Labels": null,
+            "Mountpoint": self.mountpoint,
+            "Name": self.name,
+            "Options": null,
+            "Scope": "local",
+        })
+    }
+
+    /// How much space its contents take up
+    pub fn size(&self) -> Result<u64> {
+        disk_usage(&self.mountpoint)
+    }
+}
+
+/// Creates a volume, with a random name like docker's anonymous volumes if none is given
+///
+/// Creating one that's already there is fine, it's just left as it is.
+pub fn create_volume(store: &Store, name: Option<&str>) -> Result<NamedVolume> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => random_name(),
+    };
+    let _volumes = store.lock_volumes()?;
+    let data = store.volume_path(&name)?.join("_data");
+    fs::create_dir_all(&data).with_context(|| format!("Tried to create {}", data.display()))?;
+    find_volume(store, &name)
+}
+
+/// Every named volume, sorted by name
+pub fn list_volumes(store: &Store) -> Result<Vec<NamedVolume>> {
+    let directory = store.root().join("volumes");
+    let entries = fs::read_dir(&directory)
+        .with_context(|| format!("Tried to list {}", directory.display()))?;
+    let mut volumes = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        volumes.push(find_volume(store, &name)?);
+    }
+    volumes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(volumes)
+}
+
+pub fn find_volume(store: &Store, name: &str) -> Result<NamedVolume> {
+    let path = store.volume_path(name)?;
+    // Its directory is only written to when it's created, so it's been modified since then
+    let metadata = match fs::metadata(path.join("_data")) {
+        Ok(_) => fs::metadata(&path)?,
+        Err(_) => bail!("No such volume: {}", name),
+    };
+    Ok(NamedVolume {
+        name: name.to_string(),
+        mountpoint: path.join("_data"),
+        created: metadata.mtime().max(0) as u64,
+        in_use: store.volume_in_use(name),
+    })
+}
+
+/// Removes a volume along with its contents, unless a running container has it mounted
+pub fn remove_volume(store: &Store, name: &str) -> Result<()> {
+    let _volumes = store.lock_volumes()?;
+    let volume = find_volume(store, name)?;
+    if volume.in_use {
+        bail!("Volume {} is in use by a running container", name);
+    }
+    let path = store.volume_path(name)?;
+    fs::remove_dir_all(&path).with_context(|| format!("Tried to remove {}", path.display()))?;
+    store.forget_volume(name)
+}
+
+/// Gets a volume ready to be mounted on `destination` in a container's rootfs, creating it if
+/// there's no volume by that name yet
+///
+/// Like docker, a volume that's still empty is filled with whatever the image has at
+/// `destination`, ownership and permissions of the directory included, so mounting a volume
+/// where an image keeps its data doesn't hide that data. The returned lock marks the volume as
+/// in use, keep it for as long as the container runs.
+pub fn use_volume(
+    store: &Store,
+    name: &str,
+    rootfs: &Path,
+    destination: &str,
+) -> Result<(PathBuf, File)> {
+    let _volumes = store.lock_volumes()?;
+    let data = store.volume_path(name)?.join("_data");
+    fs::create_dir_all(&data).with_context(|| format!("Tried to create {}", data.display()))?;
+    let lock = store.share_volume(name)?;
+
+    let empty = fs::read_dir(&data)
+        .with_context(|| format!("Tried to list {}", data.display()))?
+        .next()
+        .is_none();
+    let source = resolve_in_rootfs(rootfs, Path::new(destination))?;
+    if empty && source.is_dir() {
+        copy_layer(&source, &data, FileCopy::Clone).with_context(|| {
+            format!(
+                "Tried to copy the image's {} into volume {}",
+                destination, name
+            )
+        })?;
+        let metadata = fs::metadata(&source)?;
+        lchown(&data, Some(metadata.uid()), Some(metadata.gid()))
+            .and_then(|()| fs::set_permissions(&data, metadata.permissions()))
+            .with_context(|| format!("Tried to set the owner of volume {}", name))?;
+    }
+    Ok((data, lock))
+}
+
+/// The anonymous volumes a container gets for the paths its image declares in `Volumes`, leaving
+/// out paths `volumes` (from `-v`) already mount something on
+pub fn anonymous_volumes(config: &ContainerConfig, volumes: &[Volume]) -> Vec<Volume> {
+    let taken = |path: &str| {
+        volumes
+            .iter()
+            .any(|volume| volume.destination.trim_end_matches('/') == path.trim_end_matches('/'))
+    };
+    config
+        .volumes
+        .iter()
+        .flat_map(|paths| paths.keys())
+        .filter(|path| !taken(path))
+        .map(|path| Volume {
+            source: VolumeSource::Named(random_name()),
+            destination: path.clone(),
+            read_only: false,
+            propagation: Propagation::RPrivate,
+        })
+        .collect()
+}
+
+/// 64 hex digits, like the names docker gives volumes nobody named
+fn random_name() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let digest = sha256_digest(format!("volume {} {}", std::process::id(), nanos).as_bytes());
+    digest.trim_start_matches("sha256:").to_string()
+}